@@ -0,0 +1,25 @@
+MEMORY
+{
+  /* NOTE K = KiBi = 1024 bytes */
+  /* Sectors 0 and 1, the rest of flash is laid out in rtic_stm32/src/boot.rs */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 32K
+  RAM : ORIGIN = 0x20000000, LENGTH = 128K
+}
+
+/* This is where the call stack will be allocated. */
+/* The stack is of the full descending type. */
+/* NOTE Do NOT modify `_stack_start` unless you know what you are doing */
+/* _stack_start = ORIGIN(RAM) + LENGTH(RAM); */
+
+/* The image header (rtic_stm32/src/header.rs) goes at a fixed offset from the start of the image, */
+/* right after the vector table, so tools can find it in a .bin file. */
+SECTIONS
+{
+  .image_header ORIGIN(FLASH) + 0x200 :
+  {
+    KEEP(*(.image_header));
+  } > FLASH
+} INSERT AFTER .vector_table;
+
+/* The code starts after the header */
+_stext = ORIGIN(FLASH) + 0x400;
@@ -0,0 +1,8 @@
+//! The parts of the CAN bootloader that do not touch the hardware, so they can be tested on the
+//! host with `cargo test --lib --target x86_64-unknown-linux-gnu`
+
+#![no_std]
+
+pub mod protocol; // the update protocol on the CAN bus
+pub mod slots; // copying and checking slots, the update state machine
+pub mod update; // receiving an image into the download slot
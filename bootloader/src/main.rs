@@ -0,0 +1,163 @@
+//! CAN bootloader for the STM32F446
+//!
+//! Sits in sectors 0 and 1 and listens on CAN for `LISTEN_MS` after every reset. If the host
+//! starts talking it stays until the host sends `Boot` or goes quiet for `IDLE_MS`, see
+//! `protocol.rs` for the frames. A download that was cut off is dropped, the confirmed image boots.
+//! Afterwards it runs the update state machine from `stm32f446_rtic::boot` (install a pending
+//! image, count boots of an unconfirmed one, roll back) and jumps to the primary slot.
+//!
+//! Applications for the bootloader are built with the `bootloader` feature of `stm32f446-rtic`
+//! so they are linked for the primary slot, and have to call `boot::confirm` once they are up.
+//! A new image is started with the independent watchdog running, the application has to feed it
+//! at least every `boot::WATCHDOG_MS`.
+
+#![no_main]
+#![no_std]
+
+use stm32f446_rtic as _; // global logger + panicking-behavior
+
+use bxcan::filter::Mask32;
+use bxcan::{Fifo, Frame, Id, StandardId};
+use cortex_m::peripheral::{DWT, SCB};
+use cortex_m_rt::entry;
+use stm32f446_bootloader::protocol::{Request, REPLY_ID};
+use stm32f446_bootloader::{slots, update};
+use stm32f446_rtic::boot::{self, BootState, APP_ADDRESS, WATCHDOG_MS};
+use stm32f446_rtic::config::{self, keys, ConfigStore};
+use stm32f446_rtic::flash::InternalFlash;
+use stm32f4xx_hal::{pac, prelude::*, watchdog::IndependentWatchdog};
+
+/// How long to wait for a download to start before booting the application
+const LISTEN_MS: u32 = 500;
+
+/// How long the host can go quiet once it has started before the bootloader gives up on it
+const IDLE_MS: u32 = 5_000;
+
+const SYSCLK_HZ: u32 = 45_000_000; // 45 MHz is the max for CAN since it has to match the APB1 clock
+
+#[entry]
+fn main() -> ! {
+    // Cortex-M peripherals
+    let mut core = cortex_m::Peripherals::take().unwrap();
+
+    // Device specific peripherals
+    let device = pac::Peripherals::take().unwrap();
+
+    stm32f446_rtic::image::self_check();
+
+    // Set up the system clock.
+    let rcc = device.RCC.constrain();
+    let _clocks = rcc.cfgr.sysclk(SYSCLK_HZ.Hz()).freeze();
+
+    // The cycle counter times the listen window
+    core.DCB.enable_trace();
+    core.DWT.enable_cycle_counter();
+
+    let mut store = ConfigStore::new(InternalFlash::new(device.FLASH), config::SECTORS);
+    if store.load().is_err() {
+        defmt::error!("could not read the configuration");
+    }
+    defmt::info!("bootloader, boot state {}", boot::state(&store));
+
+    // Set up CAN device 1 like in the examples, PA11/PA12 alternate function 9
+    let gpioa = device.GPIOA.split();
+    let mut can = {
+        let rx = gpioa.pa11.into_alternate::<9>();
+        let tx = gpioa.pa12.into_alternate::<9>();
+        let can = device.CAN1.can((tx, rx));
+
+        bxcan::Can::builder(can)
+            .set_bit_timing(store.get_u32(keys::CAN_BIT_TIMING).unwrap_or(0x001b_0002))
+            .set_automatic_retransmit(true)
+            .enable()
+    };
+    can.modify_filters()
+        .enable_bank(0, Fifo::Fifo0, Mask32::accept_all());
+
+    // Listen for a download, once one has started wait for the host to say boot. The cycle
+    // counter wraps after 95 s, both windows are well within that.
+    let mut session = update::Session::new();
+    let mut last = DWT::cycle_count();
+    let mut window = SYSCLK_HZ / 1_000 * LISTEN_MS;
+    loop {
+        if DWT::cycle_count().wrapping_sub(last) > window {
+            if session.is_receiving() {
+                // start erased the download slot and left any pending image behind, so this
+                // boots the confirmed one
+                defmt::warn!("download abandoned, booting the current image");
+            }
+            break;
+        }
+        let frame = match can.receive() {
+            Ok(frame) => frame,
+            Err(_) => continue,
+        };
+        let id = match frame.id() {
+            Id::Standard(id) => id.as_raw(),
+            Id::Extended(_) => continue,
+        };
+        let request = match frame.data().and_then(|data| Request::parse(id, data)) {
+            Some(request) => request,
+            None => continue,
+        };
+
+        let reply = session.handle(&mut store, request);
+        let reply = Frame::new_data(StandardId::new(REPLY_ID).unwrap(), reply.encode());
+        // the host waits for each reply, the mailboxes only fill up once it is gone and then a
+        // reply is dropped instead of waiting forever for room
+        can.transmit(&reply).ok();
+        // counted from the reply, an erase takes a while
+        last = DWT::cycle_count();
+        window = SYSCLK_HZ / 1_000 * IDLE_MS;
+        if request == Request::Boot {
+            // let the reply get out before the CAN peripheral is left behind
+            while !can.is_transmitter_idle() {}
+            break;
+        }
+    }
+
+    // Run the update state machine until it says boot
+    let state = match slots::run(&mut store) {
+        Ok(state) => state,
+        // nothing sensible left to do, a reset or a power cycle will retry
+        Err(_) => defmt::panic!("installing the update failed in boot state {}", boot::state(&store)),
+    };
+    defmt::info!("starting application, boot state {}", state);
+
+    // A new image that hangs before it confirms itself gets reset, which counts as one of its
+    // boots. The watchdog can't be stopped again, the application feeds it from here on.
+    if let BootState::Testing { .. } = state {
+        let mut watchdog = IndependentWatchdog::new(device.IWDG);
+        watchdog.start(WATCHDOG_MS.millis());
+    }
+
+    // leave the CAN peripheral for the application to set up again
+    drop(can);
+    unsafe { start_app(&mut core.SCB) }
+}
+
+/// Point the vector table at the application and jump to its reset handler
+unsafe fn start_app(scb: &mut SCB) -> ! {
+    cortex_m::interrupt::disable();
+    reset_clocks();
+    scb.vtor.write(APP_ADDRESS as u32);
+    cortex_m::asm::bootload(APP_ADDRESS as *const u32)
+}
+
+/// Put the clocks back the way the application finds them after a reset: running from the HSI
+/// with the PLL off, no prescalers and no peripheral clocks. The HAL of the application assumes
+/// that when it sets up its own clocks and can't change the PLL while it is running.
+unsafe fn reset_clocks() {
+    let rcc = &*pac::RCC::ptr();
+    rcc.cr.modify(|_, w| w.hsion().set_bit());
+    while rcc.cr.read().hsirdy().bit_is_clear() {}
+    rcc.cfgr.modify(|_, w| w.sw().hsi());
+    while !rcc.cfgr.read().sws().is_hsi() {}
+    rcc.cfgr.reset();
+    rcc.cr.modify(|_, w| w.pllon().clear_bit());
+    while rcc.cr.read().pllrdy().bit_is_set() {}
+    rcc.pllcfgr.reset();
+    rcc.ahb1enr.reset();
+    rcc.apb1enr.reset();
+    rcc.apb2enr.reset();
+}
@@ -0,0 +1,119 @@
+//! The update protocol on the CAN bus
+//!
+//! The host sends commands on `COMMAND_ID` and image data on `DATA_ID`, the bootloader answers
+//! every frame it acts on with a reply on `REPLY_ID`. All numbers are little endian.
+//!
+//! | frame                            | meaning                                              |
+//! |----------------------------------|------------------------------------------------------|
+//! | `COMMAND_ID [0x01, len u32]`     | start a download of `len` bytes, erases the slot     |
+//! | `DATA_ID [seq u16, 1-6 bytes]`   | image bytes at offset `seq * 6`                      |
+//! | `COMMAND_ID [0x03, part, 6 b]`   | SHA-256 of the image, bytes `part * 6 ..`, parts 0-5 |
+//! | `COMMAND_ID [0x04, crc u32]`     | all sent, check CRC-32 and SHA-256 and mark pending  |
+//! | `COMMAND_ID [0x05]`              | leave the bootloader, installs a pending image       |
+//! | `COMMAND_ID [0x06]`              | ask for the boot state                               |
+//! | `REPLY_ID [op, status, arg u32]` | `op` is the command answered, 0x02 for data          |
+//!
+//! Data is acknowledged frame by frame with the next sequence number expected in `arg`, the host
+//! waits for the ack before sending the next frame. A frame with the wrong sequence number gets
+//! `OutOfOrder` and the host carries on from `arg`.
+
+pub const COMMAND_ID: u16 = 0x7E0;
+pub const DATA_ID: u16 = 0x7E1;
+pub const REPLY_ID: u16 = 0x7E8;
+
+/// Image bytes carried by one data frame
+pub const CHUNK_LEN: usize = 6;
+
+/// Number of digest frames needed for the 32 byte SHA-256
+pub const DIGEST_PARTS: u8 = 6;
+
+pub mod op {
+    pub const START: u8 = 0x01;
+    pub const DATA: u8 = 0x02;
+    pub const DIGEST: u8 = 0x03;
+    pub const FINISH: u8 = 0x04;
+    pub const BOOT: u8 = 0x05;
+    pub const STATUS: u8 = 0x06;
+}
+
+/// A frame from the host
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Request<'a> {
+    Start { len: u32 },
+    Data { seq: u16, bytes: &'a [u8] },
+    Digest { part: u8, bytes: &'a [u8] },
+    Finish { crc: u32 },
+    Boot,
+    Status,
+}
+
+impl<'a> Request<'a> {
+    /// `None` for frames that are not for the bootloader or are malformed
+    pub fn parse(id: u16, data: &'a [u8]) -> Option<Self> {
+        let u32_at = |at: usize| -> Option<u32> {
+            let bytes = data.get(at..at + 4)?;
+            Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        };
+
+        if id == DATA_ID {
+            if data.len() < 3 {
+                return None;
+            }
+            return Some(Request::Data {
+                seq: u16::from_le_bytes([data[0], data[1]]),
+                bytes: &data[2..],
+            });
+        }
+        if id != COMMAND_ID {
+            return None;
+        }
+
+        match *data.first()? {
+            op::START => Some(Request::Start { len: u32_at(1)? }),
+            op::DIGEST if data.len() >= 3 => Some(Request::Digest {
+                part: data[1],
+                bytes: &data[2..],
+            }),
+            op::FINISH => Some(Request::Finish { crc: u32_at(1)? }),
+            op::BOOT => Some(Request::Boot),
+            op::STATUS => Some(Request::Status),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Status {
+    Ok = 0,
+    /// The image does not fit in a slot, more data than announced or a short frame before the last
+    BadLength = 1,
+    /// Data frame with an unexpected sequence number, `arg` is the one expected
+    OutOfOrder = 2,
+    /// Erasing or programming failed
+    Flash = 3,
+    BadCrc = 4,
+    BadDigest = 5,
+    /// Data, digest or finish without a start
+    NotStarted = 6,
+    /// An update is being installed or tested, a new one has to wait
+    Busy = 7,
+}
+
+/// Answer to a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Reply {
+    pub op: u8,
+    pub status: Status,
+    pub arg: u32,
+}
+
+impl Reply {
+    pub fn new(op: u8, status: Status, arg: u32) -> Self {
+        Reply { op, status, arg }
+    }
+
+    pub fn encode(&self) -> [u8; 6] {
+        let arg = self.arg.to_le_bytes();
+        [self.op, self.status as u8, arg[0], arg[1], arg[2], arg[3]]
+    }
+}
@@ -0,0 +1,271 @@
+//! Copying and checking firmware slots, and running the update state machine over them
+
+use sha2::{Digest, Sha256};
+use stm32f446_rtic::boot::{self, BootState, Step, Trailer, DOWNLOAD_SECTOR, SLOT_SIZE};
+use stm32f446_rtic::config::{ConfigError, ConfigStore};
+use stm32f446_rtic::crc::Crc32;
+use stm32f446_rtic::flash::Flash;
+
+const CHUNK: usize = 256;
+
+/// Run `boot::next_step` until it says boot and return the state the application starts in
+///
+/// A failed copy is an error, running it again after a reset repeats the step it stopped in.
+pub fn run<F: Flash>(store: &mut ConfigStore<F>) -> Result<BootState, ConfigError<F::Error>> {
+    loop {
+        let mut state = boot::state(store);
+        if state == BootState::Pending {
+            match verify(store.flash(), DOWNLOAD_SECTOR) {
+                Ok(Some(_)) => {}
+                _ => {
+                    defmt::warn!("pending image is damaged, ignoring it");
+                    state = BootState::Confirmed;
+                }
+            }
+        }
+
+        match boot::next_step(state) {
+            Step::Boot { state } => {
+                if boot::set_state(store, state).is_err() {
+                    defmt::error!("could not store boot state {}", state);
+                }
+                return Ok(state);
+            }
+            Step::Copy { from, to, then } => {
+                defmt::info!("copying sector {} to sector {}", from, to);
+                copy(store.flash_mut(), from, to).map_err(ConfigError::Flash)?;
+                boot::set_state(store, then)?;
+            }
+        }
+    }
+}
+
+/// Erase slot `to` and copy all of slot `from` into it, trailer included
+pub fn copy<F: Flash>(flash: &mut F, from: u8, to: u8) -> Result<(), F::Error> {
+    flash.erase(to)?;
+    let mut buf = [0u8; CHUNK];
+    for offset in (0..SLOT_SIZE).step_by(CHUNK) {
+        flash.read(from, offset, &mut buf)?;
+        // the end of a slot is mostly erased, no need to program that
+        if buf.iter().any(|&byte| byte != 0xFF) {
+            flash.program(to, offset, &buf)?;
+        }
+    }
+    Ok(())
+}
+
+/// The trailer of the slot if the image in it matches its CRC and SHA-256
+pub fn verify<F: Flash>(flash: &F, sector: u8) -> Result<Option<Trailer>, F::Error> {
+    let trailer = match Trailer::read(flash, sector)? {
+        Some(trailer) => trailer,
+        None => return Ok(None),
+    };
+    let (crc, sha256) = digest(flash, sector, trailer.len as usize)?;
+    if crc == trailer.crc && sha256 == trailer.sha256 {
+        Ok(Some(trailer))
+    } else {
+        Ok(None)
+    }
+}
+
+/// CRC-32 and SHA-256 of the first `len` bytes of a slot, as they are in flash
+pub fn digest<F: Flash>(flash: &F, sector: u8, len: usize) -> Result<(u32, [u8; 32]), F::Error> {
+    let mut crc = Crc32::new();
+    let mut sha = Sha256::new();
+    let mut buf = [0u8; CHUNK];
+    for offset in (0..len).step_by(CHUNK) {
+        let chunk = &mut buf[..CHUNK.min(len - offset)];
+        flash.read(sector, offset, chunk)?;
+        crc.update(chunk);
+        sha.update(&*chunk);
+    }
+    Ok((crc.finish(), sha.finalize().into()))
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::vec::Vec;
+    use stm32f446_rtic::boot::{BACKUP_SECTOR, MAX_BOOT_ATTEMPTS, PRIMARY_SECTOR};
+    use stm32f446_rtic::config::{self, Loaded};
+    use stm32f446_rtic::crc::crc32;
+    use stm32f446_rtic::flash::SimFlash;
+
+    fn image(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed)).collect()
+    }
+
+    fn trailer(image: &[u8]) -> Trailer {
+        Trailer {
+            len: image.len() as u32,
+            crc: crc32(image),
+            sha256: Sha256::digest(image).into(),
+        }
+    }
+
+    fn put(flash: &mut SimFlash, sector: u8, image: &[u8], trailer: Trailer) {
+        flash.erase(sector).unwrap();
+        flash.program(sector, 0, image).unwrap();
+        flash.program(sector, Trailer::OFFSET, &trailer.encode()).unwrap();
+    }
+
+    fn holds(flash: &SimFlash, sector: u8, image: &[u8]) -> bool {
+        flash.sector(sector)[..image.len()] == *image
+            && Trailer::read(flash, sector).unwrap() == Some(trailer(image))
+    }
+
+    fn reset(store: ConfigStore<SimFlash>) -> ConfigStore<SimFlash> {
+        let mut flash = store.free();
+        flash.restore_power();
+        let mut store = ConfigStore::new(flash, config::SECTORS);
+        assert!(matches!(store.load(), Ok(Loaded::Stored { .. } | Loaded::Defaults)));
+        store
+    }
+
+    /// Image `old` confirmed in the primary slot and `new` waiting in the download slot
+    fn pending(old: &[u8], new: &[u8]) -> ConfigStore<SimFlash> {
+        let mut flash = SimFlash::stm32f446();
+        put(&mut flash, PRIMARY_SECTOR, old, trailer(old));
+        put(&mut flash, DOWNLOAD_SECTOR, new, trailer(new));
+        let mut store = ConfigStore::new(flash, config::SECTORS);
+        store.load().unwrap();
+        boot::set_state(&mut store, BootState::Pending).unwrap();
+        store
+    }
+
+    #[test]
+    fn copy_and_verify() {
+        let mut flash = SimFlash::stm32f446();
+        let old = image(1000, 1);
+        put(&mut flash, PRIMARY_SECTOR, &old, trailer(&old));
+        assert_eq!(verify(&flash, PRIMARY_SECTOR), Ok(Some(trailer(&old))));
+        assert_eq!(verify(&flash, BACKUP_SECTOR), Ok(None));
+
+        copy(&mut flash, PRIMARY_SECTOR, BACKUP_SECTOR).unwrap();
+        assert!(holds(&flash, BACKUP_SECTOR, &old));
+        assert_eq!(verify(&flash, BACKUP_SECTOR), Ok(Some(trailer(&old))));
+        assert_eq!(digest(&flash, BACKUP_SECTOR, old.len()), Ok((crc32(&old), Sha256::digest(&old).into())));
+    }
+
+    #[test]
+    fn nothing_to_do() {
+        let old = image(500, 1);
+        let mut flash = SimFlash::stm32f446();
+        put(&mut flash, PRIMARY_SECTOR, &old, trailer(&old));
+        let mut store = ConfigStore::new(flash, config::SECTORS);
+        store.load().unwrap();
+        assert_eq!(run(&mut store), Ok(BootState::Confirmed));
+        assert_eq!(store.sequence(), 0, "a boot that changes nothing writes nothing");
+    }
+
+    #[test]
+    fn install_and_confirm() {
+        let (old, new) = (image(500, 1), image(700, 2));
+        let mut store = pending(&old, &new);
+        assert_eq!(run(&mut store), Ok(BootState::Testing { attempts: 1 }));
+        assert!(holds(store.flash(), PRIMARY_SECTOR, &new));
+        assert!(holds(store.flash(), BACKUP_SECTOR, &old));
+
+        boot::confirm(&mut store).unwrap();
+        let mut store = reset(store);
+        assert_eq!(boot::state(&store), BootState::Confirmed);
+        assert_eq!(run(&mut store), Ok(BootState::Confirmed));
+        assert!(holds(store.flash(), PRIMARY_SECTOR, &new));
+    }
+
+    #[test]
+    fn power_cut_during_install() {
+        let (old, new) = (image(300, 1), image(400, 2));
+        for cut in 0.. {
+            let mut store = pending(&old, &new);
+            store.flash_mut().fail_after(cut);
+            let first = run(&mut store);
+            if !store.flash().lost_power() {
+                assert_eq!(first, Ok(BootState::Testing { attempts: 1 }));
+                assert!(cut > 1_000, "the install wrote only {} bytes", cut);
+                break;
+            }
+
+            // the next boot finishes what the last one started
+            let mut store = reset(store);
+            assert_eq!(run(&mut store), Ok(BootState::Testing { attempts: 1 }), "cut at byte {}", cut);
+            assert!(holds(store.flash(), PRIMARY_SECTOR, &new), "cut at byte {}", cut);
+            assert!(holds(store.flash(), BACKUP_SECTOR, &old), "cut at byte {}", cut);
+        }
+    }
+
+    #[test]
+    fn unconfirmed_image_is_rolled_back() {
+        let (old, new) = (image(500, 1), image(700, 2));
+        let mut store = pending(&old, &new);
+        for attempts in 1..=MAX_BOOT_ATTEMPTS {
+            let mut next = reset(store);
+            assert_eq!(run(&mut next), Ok(BootState::Testing { attempts }));
+            assert!(holds(next.flash(), PRIMARY_SECTOR, &new));
+            store = next;
+        }
+
+        let mut store = reset(store);
+        assert_eq!(run(&mut store), Ok(BootState::RolledBack));
+        assert!(holds(store.flash(), PRIMARY_SECTOR, &old));
+        // and it stays that way
+        let mut store = reset(store);
+        assert_eq!(run(&mut store), Ok(BootState::RolledBack));
+        boot::confirm(&mut store).unwrap();
+        assert_eq!(boot::state(&store), BootState::Confirmed);
+    }
+
+    #[test]
+    fn power_cut_during_rollback() {
+        let (old, new) = (image(300, 1), image(400, 2));
+        let mut store = pending(&old, &new);
+        run(&mut store).unwrap();
+        boot::set_state(&mut store, BootState::Testing { attempts: MAX_BOOT_ATTEMPTS }).unwrap();
+        let flash = store.free();
+
+        for cut in 0.. {
+            let mut store = ConfigStore::new(flash.clone(), config::SECTORS);
+            store.load().unwrap();
+            store.flash_mut().fail_after(cut);
+            let first = run(&mut store);
+            let lost = store.flash().lost_power();
+            let mut store = reset(store);
+            assert_eq!(run(&mut store), Ok(BootState::RolledBack), "cut at byte {}", cut);
+            assert!(holds(store.flash(), PRIMARY_SECTOR, &old), "cut at byte {}", cut);
+            if !lost {
+                assert_eq!(first, Ok(BootState::RolledBack));
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn bad_trailer_crc() {
+        let (old, new) = (image(500, 1), image(700, 2));
+        let mut store = pending(&old, &new);
+        let mut bad = trailer(&new);
+        bad.crc ^= 1;
+        put(store.flash_mut(), DOWNLOAD_SECTOR, &new, bad);
+        assert_eq!(verify(store.flash(), DOWNLOAD_SECTOR), Ok(None));
+
+        assert_eq!(run(&mut store), Ok(BootState::Confirmed));
+        assert!(holds(store.flash(), PRIMARY_SECTOR, &old));
+        assert_eq!(verify(store.flash(), BACKUP_SECTOR), Ok(None), "nothing was copied");
+    }
+
+    #[test]
+    fn damaged_download() {
+        let (old, new) = (image(500, 1), image(700, 2));
+        let mut store = pending(&old, &new);
+        store.flash_mut().sector_mut(DOWNLOAD_SECTOR)[100] ^= 0x10;
+        assert_eq!(run(&mut store), Ok(BootState::Confirmed));
+        assert!(holds(store.flash(), PRIMARY_SECTOR, &old));
+
+        // and a trailer that was never written
+        let mut store = pending(&old, &new);
+        store.flash_mut().sector_mut(DOWNLOAD_SECTOR)[Trailer::OFFSET..].fill(0xFF);
+        assert_eq!(run(&mut store), Ok(BootState::Confirmed));
+    }
+}
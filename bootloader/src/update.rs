@@ -0,0 +1,299 @@
+//! Receiving an image into the download slot
+//!
+//! The frames only ever fill the slot front to back: every one but the last carries `CHUNK_LEN`
+//! bytes and starts where the one before ended. `Finish` checks the CRC-32 and SHA-256 of what
+//! is in flash, not of what came in, so a byte that did not program shows up there.
+
+use crate::protocol::{op, Reply, Request, Status, CHUNK_LEN, DIGEST_PARTS};
+use crate::slots;
+use stm32f446_rtic::boot::{self, BootState, Trailer, DOWNLOAD_SECTOR, MAX_IMAGE_LEN};
+use stm32f446_rtic::config::ConfigStore;
+use stm32f446_rtic::flash::Flash;
+
+/// One download, from `Start` to `Finish`
+pub struct Session {
+    receiving: bool,
+    len: u32,
+    received: u32,
+    next_seq: u16,
+    digest: [u8; 32],
+    digest_parts: u8, // bit n set when part n has arrived
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Session {
+            receiving: false,
+            len: 0,
+            received: 0,
+            next_seq: 0,
+            digest: [0; 32],
+            digest_parts: 0,
+        }
+    }
+
+    /// Between `Start` and a good `Finish`
+    pub fn is_receiving(&self) -> bool {
+        self.receiving
+    }
+
+    /// Act on a request, `Boot` is left to the caller
+    pub fn handle<F: Flash>(&mut self, store: &mut ConfigStore<F>, request: Request) -> Reply {
+        match request {
+            Request::Start { len } => self.start(store, len),
+            Request::Data { seq, bytes } => self.data(store.flash_mut(), seq, bytes),
+            Request::Digest { part, bytes } => self.digest(part, bytes),
+            Request::Finish { crc } => self.finish(store, crc),
+            Request::Boot => Reply::new(op::BOOT, Status::Ok, 0),
+            Request::Status => Reply::new(op::STATUS, Status::Ok, boot::state(store).to_u32()),
+        }
+    }
+
+    fn start<F: Flash>(&mut self, store: &mut ConfigStore<F>, len: u32) -> Reply {
+        if len == 0 || len as usize > MAX_IMAGE_LEN {
+            return Reply::new(op::START, Status::BadLength, MAX_IMAGE_LEN as u32);
+        }
+        match boot::state(store) {
+            BootState::Confirmed | BootState::RolledBack => {}
+            // the waiting image is about to be overwritten
+            BootState::Pending => {
+                if boot::set_state(store, BootState::Confirmed).is_err() {
+                    return Reply::new(op::START, Status::Flash, 0);
+                }
+            }
+            state => return Reply::new(op::START, Status::Busy, state.to_u32()),
+        }
+
+        *self = Session::new();
+        if store.flash_mut().erase(DOWNLOAD_SECTOR).is_err() {
+            return Reply::new(op::START, Status::Flash, 0);
+        }
+        self.receiving = true;
+        self.len = len;
+        defmt::info!("download of {} bytes started", len);
+        Reply::new(op::START, Status::Ok, len)
+    }
+
+    fn data<F: Flash>(&mut self, flash: &mut F, seq: u16, bytes: &[u8]) -> Reply {
+        if !self.receiving {
+            return Reply::new(op::DATA, Status::NotStarted, 0);
+        }
+        // the ack got lost and the host sent the last frame again
+        if self.received > 0 && seq.wrapping_add(1) == self.next_seq {
+            return Reply::new(op::DATA, Status::Ok, self.next_seq as u32);
+        }
+        if seq != self.next_seq {
+            return Reply::new(op::DATA, Status::OutOfOrder, self.next_seq as u32);
+        }
+        let offset = seq as u32 * CHUNK_LEN as u32;
+        if offset != self.received {
+            return Reply::new(op::DATA, Status::OutOfOrder, self.next_seq as u32);
+        }
+        let end = offset + bytes.len() as u32;
+        // only the last frame can be short, anything else would leave a gap
+        if bytes.len() > CHUNK_LEN || end > self.len || (bytes.len() < CHUNK_LEN && end != self.len) {
+            return Reply::new(op::DATA, Status::BadLength, self.len);
+        }
+        if flash.program(DOWNLOAD_SECTOR, offset as usize, bytes).is_err() {
+            return Reply::new(op::DATA, Status::Flash, self.next_seq as u32);
+        }
+
+        self.received = end;
+        self.next_seq = seq.wrapping_add(1);
+        Reply::new(op::DATA, Status::Ok, self.next_seq as u32)
+    }
+
+    fn digest(&mut self, part: u8, bytes: &[u8]) -> Reply {
+        if !self.receiving {
+            return Reply::new(op::DIGEST, Status::NotStarted, 0);
+        }
+        let start = part as usize * CHUNK_LEN;
+        if part >= DIGEST_PARTS || start + bytes.len() > self.digest.len() {
+            return Reply::new(op::DIGEST, Status::BadLength, part as u32);
+        }
+        self.digest[start..start + bytes.len()].copy_from_slice(bytes);
+        self.digest_parts |= 1 << part;
+        Reply::new(op::DIGEST, Status::Ok, part as u32)
+    }
+
+    fn finish<F: Flash>(&mut self, store: &mut ConfigStore<F>, crc: u32) -> Reply {
+        if !self.receiving {
+            return Reply::new(op::FINISH, Status::NotStarted, 0);
+        }
+        if self.received != self.len {
+            return Reply::new(op::FINISH, Status::BadLength, self.received);
+        }
+        let (image_crc, sha256) = match slots::digest(store.flash(), DOWNLOAD_SECTOR, self.len as usize) {
+            Ok(digest) => digest,
+            Err(_) => return Reply::new(op::FINISH, Status::Flash, 0),
+        };
+        if image_crc != crc {
+            return Reply::new(op::FINISH, Status::BadCrc, image_crc);
+        }
+        if self.digest_parts != (1 << DIGEST_PARTS) - 1 || sha256 != self.digest {
+            return Reply::new(op::FINISH, Status::BadDigest, 0);
+        }
+
+        let trailer = Trailer {
+            len: self.len,
+            crc: image_crc,
+            sha256,
+        };
+        let flash = store.flash_mut();
+        if flash
+            .program(DOWNLOAD_SECTOR, Trailer::OFFSET, &trailer.encode())
+            .is_err()
+            || boot::set_state(store, BootState::Pending).is_err()
+        {
+            return Reply::new(op::FINISH, Status::Flash, 0);
+        }
+
+        self.receiving = false;
+        defmt::info!("download complete, crc {=u32:x}", image_crc);
+        Reply::new(op::FINISH, Status::Ok, image_crc)
+    }
+}
+
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use sha2::{Digest, Sha256};
+    use std::vec::Vec;
+    use stm32f446_rtic::config;
+    use stm32f446_rtic::crc::crc32;
+    use stm32f446_rtic::flash::SimFlash;
+
+    fn store() -> ConfigStore<SimFlash> {
+        let mut store = ConfigStore::new(SimFlash::stm32f446(), config::SECTORS);
+        store.load().unwrap();
+        store
+    }
+
+    fn image(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + 3) as u8).collect()
+    }
+
+    fn status(reply: Reply) -> (Status, u32) {
+        (reply.status, reply.arg)
+    }
+
+    fn send_data(session: &mut Session, store: &mut ConfigStore<SimFlash>, image: &[u8]) {
+        for (seq, bytes) in image.chunks(CHUNK_LEN).enumerate() {
+            let reply = session.handle(store, Request::Data { seq: seq as u16, bytes });
+            assert_eq!(status(reply), (Status::Ok, seq as u32 + 1));
+        }
+    }
+
+    fn send_digest(session: &mut Session, store: &mut ConfigStore<SimFlash>, sha256: &[u8; 32]) {
+        for (part, bytes) in sha256.chunks(CHUNK_LEN).enumerate() {
+            let reply = session.handle(store, Request::Digest { part: part as u8, bytes });
+            assert_eq!(reply.status, Status::Ok);
+        }
+    }
+
+    #[test]
+    fn download() {
+        let mut store = store();
+        let mut session = Session::new();
+        let image = image(1001);
+        assert_eq!(status(session.handle(&mut store, Request::Start { len: 1001 })), (Status::Ok, 1001));
+        assert!(session.is_receiving());
+        send_data(&mut session, &mut store, &image);
+        send_digest(&mut session, &mut store, &Sha256::digest(&image).into());
+        let reply = session.handle(&mut store, Request::Finish { crc: crc32(&image) });
+        assert_eq!(status(reply), (Status::Ok, crc32(&image)));
+        assert!(!session.is_receiving());
+
+        assert_eq!(boot::state(&store), BootState::Pending);
+        let trailer = crate::slots::verify(store.flash(), DOWNLOAD_SECTOR).unwrap().unwrap();
+        assert_eq!(trailer.len, 1001);
+        assert_eq!(store.flash().sector(DOWNLOAD_SECTOR)[..1001], image[..]);
+    }
+
+    #[test]
+    fn frames_fill_the_slot_in_order() {
+        let mut store = store();
+        let mut session = Session::new();
+        assert_eq!(status(session.handle(&mut store, Request::Data { seq: 0, bytes: &[1] })), (Status::NotStarted, 0));
+        session.handle(&mut store, Request::Start { len: 20 });
+
+        let data = |session: &mut Session, store: &mut ConfigStore<SimFlash>, seq, bytes| {
+            status(session.handle(store, Request::Data { seq, bytes }))
+        };
+        // a short frame that is not the last would leave a gap
+        assert_eq!(data(&mut session, &mut store, 0, &[1, 2, 3]), (Status::BadLength, 20));
+        assert_eq!(data(&mut session, &mut store, 1, &[1; 6]), (Status::OutOfOrder, 0));
+        assert_eq!(data(&mut session, &mut store, 0, &[1; 6]), (Status::Ok, 1));
+        // the ack got lost, the same frame again is acked and not written twice
+        assert_eq!(data(&mut session, &mut store, 0, &[1; 6]), (Status::Ok, 1));
+        assert_eq!(data(&mut session, &mut store, 2, &[2; 6]), (Status::OutOfOrder, 1));
+        assert_eq!(data(&mut session, &mut store, 1, &[2; 6]), (Status::Ok, 2));
+        assert_eq!(data(&mut session, &mut store, 2, &[3; 6]), (Status::Ok, 3));
+        // 18 of 20, the last frame is short but has to end on the length
+        assert_eq!(data(&mut session, &mut store, 3, &[4; 1]), (Status::BadLength, 20));
+        assert_eq!(data(&mut session, &mut store, 3, &[4; 3]), (Status::BadLength, 20));
+        assert_eq!(status(session.handle(&mut store, Request::Finish { crc: 0 })), (Status::BadLength, 18));
+        assert_eq!(data(&mut session, &mut store, 3, &[4; 2]), (Status::Ok, 4));
+        assert_eq!(data(&mut session, &mut store, 4, &[5; 1]), (Status::OutOfOrder, 4));
+    }
+
+    #[test]
+    fn finish_reads_the_slot_back() {
+        let mut store = store();
+        let mut session = Session::new();
+        let image = image(60);
+        session.handle(&mut store, Request::Start { len: 60 });
+        send_data(&mut session, &mut store, &image);
+        send_digest(&mut session, &mut store, &Sha256::digest(&image).into());
+
+        // a byte that did not take
+        store.flash_mut().sector_mut(DOWNLOAD_SECTOR)[30] ^= 0x04;
+        let reply = session.handle(&mut store, Request::Finish { crc: crc32(&image) });
+        assert_eq!(reply.status, Status::BadCrc);
+        assert_eq!(boot::state(&store), BootState::Confirmed);
+        assert!(session.is_receiving());
+    }
+
+    #[test]
+    fn finish_checks_the_digest() {
+        let mut store = store();
+        let mut session = Session::new();
+        let image = image(60);
+        session.handle(&mut store, Request::Start { len: 60 });
+        send_data(&mut session, &mut store, &image);
+        let reply = session.handle(&mut store, Request::Finish { crc: crc32(&image) });
+        assert_eq!(reply.status, Status::BadDigest, "no digest sent");
+
+        let mut sha256: [u8; 32] = Sha256::digest(&image).into();
+        sha256[31] ^= 1;
+        send_digest(&mut session, &mut store, &sha256);
+        let reply = session.handle(&mut store, Request::Finish { crc: crc32(&image) });
+        assert_eq!(reply.status, Status::BadDigest);
+        assert_eq!(boot::state(&store), BootState::Confirmed);
+    }
+
+    #[test]
+    fn start() {
+        let mut store = store();
+        let mut session = Session::new();
+        assert_eq!(session.handle(&mut store, Request::Start { len: 0 }).status, Status::BadLength);
+        let len = MAX_IMAGE_LEN as u32 + 1;
+        assert_eq!(session.handle(&mut store, Request::Start { len }).status, Status::BadLength);
+
+        boot::set_state(&mut store, BootState::Testing { attempts: 1 }).unwrap();
+        assert_eq!(status(session.handle(&mut store, Request::Start { len: 10 })).0, Status::Busy);
+
+        // a waiting image is given up for the new one
+        boot::set_state(&mut store, BootState::Pending).unwrap();
+        assert_eq!(session.handle(&mut store, Request::Start { len: 10 }).status, Status::Ok);
+        assert_eq!(boot::state(&store), BootState::Confirmed);
+    }
+}
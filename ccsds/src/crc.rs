@@ -0,0 +1,56 @@
+//! CRC-16-CCITT of the frame error control field, polynomial 0x1021 from all ones
+
+const POLY: u16 = 0x1021;
+
+const TABLE: [u16; 256] = {
+    let mut table = [0u16; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u16) << 8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ POLY } else { crc << 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC of a whole frame up to its error control field, which is sent high byte first
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0xFFFF, |crc, &byte| {
+        TABLE[((crc >> 8) ^ byte as u16) as usize] ^ (crc << 8)
+    })
+}
+
+/// Whether the last two bytes of `frame` are the CRC of the rest
+pub(crate) fn check(frame: &[u8]) -> bool {
+    match frame.len().checked_sub(2) {
+        Some(end) => crc16(&frame[..end]).to_be_bytes() == frame[end..],
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_value() {
+        // CRC-16/CCITT-FALSE
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+        assert_eq!(crc16(b""), 0xFFFF);
+    }
+
+    #[test]
+    fn check_a_frame() {
+        let mut frame = *b"123456789\x29\xB1";
+        assert!(check(&frame));
+        frame[3] ^= 0x10;
+        assert!(!check(&frame));
+        assert!(!check(&[0xFF]));
+        assert!(check(&[0xFF, 0xFF]));
+    }
+}
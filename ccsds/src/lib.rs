@@ -0,0 +1,39 @@
+//! CCSDS space packets and TM/TC transfer frames
+//!
+//! The wire format between the satellite and the ground, shared by the firmware and the ground
+//! software so both ends agree on every bit:
+//!
+//! - `packet`: space packets (CCSDS 133.0-B) with the mission time in the secondary header
+//! - `tm`: telemetry transfer frames (CCSDS 132.0-B), packets multiplexed onto virtual
+//!   channels on the way down and taken out again on the ground
+//! - `tc`: telecommand transfer frames (CCSDS 232.0-B)
+//!
+//! No allocation and no `std`, buffers come from the caller. With the `defmt` feature the
+//! types can be logged on the firmware.
+
+#![no_std]
+
+pub mod crc;
+pub mod packet;
+pub mod tc;
+pub mod tm;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    BufferTooSmall,
+    /// Shorter than its header
+    TooShort,
+    /// A version number other than the one these standards use
+    Version,
+    /// A length field that does not agree with the data
+    Length,
+    /// The frame error control field does not match
+    Crc,
+    /// A field out of range, like an APID over 2047
+    Range,
+    /// Longer than the buffer it has to go into
+    TooLong,
+    /// Frames went missing on a virtual channel, the packet being put together is lost
+    FrameLoss,
+}
@@ -0,0 +1,332 @@
+//! Space packets
+//!
+//! A six byte primary header (APID, sequence count, length) and the data field. When the
+//! secondary header flag is set the data field starts with the mission time as a CUC, four
+//! bytes of seconds and two of fraction without a P-field, which is this mission's choice of
+//! secondary header.
+
+use crate::Error;
+
+pub const PRIMARY_HEADER_LEN: usize = 6;
+pub const MAX_APID: u16 = 0x7FF;
+/// APID of idle packets, which fill frames and are thrown away on the ground
+pub const IDLE_APID: u16 = 0x7FF;
+pub const MAX_SEQUENCE_COUNT: u16 = 0x3FFF;
+/// The data field can be 1 to 65536 bytes
+pub const MAX_DATA_LEN: usize = 0x1_0000;
+/// Shortest packet, a header and one byte of data
+pub const MIN_PACKET_LEN: usize = PRIMARY_HEADER_LEN + 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PacketType {
+    Telemetry,
+    Telecommand,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SequenceFlags {
+    Continuation,
+    First,
+    Last,
+    /// A whole user message in one packet, nearly always this
+    Unsegmented,
+}
+
+impl SequenceFlags {
+    pub fn bits(self) -> u8 {
+        match self {
+            SequenceFlags::Continuation => 0b00,
+            SequenceFlags::First => 0b01,
+            SequenceFlags::Last => 0b10,
+            SequenceFlags::Unsegmented => 0b11,
+        }
+    }
+
+    pub fn from_bits(bits: u8) -> Self {
+        match bits & 0b11 {
+            0b00 => SequenceFlags::Continuation,
+            0b01 => SequenceFlags::First,
+            0b10 => SequenceFlags::Last,
+            _ => SequenceFlags::Unsegmented,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PrimaryHeader {
+    pub packet_type: PacketType,
+    pub secondary_header: bool,
+    pub apid: u16,
+    pub sequence_flags: SequenceFlags,
+    pub sequence_count: u16,
+    /// Bytes in the data field, the header field holds one less
+    pub data_len: usize,
+}
+
+impl PrimaryHeader {
+    pub fn encode(&self) -> Result<[u8; PRIMARY_HEADER_LEN], Error> {
+        if self.apid > MAX_APID || self.sequence_count > MAX_SEQUENCE_COUNT {
+            return Err(Error::Range);
+        }
+        if self.data_len == 0 || self.data_len > MAX_DATA_LEN {
+            return Err(Error::Length);
+        }
+        let id = ((self.packet_type == PacketType::Telecommand) as u16) << 12
+            | (self.secondary_header as u16) << 11
+            | self.apid;
+        let sequence = (self.sequence_flags.bits() as u16) << 14 | self.sequence_count;
+        let length = (self.data_len - 1) as u16;
+        let mut bytes = [0; PRIMARY_HEADER_LEN];
+        bytes[0..2].copy_from_slice(&id.to_be_bytes());
+        bytes[2..4].copy_from_slice(&sequence.to_be_bytes());
+        bytes[4..6].copy_from_slice(&length.to_be_bytes());
+        Ok(bytes)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, Error> {
+        let bytes = bytes.get(..PRIMARY_HEADER_LEN).ok_or(Error::TooShort)?;
+        let id = u16::from_be_bytes([bytes[0], bytes[1]]);
+        let sequence = u16::from_be_bytes([bytes[2], bytes[3]]);
+        let length = u16::from_be_bytes([bytes[4], bytes[5]]);
+        if id >> 13 != 0 {
+            return Err(Error::Version);
+        }
+        Ok(PrimaryHeader {
+            packet_type: if id & 1 << 12 != 0 {
+                PacketType::Telecommand
+            } else {
+                PacketType::Telemetry
+            },
+            secondary_header: id & 1 << 11 != 0,
+            apid: id & MAX_APID,
+            sequence_flags: SequenceFlags::from_bits((sequence >> 14) as u8),
+            sequence_count: sequence & MAX_SEQUENCE_COUNT,
+            data_len: length as usize + 1,
+        })
+    }
+
+    /// The whole packet, header included
+    pub fn packet_len(&self) -> usize {
+        PRIMARY_HEADER_LEN + self.data_len
+    }
+}
+
+/// Mission time, CCSDS unsegmented time code with 4 bytes of seconds and 2 of 1/65536 s
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Cuc {
+    pub seconds: u32,
+    pub fraction: u16,
+}
+
+impl Cuc {
+    pub const LEN: usize = 6;
+
+    pub fn to_bytes(self) -> [u8; Self::LEN] {
+        let mut bytes = [0; Self::LEN];
+        bytes[0..4].copy_from_slice(&self.seconds.to_be_bytes());
+        bytes[4..6].copy_from_slice(&self.fraction.to_be_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: [u8; Self::LEN]) -> Self {
+        Cuc {
+            seconds: u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            fraction: u16::from_be_bytes([bytes[4], bytes[5]]),
+        }
+    }
+
+    pub fn micros(self) -> u32 {
+        ((self.fraction as u64 * 1_000_000) >> 16) as u32
+    }
+}
+
+/// A packet with its data borrowed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SpacePacket<'a> {
+    pub header: PrimaryHeader,
+    /// The secondary header, when there is one
+    pub time: Option<Cuc>,
+    /// The user data after the secondary header
+    pub data: &'a [u8],
+}
+
+impl<'a> SpacePacket<'a> {
+    /// An unsegmented packet, with a secondary header when it has a time
+    pub fn new(packet_type: PacketType, apid: u16, sequence_count: u16, time: Option<Cuc>, data: &'a [u8]) -> Self {
+        let time_len = if time.is_some() { Cuc::LEN } else { 0 };
+        SpacePacket {
+            header: PrimaryHeader {
+                packet_type,
+                secondary_header: time.is_some(),
+                apid,
+                sequence_flags: SequenceFlags::Unsegmented,
+                sequence_count,
+                data_len: time_len + data.len(),
+            },
+            time,
+            data,
+        }
+    }
+
+    pub fn telemetry(apid: u16, sequence_count: u16, time: Cuc, data: &'a [u8]) -> Self {
+        Self::new(PacketType::Telemetry, apid, sequence_count, Some(time), data)
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        self.header.packet_len()
+    }
+
+    /// Write the packet into `out`, returns the length
+    pub fn encode(&self, out: &mut [u8]) -> Result<usize, Error> {
+        let time_len = if self.time.is_some() { Cuc::LEN } else { 0 };
+        if self.header.data_len != time_len + self.data.len() || self.header.secondary_header != self.time.is_some() {
+            return Err(Error::Length);
+        }
+        let header = self.header.encode()?;
+        let len = self.encoded_len();
+        let out = out.get_mut(..len).ok_or(Error::BufferTooSmall)?;
+        out[..PRIMARY_HEADER_LEN].copy_from_slice(&header);
+        let mut data = &mut out[PRIMARY_HEADER_LEN..];
+        if let Some(time) = self.time {
+            data[..Cuc::LEN].copy_from_slice(&time.to_bytes());
+            data = &mut data[Cuc::LEN..];
+        }
+        data.copy_from_slice(self.data);
+        Ok(len)
+    }
+
+    /// Read the packet at the start of `bytes`, which may go on with more packets
+    pub fn decode(bytes: &'a [u8]) -> Result<Self, Error> {
+        let header = PrimaryHeader::decode(bytes)?;
+        let data = bytes.get(PRIMARY_HEADER_LEN..header.packet_len()).ok_or(Error::TooShort)?;
+        let (time, data) = if header.secondary_header {
+            if data.len() < Cuc::LEN {
+                return Err(Error::Length);
+            }
+            let (time, data) = data.split_at(Cuc::LEN);
+            let mut bytes = [0; Cuc::LEN];
+            bytes.copy_from_slice(time);
+            (Some(Cuc::from_bytes(bytes)), data)
+        } else {
+            (None, data)
+        };
+        Ok(SpacePacket { header, time, data })
+    }
+}
+
+/// The header of an idle packet of `len` bytes, the data after it can be anything
+pub fn idle_header(len: usize) -> Result<[u8; PRIMARY_HEADER_LEN], Error> {
+    PrimaryHeader {
+        packet_type: PacketType::Telemetry,
+        secondary_header: false,
+        apid: IDLE_APID,
+        sequence_flags: SequenceFlags::Unsegmented,
+        sequence_count: 0,
+        data_len: len.checked_sub(PRIMARY_HEADER_LEN).ok_or(Error::Length)?,
+    }
+    .encode()
+}
+
+/// Sequence count of one APID, wraps at 14 bits
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SequenceCounter {
+    count: u16,
+}
+
+impl SequenceCounter {
+    pub const fn new() -> Self {
+        SequenceCounter { count: 0 }
+    }
+
+    /// The count for the next packet
+    pub fn next_count(&mut self) -> u16 {
+        let count = self.count;
+        self.count = (self.count + 1) & MAX_SEQUENCE_COUNT;
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header() {
+        let header = PrimaryHeader {
+            packet_type: PacketType::Telecommand,
+            secondary_header: true,
+            apid: 0x123,
+            sequence_flags: SequenceFlags::First,
+            sequence_count: 0x2345,
+            data_len: 0x100,
+        };
+        // version 000, type 1, secondary header 1, APID; flags 01, count; length - 1
+        let bytes = [0x19, 0x23, 0x63, 0x45, 0x00, 0xFF];
+        assert_eq!(header.encode(), Ok(bytes));
+        assert_eq!(PrimaryHeader::decode(&bytes), Ok(header));
+        assert_eq!(header.packet_len(), 0x106);
+
+        assert_eq!(PrimaryHeader { apid: 0x800, ..header }.encode(), Err(Error::Range));
+        assert_eq!(PrimaryHeader { sequence_count: 0x4000, ..header }.encode(), Err(Error::Range));
+        assert_eq!(PrimaryHeader { data_len: 0, ..header }.encode(), Err(Error::Length));
+        assert_eq!(PrimaryHeader { data_len: MAX_DATA_LEN + 1, ..header }.encode(), Err(Error::Length));
+        let longest = PrimaryHeader { data_len: MAX_DATA_LEN, ..header };
+        assert_eq!(PrimaryHeader::decode(&longest.encode().unwrap()), Ok(longest));
+
+        assert_eq!(PrimaryHeader::decode(&bytes[..5]), Err(Error::TooShort));
+        assert_eq!(PrimaryHeader::decode(&[0x20, 0, 0, 0, 0, 0]), Err(Error::Version));
+    }
+
+    #[test]
+    fn packet_round_trip() {
+        let time = Cuc {
+            seconds: 0x0102_0304,
+            fraction: 0x8000,
+        };
+        assert_eq!(time.micros(), 500_000);
+        let packet = SpacePacket::telemetry(0x42, 7, time, b"data");
+        let mut out = [0; 32];
+        let len = packet.encode(&mut out).unwrap();
+        assert_eq!(len, 16);
+        assert_eq!(out[..len], *b"\x08\x42\xC0\x07\x00\x09\x01\x02\x03\x04\x80\x00data");
+        // more packets can follow
+        assert_eq!(SpacePacket::decode(&out), Ok(packet));
+        assert_eq!(SpacePacket::decode(&out[..len - 1]), Err(Error::TooShort));
+        assert_eq!(packet.encode(&mut [0; 15]), Err(Error::BufferTooSmall));
+
+        let plain = SpacePacket::new(PacketType::Telemetry, 0x42, 8, None, b"x");
+        let len = plain.encode(&mut out).unwrap();
+        assert_eq!(SpacePacket::decode(&out[..len]), Ok(plain));
+
+        // a secondary header flag without room for the time
+        out[0] |= 0x08;
+        assert_eq!(SpacePacket::decode(&out[..len]), Err(Error::Length));
+        let mut mismatched = packet;
+        mismatched.data = b"dat";
+        assert_eq!(mismatched.encode(&mut out), Err(Error::Length));
+    }
+
+    #[test]
+    fn idle_packets() {
+        assert_eq!(idle_header(MIN_PACKET_LEN), Ok([0x07, 0xFF, 0xC0, 0x00, 0x00, 0x00]));
+        assert_eq!(idle_header(PRIMARY_HEADER_LEN), Err(Error::Length));
+        assert_eq!(idle_header(2), Err(Error::Length));
+        let header = PrimaryHeader::decode(&idle_header(100).unwrap()).unwrap();
+        assert_eq!((header.apid, header.packet_len()), (IDLE_APID, 100));
+    }
+
+    #[test]
+    fn sequence_count_wraps() {
+        let mut counter = SequenceCounter::new();
+        for expected in 0..=MAX_SEQUENCE_COUNT {
+            assert_eq!(counter.next_count(), expected);
+        }
+        assert_eq!(counter.next_count(), 0);
+    }
+}
@@ -0,0 +1,204 @@
+//! Telecommand transfer frames
+//!
+//! Frames are up to 1024 bytes: a five byte header, the data field and the frame error
+//! control field, which this mission always sends. The data field starts with a segment
+//! header when the virtual channel uses MAP channels, `TcFrame::segment` reads it; the packets
+//! with the commands come after it.
+//!
+//! Frame acceptance (FARM) is up to the caller: bypass frames are taken as they come, for
+//! sequence controlled ones the caller checks `sequence` against what it expects.
+
+use crate::crc;
+use crate::packet::SequenceFlags;
+use crate::Error;
+
+pub const PRIMARY_HEADER_LEN: usize = 5;
+pub const FECF_LEN: usize = 2;
+pub const MAX_FRAME_LEN: usize = 1024;
+pub const MAX_SPACECRAFT_ID: u16 = 0x3FF;
+pub const MAX_VIRTUAL_CHANNEL: u8 = 0x3F;
+pub const MAX_MAP: u8 = 0x3F;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TcHeader {
+    /// Bypass the sequence checks, type B frames
+    pub bypass: bool,
+    /// Control commands for the FARM instead of data
+    pub control: bool,
+    pub spacecraft_id: u16,
+    pub virtual_channel: u8,
+    /// The whole frame, header and error control included
+    pub frame_len: usize,
+    pub sequence: u8,
+}
+
+impl TcHeader {
+    pub fn encode(&self) -> Result<[u8; PRIMARY_HEADER_LEN], Error> {
+        if self.spacecraft_id > MAX_SPACECRAFT_ID || self.virtual_channel > MAX_VIRTUAL_CHANNEL {
+            return Err(Error::Range);
+        }
+        if self.frame_len < PRIMARY_HEADER_LEN + FECF_LEN || self.frame_len > MAX_FRAME_LEN {
+            return Err(Error::Length);
+        }
+        // version 00
+        let id = (self.bypass as u16) << 13 | (self.control as u16) << 12 | self.spacecraft_id;
+        let length = (self.virtual_channel as u16) << 10 | (self.frame_len - 1) as u16;
+        let mut bytes = [0; PRIMARY_HEADER_LEN];
+        bytes[0..2].copy_from_slice(&id.to_be_bytes());
+        bytes[2..4].copy_from_slice(&length.to_be_bytes());
+        bytes[4] = self.sequence;
+        Ok(bytes)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, Error> {
+        let bytes = bytes.get(..PRIMARY_HEADER_LEN).ok_or(Error::TooShort)?;
+        let id = u16::from_be_bytes([bytes[0], bytes[1]]);
+        let length = u16::from_be_bytes([bytes[2], bytes[3]]);
+        if id >> 14 != 0 {
+            return Err(Error::Version);
+        }
+        Ok(TcHeader {
+            bypass: id & 1 << 13 != 0,
+            control: id & 1 << 12 != 0,
+            spacecraft_id: id & MAX_SPACECRAFT_ID,
+            virtual_channel: (length >> 10) as u8,
+            frame_len: (length & 0x3FF) as usize + 1,
+            sequence: bytes[4],
+        })
+    }
+}
+
+/// The segment header at the start of the data field
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Segment<'a> {
+    pub flags: SequenceFlags,
+    pub map: u8,
+    pub data: &'a [u8],
+}
+
+/// A frame with its data field borrowed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TcFrame<'a> {
+    pub header: TcHeader,
+    pub data: &'a [u8],
+}
+
+impl<'a> TcFrame<'a> {
+    /// Read the frame at the start of `bytes` and check its error control field
+    pub fn decode(bytes: &'a [u8]) -> Result<Self, Error> {
+        let header = TcHeader::decode(bytes)?;
+        if header.frame_len < PRIMARY_HEADER_LEN + FECF_LEN {
+            return Err(Error::Length);
+        }
+        let frame = bytes.get(..header.frame_len).ok_or(Error::TooShort)?;
+        if !crc::check(frame) {
+            return Err(Error::Crc);
+        }
+        Ok(TcFrame {
+            header,
+            data: &frame[PRIMARY_HEADER_LEN..frame.len() - FECF_LEN],
+        })
+    }
+
+    /// The data field as a segment, for virtual channels that have MAP channels
+    pub fn segment(&self) -> Result<Segment<'a>, Error> {
+        let (&first, data) = self.data.split_first().ok_or(Error::TooShort)?;
+        Ok(Segment {
+            flags: SequenceFlags::from_bits(first >> 6),
+            map: first & MAX_MAP,
+            data,
+        })
+    }
+
+    /// Build a frame in `out` around `data`, the length in the header is filled in. Returns
+    /// the frame length.
+    pub fn encode(header: TcHeader, data: &[u8], out: &mut [u8]) -> Result<usize, Error> {
+        let len = PRIMARY_HEADER_LEN + data.len() + FECF_LEN;
+        let header = TcHeader { frame_len: len, ..header }.encode()?;
+        let out = out.get_mut(..len).ok_or(Error::BufferTooSmall)?;
+        out[..PRIMARY_HEADER_LEN].copy_from_slice(&header);
+        out[PRIMARY_HEADER_LEN..len - FECF_LEN].copy_from_slice(data);
+        let crc = crc::crc16(&out[..len - FECF_LEN]);
+        out[len - FECF_LEN..].copy_from_slice(&crc.to_be_bytes());
+        Ok(len)
+    }
+}
+
+/// A segment header byte
+pub fn segment_header(flags: SequenceFlags, map: u8) -> Result<u8, Error> {
+    if map > MAX_MAP {
+        return Err(Error::Range);
+    }
+    Ok(flags.bits() << 6 | map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: TcHeader = TcHeader {
+        bypass: true,
+        control: false,
+        spacecraft_id: 0x2AB,
+        virtual_channel: 5,
+        frame_len: 0,
+        sequence: 0x9A,
+    };
+
+    #[test]
+    fn round_trip() {
+        let segment = segment_header(SequenceFlags::Unsegmented, 3).unwrap();
+        let data = [segment, 0x18, 0x01, 0xC0, 0x00, 0x00, 0x00, 0x2A];
+        let mut out = [0; 32];
+        let len = TcFrame::encode(HEADER, &data, &mut out).unwrap();
+        assert_eq!(len, 15);
+        // version 00, bypass, SCID; VC and length - 1; sequence
+        assert_eq!(out[..5], [0x22, 0xAB, 0x14, 0x0E, 0x9A]);
+        let crc = crc::crc16(&out[..13]).to_be_bytes();
+        assert_eq!(out[13..15], crc);
+
+        // more bytes after the frame are left alone
+        let frame = TcFrame::decode(&out).unwrap();
+        assert_eq!(frame.header, TcHeader { frame_len: 15, ..HEADER });
+        assert_eq!(frame.data, data);
+        let segment = frame.segment().unwrap();
+        assert_eq!((segment.flags, segment.map), (SequenceFlags::Unsegmented, 3));
+        assert_eq!(segment.data, &data[1..]);
+    }
+
+    #[test]
+    fn errors() {
+        let mut out = [0; MAX_FRAME_LEN + 1];
+        let len = TcFrame::encode(HEADER, b"command", &mut out).unwrap();
+
+        let mut bad = out;
+        bad[8] ^= 1;
+        assert_eq!(TcFrame::decode(&bad[..len]), Err(Error::Crc));
+        assert_eq!(TcFrame::decode(&out[..len - 1]), Err(Error::TooShort));
+        assert_eq!(TcFrame::decode(&out[..4]), Err(Error::TooShort));
+        bad = out;
+        bad[0] |= 0x40;
+        assert_eq!(TcFrame::decode(&bad[..len]), Err(Error::Version));
+        // a length field too short for the header and the error control
+        bad = out;
+        bad[3] = 0x05;
+        assert_eq!(TcFrame::decode(&bad[..len]), Err(Error::Length));
+
+        assert_eq!(TcFrame::encode(HEADER, b"command", &mut [0; 13]), Err(Error::BufferTooSmall));
+        let data = [0; MAX_FRAME_LEN - PRIMARY_HEADER_LEN - FECF_LEN];
+        assert_eq!(TcFrame::encode(HEADER, &data, &mut out), Ok(MAX_FRAME_LEN));
+        assert_eq!(TcFrame::decode(&out).map(|frame| frame.data.len()), Ok(data.len()));
+        assert_eq!(TcFrame::encode(HEADER, &out[..data.len() + 1], &mut [0; 2048]), Err(Error::Length));
+        let header = TcHeader { spacecraft_id: 0x400, ..HEADER };
+        assert_eq!(TcFrame::encode(header, b"", &mut out), Err(Error::Range));
+        let header = TcHeader { virtual_channel: 0x40, ..HEADER };
+        assert_eq!(TcFrame::encode(header, b"", &mut out), Err(Error::Range));
+
+        assert_eq!(segment_header(SequenceFlags::First, 0x40), Err(Error::Range));
+        let len = TcFrame::encode(HEADER, b"", &mut out).unwrap();
+        assert_eq!(TcFrame::decode(&out[..len]).unwrap().segment(), Err(Error::TooShort));
+    }
+}
@@ -0,0 +1,525 @@
+//! Telemetry transfer frames
+//!
+//! All frames on the downlink have the same length. Packets go into the data field of a
+//! virtual channel's frame back to back and carry on in the next frame when they do not fit;
+//! the first header pointer tells the ground where the first packet starting in a frame is,
+//! so it can find its way back in after a lost frame. `flush` fills the rest of a frame with
+//! an idle packet when there is nothing more to send.
+//!
+//! Frames carry a frame error control field (CRC-16) when the mission config says so, no
+//! operational control field and no secondary header.
+
+use crate::crc;
+use crate::packet::{self, PrimaryHeader, IDLE_APID, MIN_PACKET_LEN, PRIMARY_HEADER_LEN as PACKET_HEADER_LEN};
+use crate::Error;
+
+pub const PRIMARY_HEADER_LEN: usize = 6;
+pub const FECF_LEN: usize = 2;
+pub const MAX_FRAME_LEN: usize = 2048;
+pub const MAX_SPACECRAFT_ID: u16 = 0x3FF;
+pub const MAX_VIRTUAL_CHANNEL: u8 = 7;
+/// First header pointer of a frame where no packet starts
+pub const NO_PACKET_START: u16 = 0x7FF;
+/// First header pointer of a frame with only idle data
+pub const IDLE_DATA: u16 = 0x7FE;
+
+/// What goes into the idle packets
+const IDLE_FILL: u8 = 0x55;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TmHeader {
+    pub spacecraft_id: u16,
+    pub virtual_channel: u8,
+    /// Counts all frames on the physical channel
+    pub master_count: u8,
+    /// Counts the frames of this virtual channel
+    pub virtual_count: u8,
+    /// Where in the data field the first packet starts, or `NO_PACKET_START`
+    pub first_header: u16,
+}
+
+impl TmHeader {
+    pub fn encode(&self) -> Result<[u8; PRIMARY_HEADER_LEN], Error> {
+        if self.spacecraft_id > MAX_SPACECRAFT_ID
+            || self.virtual_channel > MAX_VIRTUAL_CHANNEL
+            || self.first_header > NO_PACKET_START
+        {
+            return Err(Error::Range);
+        }
+        // version 00, no operational control field
+        let id = self.spacecraft_id << 4 | (self.virtual_channel as u16) << 1;
+        // no secondary header, packets in order, segment length id 11
+        let status = 0b0001_1000_0000_0000 | self.first_header;
+        let mut bytes = [0; PRIMARY_HEADER_LEN];
+        bytes[0..2].copy_from_slice(&id.to_be_bytes());
+        bytes[2] = self.master_count;
+        bytes[3] = self.virtual_count;
+        bytes[4..6].copy_from_slice(&status.to_be_bytes());
+        Ok(bytes)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, Error> {
+        let bytes = bytes.get(..PRIMARY_HEADER_LEN).ok_or(Error::TooShort)?;
+        let id = u16::from_be_bytes([bytes[0], bytes[1]]);
+        let status = u16::from_be_bytes([bytes[4], bytes[5]]);
+        if id >> 14 != 0 {
+            return Err(Error::Version);
+        }
+        Ok(TmHeader {
+            spacecraft_id: id >> 4 & MAX_SPACECRAFT_ID,
+            virtual_channel: (id >> 1 & 0x7) as u8,
+            master_count: bytes[2],
+            virtual_count: bytes[3],
+            first_header: status & 0x7FF,
+        })
+    }
+}
+
+/// A frame with its data field borrowed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TmFrame<'a> {
+    pub header: TmHeader,
+    pub data: &'a [u8],
+}
+
+impl<'a> TmFrame<'a> {
+    /// Read a whole frame, checking the error control field if the frame has one
+    pub fn decode(frame: &'a [u8], fecf: bool) -> Result<Self, Error> {
+        let trailer = if fecf { FECF_LEN } else { 0 };
+        if frame.len() < PRIMARY_HEADER_LEN + trailer {
+            return Err(Error::TooShort);
+        }
+        if fecf && !crc::check(frame) {
+            return Err(Error::Crc);
+        }
+        let header = TmHeader::decode(frame)?;
+        let data = &frame[PRIMARY_HEADER_LEN..frame.len() - trailer];
+        if header.first_header < IDLE_DATA && header.first_header as usize >= data.len() {
+            return Err(Error::Length);
+        }
+        Ok(TmFrame { header, data })
+    }
+}
+
+/// The frame settings of the mission, the same for every frame on the downlink
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TmConfig {
+    pub spacecraft_id: u16,
+    pub fecf: bool,
+}
+
+/// The physical channel, counts the frames of all virtual channels. `LEN` is the frame length.
+pub struct MasterChannel<const LEN: usize> {
+    config: TmConfig,
+    count: u8,
+}
+
+impl<const LEN: usize> MasterChannel<LEN> {
+    pub fn new(config: TmConfig) -> Result<Self, Error> {
+        if config.spacecraft_id > MAX_SPACECRAFT_ID {
+            return Err(Error::Range);
+        }
+        if LEN > MAX_FRAME_LEN || LEN < PRIMARY_HEADER_LEN + FECF_LEN + MIN_PACKET_LEN {
+            return Err(Error::Length);
+        }
+        Ok(MasterChannel { config, count: 0 })
+    }
+
+    pub fn config(&self) -> TmConfig {
+        self.config
+    }
+
+    /// Bytes of packets a frame holds
+    pub const fn data_len(&self) -> usize {
+        LEN - PRIMARY_HEADER_LEN - if self.config.fecf { FECF_LEN } else { 0 }
+    }
+}
+
+/// One virtual channel filling its frame with packets
+pub struct VirtualChannel<const LEN: usize> {
+    id: u8,
+    count: u8,
+    frame: [u8; LEN],
+    /// Bytes in the data field so far
+    fill: usize,
+    first_header: Option<usize>,
+}
+
+impl<const LEN: usize> VirtualChannel<LEN> {
+    pub fn new(id: u8) -> Result<Self, Error> {
+        if id > MAX_VIRTUAL_CHANNEL {
+            return Err(Error::Range);
+        }
+        Ok(VirtualChannel {
+            id,
+            count: 0,
+            frame: [0; LEN],
+            fill: 0,
+            first_header: None,
+        })
+    }
+
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    /// Whether part of a frame is waiting for more packets
+    pub fn is_pending(&self) -> bool {
+        self.fill > 0
+    }
+
+    /// Add an encoded packet, `emit` gets every frame it fills
+    pub fn push(
+        &mut self,
+        master: &mut MasterChannel<LEN>,
+        packet: &[u8],
+        mut emit: impl FnMut(&[u8]),
+    ) -> Result<(), Error> {
+        let header = PrimaryHeader::decode(packet)?;
+        if header.packet_len() != packet.len() {
+            return Err(Error::Length);
+        }
+        self.write(master, packet, true, &mut emit);
+        Ok(())
+    }
+
+    /// Fill the rest of the frame with an idle packet and send it. Nothing happens when no
+    /// frame is started.
+    pub fn flush(&mut self, master: &mut MasterChannel<LEN>, mut emit: impl FnMut(&[u8])) {
+        if self.fill == 0 {
+            return;
+        }
+        // an idle packet too short to fit is stretched into the next frame
+        let len = (master.data_len() - self.fill).max(MIN_PACKET_LEN);
+        if let Ok(header) = packet::idle_header(len) {
+            self.write(master, &header, true, &mut emit);
+            let fill = [IDLE_FILL; 32];
+            let mut left = len - PACKET_HEADER_LEN;
+            while left > 0 {
+                let chunk = left.min(fill.len());
+                self.write(master, &fill[..chunk], false, &mut emit);
+                left -= chunk;
+            }
+        }
+    }
+
+    /// Copy bytes into the data field, starting a packet if `start`
+    fn write(&mut self, master: &mut MasterChannel<LEN>, mut bytes: &[u8], start: bool, emit: &mut impl FnMut(&[u8])) {
+        if start && self.first_header.is_none() {
+            self.first_header = Some(self.fill);
+        }
+        let data_len = master.data_len();
+        while !bytes.is_empty() {
+            let n = bytes.len().min(data_len - self.fill);
+            let at = PRIMARY_HEADER_LEN + self.fill;
+            self.frame[at..at + n].copy_from_slice(&bytes[..n]);
+            self.fill += n;
+            bytes = &bytes[n..];
+            if self.fill == data_len {
+                self.finish(master);
+                emit(&self.frame);
+            }
+        }
+    }
+
+    /// Fill in the header and error control of a full frame and start the next one
+    fn finish(&mut self, master: &mut MasterChannel<LEN>) {
+        let header = TmHeader {
+            spacecraft_id: master.config.spacecraft_id,
+            virtual_channel: self.id,
+            master_count: master.count,
+            virtual_count: self.count,
+            first_header: self.first_header.map_or(NO_PACKET_START, |at| at as u16),
+        };
+        // checked in `new`
+        if let Ok(bytes) = header.encode() {
+            self.frame[..PRIMARY_HEADER_LEN].copy_from_slice(&bytes);
+        }
+        if master.config.fecf {
+            let crc = crc::crc16(&self.frame[..LEN - FECF_LEN]);
+            self.frame[LEN - FECF_LEN..].copy_from_slice(&crc.to_be_bytes());
+        }
+        master.count = master.count.wrapping_add(1);
+        self.count = self.count.wrapping_add(1);
+        self.fill = 0;
+        self.first_header = None;
+    }
+}
+
+/// Takes the packets out of the frames of one virtual channel on the ground, packets up to
+/// `MAX` bytes long
+pub struct PacketExtractor<const MAX: usize> {
+    buffer: [u8; MAX],
+    len: usize,
+    /// Expected virtual channel count of the next frame
+    next_count: Option<u8>,
+    /// Whether the next byte is the start of a packet or follows `buffer`
+    synced: bool,
+}
+
+impl<const MAX: usize> PacketExtractor<MAX> {
+    pub const fn new() -> Self {
+        PacketExtractor {
+            buffer: [0; MAX],
+            len: 0,
+            next_count: None,
+            synced: false,
+        }
+    }
+
+    /// Take in the next frame, `emit` gets every packet it completes apart from idle ones,
+    /// and the errors along the way
+    pub fn push(&mut self, frame: &TmFrame, mut emit: impl FnMut(Result<&[u8], Error>)) {
+        let count = frame.header.virtual_count;
+        if matches!(self.next_count, Some(next) if next != count) {
+            if self.synced && self.len > 0 {
+                emit(Err(Error::FrameLoss));
+            }
+            self.synced = false;
+        }
+        self.next_count = Some(count.wrapping_add(1));
+
+        let first_header = frame.header.first_header;
+        let mut data = frame.data;
+        if first_header == IDLE_DATA {
+            return;
+        }
+        if first_header != NO_PACKET_START {
+            let (before, from) = data.split_at((first_header as usize).min(data.len()));
+            if self.synced {
+                self.take(before, &mut emit);
+                if self.len > 0 {
+                    // the pointer says a packet starts where this one is not finished
+                    emit(Err(Error::Length));
+                }
+            }
+            self.synced = true;
+            self.len = 0;
+            data = from;
+        }
+        if self.synced {
+            self.take(data, &mut emit);
+        }
+    }
+
+    /// Add bytes to the packet being put together, emitting the ones that complete
+    fn take(&mut self, mut data: &[u8], emit: &mut impl FnMut(Result<&[u8], Error>)) {
+        while !data.is_empty() {
+            let wanted = if self.len < PACKET_HEADER_LEN {
+                PACKET_HEADER_LEN
+            } else {
+                match PrimaryHeader::decode(&self.buffer[..self.len]) {
+                    Ok(header) if header.packet_len() <= MAX => header.packet_len(),
+                    result => {
+                        emit(Err(result.err().unwrap_or(Error::TooLong)));
+                        // wait for the next first header pointer
+                        self.synced = false;
+                        self.len = 0;
+                        return;
+                    }
+                }
+            };
+            let n = (wanted - self.len).min(data.len());
+            self.buffer[self.len..self.len + n].copy_from_slice(&data[..n]);
+            self.len += n;
+            data = &data[n..];
+
+            if self.len == wanted && wanted > PACKET_HEADER_LEN {
+                let packet = &self.buffer[..self.len];
+                if !matches!(PrimaryHeader::decode(packet), Ok(header) if header.apid == IDLE_APID) {
+                    emit(Ok(packet));
+                }
+                self.len = 0;
+            }
+        }
+    }
+}
+
+impl<const MAX: usize> Default for PacketExtractor<MAX> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::packet::{Cuc, SpacePacket};
+    use std::{vec, vec::Vec};
+
+    const LEN: usize = 64;
+    const CONFIG: TmConfig = TmConfig {
+        spacecraft_id: 0x1A5,
+        fecf: true,
+    };
+
+    fn packet(apid: u16, count: u16, len: usize) -> Vec<u8> {
+        let data: Vec<u8> = (0..len).map(|i| (i + count as usize) as u8).collect();
+        let packet = SpacePacket::telemetry(apid, count, Cuc { seconds: count as u32, fraction: 0 }, &data);
+        let mut out = vec![0; packet.encoded_len()];
+        packet.encode(&mut out).unwrap();
+        out
+    }
+
+    fn channels() -> (MasterChannel<LEN>, VirtualChannel<LEN>) {
+        (MasterChannel::new(CONFIG).unwrap(), VirtualChannel::new(3).unwrap())
+    }
+
+    /// Decode the frames and take the packets out of them
+    fn extract(frames: &[Vec<u8>]) -> Vec<Result<Vec<u8>, Error>> {
+        let mut extractor = PacketExtractor::<256>::new();
+        let mut packets = Vec::new();
+        for frame in frames {
+            let frame = TmFrame::decode(frame, true).unwrap();
+            extractor.push(&frame, |packet| packets.push(packet.map(<[u8]>::to_vec)));
+        }
+        packets
+    }
+
+    #[test]
+    fn header() {
+        let header = TmHeader {
+            spacecraft_id: 0x1A5,
+            virtual_channel: 3,
+            master_count: 0x12,
+            virtual_count: 0x34,
+            first_header: 0x123,
+        };
+        // version 00, SCID, VC, no OCF; counts; no secondary header, sync, segment length 11
+        let bytes = [0x1A, 0x56, 0x12, 0x34, 0x19, 0x23];
+        assert_eq!(header.encode(), Ok(bytes));
+        assert_eq!(TmHeader::decode(&bytes), Ok(header));
+        assert_eq!(TmHeader { virtual_channel: 8, ..header }.encode(), Err(Error::Range));
+        assert_eq!(TmHeader { first_header: 0x800, ..header }.encode(), Err(Error::Range));
+        assert_eq!(TmHeader::decode(&[0x40, 0, 0, 0, 0, 0]), Err(Error::Version));
+
+        assert!(MasterChannel::<LEN>::new(TmConfig { spacecraft_id: 0x400, ..CONFIG }).is_err());
+        assert!(MasterChannel::<14>::new(CONFIG).is_err());
+        assert!(MasterChannel::<{ MAX_FRAME_LEN + 1 }>::new(CONFIG).is_err());
+        assert!(VirtualChannel::<LEN>::new(8).is_err());
+    }
+
+    #[test]
+    fn packets_spanning_frames() {
+        let (mut master, mut channel) = channels();
+        assert_eq!(master.data_len(), 56);
+        // 20, 100 and 30 byte packets over three and a bit frames
+        let packets = [packet(1, 0, 8), packet(2, 1, 88), packet(1, 2, 18)];
+        let mut frames = Vec::new();
+        for packet in &packets {
+            channel.push(&mut master, packet, |frame| frames.push(frame.to_vec())).unwrap();
+        }
+        assert_eq!(frames.len(), 2);
+        assert!(channel.is_pending());
+        channel.flush(&mut master, |frame| frames.push(frame.to_vec()));
+        assert!(!channel.is_pending());
+        assert_eq!(frames.len(), 3);
+
+        let headers: Vec<TmHeader> = frames.iter().map(|f| TmFrame::decode(f, true).unwrap().header).collect();
+        // the first packet at 0; the second started in frame 0 so frame 1 has no start; the
+        // third starts at 120 - 112 = 8 in frame 2, followed by the idle packet
+        let pointers: Vec<u16> = headers.iter().map(|header| header.first_header).collect();
+        assert_eq!(pointers, [0, NO_PACKET_START, 8]);
+        assert!(headers.iter().enumerate().all(|(i, header)| header.virtual_count as usize == i));
+        assert!(headers.iter().all(|header| header.spacecraft_id == 0x1A5 && header.virtual_channel == 3));
+
+        let extracted = extract(&frames);
+        assert_eq!(extracted, packets.iter().cloned().map(Ok).collect::<Vec<_>>());
+
+        assert_eq!(channel.push(&mut master, &packets[0][..19], |_| {}), Err(Error::Length));
+    }
+
+    #[test]
+    fn flush_with_too_little_room() {
+        let (mut master, mut channel) = channels();
+        let mut frames = Vec::new();
+        // 52 of 56 bytes, not enough for the shortest idle packet
+        let first = packet(1, 0, 40);
+        channel.push(&mut master, &first, |frame| frames.push(frame.to_vec())).unwrap();
+        channel.flush(&mut master, |frame| frames.push(frame.to_vec()));
+        // the idle packet goes on into the next frame, which is not sent yet
+        assert_eq!(frames.len(), 1);
+        assert!(channel.is_pending());
+
+        let second = packet(2, 1, 4);
+        channel.push(&mut master, &second, |frame| frames.push(frame.to_vec())).unwrap();
+        channel.flush(&mut master, |frame| frames.push(frame.to_vec()));
+        assert!(!channel.is_pending());
+        assert_eq!(frames.len(), 2);
+
+        let pointers: Vec<u16> =
+            frames.iter().map(|frame| TmFrame::decode(frame, true).unwrap().header.first_header).collect();
+        // the rest of the idle packet is 3 bytes
+        assert_eq!(pointers, [0, 3]);
+        assert_eq!(extract(&frames), [Ok(first), Ok(second)]);
+
+        // nothing started, nothing sent
+        channel.flush(&mut master, |_| panic!("no frame to flush"));
+    }
+
+    #[test]
+    fn frame_loss() {
+        let (mut master, mut channel) = channels();
+        let packets: Vec<Vec<u8>> = (0..8).map(|count| packet(1, count, 30)).collect();
+        let mut frames = Vec::new();
+        for packet in &packets {
+            channel.push(&mut master, packet, |frame| frames.push(frame.to_vec())).unwrap();
+        }
+        channel.flush(&mut master, |frame| frames.push(frame.to_vec()));
+        assert_eq!(frames.len(), 6);
+
+        // frame 2 is lost: packet 2 is cut off and the count jumps from 1 to 3
+        let mut received = frames.clone();
+        received.remove(2);
+        let extracted = extract(&received);
+        let mut expected: Vec<Result<Vec<u8>, Error>> = packets[..2].iter().cloned().map(Ok).collect();
+        expected.push(Err(Error::FrameLoss));
+        // frame 3 starts at the first packet header in it, packet 4
+        expected.extend(packets[4..].iter().cloned().map(Ok));
+        assert_eq!(extracted, expected);
+
+        // a count that wraps is no loss
+        for (i, frame) in frames.iter_mut().enumerate() {
+            frame[3] = 254u8.wrapping_add(i as u8);
+            let crc = crc::crc16(&frame[..LEN - FECF_LEN]);
+            frame[LEN - FECF_LEN..].copy_from_slice(&crc.to_be_bytes());
+        }
+        assert_eq!(extract(&frames), packets.iter().cloned().map(Ok).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn bad_frames() {
+        let (mut master, mut channel) = channels();
+        let mut frames = Vec::new();
+        channel.push(&mut master, &packet(1, 0, 50), |frame| frames.push(frame.to_vec())).unwrap();
+        let frame = &frames[0];
+
+        let mut bad = frame.clone();
+        bad[20] ^= 4;
+        assert_eq!(TmFrame::decode(&bad, true), Err(Error::Crc));
+        assert_eq!(TmFrame::decode(&frame[..7], true), Err(Error::TooShort));
+        // without the error control field the CRC bytes are data
+        assert_eq!(TmFrame::decode(frame, false).map(|frame| frame.data.len()), Ok(LEN - PRIMARY_HEADER_LEN));
+
+        // a first header pointer past the end of the data field
+        let header = TmHeader {
+            first_header: 56,
+            ..TmHeader::decode(frame).unwrap()
+        };
+        let mut bad = frame.clone();
+        bad[..PRIMARY_HEADER_LEN].copy_from_slice(&header.encode().unwrap());
+        let crc = crc::crc16(&bad[..LEN - FECF_LEN]);
+        bad[LEN - FECF_LEN..].copy_from_slice(&crc.to_be_bytes());
+        assert_eq!(TmFrame::decode(&bad, true), Err(Error::Length));
+
+        // a packet too long for the extractor
+        let mut extractor = PacketExtractor::<32>::new();
+        let mut results = Vec::new();
+        extractor.push(&TmFrame::decode(frame, true).unwrap(), |packet| results.push(packet.map(<[u8]>::to_vec)));
+        assert_eq!(results, [Err(Error::TooLong)]);
+    }
+}
@@ -0,0 +1,82 @@
+//! This build script copies the memory layout for the selected feature into a directory where the
+//! linker can always find it as `memory.x`, and generates the build information that goes into
+//! the image header (see `src/image.rs`).
+//!
+//! `memory/plain.x` is for flashing with probe-run, the application starts at the beginning of
+//! flash. `memory/bootloader.x` is used with the `bootloader` feature and links the application
+//! for the primary slot of the CAN bootloader instead.
+//!
+//! Crates with a layout of their own (like the bootloader) turn off the `memory-x` feature so
+//! this one does not end up on their linker search path.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
+
+    println!("cargo:rerun-if-changed=memory");
+
+    // Rebuild the build information when a commit is made or checked out
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/index");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    fs::write(out.join("build_info.rs"), build_info()).unwrap();
+
+    if env::var_os("CARGO_FEATURE_MEMORY_X").is_none() {
+        return;
+    }
+
+    let layout = if env::var_os("CARGO_FEATURE_BOOTLOADER").is_some() {
+        "memory/bootloader.x"
+    } else {
+        "memory/plain.x"
+    };
+
+    // Put `memory.x` in our output directory and ensure it's on the linker search path.
+    fs::copy(layout, out.join("memory.x")).unwrap();
+    println!("cargo:rustc-link-search={}", out.display());
+}
+
+fn build_info() -> String {
+    let version = [
+        env::var("CARGO_PKG_VERSION_MAJOR").unwrap(),
+        env::var("CARGO_PKG_VERSION_MINOR").unwrap(),
+        env::var("CARGO_PKG_VERSION_PATCH").unwrap(),
+    ];
+
+    // Without git (a source tarball for example) the hash is left empty
+    let hash = git(&["rev-parse", "--short=12", "HEAD"]).unwrap_or_default();
+    let mut git_hash = [0u8; 12];
+    for (dst, src) in git_hash.iter_mut().zip(hash.bytes()) {
+        *dst = src;
+    }
+    let dirty = git(&["status", "--porcelain", "--untracked-files=no"])
+        .map(|status| !status.is_empty())
+        .unwrap_or(false);
+
+    // SOURCE_DATE_EPOCH makes the build reproducible
+    let timestamp = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs());
+
+    format!(
+        "pub const VERSION: [u16; 3] = [{}, {}, {}];\n\
+         pub const GIT_HASH: [u8; 12] = {:?};\n\
+         pub const GIT_DIRTY: bool = {};\n\
+         pub const BUILD_TIMESTAMP: u64 = {};\n",
+        version[0], version[1], version[2], git_hash, dirty, timestamp
+    )
+}
+
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8(output.stdout).ok()?.trim().to_string())
+}
@@ -0,0 +1,105 @@
+#![no_main]
+#![no_std]
+
+use stm32f446_rtic as _; // global logger + panicking-behavior + memory layout
+
+// A beacon every 10 s as an AX.25 UI frame, handed to a KISS TNC on USART2 (the ST-LINK
+// virtual COM port, so a TNC program on the PC like Dire Wolf or kissutil can take it).
+// Put your own callsign in SOURCE before anything goes on air.
+#[rtic::app(device = stm32f4xx_hal::pac, dispatchers = [USART1])]
+mod app {
+    use core::fmt::Write;
+    use dwt_systick_monotonic::{DwtSystick, ExtU32};
+    use heapless::String;
+    use stm32f446_rtic::ax25::{Address, UiFrame, MAX_FRAME_LEN};
+    use stm32f446_rtic::kiss;
+    use stm32f4xx_hal::{pac::USART2, prelude::*, serial::Tx};
+
+    // Needed for scheduling monotonic tasks
+    #[monotonic(binds = SysTick, default = true)]
+    type MyMono = DwtSystick<48_000_000>; // 48 MHz
+
+    const SOURCE: &str = "NOCALL";
+    const DESTINATION: &str = "CQ";
+
+    // Holds the shared resources (used by multiple tasks)
+    #[shared]
+    struct Shared {}
+
+    // Holds the local resources (used by a single task)
+    #[local]
+    struct Local {
+        tnc: Tx<USART2>,
+        count: u32,
+    }
+
+    // The init function is called in the beginning of the program
+    #[init]
+    fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
+        defmt::info!("init");
+
+        // Cortex-M peripherals
+        let mut _core : cortex_m::Peripherals = ctx.core;
+
+        // Device specific peripherals
+        let mut _device : stm32f4xx_hal::pac::Peripherals = ctx.device;
+
+        // Set up the system clock.
+        let rcc = _device.RCC.constrain();
+        let clocks = rcc.cfgr.sysclk(48.MHz()).freeze();
+
+        // KISS TNCs talk 9600 8N1 unless told otherwise
+        let gpioa = _device.GPIOA.split();
+        let tnc = _device
+            .USART2
+            .tx(gpioa.pa2.into_alternate(), 9600.bps(), &clocks)
+            .unwrap();
+
+        // enable tracing and the cycle counter for the monotonic timer
+        _core.DCB.enable_trace();
+        _core.DWT.enable_cycle_counter();
+
+        // Set up the monotonic timer
+        let mono = DwtSystick::new(
+            &mut _core.DCB,
+            _core.DWT,
+            _core.SYST,
+            clocks.hclk().to_Hz(),
+        );
+
+        defmt::info!("Init done!");
+        beacon::spawn().ok();
+        (Shared {}, Local { tnc, count: 0 }, init::Monotonics(mono))
+    }
+
+    // The idle function is called when there is nothing else to do
+    #[idle]
+    fn idle(_: idle::Context) -> ! {
+        loop {
+            continue;
+        }
+    }
+
+    // The task functions are called by the scheduler
+    #[task(local = [tnc, count])]
+    fn beacon(ctx: beacon::Context) {
+        *ctx.local.count += 1;
+        let mut info: String<64> = String::new();
+        write!(info, "beacon {} from stm32f446", ctx.local.count).ok();
+
+        let frame = UiFrame::new(
+            Address::new(DESTINATION, 0).unwrap(),
+            Address::new(SOURCE, 1).unwrap(),
+            info.as_bytes(),
+        );
+        let mut bytes = [0; MAX_FRAME_LEN];
+        match frame.encode(&mut bytes) {
+            Ok(len) => match kiss::send(ctx.local.tnc, &bytes[..len]) {
+                Ok(()) => defmt::info!("sent {} bytes from {}", len, frame.source),
+                Err(error) => defmt::error!("kiss: {}", error),
+            },
+            Err(error) => defmt::error!("ax25: {}", error),
+        }
+        beacon::spawn_after(10.secs()).ok();
+    }
+}
@@ -31,6 +31,7 @@ mod app {
     #[init]
     fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
         defmt::info!("init");
+        stm32f446_rtic::image::self_check();
 
         // Cortex-M peripherals
         let mut _core : cortex_m::Peripherals = ctx.core;
@@ -11,6 +11,9 @@ mod app {
     use core::sync::atomic::{AtomicUsize, Ordering};
     use defmt::*;
     use dwt_systick_monotonic::{DwtSystick, ExtU32};
+    use fugit::TimerInstantU64;
+    use stm32f446_rtic::channel::{Channel, Receiver, Sender};
+    use stm32f446_rtic::timers::Timers;
     use stm32f4xx_hal::{
         can::Can,
         gpio::{
@@ -30,6 +33,9 @@ mod app {
     #[shared]
     struct Shared {
         can1: bxcan::Can<Can<CAN1, (PA12<Alternate<9>>, PA11<Alternate<9>>)>>,
+        timers: Timers<Timeout, 45_000_000, 4>,
+        // the pending spawn of timer_service, to move it when a timer is due earlier
+        timer_handle: Option<timer_service::SpawnHandle>,
     }
 
     // Holds the local resources (used by a single task)
@@ -38,13 +44,26 @@ mod app {
     struct Local {
         led: PA5<Output<PushPull>>,
         test_frame: [u8; 8],
+        // Received frames, from the CAN interrupt to can_handle
+        rx_frames: Sender<'static, Frame, RX_FRAMES>,
+        frames: Receiver<'static, Frame, RX_FRAMES>,
     }
 
     // Atomic counter
     static COUNTER: AtomicUsize = AtomicUsize::new(0);
 
+    // Frames that can wait for can_handle, the hardware FIFO only holds 3
+    const RX_FRAMES: usize = 16;
+
+    // Software timers, served by timer_service
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    pub enum Timeout {
+        // no frame came back after the last one sent
+        Ack,
+    }
+
     // The init function is called in the beginning of the program
-    #[init]
+    #[init(local = [rx_channel: Channel<Frame, RX_FRAMES> = Channel::new()])]
     fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
         info!("init");
 
@@ -114,12 +133,14 @@ mod app {
         // Set up the monotonic timer
         let mono = DwtSystick::new(&mut _core.DCB, _core.DWT, _core.SYST, clocks.hclk().to_Hz());
 
+        let (rx_frames, frames) = ctx.local.rx_channel.split();
+
         info!("Init done!");
         blink::spawn_after(1.secs()).ok();
         can_send::spawn_after(1.secs()).ok();
         (
-            Shared { can1 },
-            Local { led, test_frame },
+            Shared { can1, timers: Timers::new(), timer_handle: None },
+            Local { led, test_frame, rx_frames, frames },
             init::Monotonics(mono),
         )
     }
@@ -140,8 +161,8 @@ mod app {
         blink::spawn_after(1.secs()).ok();
     }
 
-    // send a meesage via CAN
-    #[task(shared = [can1], local = [test_frame], priority=2)]
+    // send a meesage via CAN, the answer is expected within 100 ms
+    #[task(shared = [can1, timers, timer_handle], local = [test_frame], priority=2)]
     fn can_send(mut ctx: can_send::Context) {
         let test_frame = ctx.local.test_frame;
         let id: u16 = 0x500;
@@ -152,19 +173,80 @@ mod app {
         info!("Sending frame with first byte: {}", test_frame[0]);
 
         ctx.shared.can1.lock(|can1| can1.transmit(&frame).unwrap());
+
+        (ctx.shared.timers, ctx.shared.timer_handle).lock(|timers, handle| {
+            match timers.start(Timeout::Ack, monotonics::now(), 100.millis()) {
+                Ok(at) => arm(handle, at),
+                Err(error) => warn!("timer: {}", error),
+            }
+        });
+    }
+
+    // nothing came back, send again
+    #[task]
+    fn ack_timeout(_: ack_timeout::Context) {
+        warn!("no answer within 100 ms");
+        can_send::spawn().ok();
+    }
+
+    // expires the timers that are due and waits for the next one
+    #[task(shared = [timers, timer_handle], priority = 2)]
+    fn timer_service(ctx: timer_service::Context) {
+        (ctx.shared.timers, ctx.shared.timer_handle).lock(|timers, handle| {
+            let next = timers.expire(monotonics::now(), |timeout| match timeout {
+                Timeout::Ack => ack_timeout::spawn().ok(),
+            });
+            *handle = None;
+            arm(handle, next);
+        });
+    }
+
+    // moves timer_service to run at `at`, or spawns it if it is not pending
+    fn arm(
+        handle: &mut Option<timer_service::SpawnHandle>,
+        at: Option<TimerInstantU64<45_000_000>>,
+    ) {
+        if let Some(at) = at {
+            match handle.take() {
+                // an error means it is about to run, and it arms itself again
+                Some(spawned) => *handle = spawned.reschedule_at(at).ok(),
+                None => *handle = timer_service::spawn_at(at).ok(),
+            }
+        }
     }
 
-    // receive a message via CAN
-    #[task(binds = CAN1_RX0, shared = [can1])]
+    // receive messages via CAN, everything in the FIFO goes to can_handle
+    #[task(binds = CAN1_RX0, shared = [can1], local = [rx_frames], priority = 3)]
     fn can_receive(ctx: can_receive::Context) {
         let mut can1 = ctx.shared.can1;
-        let frame = can1.lock(|can1| can1.receive().unwrap());
-
-        info!(
-            "Received frame with first byte: {}",
-            frame.data().unwrap().first().unwrap()
-        );
+        let rx_frames = ctx.local.rx_frames;
+        can1.lock(|can1| loop {
+            match can1.receive() {
+                Ok(frame) => {
+                    if rx_frames.send(frame).is_err() {
+                        warn!("rx channel full");
+                    }
+                }
+                Err(nb::Error::WouldBlock) => break,
+                Err(nb::Error::Other(_)) => warn!("rx fifo overrun"),
+            }
+        });
+        can_handle::spawn().ok();
+    }
 
-        can_send::spawn().ok();
+    // answer every frame that came in
+    #[task(shared = [timers], local = [frames])]
+    fn can_handle(mut ctx: can_handle::Context) {
+        let frames = ctx.local.frames;
+        for frame in frames.drain() {
+            // the answer to the last frame sent
+            ctx.shared.timers.lock(|timers| timers.cancel(Timeout::Ack));
+            info!(
+                "Received frame with first byte: {}",
+                frame.data().and_then(|data| data.first()).copied().unwrap_or(0)
+            );
+            can_send::spawn().ok();
+        }
+        debug!("rx channel: {}", frames.stats());
     }
 }
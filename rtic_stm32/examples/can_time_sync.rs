@@ -0,0 +1,222 @@
+#![no_main]
+#![no_std]
+
+use stm32f446_rtic as _; // global logger + panicking-behavior + memory layout
+
+// Common time base over CAN, wired up like can_ping_pong.
+// The node with TIME_SYNC_MASTER set to 1 in its configuration sends a SYNC and a FOLLOW_UP
+// every second, the others follow its clock and log how far off their own is.
+#[rtic::app(device = stm32f4xx_hal::pac, dispatchers = [USART1, USART2])]
+mod app {
+    use bxcan::filter::Mask32;
+    use bxcan::{Data, Fifo, Frame, Id, Mailbox, StandardId};
+    use defmt::*;
+    use dwt_systick_monotonic::{DwtSystick, ExtU32};
+    use stm32f446_rtic::config::{self, keys, ConfigStore};
+    use stm32f446_rtic::flash::InternalFlash;
+    use stm32f446_rtic::timesync::{self, CaptureClock, Master, Message, SyncEstimator, Transmit};
+    use stm32f4xx_hal::{
+        can::Can,
+        gpio::{
+            gpioa::{PA11, PA12},
+            Alternate,
+        },
+        pac::CAN1,
+        prelude::*,
+    };
+
+    // Needed for scheduling monotonic tasks
+    #[monotonic(binds = SysTick, default = true)]
+    type MyMono = DwtSystick<45_000_000>; // 45 MHz
+
+    type Can1 = bxcan::Can<Can<CAN1, (PA12<Alternate<9>>, PA11<Alternate<9>>)>>;
+
+    // Holds the shared resources (used by multiple tasks)
+    #[shared]
+    struct Shared {
+        can1: Can1,
+        master: Master,
+        // mailbox the last SYNC went out of
+        sync_mailbox: Option<Mailbox>,
+        estimator: SyncEstimator,
+    }
+
+    // Holds the local resources (used by a single task)
+    #[local]
+    struct Local {
+        tx_clock: CaptureClock,
+        rx_clock: CaptureClock,
+    }
+
+    /// Local time in microseconds
+    fn now() -> u64 {
+        monotonics::now().duration_since_epoch().to_micros()
+    }
+
+    // The init function is called in the beginning of the program
+    #[init]
+    fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
+        info!("init");
+
+        // Cortex-M peripherals
+        let mut _core: cortex_m::Peripherals = ctx.core;
+
+        // Device specific peripherals
+        let mut _device: stm32f4xx_hal::pac::Peripherals = ctx.device;
+
+        // Set up the system clock.
+        let rcc = _device.RCC.constrain();
+        let clocks = rcc.cfgr.sysclk(45.MHz()).freeze(); // Important: 45 MHz is the max for CAN since it has to match the APB1 clock
+
+        let mut config = ConfigStore::new(InternalFlash::new(_device.FLASH), config::SECTORS);
+        config.load().ok();
+        let is_master = config.get_u32(keys::TIME_SYNC_MASTER) == Some(1);
+        let bit_timing = config.get_u32(keys::CAN_BIT_TIMING).unwrap_or(0x001b0002);
+        info!("time sync {}", if is_master { "master" } else { "slave" });
+
+        // Set up CAN device 1 with start of frame time stamps
+        let gpioa = _device.GPIOA.split();
+        let mut can1 = {
+            let rx = gpioa.pa11.into_alternate::<9>();
+            let tx = gpioa.pa12.into_alternate::<9>();
+            let can = _device.CAN1.can((tx, rx));
+
+            let builder = bxcan::Can::builder(can)
+                .set_bit_timing(bit_timing)
+                .set_automatic_retransmit(true);
+            timesync::enable_timestamps();
+            builder.enable()
+        };
+
+        can1.enable_interrupts({
+            use bxcan::Interrupts as If;
+            If::FIFO0_MESSAGE_PENDING | If::TRANSMIT_MAILBOX_EMPTY
+        });
+        can1.modify_filters()
+            .enable_bank(0, Fifo::Fifo0, Mask32::accept_all());
+
+        let tick = timesync::bit_time_micros(bit_timing, clocks.pclk1().to_Hz());
+
+        // enable tracing and the cycle counter for the monotonic timer
+        _core.DCB.enable_trace();
+        _core.DWT.enable_cycle_counter();
+
+        // Set up the monotonic timer
+        let mono = DwtSystick::new(&mut _core.DCB, _core.DWT, _core.SYST, clocks.hclk().to_Hz());
+
+        info!("Init done!");
+        if is_master {
+            send_sync::spawn_after(1.secs()).ok();
+        } else {
+            report::spawn_after(5.secs()).ok();
+        }
+        (
+            Shared {
+                can1,
+                master: Master::new(),
+                sync_mailbox: None,
+                estimator: SyncEstimator::new(),
+            },
+            Local {
+                tx_clock: CaptureClock::new(tick),
+                rx_clock: CaptureClock::new(tick),
+            },
+            init::Monotonics(mono),
+        )
+    }
+
+    // The idle function is called when there is nothing else to do
+    #[idle]
+    fn idle(_: idle::Context) -> ! {
+        loop {
+            continue;
+        }
+    }
+
+    fn transmit(can1: &mut Can1, message: Message) -> Option<Mailbox> {
+        let mut buf = [0; 8];
+        let data = Data::new(message.encode(&mut buf)).unwrap();
+        let frame = Frame::new_data(StandardId::new(message.id()).unwrap(), data);
+        can1.transmit(&frame).ok().map(|status| status.mailbox())
+    }
+
+    // master: start a sync, the follow up is sent once the frame is out
+    #[task(shared = [can1, master, sync_mailbox], priority = 2)]
+    fn send_sync(ctx: send_sync::Context) {
+        (ctx.shared.can1, ctx.shared.master, ctx.shared.sync_mailbox).lock(|can1, master, sync_mailbox| {
+            *sync_mailbox = transmit(can1, master.sync());
+        });
+        send_sync::spawn_after(1.secs()).ok();
+    }
+
+    #[task(shared = [can1, master], priority = 2)]
+    fn send_follow_up(ctx: send_follow_up::Context, sent: u64) {
+        (ctx.shared.can1, ctx.shared.master).lock(|can1, master| {
+            transmit(can1, master.follow_up(sent));
+        });
+    }
+
+    // a frame went out, the SYNC gets its time stamp
+    #[task(binds = CAN1_TX, shared = [sync_mailbox], local = [tx_clock], priority = 3)]
+    fn can_sent(mut ctx: can_sent::Context) {
+        let now = now();
+        let sync_mailbox = ctx.shared.sync_mailbox.lock(|mailbox| *mailbox);
+        for mailbox in [Mailbox::Mailbox0, Mailbox::Mailbox1, Mailbox::Mailbox2] {
+            match timesync::tx_timestamp(mailbox) {
+                Transmit::Sent(capture) if Some(mailbox) == sync_mailbox => {
+                    let sent = ctx.local.tx_clock.sample(capture, now);
+                    send_follow_up::spawn(sent).ok();
+                }
+                Transmit::Failed if Some(mailbox) == sync_mailbox => warn!("SYNC was not sent"),
+                _ => {}
+            }
+        }
+    }
+
+    // slave: pair up SYNC and FOLLOW_UP
+    #[task(binds = CAN1_RX0, shared = [can1, estimator], local = [rx_clock], priority = 3)]
+    fn can_receive(ctx: can_receive::Context) {
+        let now = now();
+        let capture = timesync::rx_timestamp(Fifo::Fifo0);
+        (ctx.shared.can1, ctx.shared.estimator).lock(|can1, estimator| {
+            let frame = match can1.receive() {
+                Ok(frame) => frame,
+                Err(_) => return,
+            };
+            let id = match frame.id() {
+                Id::Standard(id) => id.as_raw(),
+                Id::Extended(_) => return,
+            };
+            match frame.data().and_then(|data| Message::parse(id, data)) {
+                Some(Message::Sync { sequence }) => {
+                    estimator.sync(sequence, ctx.local.rx_clock.sample(capture, now));
+                }
+                Some(Message::FollowUp {
+                    sequence,
+                    master_micros,
+                }) => {
+                    if let Some(fit) = estimator.follow_up(sequence, master_micros) {
+                        debug!("sync {}: {}", sequence, fit);
+                    }
+                }
+                None => {}
+            }
+        });
+    }
+
+    #[task(shared = [estimator])]
+    fn report(mut ctx: report::Context) {
+        let local = now();
+        let (fit, master) = ctx.shared.estimator.lock(|estimator| (estimator.fit(), estimator.to_master(local)));
+        match (fit, master) {
+            (Some(fit), Some(master)) => info!(
+                "master time {} us, offset {} us, drift {} ppm",
+                master,
+                fit.offset_micros,
+                fit.drift_ppm
+            ),
+            _ => info!("no sync from the master yet"),
+        }
+        report::spawn_after(5.secs()).ok();
+    }
+}
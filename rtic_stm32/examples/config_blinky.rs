@@ -0,0 +1,127 @@
+#![no_main]
+#![no_std]
+
+use stm32f446_rtic as _; // global logger + panicking-behavior + memory layout
+
+// Blinky with the period read from the configuration store.
+// Pressing the user button (PC13) halves the period and saves it, so it survives a reset.
+#[rtic::app(device = stm32f4xx_hal::pac, dispatchers = [USART1])]
+mod app {
+    use dwt_systick_monotonic::{DwtSystick, ExtU32};
+    use stm32f446_rtic::config::{self, keys, ConfigStore};
+    use stm32f446_rtic::flash::InternalFlash;
+    use stm32f4xx_hal::{
+        gpio::{gpioa::PA5, gpioc::PC13, Edge, Input, Output, PushPull},
+        prelude::*,
+    };
+
+    // Needed for scheduling monotonic tasks
+    #[monotonic(binds = SysTick, default = true)]
+    type MyMono = DwtSystick<48_000_000>; // 48 MHz
+
+    // Holds the shared resources (used by multiple tasks)
+    #[shared]
+    struct Shared {
+        config: ConfigStore<InternalFlash>,
+    }
+
+    // Holds the local resources (used by a single task)
+    #[local]
+    struct Local {
+        led: PA5<Output<PushPull>>,
+        button: PC13<Input>,
+    }
+
+    // The init function is called in the beginning of the program
+    #[init]
+    fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
+        defmt::info!("init");
+
+        // Cortex-M peripherals
+        let mut _core : cortex_m::Peripherals = ctx.core;
+
+        // Device specific peripherals
+        let mut _device : stm32f4xx_hal::pac::Peripherals = ctx.device;
+
+        // Set up the system clock.
+        let rcc = _device.RCC.constrain();
+        let clocks = rcc.cfgr.sysclk(48.MHz()).freeze();
+
+        // Load the configuration, falls back to the defaults on a fresh board
+        let mut config = ConfigStore::new(InternalFlash::new(_device.FLASH), config::SECTORS);
+        match config.load() {
+            Ok(loaded) => defmt::info!("config: {}", loaded),
+            Err(_) => defmt::error!("config: could not read flash, using defaults"),
+        }
+
+        // Set up the LED. On the Nucleo-F446RE it's connected to pin PA5.
+        let gpioa = _device.GPIOA.split();
+        let led = gpioa.pa5.into_push_pull_output();
+
+        // Set up the button. On the Nucleo-F446RE it's connected to pin PC13.
+        let gpioc = _device.GPIOC.split();
+        let mut button = gpioc.pc13.into_floating_input();
+        let mut sys_cfg = _device.SYSCFG.constrain();
+        button.make_interrupt_source(&mut sys_cfg);
+        button.enable_interrupt(&mut _device.EXTI);
+        button.trigger_on_edge(&mut _device.EXTI, Edge::Falling);
+
+        // enable tracing and the cycle counter for the monotonic timer
+        _core.DCB.enable_trace();
+        _core.DWT.enable_cycle_counter();
+
+        // Set up the monotonic timer
+        let mono = DwtSystick::new(
+            &mut _core.DCB,
+            _core.DWT,
+            _core.SYST,
+            clocks.hclk().to_Hz(),
+        );
+
+        defmt::info!("Init done!");
+        blink::spawn().ok();
+        (Shared { config }, Local { led, button }, init::Monotonics(mono))
+    }
+
+    // The idle function is called when there is nothing else to do
+    #[idle]
+    fn idle(_: idle::Context) -> ! {
+        loop {
+            continue;
+        }
+    }
+
+    // Blink with whatever period is in the configuration right now
+    #[task(shared = [config], local = [led])]
+    fn blink(mut ctx: blink::Context) {
+        ctx.local.led.toggle();
+        let period = ctx
+            .shared
+            .config
+            .lock(|config| config.get_u32(keys::BLINK_PERIOD_MS))
+            .unwrap_or(1_000);
+        blink::spawn_after(period.millis()).ok();
+    }
+
+    // Halve the period, going back to 2 s when it gets too fast to see
+    #[task(shared = [config])]
+    fn speed_up(mut ctx: speed_up::Context) {
+        ctx.shared.config.lock(|config| {
+            let period = match config.get_u32(keys::BLINK_PERIOD_MS) {
+                Some(period) if period > 125 => period / 2,
+                _ => 2_000,
+            };
+            // erasing a sector stalls the CPU for a while, this is why it runs at the lowest priority
+            match config.update_u32(keys::BLINK_PERIOD_MS, period) {
+                Ok(()) => defmt::info!("blink period {} ms saved (seq {})", period, config.sequence()),
+                Err(_) => defmt::error!("could not save blink period"),
+            }
+        });
+    }
+
+    #[task(binds = EXTI15_10, local = [button])]
+    fn on_button(ctx: on_button::Context) {
+        ctx.local.button.clear_interrupt_pending_bit();
+        speed_up::spawn().ok();
+    }
+}
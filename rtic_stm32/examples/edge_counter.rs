@@ -6,9 +6,16 @@
 use stm32f446_rtic as _; // global logger + panicking-behavior + memory layout
 use rtic::app;
 
-#[app(device = stm32f4xx_hal::pac, peripherals = true, dispatchers = [SPI1])]
+// Presses of the user button (PC13) go from the EXTI handler to the blink task through a
+// channel, time stamped, so every press and release is seen and how long it was held.
+// A bouncing button makes bursts of edges, the handler logs at most 5 of them at once and
+// one every 200 ms after that. The log also goes to the log store in flash.
+#[app(device = stm32f4xx_hal::pac, peripherals = true, dispatchers = [SPI1, SPI2])]
 mod app {
-    use core::sync::atomic::{AtomicUsize, Ordering};
+    use stm32f446_rtic::channel::{Channel, Receiver, Sender, Stamped};
+    use stm32f446_rtic::flash::InternalFlash;
+    use stm32f446_rtic::log::{self, modules, RateLimit, Sinks};
+    use stm32f446_rtic::logstore::{self, LogStore};
     use stm32f4xx_hal::{
         gpio::{gpioa::PA5, gpioc::PC13, Alternate, Edge, Input, Output, Pin, PushPull},
         prelude::*,
@@ -17,8 +24,8 @@ mod app {
     use dwt_systick_monotonic::{DwtSystick, ExtU32};
     use rtic::Mutex;
 
-    // AtomicUsize is a thread-safe integer type
-    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    // Room for 15 edges between two blinks
+    const EDGES: usize = 16;
 
     #[shared]
     struct Shared {
@@ -29,12 +36,17 @@ mod app {
     struct Local {
         led: Pin<'A', 5, Output<PushPull>>,
         button: Pin<'C', 13, Input>,
+        // true when pressed, the button pulls the pin low
+        edges: Sender<'static, Stamped<bool>, EDGES>,
+        events: Receiver<'static, Stamped<bool>, EDGES>,
+        pressed_at: Option<u64>,
+        store: LogStore<InternalFlash, 1>,
     }
 
     #[monotonic(binds = SysTick, default = true)]
     type MyMono = DwtSystick<48_000_000>; // 48 MHz
 
-    #[init]
+    #[init(local = [channel: Channel<Stamped<bool>, EDGES> = Channel::new()])]
     fn init(mut ctx: init::Context) -> (Shared, Local, init::Monotonics) {
         defmt::info!("init");
 
@@ -60,7 +72,8 @@ mod app {
         let mut sys_cfg = _device.SYSCFG.constrain();
         button.make_interrupt_source(&mut sys_cfg);
         button.enable_interrupt(&mut _device.EXTI);
-        button.trigger_on_edge(&mut _device.EXTI, Edge::Falling);
+        button.trigger_on_edge(&mut _device.EXTI, Edge::RisingFalling);
+        let (edges, events) = ctx.local.channel.split();
 
         // enable tracing and the cycle counter for the monotonic timer
         _core.DCB.enable_trace();
@@ -76,9 +89,21 @@ mod app {
 
         let mut exti = _device.EXTI;
 
+        // erases the sector the first time, which stalls everything for about a second
+        let mut store = LogStore::new(InternalFlash::new(_device.FLASH), logstore::SECTORS);
+        match store.load() {
+            Ok(()) => log::set_sinks(Sinks::RTT | Sinks::FLASH),
+            Err(error) => defmt::error!("log store: {}", error),
+        }
+
         blink::spawn().ok();
+        save_log::spawn().ok();
 
-        (Shared { exti }, Local { button, led }, init::Monotonics(mono))
+        (
+            Shared { exti },
+            Local { button, led, edges, events, pressed_at: None, store },
+            init::Monotonics(mono),
+        )
     }
 
     // The idle function is called when there is nothing else to do
@@ -89,18 +114,41 @@ mod app {
         }
     }
 
-    #[task(local = [led], priority = 4)]
+    #[task(local = [led, events, pressed_at], priority = 4)]
     fn blink(ctx: blink::Context) {
-        // reset the counter and get the old value.
-        let count = COUNTER.swap(0, Ordering::SeqCst);
-        defmt::info!("{}", count);
+        // everything that happened since the last blink
+        let mut count = 0;
+        for edge in ctx.local.events.drain() {
+            if edge.value {
+                count += 1;
+                *ctx.local.pressed_at = Some(edge.at);
+            } else if let Some(pressed_at) = ctx.local.pressed_at.take() {
+                log::info!(modules::APP, "held for {} ms", (edge.at - pressed_at) / 1000);
+            }
+        }
+        log::info!(modules::APP, "{} presses, {}", count, ctx.local.events.stats());
         ctx.local.led.toggle();
         blink::spawn_after(1.secs()).ok();
     }
 
+    // Flash is slow to write, so the log goes there at the lowest priority
+    #[task(local = [store], priority = 1)]
+    fn save_log(ctx: save_log::Context) {
+        if let Err(error) = log::drain_flash(ctx.local.store) {
+            // to RTT only, or it would come straight back here
+            log::set_sinks(Sinks::RTT);
+            defmt::error!("log store: {}", error);
+        }
+        save_log::spawn_after(1.secs()).ok();
+    }
+
     // This is the interrupt handler for the button, it is bound to the EXTI15_10 interrupt
     // as the the button is connected to pin PC13 and 13 is in the range 10-15.
-    #[task(binds = EXTI15_10, local = [button], shared = [exti])]
+    #[task(
+        binds = EXTI15_10,
+        local = [button, edges, edge_log: RateLimit = RateLimit::new(200_000, 5)],
+        shared = [exti]
+    )]
     fn on_exti(mut ctx: on_exti::Context) {
         
         // Lock the mutex to get access to the EXTI peripheral
@@ -110,14 +158,18 @@ mod app {
 
         // If it's not from the button, return
         if !is_button {
-            defmt::info!("not button");
+            log::info!(modules::APP, "not button");
             ctx.local.button.clear_interrupt_pending_bit();
             return;
         }
 
         // Clear the interrupt pending bit as rtic does not do this automatically.
         ctx.local.button.clear_interrupt_pending_bit();
-        defmt::info!("incrementing");
-        COUNTER.fetch_add(1, Ordering::SeqCst);
+        let at = monotonics::now().duration_since_epoch().to_micros();
+        // a full channel counts the overflow, nothing else to do about it here
+        ctx.local.edges.send_at(at, ctx.local.button.is_low()).ok();
+        if let Some(suppressed) = ctx.local.edge_log.allow(at) {
+            log::debug!(modules::APP, "edge at {} us, {} not logged before it", at, suppressed);
+        }
     }
 }
@@ -0,0 +1,103 @@
+#![no_main]
+#![no_std]
+
+use stm32f446_rtic as _; // global logger + panicking-behavior + memory layout
+
+// Times the downlink coding on the board with the cycle counter: Reed-Solomon encoding of a
+// codeblock interleaved to depth 5 (1115 bytes of frame), the randomizer, and decoding the
+// block with 16 errors put into every codeword.
+#[rtic::app(device = stm32f4xx_hal::pac, dispatchers = [USART1])]
+mod app {
+    use cortex_m::peripheral::DWT;
+    use dwt_systick_monotonic::DwtSystick;
+    use stm32f446_rtic::fec::{self, N};
+    use stm32f4xx_hal::prelude::*;
+
+    // Needed for scheduling monotonic tasks
+    #[monotonic(binds = SysTick, default = true)]
+    type MyMono = DwtSystick<48_000_000>; // 48 MHz
+
+    const DEPTH: usize = 5;
+
+    // Holds the shared resources (used by multiple tasks)
+    #[shared]
+    struct Shared {}
+
+    // Holds the local resources (used by a single task)
+    #[local]
+    struct Local {}
+
+    // The init function is called in the beginning of the program
+    #[init]
+    fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
+        defmt::info!("init");
+
+        // Cortex-M peripherals
+        let mut _core : cortex_m::Peripherals = ctx.core;
+
+        // Device specific peripherals
+        let mut _device : stm32f4xx_hal::pac::Peripherals = ctx.device;
+
+        // Set up the system clock.
+        let rcc = _device.RCC.constrain();
+        let clocks = rcc.cfgr.sysclk(48.MHz()).freeze();
+
+        // enable tracing and the cycle counter for the monotonic timer
+        _core.DCB.enable_trace();
+        _core.DWT.enable_cycle_counter();
+
+        // Set up the monotonic timer
+        let mono = DwtSystick::new(
+            &mut _core.DCB,
+            _core.DWT,
+            _core.SYST,
+            clocks.hclk().to_Hz(),
+        );
+
+        defmt::info!("Init done!");
+        measure::spawn().ok();
+        (Shared {}, Local {}, init::Monotonics(mono))
+    }
+
+    // The idle function is called when there is nothing else to do
+    #[idle]
+    fn idle(_: idle::Context) -> ! {
+        loop {
+            continue;
+        }
+    }
+
+    // The task functions are called by the scheduler
+    #[task(local = [block: [u8; N * DEPTH] = [0; N * DEPTH]])]
+    fn measure(ctx: measure::Context) {
+        let block = ctx.local.block;
+        for (i, byte) in block.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+
+        let start = DWT::cycle_count();
+        fec::encode(block, DEPTH).unwrap();
+        let encode = DWT::cycle_count().wrapping_sub(start);
+
+        let start = DWT::cycle_count();
+        fec::randomize(block);
+        fec::randomize(block);
+        let randomize = DWT::cycle_count().wrapping_sub(start) / 2;
+
+        // 16 errors in every codeword, all the code can take
+        for i in 0..16 * DEPTH {
+            block[i * 13 % block.len()] ^= 0x5A;
+        }
+        let start = DWT::cycle_count();
+        let result = fec::decode(block, DEPTH);
+        let decode = DWT::cycle_count().wrapping_sub(start);
+
+        defmt::info!(
+            "encode {} cycles, randomize {} cycles, decode {} cycles: {}",
+            encode,
+            randomize,
+            decode,
+            result
+        );
+    }
+}
@@ -3,6 +3,12 @@
 
 use stm32f446_rtic as _; // global logger + panicking-behavior + memory layout
 
+// blink and add are released on a fixed grid with `spawn_at`, so their run time does not add
+// to the period; add is 500 ms off the second so the two never come due together
+//
+// Every task records into the trace, which is dumped every 10 s; `tools/tracetool` turns the
+// log into a timeline for Perfetto
+
 #[rtic::app(device = stm32f4xx_hal::pac, dispatchers = [USART1, USART2])]
 mod app {
     use dwt_systick_monotonic::{DwtSystick, ExtU32};
@@ -10,6 +16,16 @@ mod app {
         gpio::{gpioa::PA5,gpioa::PA1, Input, Output, PushPull},
         prelude::*,
     };
+    use stm32f446_rtic::periodic::Periodic;
+    use stm32f446_rtic::trace;
+
+    // What the ids in the trace stand for
+    const NAMES: &[&str] = &["blink", "add", "fancy", "global", "dump"];
+    const BLINK: u8 = 0;
+    const ADD: u8 = 1;
+    const FANCY: u8 = 2;
+    const GLOBAL: u8 = 3; // ceiling 2, blink and add
+    const DUMP: u8 = 4;
 
     // Needed for scheduling monotonic tasks
     #[monotonic(binds = SysTick, default = true)]
@@ -31,6 +47,8 @@ mod app {
         ex_led: PA1<Output<PushPull>>,
         a: u32,
         b: u32,
+        blink_period: Periodic<48_000_000>,
+        add_period: Periodic<48_000_000>,
     }
 
     // The init function is called in the beginning of the program
@@ -67,10 +85,13 @@ mod app {
         );
 
         defmt::info!("Init done!");
-        //spawn the functions
-        blink::spawn_after(1.secs()).ok();
-        add::spawn_after(2.secs()).ok();
-        (Shared {global:0}, Local { led, ex_led, a:0, b:0 }, init::Monotonics(mono))
+        //spawn the functions at their first release
+        let blink_period = Periodic::new(1.secs());
+        let add_period = Periodic::new(2.secs()).phase(500.millis());
+        blink::spawn_at(blink_period.release()).ok();
+        add::spawn_at(add_period.release()).ok();
+        dump::spawn_after(10.secs()).ok();
+        (Shared {global:0}, Local { led, ex_led, a:0, b:0, blink_period, add_period }, init::Monotonics(mono))
     }
 
     // The idle function is called when there is nothing else to do
@@ -86,37 +107,65 @@ mod app {
 
     #[task]
     fn fancy(ctx: fancy::Context){
+        let _trace = trace::task(FANCY);
         defmt::info!("this task has run");
     }
 
 
 
     // The task functions are called by the scheduler
-    #[task(priority=2, shared=[global], local = [led, ex_led])]
+    #[task(priority=2, shared=[global], local = [led, ex_led, blink_period])]
     fn blink(mut ctx: blink::Context) {                 //changing predefined values add mut to ctx
+        let _trace = trace::task(BLINK);
         ctx.local.led.toggle();                         //toggle internal led
         ctx.local.ex_led.toggle();
-        ctx.shared.global.lock(|global| *global +=1);   //lock and increment global
+        ctx.shared.global.lock(|global| {
+            let _trace = trace::lock(GLOBAL, 2);
+            *global +=1                                 //lock and increment global
+        });
         defmt::info!("Blink!");
+        trace::spawn(FANCY);
         fancy::spawn().ok();
-        blink::spawn_after(1.secs()).ok();
+        let next = ctx.local.blink_period.next(monotonics::now());
+        blink::spawn_at(next).ok();
     }
 
 
 
-    #[task(priority=1, shared=[global], local=[a, b])]
+    #[task(priority=1, shared=[global], local=[a, b, add_period])]
     fn add(mut ctx: add::Context) {                           //changing predefined values add mut to ctx
+    let _trace = trace::task(ADD);
     defmt::info!("task2");
-    ctx.shared.global.lock(|global| *global +=1);   //lock and increment global
+    ctx.shared.global.lock(|global| {
+        let _trace = trace::lock(GLOBAL, 2);
+        *global +=1                                 //lock and increment global
+    });
     let mut d:u32=0;                                          //needs to be equal to something, type is not needed
-    ctx.shared.global.lock(|global| d=*global);     //lock and assign d with global value
+    ctx.shared.global.lock(|global| {
+        let _trace = trace::lock(GLOBAL, 2);
+        d=*global                                   //lock and assign d with global value
+    });
     *ctx.local.a+=1;
     *ctx.local.b+=1;
     let result = *ctx.local.a + *ctx.local.b;
     defmt::info!("global: {}", d);
     //defmt::info!("Result: {}", result);
-    add::spawn_after(2.secs()).ok();
+    let next = ctx.local.add_period.next(monotonics::now());
+    add::spawn_at(next).ok();
+    if ctx.local.add_period.stats().overruns > 0 {
+        defmt::warn!("add overran: {}", ctx.local.add_period.stats());
+    }
 }
+
+
+
+    // the trace of the last 10 s, or of the last 512 events of it
+    #[task(priority=1)]
+    fn dump(_: dump::Context) {
+        trace::mark(DUMP, trace::lost() as u16);
+        trace::dump_defmt(48_000_000, NAMES);
+        dump::spawn_after(10.secs()).ok();
+    }
     
 }
     
\ No newline at end of file
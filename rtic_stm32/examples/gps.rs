@@ -0,0 +1,147 @@
+#![no_main]
+#![no_std]
+
+use stm32f446_rtic as _; // global logger + panicking-behavior + memory layout
+
+// GPS receiver on USART6 at 9600 baud, its TX wired to PC7 (D9).
+// The sentences come in over DMA, the parser keeps the latest fix, and the time from a valid
+// RMC or ZDA sets the RTC through the time service: right away the first time, then at every
+// full minute. Without the PPS line the time is only good to a few ms, how late the sentence
+// comes after the second it names is up to the receiver.
+#[rtic::app(device = stm32f4xx_hal::pac, dispatchers = [USART1])]
+mod app {
+    use dwt_systick_monotonic::DwtSystick;
+    use stm32f446_rtic::nmea::{Gps, Parser};
+    use stm32f446_rtic::rtc::Rtc;
+    use stm32f446_rtic::time::{Source, TaiTime, TimeService, DEFAULT_LEAP_SECONDS, MICROS_PER_SEC};
+    use stm32f446_rtic::uart::{self, UartRx};
+    use stm32f4xx_hal::prelude::*;
+
+    // Needed for scheduling monotonic tasks
+    #[monotonic(binds = SysTick, default = true)]
+    type MyMono = DwtSystick<48_000_000>; // 48 MHz
+
+    const BAUD: u32 = 9_600;
+
+    // From the second named in the sentence to the end of the sentence arriving, measured
+    // against PPS for the receiver on the bench
+    const SENTENCE_DELAY_MICROS: u64 = 120_000;
+
+    // Holds the shared resources (used by multiple tasks)
+    #[shared]
+    struct Shared {
+        rx: UartRx,
+        time: TimeService<Rtc>,
+    }
+
+    // Holds the local resources (used by a single task)
+    #[local]
+    struct Local {
+        parser: Parser,
+        gps: Gps,
+    }
+
+    // The init function is called in the beginning of the program
+    #[init]
+    fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
+        defmt::info!("init");
+
+        // Cortex-M peripherals
+        let mut _core : cortex_m::Peripherals = ctx.core;
+
+        // Device specific peripherals
+        let mut _device : stm32f4xx_hal::pac::Peripherals = ctx.device;
+
+        // Set up the system clock.
+        let rcc = _device.RCC.constrain();
+        let clocks = rcc.cfgr.sysclk(48.MHz()).freeze();
+
+        let rtc = Rtc::new(_device.RTC, &mut _device.PWR);
+        let time = TimeService::new(rtc, TaiTime(0), DEFAULT_LEAP_SECONDS, 0.0);
+
+        let gpioc = _device.GPIOC.split();
+        let buffer: uart::Buffer = cortex_m::singleton!(: [u8; 512] = [0; 512]).unwrap();
+        let rx = UartRx::new(
+            _device.USART6,
+            gpioc.pc7.into_alternate(),
+            BAUD,
+            clocks.pclk2().to_Hz(),
+            buffer,
+        );
+
+        // enable tracing and the cycle counter for the monotonic timer
+        _core.DCB.enable_trace();
+        _core.DWT.enable_cycle_counter();
+
+        // Set up the monotonic timer
+        let mono = DwtSystick::new(
+            &mut _core.DCB,
+            _core.DWT,
+            _core.SYST,
+            clocks.hclk().to_Hz(),
+        );
+
+        defmt::info!("Init done!");
+        (
+            Shared { rx, time },
+            Local {
+                parser: Parser::new(),
+                gps: Gps::new(),
+            },
+            init::Monotonics(mono),
+        )
+    }
+
+    // The idle function is called when there is nothing else to do
+    #[idle]
+    fn idle(_: idle::Context) -> ! {
+        loop {
+            continue;
+        }
+    }
+
+    // The line went idle after a burst
+    #[task(binds = USART6, shared = [rx], priority = 2)]
+    fn uart_idle(mut ctx: uart_idle::Context) {
+        ctx.shared.rx.lock(|rx| rx.on_interrupt());
+        process::spawn().ok();
+    }
+
+    // Half of the buffer filled up
+    #[task(binds = DMA2_STREAM1, shared = [rx], priority = 2)]
+    fn uart_dma(mut ctx: uart_dma::Context) {
+        ctx.shared.rx.lock(|rx| rx.on_interrupt());
+        process::spawn().ok();
+    }
+
+    // The task functions are called by the scheduler
+    #[task(shared = [rx, time], local = [parser, gps])]
+    fn process(mut ctx: process::Context) {
+        let parser = ctx.local.parser;
+        let gps = ctx.local.gps;
+        let mut utc = None;
+        ctx.shared.rx.lock(|rx| {
+            rx.read(|byte| match parser.push(byte) {
+                Some(Ok(sentence)) => utc = gps.update(sentence).or(utc),
+                Some(Err(error)) => defmt::debug!("nmea: {}", error),
+                None => {}
+            });
+        });
+
+        let utc = match utc {
+            Some(utc) => utc,
+            None => return,
+        };
+        ctx.shared.time.lock(|time| {
+            if time.is_synced() && utc / MICROS_PER_SEC % 60 != 0 {
+                return;
+            }
+            let reference = TaiTime::from_unix_utc(utc + SENTENCE_DELAY_MICROS, time.leap_seconds());
+            let correction = time.sync(reference, Source::Gps);
+            defmt::info!("time from gps: {}", correction);
+        });
+        if let Some(fix) = gps.fix {
+            defmt::info!("{} at {}, {} satellites", fix.quality, gps.position, fix.satellites);
+        }
+    }
+}
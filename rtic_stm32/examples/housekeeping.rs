@@ -0,0 +1,120 @@
+#![no_main]
+#![no_std]
+
+use stm32f446_rtic as _; // global logger + panicking-behavior + memory layout
+
+// Board health: MCU temperature, VDDA and two external channels, once a second.
+// A0 (PA0) is read as a battery behind a 10k/10k divider, A1 (PA1) as a plain voltage.
+#[rtic::app(device = stm32f4xx_hal::pac, dispatchers = [USART1])]
+mod app {
+    use cortex_m::singleton;
+    use dwt_systick_monotonic::{DwtSystick, ExtU32};
+    use stm32f446_rtic::adc::{self, Housekeeping, Readings, Scaling};
+    use stm32f4xx_hal::{
+        adc::config::{SampleTime, Sequence},
+        dma::StreamsTuple,
+        prelude::*,
+    };
+
+    // Needed for scheduling monotonic tasks
+    #[monotonic(binds = SysTick, default = true)]
+    type MyMono = DwtSystick<48_000_000>; // 48 MHz
+
+    // What the external channels are, in scan order
+    const EXTERNAL: &[Scaling] = &[
+        Scaling {
+            name: "battery",
+            gain: 2.0,
+            offset: 0.0,
+        },
+        Scaling::volts("a1"),
+    ];
+
+    // Holds the shared resources (used by multiple tasks)
+    #[shared]
+    struct Shared {
+        housekeeping: Housekeeping,
+    }
+
+    // Holds the local resources (used by a single task)
+    // Needed even if we don't use it
+    #[local]
+    struct Local {}
+
+    // The init function is called in the beginning of the program
+    #[init]
+    fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
+        defmt::info!("init");
+
+        // Cortex-M peripherals
+        let mut _core : cortex_m::Peripherals = ctx.core;
+
+        // Device specific peripherals
+        let mut _device : stm32f4xx_hal::pac::Peripherals = ctx.device;
+
+        // Set up the system clock.
+        let rcc = _device.RCC.constrain();
+        let clocks = rcc.cfgr.sysclk(48.MHz()).freeze();
+
+        // Temperature and VREFINT come first, then the external channels
+        let gpioa = _device.GPIOA.split();
+        let a0 = gpioa.pa0.into_analog();
+        let a1 = gpioa.pa1.into_analog();
+        let mut adc1 = adc::adc1(_device.ADC1);
+        adc1.configure_channel(&a0, Sequence::Three, SampleTime::Cycles_112);
+        adc1.configure_channel(&a1, Sequence::Four, SampleTime::Cycles_112);
+
+        let dma = StreamsTuple::new(_device.DMA2);
+        let first: adc::Buffer = singleton!(: [u16; 4] = [0; 4]).unwrap();
+        let second: adc::Buffer = singleton!(: [u16; 4] = [0; 4]).unwrap();
+        let housekeeping = Housekeeping::new(adc1, dma.0, [first, second], EXTERNAL);
+        defmt::info!("adc calibration: {}", housekeeping.calibration());
+
+        // enable tracing and the cycle counter for the monotonic timer
+        _core.DCB.enable_trace();
+        _core.DWT.enable_cycle_counter();
+
+        // Set up the monotonic timer
+        let mono = DwtSystick::new(
+            &mut _core.DCB,
+            _core.DWT,
+            _core.SYST,
+            clocks.hclk().to_Hz(),
+        );
+
+        defmt::info!("Init done!");
+        sample::spawn_after(1.secs()).ok();
+        (Shared { housekeeping }, Local {}, init::Monotonics(mono))
+    }
+
+    // The idle function is called when there is nothing else to do
+    #[idle]
+    fn idle(_: idle::Context) -> ! {
+        loop {
+            continue;
+        }
+    }
+
+    // The task functions are called by the scheduler
+    #[task(shared = [housekeeping])]
+    fn sample(mut ctx: sample::Context) {
+        ctx.shared.housekeeping.lock(|housekeeping| housekeeping.start());
+        sample::spawn_after(1.secs()).ok();
+    }
+
+    // The scan is in, swap buffers and hand the readings on
+    #[task(binds = DMA2_STREAM0, shared = [housekeeping], priority = 2)]
+    fn adc_done(mut ctx: adc_done::Context) {
+        if let Some(readings) = ctx.shared.housekeeping.lock(|housekeeping| housekeeping.transfer_complete()) {
+            report::spawn(readings).ok();
+        }
+    }
+
+    #[task]
+    fn report(_: report::Context, readings: Readings) {
+        defmt::info!("vdda {} mV, mcu {} C", readings.vdda_mv, readings.temperature);
+        for (scaling, value) in EXTERNAL.iter().zip(readings.external()) {
+            defmt::info!("{}: {}", scaling.name, value);
+        }
+    }
+}
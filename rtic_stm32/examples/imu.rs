@@ -0,0 +1,120 @@
+#![no_main]
+#![no_std]
+
+use stm32f446_rtic as _; // global logger + panicking-behavior + memory layout
+
+// LSM9DS1 breakout on I2C1: SCL on PB8 (D15), SDA on PB9 (D14).
+// Reads the IMU at 10 Hz, logs every 10th reading and the bus counters every 10 s.
+#[rtic::app(device = stm32f4xx_hal::pac, dispatchers = [USART1])]
+mod app {
+    use dwt_systick_monotonic::{DwtSystick, ExtU32};
+    use stm32f446_rtic::i2c::{I2cBus, Speed};
+    use stm32f446_rtic::imu::{Calibration, Config, Lsm9ds1};
+    use stm32f4xx_hal::prelude::*;
+
+    // Needed for scheduling monotonic tasks
+    #[monotonic(binds = SysTick, default = true)]
+    type MyMono = DwtSystick<48_000_000>; // 48 MHz
+
+    // Holds the shared resources (used by multiple tasks)
+    #[shared]
+    struct Shared {
+        i2c: I2cBus,
+    }
+
+    // Holds the local resources (used by a single task)
+    #[local]
+    struct Local {
+        imu: Lsm9ds1,
+        count: u32,
+    }
+
+    // The init function is called in the beginning of the program
+    #[init]
+    fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
+        defmt::info!("init");
+
+        // Cortex-M peripherals
+        let mut _core : cortex_m::Peripherals = ctx.core;
+
+        // Device specific peripherals
+        let mut _device : stm32f4xx_hal::pac::Peripherals = ctx.device;
+
+        // Set up the system clock.
+        let rcc = _device.RCC.constrain();
+        let clocks = rcc.cfgr.sysclk(48.MHz()).freeze();
+
+        // enable tracing and the cycle counter, the I2C timeouts need it before the monotonic
+        _core.DCB.enable_trace();
+        _core.DWT.enable_cycle_counter();
+
+        let gpiob = _device.GPIOB.split();
+        let scl = gpiob.pb8.into_alternate_open_drain();
+        let sda = gpiob.pb9.into_alternate_open_drain();
+        let mut i2c = I2cBus::new(
+            _device.I2C1,
+            (scl, sda),
+            Speed::Fast,
+            clocks.pclk1().to_Hz(),
+            clocks.sysclk().to_Hz(),
+            1_000,
+        );
+
+        let mut imu = Lsm9ds1::new(Config::default(), Calibration::default());
+        match imu.init(&mut i2c) {
+            Ok(()) => {
+                defmt::info!("imu found, calibrating the gyro, keep the board still");
+                match imu.calibrate_gyro(&mut i2c, 100) {
+                    Ok(bias) => defmt::info!("gyro bias {} rad/s", bias),
+                    Err(error) => defmt::error!("gyro calibration failed: {}", error),
+                }
+            }
+            Err(error) => defmt::error!("imu: {}", error),
+        }
+
+        // Set up the monotonic timer
+        let mono = DwtSystick::new(
+            &mut _core.DCB,
+            _core.DWT,
+            _core.SYST,
+            clocks.hclk().to_Hz(),
+        );
+
+        defmt::info!("Init done!");
+        read_imu::spawn().ok();
+        bus_stats::spawn_after(10.secs()).ok();
+        (Shared { i2c }, Local { imu, count: 0 }, init::Monotonics(mono))
+    }
+
+    // The idle function is called when there is nothing else to do
+    #[idle]
+    fn idle(_: idle::Context) -> ! {
+        loop {
+            continue;
+        }
+    }
+
+    // The task functions are called by the scheduler
+    #[task(shared = [i2c], local = [imu, count], priority = 2)]
+    fn read_imu(mut ctx: read_imu::Context) {
+        let imu = ctx.local.imu;
+        match ctx.shared.i2c.lock(|i2c| imu.read(i2c)) {
+            Ok(reading) => {
+                *ctx.local.count += 1;
+                if *ctx.local.count % 10 == 0 {
+                    defmt::info!("{}", reading);
+                }
+            }
+            Err(error) => defmt::warn!("imu: {}", error),
+        }
+        read_imu::spawn_after(100.millis()).ok();
+    }
+
+    // Another user of the bus, gets its turn through the lock
+    #[task(shared = [i2c])]
+    fn bus_stats(mut ctx: bus_stats::Context) {
+        let stats = ctx.shared.i2c.lock(|i2c| i2c.stats());
+        defmt::info!("i2c: {}", stats);
+        bus_stats::spawn_after(10.secs()).ok();
+    }
+}
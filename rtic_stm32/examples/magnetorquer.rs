@@ -0,0 +1,163 @@
+#![no_main]
+#![no_std]
+
+use stm32f446_rtic as _; // global logger + panicking-behavior + memory layout
+
+// Three magnetorquers: X on a half bridge pair driven by TIM1 with dead time, Y and Z on a
+// DRV8833 driven by TIM3. The control task commands a dipole that turns around the Z axis.
+// Pulling the fault line on PB12 low turns all of them off, they are re-armed a second later.
+#[rtic::app(device = stm32f4xx_hal::pac, dispatchers = [USART1])]
+mod app {
+    use dwt_systick_monotonic::{DwtSystick, ExtU32};
+    use stm32f446_rtic::pwm::{Magnetorquer, Pair, Tim1Bridge, Tim3Pwm};
+    use stm32f4xx_hal::prelude::*;
+
+    // Needed for scheduling monotonic tasks
+    #[monotonic(binds = SysTick, default = true)]
+    type MyMono = DwtSystick<48_000_000>; // 48 MHz
+
+    const PWM_HZ: u32 = 20_000;
+    const DEAD_TIME_NS: u32 = 500;
+    const SUPPLY_VOLTS: f32 = 5.0;
+
+    // 200 turns of 50 cm², 25 Ω
+    const TORQUER: Magnetorquer = Magnetorquer {
+        dipole_per_amp: 1.0,
+        resistance: 25.0,
+        min_duty: 0.01,
+    };
+
+    // Holds the shared resources (used by multiple tasks)
+    #[shared]
+    struct Shared {
+        x: Tim1Bridge,
+        yz: Tim3Pwm,
+    }
+
+    // Holds the local resources (used by a single task)
+    #[local]
+    struct Local {
+        step: u32,
+    }
+
+    // The init function is called in the beginning of the program
+    #[init]
+    fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
+        defmt::info!("init");
+
+        // Cortex-M peripherals
+        let mut _core : cortex_m::Peripherals = ctx.core;
+
+        // Device specific peripherals
+        let mut _device : stm32f4xx_hal::pac::Peripherals = ctx.device;
+
+        // Set up the system clock.
+        let rcc = _device.RCC.constrain();
+        let clocks = rcc.cfgr.sysclk(48.MHz()).freeze();
+
+        // Pins for the timers, see pwm.rs. The fault line has its pull-up on the driver board.
+        let gpioa = _device.GPIOA.split();
+        let gpiob = _device.GPIOB.split();
+        let gpioc = _device.GPIOC.split();
+        let _tim1_pins = (
+            gpioa.pa8.into_alternate::<1>(),
+            gpiob.pb13.into_alternate::<1>(),
+            gpioa.pa9.into_alternate::<1>(),
+            gpiob.pb14.into_alternate::<1>(),
+            gpiob.pb12.into_alternate::<1>(),
+        );
+        let _tim3_pins = (
+            gpiob.pb4.into_alternate::<2>(),
+            gpiob.pb5.into_alternate::<2>(),
+            gpioc.pc8.into_alternate::<2>(),
+            gpioc.pc9.into_alternate::<2>(),
+        );
+
+        let mut x = Tim1Bridge::new(_device.TIM1, clocks.timclk2().to_Hz(), PWM_HZ, DEAD_TIME_NS);
+        let yz = Tim3Pwm::new(_device.TIM3, clocks.timclk1().to_Hz(), PWM_HZ);
+        if !x.arm() {
+            defmt::warn!("fault line active, X torquer stays off");
+        }
+        defmt::info!("max dipole {} Am2", TORQUER.max_dipole(SUPPLY_VOLTS, 20.0));
+
+        // enable tracing and the cycle counter for the monotonic timer
+        _core.DCB.enable_trace();
+        _core.DWT.enable_cycle_counter();
+
+        // Set up the monotonic timer
+        let mono = DwtSystick::new(
+            &mut _core.DCB,
+            _core.DWT,
+            _core.SYST,
+            clocks.hclk().to_Hz(),
+        );
+
+        defmt::info!("Init done!");
+        control::spawn().ok();
+        (Shared { x, yz }, Local { step: 0 }, init::Monotonics(mono))
+    }
+
+    // The idle function is called when there is nothing else to do
+    #[idle]
+    fn idle(_: idle::Context) -> ! {
+        loop {
+            continue;
+        }
+    }
+
+    // The task functions are called by the scheduler
+    #[task(shared = [x, yz], local = [step])]
+    fn control(ctx: control::Context) {
+        // a dipole of 0.15 Am2 turning once every 10 s in the XY plane
+        let angle = *ctx.local.step as f32 * 0.062_831_85;
+        *ctx.local.step = (*ctx.local.step + 1) % 100;
+        let dipole = [0.15 * cos(angle), 0.15 * cos(angle - 1.570_796_3), 0.0];
+
+        let [x, y, z] = dipole.map(|dipole| TORQUER.command(dipole, SUPPLY_VOLTS, 20.0));
+        if x.saturated || y.saturated || z.saturated {
+            defmt::warn!("dipole {} out of range", dipole);
+        }
+        (ctx.shared.x, ctx.shared.yz).lock(|bridge_x, yz| {
+            bridge_x.set(x.duty);
+            yz.set_bridge(Pair::Ch1Ch2, y.duty);
+            yz.set_bridge(Pair::Ch3Ch4, z.duty);
+        });
+        control::spawn_after(100.millis()).ok();
+    }
+
+    // The fault line tripped TIM1, the hardware already turned X off
+    #[task(binds = TIM1_BRK_TIM9, shared = [x, yz], priority = 3)]
+    fn fault(ctx: fault::Context) {
+        (ctx.shared.x, ctx.shared.yz).lock(|x, yz| {
+            if x.on_break() {
+                x.safe_off();
+                yz.safe_off();
+                defmt::error!("torquer fault, all outputs off");
+                rearm::spawn_after(1.secs()).ok();
+            }
+        });
+    }
+
+    #[task(shared = [x])]
+    fn rearm(mut ctx: rearm::Context) {
+        if ctx.shared.x.lock(|x| x.arm()) {
+            defmt::info!("torquers armed again");
+        } else {
+            rearm::spawn_after(1.secs()).ok();
+        }
+    }
+
+    /// Cosine good to about 1e-3, enough for a demo pattern
+    fn cos(angle: f32) -> f32 {
+        use core::f32::consts::PI;
+        let mut x = angle;
+        while x > PI {
+            x -= 2.0 * PI;
+        }
+        while x < -PI {
+            x += 2.0 * PI;
+        }
+        let x2 = x * x;
+        1.0 - x2 / 2.0 + x2 * x2 / 24.0 - x2 * x2 * x2 / 720.0 + x2 * x2 * x2 * x2 / 40_320.0
+    }
+}
@@ -0,0 +1,127 @@
+#![no_main]
+#![no_std]
+
+use stm32f446_rtic as _; // global logger + panicking-behavior + memory layout
+
+// Mission time from the RTC.
+// Every 10 s logs UTC, the mission elapsed time and a telemetry time stamp. The RTC keeps
+// running through a reset, so MET carries on where it was.
+// `set_time` is what a telecommand or a GPS fix would spawn with the time it carries, here it
+// is spawned once with a made up time so the stepping and slewing can be seen.
+#[rtic::app(device = stm32f4xx_hal::pac, dispatchers = [USART1])]
+mod app {
+    use dwt_systick_monotonic::{DwtSystick, ExtU32};
+    use stm32f446_rtic::config::{self, keys, ConfigStore};
+    use stm32f446_rtic::flash::InternalFlash;
+    use stm32f446_rtic::rtc::Rtc;
+    use stm32f446_rtic::time::{Source, TaiTime, TimeService, DEFAULT_LEAP_SECONDS, MICROS_PER_SEC};
+    use stm32f4xx_hal::prelude::*;
+
+    // Needed for scheduling monotonic tasks
+    #[monotonic(binds = SysTick, default = true)]
+    type MyMono = DwtSystick<48_000_000>; // 48 MHz
+
+    // Holds the shared resources (used by multiple tasks)
+    #[shared]
+    struct Shared {
+        time: TimeService<Rtc>,
+    }
+
+    // Holds the local resources (used by a single task)
+    #[local]
+    struct Local {
+        config: ConfigStore<InternalFlash>,
+    }
+
+    // The init function is called in the beginning of the program
+    #[init]
+    fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
+        defmt::info!("init");
+
+        // Cortex-M peripherals
+        let mut _core : cortex_m::Peripherals = ctx.core;
+
+        // Device specific peripherals
+        let mut _device : stm32f4xx_hal::pac::Peripherals = ctx.device;
+
+        // Set up the system clock.
+        let rcc = _device.RCC.constrain();
+        let clocks = rcc.cfgr.sysclk(48.MHz()).freeze();
+
+        // The mission epoch, leap seconds and RTC correction are kept in the configuration
+        let mut config = ConfigStore::new(InternalFlash::new(_device.FLASH), config::SECTORS);
+        config.load().ok();
+        let epoch = TaiTime::from_secs(config.get_u32(keys::MISSION_EPOCH).unwrap_or(0) as u64);
+        let leap_seconds = config
+            .get_u32(keys::LEAP_SECONDS)
+            .map_or(DEFAULT_LEAP_SECONDS, |value| value as i32);
+        let calibration = config
+            .get_u32(keys::RTC_CALIBRATION_PPB)
+            .map_or(0.0, |value| value as i32 as f32 / 1000.0);
+
+        let rtc = Rtc::new(_device.RTC, &mut _device.PWR);
+        let time = TimeService::new(rtc, epoch, leap_seconds, calibration);
+
+        // enable tracing and the cycle counter for the monotonic timer
+        _core.DCB.enable_trace();
+        _core.DWT.enable_cycle_counter();
+
+        // Set up the monotonic timer
+        let mono = DwtSystick::new(
+            &mut _core.DCB,
+            _core.DWT,
+            _core.SYST,
+            clocks.hclk().to_Hz(),
+        );
+
+        defmt::info!("Init done!");
+        report::spawn().ok();
+        // 2024-01-01 00:00:00 UTC
+        let reference = TaiTime::from_unix_utc(1_704_067_200 * MICROS_PER_SEC, leap_seconds);
+        set_time::spawn_after(5.secs(), reference, Source::Telecommand).ok();
+        (Shared { time }, Local { config }, init::Monotonics(mono))
+    }
+
+    // The idle function is called when there is nothing else to do
+    #[idle]
+    fn idle(_: idle::Context) -> ! {
+        loop {
+            continue;
+        }
+    }
+
+    // The task functions are called by the scheduler
+    #[task(shared = [time])]
+    fn report(mut ctx: report::Context) {
+        ctx.shared.time.lock(|time| {
+            let met = time.met();
+            defmt::info!(
+                "utc {} met {}.{:06} s, synced {}",
+                time.utc(),
+                met / MICROS_PER_SEC,
+                met % MICROS_PER_SEC,
+                time.is_synced()
+            );
+            // this is what goes into a telemetry packet
+            defmt::info!("time stamp {=[u8]:02x}", time.now().to_cuc());
+        });
+        report::spawn_after(10.secs()).ok();
+    }
+
+    // Bring the clock in line with a time from the ground or from GPS
+    #[task(shared = [time], local = [config])]
+    fn set_time(mut ctx: set_time::Context, reference: TaiTime, source: Source) {
+        let (correction, calibration) = ctx.shared.time.lock(|time| {
+            (time.sync(reference, source), time.calibration_ppm())
+        });
+        defmt::info!("set time from {}: {}", source, correction);
+
+        // keep the RTC correction for the next boot
+        let ppb = (calibration * 1000.0) as i32 as u32;
+        if ctx.local.config.get_u32(keys::RTC_CALIBRATION_PPB) != Some(ppb)
+            && ctx.local.config.update_u32(keys::RTC_CALIBRATION_PPB, ppb).is_err()
+        {
+            defmt::error!("could not save the rtc correction");
+        }
+    }
+}
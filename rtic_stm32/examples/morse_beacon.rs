@@ -0,0 +1,149 @@
+#![no_main]
+#![no_std]
+
+use stm32f446_rtic as _; // global logger + panicking-behavior + memory layout
+
+// A CW beacon on the LED (PA5) every 60 s: the call sign, the MCU temperature and VDDA from
+// the housekeeping ADC, e.g. "NOCALL NOCALL T 27 V 3296 K".
+// Any push-pull pin works as the key, the keying input of a transmitter the same as the LED.
+// Put your own callsign in CALLSIGN before a radio is keyed.
+#[rtic::app(device = stm32f4xx_hal::pac, dispatchers = [USART1])]
+mod app {
+    use cortex_m::singleton;
+    use dwt_systick_monotonic::{DwtSystick, ExtU32};
+    use stm32f446_rtic::adc::{self, Housekeeping, Readings};
+    use stm32f446_rtic::morse::{self, write_field, Error, Keyer};
+    use stm32f4xx_hal::{
+        dma::StreamsTuple,
+        gpio::{gpioa::PA5, Output, PushPull},
+        prelude::*,
+    };
+
+    // Needed for scheduling monotonic tasks
+    #[monotonic(binds = SysTick, default = true)]
+    type MyMono = DwtSystick<48_000_000>; // 48 MHz
+
+    const CALLSIGN: &str = "NOCALL";
+    const TEMPLATE: &str = "{call} {call} T {temp} V {vdda} K";
+    const WPM: u32 = 20;
+    const MESSAGE_LEN: usize = 64;
+
+    // Holds the shared resources (used by multiple tasks)
+    #[shared]
+    struct Shared {
+        housekeeping: Housekeeping,
+        readings: Option<Readings>,
+        keyer: Keyer<PA5<Output<PushPull>>, MESSAGE_LEN>,
+    }
+
+    // Holds the local resources (used by a single task)
+    // Needed even if we don't use it
+    #[local]
+    struct Local {}
+
+    // The init function is called in the beginning of the program
+    #[init]
+    fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
+        defmt::info!("init");
+
+        // Cortex-M peripherals
+        let mut _core : cortex_m::Peripherals = ctx.core;
+
+        // Device specific peripherals
+        let mut _device : stm32f4xx_hal::pac::Peripherals = ctx.device;
+
+        // Set up the system clock.
+        let rcc = _device.RCC.constrain();
+        let clocks = rcc.cfgr.sysclk(48.MHz()).freeze();
+
+        let gpioa = _device.GPIOA.split();
+        let keyer = Keyer::new(gpioa.pa5.into_push_pull_output(), WPM);
+        defmt::info!("{} ms a dot", keyer.timing().unit_ms);
+
+        // only the temperature and VREFINT
+        let dma = StreamsTuple::new(_device.DMA2);
+        let first: adc::Buffer = singleton!(: [u16; 2] = [0; 2]).unwrap();
+        let second: adc::Buffer = singleton!(: [u16; 2] = [0; 2]).unwrap();
+        let housekeeping = Housekeeping::new(adc::adc1(_device.ADC1), dma.0, [first, second], &[]);
+
+        // enable tracing and the cycle counter for the monotonic timer
+        _core.DCB.enable_trace();
+        _core.DWT.enable_cycle_counter();
+
+        // Set up the monotonic timer
+        let mono = DwtSystick::new(
+            &mut _core.DCB,
+            _core.DWT,
+            _core.SYST,
+            clocks.hclk().to_Hz(),
+        );
+
+        defmt::info!("Init done!");
+        sample::spawn().ok();
+        beacon::spawn_after(2.secs()).ok();
+        (
+            Shared {
+                housekeeping,
+                readings: None,
+                keyer,
+            },
+            Local {},
+            init::Monotonics(mono),
+        )
+    }
+
+    // The idle function is called when there is nothing else to do
+    #[idle]
+    fn idle(_: idle::Context) -> ! {
+        loop {
+            continue;
+        }
+    }
+
+    // The task functions are called by the scheduler
+    #[task(shared = [housekeeping])]
+    fn sample(mut ctx: sample::Context) {
+        ctx.shared.housekeeping.lock(|housekeeping| housekeeping.start());
+        sample::spawn_after(5.secs()).ok();
+    }
+
+    #[task(binds = DMA2_STREAM0, shared = [housekeeping, readings], priority = 2)]
+    fn adc_done(mut ctx: adc_done::Context) {
+        if let Some(readings) = ctx.shared.housekeeping.lock(|housekeeping| housekeeping.transfer_complete()) {
+            ctx.shared.readings.lock(|latest| *latest = Some(readings));
+        }
+    }
+
+    // Put the message together from the latest readings and start keying it
+    #[task(shared = [readings, keyer])]
+    fn beacon(mut ctx: beacon::Context) {
+        let readings = ctx.shared.readings.lock(|readings| *readings);
+        let message = morse::expand::<MESSAGE_LEN>(TEMPLATE, |name, message| match (name, readings) {
+            ("call", _) => write_field(message, format_args!("{}", CALLSIGN)),
+            ("temp", Some(readings)) => write_field(message, format_args!("{}", readings.temperature as i32)),
+            ("vdda", Some(readings)) => write_field(message, format_args!("{}", readings.vdda_mv)),
+            // no readings yet
+            ("temp" | "vdda", None) => write_field(message, format_args!("?")),
+            _ => Err(Error::UnknownField),
+        });
+        match message.and_then(|message| {
+            defmt::info!("beacon: {}", message.as_str());
+            ctx.shared.keyer.lock(|keyer| keyer.start(&message))
+        }) {
+            Ok(()) => key::spawn().ok(),
+            Err(error) => {
+                defmt::error!("beacon: {}", error);
+                beacon::spawn_after(60.secs()).ok()
+            }
+        };
+    }
+
+    // One dot, dash or gap, then again after its duration until the message is done
+    #[task(shared = [keyer])]
+    fn key(mut ctx: key::Context) {
+        match ctx.shared.keyer.lock(|keyer| keyer.step()) {
+            Some(ms) => key::spawn_after(ms.millis()).ok(),
+            None => beacon::spawn_after(60.secs()).ok(),
+        };
+    }
+}
@@ -0,0 +1,111 @@
+#![no_main]
+#![no_std]
+
+use stm32f446_rtic as _; // global logger + panicking-behavior + memory layout
+
+// The defmt log out of USART2 at 115200 baud, the ST-LINK virtual COM port, as well as RTT,
+// so it can be read with nothing but the USB cable:
+//
+//     stty -F /dev/ttyACM0 115200 raw -echo
+//     logtool show target/thumbv7em-none-eabihf/debug/examples/serial_log /dev/ttyACM0
+#[rtic::app(device = stm32f4xx_hal::pac, dispatchers = [USART1])]
+mod app {
+    use dwt_systick_monotonic::{DwtSystick, ExtU32};
+    use stm32f446_rtic::log::{self, modules, Sinks};
+    use stm32f446_rtic::log_uart::{self, LogUart};
+    use stm32f4xx_hal::{
+        gpio::{gpioa::PA5, Output, PushPull},
+        prelude::*,
+    };
+
+    // Needed for scheduling monotonic tasks
+    #[monotonic(binds = SysTick, default = true)]
+    type MyMono = DwtSystick<48_000_000>; // 48 MHz
+
+    const BAUD: u32 = 115_200;
+
+    // Holds the shared resources (used by multiple tasks)
+    #[shared]
+    struct Shared {}
+
+    // Holds the local resources (used by a single task)
+    #[local]
+    struct Local {
+        led: PA5<Output<PushPull>>,
+        uart: LogUart,
+        count: u32,
+    }
+
+    // The init function is called in the beginning of the program
+    #[init]
+    fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
+        // Cortex-M peripherals
+        let mut _core : cortex_m::Peripherals = ctx.core;
+
+        // Device specific peripherals
+        let mut _device : stm32f4xx_hal::pac::Peripherals = ctx.device;
+
+        // Set up the system clock.
+        let rcc = _device.RCC.constrain();
+        let clocks = rcc.cfgr.sysclk(48.MHz()).freeze();
+
+        // enable tracing and the cycle counter for the monotonic timer and the log timestamps
+        _core.DCB.enable_trace();
+        _core.DWT.enable_cycle_counter();
+        log::set_clock_hz(clocks.hclk().to_Hz());
+
+        // PA2 is USART2 TX, which the ST-LINK passes on over USB
+        let gpioa = _device.GPIOA.split();
+        let buffer: log_uart::Buffer = cortex_m::singleton!(: [u8; 128] = [0; 128]).unwrap();
+        let uart = LogUart::new(
+            _device.USART2,
+            gpioa.pa2.into_alternate(),
+            BAUD,
+            clocks.pclk1().to_Hz(),
+            buffer,
+        );
+        log::set_sinks(Sinks::RTT | Sinks::UART);
+        defmt::info!("init");
+
+        // Set up the LED. On the Nucleo-F446RE it's connected to pin PA5.
+        let led = gpioa.pa5.into_push_pull_output();
+
+        // Set up the monotonic timer
+        let mono = DwtSystick::new(
+            &mut _core.DCB,
+            _core.DWT,
+            _core.SYST,
+            clocks.hclk().to_Hz(),
+        );
+
+        defmt::info!("Init done!");
+        blink::spawn_after(1.secs()).ok();
+        (Shared {}, Local { led, uart, count: 0 }, init::Monotonics(mono))
+    }
+
+    // The idle function is called when there is nothing else to do
+    #[idle]
+    fn idle(_: idle::Context) -> ! {
+        loop {
+            continue;
+        }
+    }
+
+    // A transfer finished, or the logger queued a frame
+    #[task(binds = DMA1_STREAM6, local = [uart], priority = 2)]
+    fn log_dma(ctx: log_dma::Context) {
+        ctx.local.uart.on_interrupt();
+    }
+
+    // The task functions are called by the scheduler
+    #[task(local = [led, count])]
+    fn blink(ctx: blink::Context) {
+        ctx.local.led.toggle();
+        *ctx.local.count += 1;
+        log::info!(modules::APP, "Blink {}!", *ctx.local.count);
+        if *ctx.local.count % 10 == 0 {
+            log::debug!(modules::APP, "{}", log::stats());
+        }
+        blink::spawn_after(1.secs()).ok();
+    }
+}
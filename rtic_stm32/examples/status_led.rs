@@ -0,0 +1,110 @@
+#![no_main]
+#![no_std]
+
+use stm32f446_rtic as _; // global logger + panicking-behavior + memory layout
+
+// Status patterns on the LED (PA5): a heartbeat, then error code 3 from 10 s, a fault on top
+// of it from 20 s, the fault gone at 25 s so the error code is back, and at 35 s the error
+// cleared too, back to the heartbeat.
+// Any task can change the LED with `status_led.show(..)`, the `led` task does the timing.
+#[rtic::app(device = stm32f4xx_hal::pac, dispatchers = [USART1])]
+mod app {
+    use dwt_systick_monotonic::{DwtSystick, ExtU32};
+    use stm32f446_rtic::status_led::{Pattern, Priority, StatusLed, Switched, TICK_MS};
+    use stm32f4xx_hal::{
+        gpio::{gpioa::PA5, Output, PushPull},
+        prelude::*,
+    };
+
+    // Needed for scheduling monotonic tasks
+    #[monotonic(binds = SysTick, default = true)]
+    type MyMono = DwtSystick<48_000_000>; // 48 MHz
+
+    // Holds the shared resources (used by multiple tasks)
+    #[shared]
+    struct Shared {
+        status_led: StatusLed<Switched<PA5<Output<PushPull>>>>,
+    }
+
+    // Holds the local resources (used by a single task)
+    #[local]
+    struct Local {
+        step: u32,
+    }
+
+    // The init function is called in the beginning of the program
+    #[init]
+    fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
+        defmt::info!("init");
+
+        // Cortex-M peripherals
+        let mut _core : cortex_m::Peripherals = ctx.core;
+
+        // Device specific peripherals
+        let mut _device : stm32f4xx_hal::pac::Peripherals = ctx.device;
+
+        // Set up the system clock.
+        let rcc = _device.RCC.constrain();
+        let clocks = rcc.cfgr.sysclk(48.MHz()).freeze();
+
+        // Set up the LED. On the Nucleo-F446RE it's connected to pin PA5.
+        let gpioa = _device.GPIOA.split();
+        let mut status_led = StatusLed::new(Switched(gpioa.pa5.into_push_pull_output()));
+        status_led.show(Pattern::Heartbeat);
+
+        // enable tracing and the cycle counter for the monotonic timer
+        _core.DCB.enable_trace();
+        _core.DWT.enable_cycle_counter();
+
+        // Set up the monotonic timer
+        let mono = DwtSystick::new(
+            &mut _core.DCB,
+            _core.DWT,
+            _core.SYST,
+            clocks.hclk().to_Hz(),
+        );
+
+        defmt::info!("Init done!");
+        led::spawn().ok();
+        demo::spawn_after(10.secs()).ok();
+        (Shared { status_led }, Local { step: 0 }, init::Monotonics(mono))
+    }
+
+    // The idle function is called when there is nothing else to do
+    #[idle]
+    fn idle(_: idle::Context) -> ! {
+        loop {
+            continue;
+        }
+    }
+
+    // The task functions are called by the scheduler
+    #[task(shared = [status_led], priority = 2)]
+    fn led(mut ctx: led::Context) {
+        let now_ms = monotonics::now().duration_since_epoch().to_millis() as u32;
+        ctx.shared.status_led.lock(|status_led| status_led.update(now_ms));
+        led::spawn_after(TICK_MS.millis()).ok();
+    }
+
+    // What the rest of an application would do when things go wrong and right again
+    #[task(shared = [status_led], local = [step])]
+    fn demo(mut ctx: demo::Context) {
+        *ctx.local.step += 1;
+        let step = *ctx.local.step;
+        ctx.shared.status_led.lock(|status_led| {
+            match step {
+                1 => status_led.show(Pattern::Error(3)),
+                2 => status_led.show(Pattern::Fault),
+                3 => status_led.clear(Priority::Fault),
+                _ => status_led.clear(Priority::Error),
+            }
+            defmt::info!("showing {}", status_led.current());
+        });
+        let next = match step {
+            1 | 3 => 10,
+            2 => 5,
+            _ => return,
+        };
+        demo::spawn_after(next.secs()).ok();
+    }
+}
@@ -0,0 +1,241 @@
+#![no_main]
+#![no_std]
+
+use stm32f446_rtic as _; // global logger + panicking-behavior + memory layout
+
+// CCSDS telemetry and telecommands over a KISS TNC on USART2 (the ST-LINK virtual COM port).
+// A housekeeping packet with the mission time goes into virtual channel 0 every second and
+// fills 223 byte transfer frames, the size of a Reed-Solomon block so `fec` can be put in
+// front of the radio later. Telecommand frames coming in are checked and the packet in them
+// is logged and acknowledged on virtual channel 1. The telecommand frames carry one packet
+// straight in the data field, no segment header. Commands to `LOG_APID` change the log levels
+// and where the log goes, see `stm32f446_rtic::log::Command`; `ccsdstool tc 0x1ab 0x012 010103 tc.bin`
+// turns the CAN module down to warnings.
+// `tools/ccsdstool` decodes the frames on the PC and makes telecommands to send.
+#[rtic::app(device = stm32f4xx_hal::pac, dispatchers = [USART1])]
+mod app {
+    use ccsds::packet::{Cuc, PacketType, SequenceCounter, SpacePacket};
+    use ccsds::tc::TcFrame;
+    use ccsds::tm::{MasterChannel, TmConfig, VirtualChannel};
+    use dwt_systick_monotonic::{DwtSystick, ExtU32};
+    use stm32f446_rtic::kiss;
+    use stm32f446_rtic::log;
+    use stm32f446_rtic::rtc::Rtc;
+    use stm32f446_rtic::time::{TaiTime, TimeService, DEFAULT_LEAP_SECONDS};
+    use stm32f4xx_hal::{
+        pac::USART2,
+        prelude::*,
+        serial::{Event, Rx, Tx},
+    };
+
+    // Needed for scheduling monotonic tasks
+    #[monotonic(binds = SysTick, default = true)]
+    type MyMono = DwtSystick<48_000_000>; // 48 MHz
+
+    const SPACECRAFT_ID: u16 = 0x1AB;
+    const FRAME_LEN: usize = 223;
+    const HOUSEKEEPING_APID: u16 = 0x010;
+    const ACK_APID: u16 = 0x011;
+    const LOG_APID: u16 = 0x012;
+    /// Longest packet sent down here
+    const MAX_PACKET_LEN: usize = 64;
+
+    pub struct Downlink {
+        tnc: Tx<USART2>,
+        master: MasterChannel<FRAME_LEN>,
+        housekeeping: VirtualChannel<FRAME_LEN>,
+        acks: VirtualChannel<FRAME_LEN>,
+    }
+
+    // Holds the shared resources (used by multiple tasks)
+    #[shared]
+    struct Shared {
+        downlink: Downlink,
+        time: TimeService<Rtc>,
+    }
+
+    // Holds the local resources (used by a single task)
+    #[local]
+    struct Local {
+        uplink: Rx<USART2>,
+        decoder: kiss::Decoder,
+        housekeeping_count: SequenceCounter,
+        ack_count: SequenceCounter,
+        seconds: u32,
+    }
+
+    // The init function is called in the beginning of the program
+    #[init]
+    fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
+        defmt::info!("init");
+
+        // Cortex-M peripherals
+        let mut _core : cortex_m::Peripherals = ctx.core;
+
+        // Device specific peripherals
+        let mut _device : stm32f4xx_hal::pac::Peripherals = ctx.device;
+
+        // Set up the system clock.
+        let rcc = _device.RCC.constrain();
+        let clocks = rcc.cfgr.sysclk(48.MHz()).freeze();
+
+        // KISS TNCs talk 9600 8N1 unless told otherwise
+        let gpioa = _device.GPIOA.split();
+        let mut serial = _device
+            .USART2
+            .serial(
+                (gpioa.pa2.into_alternate(), gpioa.pa3.into_alternate()),
+                9600.bps(),
+                &clocks,
+            )
+            .unwrap();
+        serial.listen(Event::Rxne);
+        let (tnc, uplink) = serial.split();
+
+        let config = TmConfig {
+            spacecraft_id: SPACECRAFT_ID,
+            fecf: true,
+        };
+        let downlink = Downlink {
+            tnc,
+            master: MasterChannel::new(config).unwrap(),
+            housekeeping: VirtualChannel::new(0).unwrap(),
+            acks: VirtualChannel::new(1).unwrap(),
+        };
+
+        let rtc = Rtc::new(_device.RTC, &mut _device.PWR);
+        let time = TimeService::new(rtc, TaiTime::from_secs(0), DEFAULT_LEAP_SECONDS, 0.0);
+
+        // enable tracing and the cycle counter for the monotonic timer
+        _core.DCB.enable_trace();
+        _core.DWT.enable_cycle_counter();
+
+        // Set up the monotonic timer
+        let mono = DwtSystick::new(
+            &mut _core.DCB,
+            _core.DWT,
+            _core.SYST,
+            clocks.hclk().to_Hz(),
+        );
+
+        defmt::info!("Init done!");
+        housekeeping::spawn().ok();
+        (
+            Shared { downlink, time },
+            Local {
+                uplink,
+                decoder: kiss::Decoder::new(),
+                housekeeping_count: SequenceCounter::new(),
+                ack_count: SequenceCounter::new(),
+                seconds: 0,
+            },
+            init::Monotonics(mono),
+        )
+    }
+
+    // The idle function is called when there is nothing else to do
+    #[idle]
+    fn idle(_: idle::Context) -> ! {
+        loop {
+            continue;
+        }
+    }
+
+    // The task functions are called by the scheduler
+    #[task(shared = [downlink, time], local = [housekeeping_count, seconds])]
+    fn housekeeping(mut ctx: housekeeping::Context) {
+        *ctx.local.seconds += 1;
+        let seconds = *ctx.local.seconds;
+        let now = ctx.shared.time.lock(|time| Cuc::from_bytes(time.now().to_cuc()));
+
+        // uptime and a made up battery voltage in mV
+        let mut data = [0; 6];
+        data[..4].copy_from_slice(&seconds.to_be_bytes());
+        data[4..].copy_from_slice(&3700u16.to_be_bytes());
+        let packet = SpacePacket::telemetry(HOUSEKEEPING_APID, ctx.local.housekeeping_count.next_count(), now, &data);
+
+        ctx.shared.downlink.lock(|downlink| {
+            send(downlink, packet, false);
+            // do not keep the ground waiting for a frame to fill up
+            if seconds % 10 == 0 {
+                let Downlink { tnc, master, housekeeping, .. } = downlink;
+                housekeeping.flush(master, |frame| transmit(tnc, frame));
+            }
+        });
+        housekeeping::spawn_after(1.secs()).ok();
+    }
+
+    // A byte from the TNC, a whole KISS frame is a telecommand transfer frame
+    #[task(binds = USART2, shared = [downlink, time], local = [uplink, decoder, ack_count])]
+    fn uplink(mut ctx: uplink::Context) {
+        let byte = match ctx.local.uplink.read() {
+            Ok(byte) => byte,
+            Err(_) => return,
+        };
+        let frame = match ctx.local.decoder.push(byte) {
+            Some(Ok(frame)) if frame.command == kiss::Command::Data => frame,
+            Some(Err(error)) => {
+                defmt::warn!("kiss: {}", error);
+                return;
+            }
+            _ => return,
+        };
+
+        let command = match TcFrame::decode(frame.data).and_then(|frame| {
+            defmt::info!("tc frame {}", frame.header);
+            SpacePacket::decode(frame.data)
+        }) {
+            Ok(packet) if packet.header.packet_type == PacketType::Telecommand => packet,
+            Ok(packet) => {
+                defmt::warn!("not a telecommand: {}", packet.header);
+                return;
+            }
+            Err(error) => {
+                defmt::warn!("tc: {}", error);
+                return;
+            }
+        };
+        defmt::info!("command for apid {}: {=[u8]:02x}", command.header.apid, command.data);
+        if command.header.apid == LOG_APID {
+            if let Err(error) = log::Command::decode(command.data).and_then(|change| change.apply()) {
+                defmt::warn!("log command: {}", error);
+                return;
+            }
+        }
+
+        // the acknowledgement is the primary header of the command
+        let header = match command.header.encode() {
+            Ok(header) => header,
+            Err(_) => return,
+        };
+        let now = ctx.shared.time.lock(|time| Cuc::from_bytes(time.now().to_cuc()));
+        let ack = SpacePacket::telemetry(ACK_APID, ctx.local.ack_count.next_count(), now, &header);
+        ctx.shared.downlink.lock(|downlink| send(downlink, ack, true));
+    }
+
+    /// Put a packet into its virtual channel, acknowledgements go out right away
+    fn send(downlink: &mut Downlink, packet: SpacePacket, ack: bool) {
+        let mut bytes = [0; MAX_PACKET_LEN];
+        let len = match packet.encode(&mut bytes) {
+            Ok(len) => len,
+            Err(error) => {
+                defmt::error!("packet: {}", error);
+                return;
+            }
+        };
+        let Downlink { tnc, master, housekeeping, acks } = downlink;
+        let channel = if ack { acks } else { housekeeping };
+        if let Err(error) = channel.push(master, &bytes[..len], |frame| transmit(tnc, frame)) {
+            defmt::error!("tm: {}", error);
+        }
+        if ack {
+            channel.flush(master, |frame| transmit(tnc, frame));
+        }
+    }
+
+    fn transmit(tnc: &mut Tx<USART2>, frame: &[u8]) {
+        if let Err(error) = kiss::send(tnc, frame) {
+            defmt::error!("kiss: {}", error);
+        }
+    }
+}
@@ -0,0 +1,111 @@
+#![no_main]
+#![no_std]
+
+use stm32f446_rtic as _; // global logger + panicking-behavior + memory layout
+
+// Blinky for flashing over CAN through the bootloader.
+// Build it for the primary slot with: cargo build --example updatable_blinky --features bootloader
+// and convert it to a binary with: cargo objcopy --example updatable_blinky --features bootloader -- -O binary blinky.bin
+// After 10 s of blinking it confirms itself, if it never gets that far the bootloader rolls back.
+// A new image runs with the watchdog started by the bootloader, blink feeds it.
+#[rtic::app(device = stm32f4xx_hal::pac, dispatchers = [USART1])]
+mod app {
+    use dwt_systick_monotonic::{DwtSystick, ExtU32};
+    use stm32f446_rtic::boot;
+    use stm32f446_rtic::config::{self, ConfigStore};
+    use stm32f446_rtic::flash::InternalFlash;
+    use stm32f4xx_hal::{
+        gpio::{gpioa::PA5, Output, PushPull},
+        prelude::*,
+        watchdog::IndependentWatchdog,
+    };
+
+    // Needed for scheduling monotonic tasks
+    #[monotonic(binds = SysTick, default = true)]
+    type MyMono = DwtSystick<48_000_000>; // 48 MHz
+
+    // Holds the shared resources (used by multiple tasks)
+    // Needed even if we don't use it
+    #[shared]
+    struct Shared {}
+
+    // Holds the local resources (used by a single task)
+    #[local]
+    struct Local {
+        led: PA5<Output<PushPull>>,
+        config: ConfigStore<InternalFlash>,
+        watchdog: IndependentWatchdog,
+    }
+
+    // The init function is called in the beginning of the program
+    #[init]
+    fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
+        defmt::info!("init");
+        stm32f446_rtic::image::self_check();
+
+        // Cortex-M peripherals
+        let mut _core : cortex_m::Peripherals = ctx.core;
+
+        // Device specific peripherals
+        let mut _device : stm32f4xx_hal::pac::Peripherals = ctx.device;
+
+        // Set up the system clock.
+        let rcc = _device.RCC.constrain();
+        let clocks = rcc.cfgr.sysclk(48.MHz()).freeze();
+
+        // The boot state lives in the configuration store
+        let mut config = ConfigStore::new(InternalFlash::new(_device.FLASH), config::SECTORS);
+        config.load().ok();
+        defmt::info!("boot state: {}", boot::state(&config));
+
+        // Set up the LED. On the Nucleo-F446RE it's connected to pin PA5.
+        let gpioa = _device.GPIOA.split();
+        let led = gpioa.pa5.into_push_pull_output();
+
+        // Only running when the bootloader is testing this image, feeding it otherwise does nothing
+        let watchdog = IndependentWatchdog::new(_device.IWDG);
+
+        // enable tracing and the cycle counter for the monotonic timer
+        _core.DCB.enable_trace();
+        _core.DWT.enable_cycle_counter();
+
+        // Set up the monotonic timer
+        let mono = DwtSystick::new(
+            &mut _core.DCB,
+            _core.DWT,
+            _core.SYST,
+            clocks.hclk().to_Hz(),
+        );
+
+        defmt::info!("Init done!");
+        blink::spawn_after(1.secs()).ok();
+        confirm::spawn_after(10.secs()).ok();
+        (Shared {}, Local { led, config, watchdog }, init::Monotonics(mono))
+    }
+
+    // The idle function is called when there is nothing else to do
+    #[idle]
+    fn idle(_: idle::Context) -> ! {
+        loop {
+            continue;
+        }
+    }
+
+    // The task functions are called by the scheduler
+    #[task(local = [led, watchdog])]
+    fn blink(ctx: blink::Context) {
+        ctx.local.watchdog.feed();
+        ctx.local.led.toggle();
+        defmt::info!("Blink!");
+        blink::spawn_after(1.secs()).ok();
+    }
+
+    // We made it this far, tell the bootloader to keep this image
+    #[task(local = [config])]
+    fn confirm(ctx: confirm::Context) {
+        match boot::confirm(ctx.local.config) {
+            Ok(()) => defmt::info!("image confirmed"),
+            Err(_) => defmt::error!("could not confirm image"),
+        }
+    }
+}
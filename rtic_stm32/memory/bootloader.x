@@ -0,0 +1,25 @@
+MEMORY
+{
+  /* NOTE K = KiBi = 1024 bytes */
+  /* The application slot of the CAN bootloader, sector 5. The last 48 bytes are the image trailer, see src/boot.rs. */
+  FLASH : ORIGIN = 0x08020000, LENGTH = 128K - 48
+  RAM : ORIGIN = 0x20000000, LENGTH = 128K
+}
+
+/* This is where the call stack will be allocated. */
+/* The stack is of the full descending type. */
+/* NOTE Do NOT modify `_stack_start` unless you know what you are doing */
+/* _stack_start = ORIGIN(RAM) + LENGTH(RAM); */
+
+/* The image header (src/header.rs) goes at a fixed offset from the start of the image, */
+/* right after the vector table, so tools can find it in a .bin file. */
+SECTIONS
+{
+  .image_header ORIGIN(FLASH) + 0x200 :
+  {
+    KEEP(*(.image_header));
+  } > FLASH
+} INSERT AFTER .vector_table;
+
+/* The code starts after the header */
+_stext = ORIGIN(FLASH) + 0x400;
@@ -0,0 +1,27 @@
+MEMORY
+{
+  /* NOTE K = KiBi = 1024 bytes */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 512K
+  RAM : ORIGIN = 0x20000000, LENGTH = 128K
+}
+
+/* This is where the call stack will be allocated. */
+/* The stack is of the full descending type. */
+/* NOTE Do NOT modify `_stack_start` unless you know what you are doing */
+/* _stack_start = ORIGIN(RAM) + LENGTH(RAM); */
+
+/* Sectors 2 and 3 (0x08008000 - 0x0800FFFF) hold the configuration store, see src/config.rs, */
+/* and sector 4 (0x08010000 - 0x0801FFFF) the log store, see src/logstore.rs. */
+/* The vector table stays at the start of sector 0 and the code starts after the stores at sector 5. */
+/* imagetool does not stamp images linked like this, their CRC would cover the stores. */
+_stext = ORIGIN(FLASH) + 128K;
+
+/* The image header (src/header.rs) goes at a fixed offset from the start of the image, */
+/* right after the vector table, so tools can find it in a .bin file. */
+SECTIONS
+{
+  .image_header ORIGIN(FLASH) + 0x200 :
+  {
+    KEEP(*(.image_header));
+  } > FLASH
+} INSERT AFTER .vector_table;
@@ -0,0 +1,264 @@
+//! Housekeeping with ADC1: MCU temperature, VDDA and external channels
+//!
+//! ADC1 scans the internal temperature sensor, VREFINT and up to `MAX_EXTERNAL` external
+//! channels into a buffer with DMA2 stream 0. There are two buffers, while the DMA fills one the
+//! other is converted, the same way as the adc_dma_rtic example of stm32f4xx-hal.
+//!
+//! VREFINT was measured at the factory with VDDA at 3.3 V, so comparing it against the
+//! calibration value gives VDDA, and that scales everything else. The temperature sensor was
+//! measured at 30 and 110 °C, the temperature is interpolated between those.
+
+use stm32f4xx_hal::{
+    adc::{
+        config::{AdcConfig, Dma, SampleTime, Scan, Sequence},
+        Adc, Temperature, Vref,
+    },
+    dma::{config::DmaConfig, PeripheralToMemory, Stream0, Transfer},
+    pac::{ADC1, DMA2},
+};
+
+/// External channels after the temperature sensor and VREFINT
+pub const MAX_EXTERNAL: usize = 6;
+
+/// Full scale of a 12 bit conversion
+pub const FULL_SCALE: u32 = 4095;
+
+/// VDDA the factory calibration was done at
+pub const CAL_VDDA_MV: u32 = 3300;
+
+/// Temperatures of the two temperature sensor calibration points
+pub const TS_CAL1_TEMP: f32 = 30.0;
+pub const TS_CAL2_TEMP: f32 = 110.0;
+
+// Where the calibration values are in system memory, from the datasheet
+const VREFINT_CAL_ADDR: usize = 0x1FFF_7A2A;
+const TS_CAL1_ADDR: usize = 0x1FFF_7A2C;
+const TS_CAL2_ADDR: usize = 0x1FFF_7A2E;
+
+/// Factory calibration values, raw readings at VDDA = 3.3 V
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Calibration {
+    pub vrefint: u16,
+    pub ts_cal1: u16,
+    pub ts_cal2: u16,
+}
+
+impl Calibration {
+    /// The values programmed into this chip
+    pub fn factory() -> Self {
+        // Safety: read only system memory that is always there on the F446
+        unsafe {
+            Calibration {
+                vrefint: core::ptr::read_volatile(VREFINT_CAL_ADDR as *const u16),
+                ts_cal1: core::ptr::read_volatile(TS_CAL1_ADDR as *const u16),
+                ts_cal2: core::ptr::read_volatile(TS_CAL2_ADDR as *const u16),
+            }
+        }
+    }
+
+    /// VDDA in millivolts from a VREFINT reading
+    pub fn vdda_mv(&self, vrefint_raw: u16) -> u32 {
+        if vrefint_raw == 0 {
+            return 0;
+        }
+        CAL_VDDA_MV * self.vrefint as u32 / vrefint_raw as u32
+    }
+
+    /// Temperature in °C from a temperature sensor reading taken at `vdda_mv`
+    pub fn temperature(&self, ts_raw: u16, vdda_mv: u32) -> f32 {
+        // what the reading would have been at the calibration VDDA
+        let raw = ts_raw as f32 * vdda_mv as f32 / CAL_VDDA_MV as f32;
+        let slope = (TS_CAL2_TEMP - TS_CAL1_TEMP) / (self.ts_cal2 as f32 - self.ts_cal1 as f32);
+        TS_CAL1_TEMP + (raw - self.ts_cal1 as f32) * slope
+    }
+}
+
+/// Millivolts on a pin for a raw reading
+pub fn millivolts(raw: u16, vdda_mv: u32) -> u32 {
+    raw as u32 * vdda_mv / FULL_SCALE
+}
+
+/// How an external channel turns into a physical value, `volts * gain + offset`
+///
+/// For a battery behind a divider of R1 over R2 the gain is (R1 + R2) / R2, for a current
+/// sense amplifier it is 1 / (gain * shunt).
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub struct Scaling {
+    pub name: &'static str,
+    pub gain: f32,
+    pub offset: f32,
+}
+
+impl Scaling {
+    /// The voltage on the pin
+    pub const fn volts(name: &'static str) -> Self {
+        Scaling {
+            name,
+            gain: 1.0,
+            offset: 0.0,
+        }
+    }
+
+    pub fn apply(&self, millivolts: u32) -> f32 {
+        millivolts as f32 / 1000.0 * self.gain + self.offset
+    }
+}
+
+/// One scan in physical units
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub struct Readings {
+    pub vdda_mv: u32,
+    pub temperature: f32,
+    external: [f32; MAX_EXTERNAL],
+    external_len: usize,
+}
+
+impl Readings {
+    /// Convert a scan, `raw` is temperature, VREFINT and then the external channels
+    pub fn convert(calibration: &Calibration, raw: &[u16], scaling: &[Scaling]) -> Self {
+        let vdda_mv = calibration.vdda_mv(raw[1]);
+        let mut readings = Readings {
+            vdda_mv,
+            temperature: calibration.temperature(raw[0], vdda_mv),
+            external: [0.0; MAX_EXTERNAL],
+            external_len: scaling.len().min(MAX_EXTERNAL),
+        };
+        for ((value, &raw), scaling) in readings.external.iter_mut().zip(&raw[2..]).zip(scaling) {
+            *value = scaling.apply(millivolts(raw, vdda_mv));
+        }
+        readings
+    }
+
+    /// External channel values, in the order of the scalings
+    pub fn external(&self) -> &[f32] {
+        &self.external[..self.external_len]
+    }
+}
+
+/// A buffer for one scan, two of them are needed
+pub type Buffer = &'static mut [u16];
+
+type AdcTransfer = Transfer<Stream0<DMA2>, 0, Adc<ADC1>, PeripheralToMemory, Buffer>;
+
+/// ADC1 set up for a scan of the temperature sensor and VREFINT with DMA
+///
+/// External channels go on from `Sequence::Three`, configure them on the returned ADC before
+/// handing it to `Housekeeping::new`.
+pub fn adc1(adc: ADC1) -> Adc<ADC1> {
+    let config = AdcConfig::default().dma(Dma::Continuous).scan(Scan::Enabled);
+    let mut adc = Adc::adc1(adc, true, config);
+    // the temperature sensor needs at least 10 us of sampling
+    adc.configure_channel(&Temperature, Sequence::One, SampleTime::Cycles_480);
+    adc.configure_channel(&Vref, Sequence::Two, SampleTime::Cycles_480);
+    adc.enable_temperature_and_vref();
+    adc
+}
+
+/// Scans started by `start`, converted in the DMA interrupt
+pub struct Housekeeping {
+    transfer: AdcTransfer,
+    spare: Option<Buffer>,
+    calibration: Calibration,
+    scaling: &'static [Scaling],
+}
+
+impl Housekeeping {
+    /// The buffers have to be two longer than `scaling`, one per channel in the scan
+    pub fn new(adc: Adc<ADC1>, stream: Stream0<DMA2>, buffers: [Buffer; 2], scaling: &'static [Scaling]) -> Self {
+        assert!(scaling.len() <= MAX_EXTERNAL);
+        let [first, second] = buffers;
+        assert!(first.len() == 2 + scaling.len() && second.len() == first.len());
+
+        let config = DmaConfig::default()
+            .transfer_complete_interrupt(true)
+            .memory_increment(true)
+            .double_buffer(false);
+        Housekeeping {
+            transfer: Transfer::init_peripheral_to_memory(stream, adc, first, None, config),
+            spare: Some(second),
+            calibration: Calibration::factory(),
+            scaling,
+        }
+    }
+
+    pub fn calibration(&self) -> &Calibration {
+        &self.calibration
+    }
+
+    /// Start a scan, the DMA2_STREAM0 interrupt comes when it is done
+    pub fn start(&mut self) {
+        self.transfer.start(|adc| adc.start_conversion());
+    }
+
+    /// Call from the DMA2_STREAM0 interrupt, hands the DMA the other buffer and converts this one
+    pub fn transfer_complete(&mut self) -> Option<Readings> {
+        self.transfer.clear_transfer_complete_interrupt();
+        let spare = self.spare.take()?;
+        let (filled, _) = match self.transfer.next_transfer(spare) {
+            Ok(done) => done,
+            Err(_) => {
+                defmt::error!("adc: dma error");
+                return None;
+            }
+        };
+        let readings = Readings::convert(&self.calibration, filled, self.scaling);
+        self.spare = Some(filled);
+        Some(readings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Typical values from the datasheet: VREFINT 1.21 V, V30 0.76 V and a slope of 2.5 mV/°C,
+    // read at VDDA = 3.3 V
+    const CAL: Calibration = Calibration {
+        vrefint: 1502, // 1.21 V / 3.3 V * 4095
+        ts_cal1: 943,  // 0.76 V
+        ts_cal2: 1191, // 0.96 V
+    };
+
+    fn raw(millivolts: f32, vdda_mv: f32) -> u16 {
+        (millivolts / vdda_mv * FULL_SCALE as f32).round() as u16
+    }
+
+    #[test]
+    fn vdda_from_vrefint() {
+        assert_eq!(CAL.vdda_mv(CAL.vrefint), 3300);
+        for vdda in [1800, 2500, 3000, 3300, 3600] {
+            let mv = CAL.vdda_mv(raw(1210.0, vdda as f32));
+            assert!(mv.abs_diff(vdda) <= 3, "{mv} mV for {vdda} mV");
+        }
+        assert_eq!(CAL.vdda_mv(0), 0);
+    }
+
+    #[test]
+    fn temperature_between_the_calibration_points() {
+        assert!((CAL.temperature(CAL.ts_cal1, 3300) - TS_CAL1_TEMP).abs() < 1e-3);
+        assert!((CAL.temperature(CAL.ts_cal2, 3300) - TS_CAL2_TEMP).abs() < 1e-3);
+        for (vdda, celsius) in [(3300.0, 25.0), (3300.0, 85.0), (3000.0, -40.0), (2500.0, 60.0), (3600.0, 125.0)] {
+            let sensor = 760.0 + (celsius - 30.0) * 2.5;
+            let t = CAL.temperature(raw(sensor, vdda), vdda as u32);
+            assert!((t - celsius).abs() < 0.5, "{t} °C for {celsius} °C at {vdda} mV");
+        }
+    }
+
+    #[test]
+    fn convert_a_scan() {
+        let vdda = 3000.0;
+        let battery = Scaling {
+            name: "battery",
+            gain: (100.0 + 47.0) / 47.0,
+            offset: 0.0,
+        };
+        let scan = [raw(760.0, vdda), raw(1210.0, vdda), FULL_SCALE as u16, raw(2000.0, vdda), 1234];
+        let readings = Readings::convert(&CAL, &scan, &[Scaling::volts("pin"), battery]);
+        assert!(readings.vdda_mv.abs_diff(3000) <= 3);
+        assert!((readings.temperature - 30.0).abs() < 0.5);
+        assert_eq!(readings.external().len(), 2);
+        assert!((readings.external()[0] - 3.0).abs() < 0.01);
+        assert!((readings.external()[1] - 2.0 * battery.gain).abs() < 0.01);
+        assert_eq!(millivolts(FULL_SCALE as u16, 3300), 3300);
+    }
+}
@@ -0,0 +1,556 @@
+//! AX.25 UI frames for the radio link
+//!
+//! Unnumbered information frames are all the downlink needs: no connection, no
+//! acknowledgements, each frame stands alone like a datagram. A frame is the destination and
+//! source callsigns, up to `MAX_DIGIPEATERS` repeaters, control 0x03, a protocol id and the
+//! information field with the telemetry.
+//!
+//! `UiFrame::encode` gives the frame the way a KISS TNC takes it (see `kiss`), without flags
+//! and frame check sequence. For a radio that takes raw bits, `hdlc_encode` adds the FCS, bit
+//! stuffing and flags and `HdlcDecoder` goes the other way. NRZI and scrambling are left to
+//! the modem.
+
+use crate::crc::crc16_x25;
+use heapless::Vec;
+
+pub const MAX_DIGIPEATERS: usize = 2;
+pub const MAX_INFO_LEN: usize = 256;
+
+/// Longest frame without flags, FCS included
+pub const MAX_FRAME_LEN: usize = 7 * (2 + MAX_DIGIPEATERS) + 2 + MAX_INFO_LEN + 2;
+
+pub const CONTROL_UI: u8 = 0x03;
+/// No layer 3 protocol, what APRS and most satellites use
+pub const PID_NONE: u8 = 0xF0;
+pub const FLAG: u8 = 0x7E;
+
+/// Addresses, control and PID of a UI frame with no digipeaters, plus the FCS
+const MIN_FRAME_LEN: usize = 7 * 2 + 2 + 2;
+const POLL_BIT: u8 = 0x10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+    /// Up to six capital letters and digits
+    Callsign,
+    /// Above 15
+    Ssid,
+    /// The information field is over `MAX_INFO_LEN`, or there are too many digipeaters
+    TooLong,
+    BufferTooSmall,
+    /// Shorter than the addresses, control and PID
+    TooShort,
+    /// An address field that makes no sense
+    Address,
+    /// Not a UI frame
+    NotUi,
+    /// The frame check sequence does not match
+    Fcs,
+    /// The frame did not end on a byte boundary
+    Alignment,
+}
+
+/// A callsign and SSID
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Address {
+    callsign: [u8; 6], // padded with spaces
+    ssid: u8,
+}
+
+impl Address {
+    pub fn new(callsign: &str, ssid: u8) -> Result<Self, Error> {
+        let valid = |b: u8| b.is_ascii_uppercase() || b.is_ascii_digit();
+        if callsign.is_empty() || callsign.len() > 6 || !callsign.bytes().all(valid) {
+            return Err(Error::Callsign);
+        }
+        if ssid > 15 {
+            return Err(Error::Ssid);
+        }
+        let mut padded = [b' '; 6];
+        padded[..callsign.len()].copy_from_slice(callsign.as_bytes());
+        Ok(Address {
+            callsign: padded,
+            ssid,
+        })
+    }
+
+    pub fn callsign(&self) -> &str {
+        let len = self.callsign.iter().position(|&b| b == b' ').unwrap_or(6);
+        // only ever holds ASCII
+        core::str::from_utf8(&self.callsign[..len]).unwrap_or("")
+    }
+
+    pub fn ssid(&self) -> u8 {
+        self.ssid
+    }
+
+    /// Seven bytes, the characters shifted up by one. `flag` is the C bit of the destination
+    /// and source, the H bit of a digipeater, `last` ends the address field.
+    fn encode(&self, flag: bool, last: bool) -> [u8; 7] {
+        let mut bytes = [0; 7];
+        for (byte, &c) in bytes.iter_mut().zip(&self.callsign) {
+            *byte = c << 1;
+        }
+        bytes[6] = (flag as u8) << 7 | 0b0110_0000 | self.ssid << 1 | last as u8;
+        bytes
+    }
+
+    /// The address and whether it is the last one
+    fn decode(bytes: &[u8]) -> Result<(Address, bool), Error> {
+        let mut callsign = [b' '; 6];
+        for (c, &byte) in callsign.iter_mut().zip(bytes) {
+            // the extension bit is only set on the SSID byte
+            if byte & 1 != 0 {
+                return Err(Error::Address);
+            }
+            *c = byte >> 1;
+        }
+        let len = callsign.iter().position(|&b| b == b' ').unwrap_or(6);
+        let callsign = core::str::from_utf8(&callsign[..len]).map_err(|_| Error::Address)?;
+        let address = Address::new(callsign, (bytes[6] >> 1) & 0x0F).map_err(|_| Error::Address)?;
+        Ok((address, bytes[6] & 1 != 0))
+    }
+}
+
+impl defmt::Format for Address {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "{=str}-{=u8}", self.callsign(), self.ssid)
+    }
+}
+
+/// An unnumbered information frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UiFrame<'a> {
+    pub destination: Address,
+    pub source: Address,
+    /// The repeaters to go through, whether they already did is not kept
+    pub digipeaters: Vec<Address, MAX_DIGIPEATERS>,
+    pub pid: u8,
+    pub info: &'a [u8],
+}
+
+impl<'a> UiFrame<'a> {
+    pub fn new(destination: Address, source: Address, info: &'a [u8]) -> Self {
+        UiFrame {
+            destination,
+            source,
+            digipeaters: Vec::new(),
+            pid: PID_NONE,
+            info,
+        }
+    }
+
+    /// Bytes `encode` needs
+    pub fn encoded_len(&self) -> usize {
+        7 * (2 + self.digipeaters.len()) + 2 + self.info.len()
+    }
+
+    /// The frame without flags and FCS, sent as a command like AX.25 2.0 wants, returns the
+    /// length
+    pub fn encode(&self, out: &mut [u8]) -> Result<usize, Error> {
+        if self.info.len() > MAX_INFO_LEN {
+            return Err(Error::TooLong);
+        }
+        let len = self.encoded_len();
+        let out = out.get_mut(..len).ok_or(Error::BufferTooSmall)?;
+
+        let last_digipeater = self.digipeaters.len().checked_sub(1);
+        out[0..7].copy_from_slice(&self.destination.encode(true, false));
+        out[7..14].copy_from_slice(&self.source.encode(false, last_digipeater.is_none()));
+        for (i, digipeater) in self.digipeaters.iter().enumerate() {
+            let start = 14 + 7 * i;
+            out[start..start + 7].copy_from_slice(&digipeater.encode(false, Some(i) == last_digipeater));
+        }
+        let header = 7 * (2 + self.digipeaters.len());
+        out[header] = CONTROL_UI;
+        out[header + 1] = self.pid;
+        out[header + 2..].copy_from_slice(self.info);
+        Ok(len)
+    }
+
+    /// Read a frame without flags and FCS
+    pub fn decode(frame: &'a [u8]) -> Result<Self, Error> {
+        let mut addresses = frame.chunks(7);
+        let mut next_address = || match addresses.next() {
+            Some(bytes) if bytes.len() == 7 => Address::decode(bytes),
+            _ => Err(Error::TooShort),
+        };
+        let (destination, last) = next_address()?;
+        if last {
+            return Err(Error::Address);
+        }
+        let (source, mut last) = next_address()?;
+        let mut digipeaters = Vec::new();
+        while !last {
+            let (digipeater, end) = next_address()?;
+            digipeaters.push(digipeater).map_err(|_| Error::TooLong)?;
+            last = end;
+        }
+
+        let header = 7 * (2 + digipeaters.len());
+        match frame.get(header..header + 2) {
+            Some(&[control, pid]) if control & !POLL_BIT == CONTROL_UI => Ok(UiFrame {
+                destination,
+                source,
+                digipeaters,
+                pid,
+                info: &frame[header + 2..],
+            }),
+            Some(_) => Err(Error::NotUi),
+            None => Err(Error::TooShort),
+        }
+    }
+}
+
+/// Bits packed least significant first, the order they go on air
+struct BitWriter<'a> {
+    out: &'a mut [u8],
+    bits: usize,
+    ones: u8,
+}
+
+impl BitWriter<'_> {
+    fn raw(&mut self, bit: bool) -> Result<(), Error> {
+        let byte = self.out.get_mut(self.bits / 8).ok_or(Error::BufferTooSmall)?;
+        if self.bits & 7 == 0 {
+            *byte = 0;
+        }
+        *byte |= (bit as u8) << (self.bits % 8);
+        self.bits += 1;
+        Ok(())
+    }
+
+    /// A data bit, with a 0 stuffed in after five 1s
+    fn data(&mut self, byte: u8) -> Result<(), Error> {
+        for i in 0..8 {
+            let bit = byte >> i & 1 != 0;
+            self.raw(bit)?;
+            self.ones = if bit { self.ones + 1 } else { 0 };
+            if self.ones == 5 {
+                self.raw(false)?;
+                self.ones = 0;
+            }
+        }
+        Ok(())
+    }
+
+    fn flag(&mut self) -> Result<(), Error> {
+        for i in 0..8 {
+            self.raw(FLAG >> i & 1 != 0)?;
+        }
+        self.ones = 0;
+        Ok(())
+    }
+}
+
+/// Add the FCS, stuff the bits and put `preamble` flags in front and one behind, returns the
+/// number of bits written into `out`
+pub fn hdlc_encode(frame: &[u8], preamble: usize, out: &mut [u8]) -> Result<usize, Error> {
+    let mut writer = BitWriter {
+        out,
+        bits: 0,
+        ones: 0,
+    };
+    for _ in 0..preamble.max(1) {
+        writer.flag()?;
+    }
+    for &byte in frame {
+        writer.data(byte)?;
+    }
+    for byte in crc16_x25(frame).to_le_bytes() {
+        writer.data(byte)?;
+    }
+    writer.flag()?;
+    Ok(writer.bits)
+}
+
+/// Finds frames in a received bit stream and checks them
+pub struct HdlcDecoder {
+    buffer: [u8; MAX_FRAME_LEN],
+    len: usize,
+    /// Data bits collected towards the next byte
+    byte: u8,
+    bits: u8,
+    /// The last eight bits received, the newest on top
+    pattern: u8,
+}
+
+impl HdlcDecoder {
+    pub const fn new() -> Self {
+        HdlcDecoder {
+            buffer: [0; MAX_FRAME_LEN],
+            len: 0,
+            byte: 0,
+            bits: 0,
+            pattern: 0,
+        }
+    }
+
+    /// Feed one bit, gives the frame without FCS at the closing flag. Noise between frames
+    /// is dropped quietly, only something long enough to be a frame gives an error.
+    pub fn push_bit(&mut self, bit: bool) -> Option<Result<&[u8], Error>> {
+        self.pattern = self.pattern >> 1 | (bit as u8) << 7;
+
+        if self.pattern == FLAG {
+            // the flag's first seven bits went in as data
+            let result = match (self.len, self.bits) {
+                (len, _) if len < MIN_FRAME_LEN => None,
+                (_, 7) => Some(self.check()),
+                _ => Some(Err(Error::Alignment)),
+            };
+            self.restart();
+            return result.map(|result| result.map(|len| &self.buffer[..len]));
+        }
+        if self.pattern & 0xFE == 0xFE {
+            // seven 1s abort the frame
+            self.restart();
+            return None;
+        }
+        if self.pattern >> 2 == 0x1F {
+            // a 0 after five 1s was stuffed in
+            return None;
+        }
+
+        self.byte = self.byte >> 1 | (bit as u8) << 7;
+        self.bits += 1;
+        if self.bits == 8 {
+            if self.len == MAX_FRAME_LEN {
+                // too long to be ours, wait for the next flag
+                self.restart();
+                return None;
+            }
+            self.buffer[self.len] = self.byte;
+            self.len += 1;
+            self.bits = 0;
+        }
+        None
+    }
+
+    fn restart(&mut self) {
+        self.len = 0;
+        self.bits = 0;
+    }
+
+    /// The frame length without FCS
+    fn check(&self) -> Result<usize, Error> {
+        let (frame, fcs) = self.buffer[..self.len].split_at(self.len - 2);
+        if crc16_x25(frame).to_le_bytes() == fcs {
+            Ok(frame.len())
+        } else {
+            Err(Error::Fcs)
+        }
+    }
+}
+
+impl Default for HdlcDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use std::{vec, vec::Vec};
+
+    fn address(callsign: &str, ssid: u8) -> Address {
+        Address::new(callsign, ssid).unwrap()
+    }
+
+    fn encode(frame: &UiFrame) -> Vec<u8> {
+        let mut out = [0; MAX_FRAME_LEN];
+        let len = frame.encode(&mut out).unwrap();
+        out[..len].to_vec()
+    }
+
+    /// The HDLC bits of a frame, in the order they go out
+    fn hdlc(frame: &[u8], preamble: usize) -> Vec<bool> {
+        let mut out = [0; 2 * MAX_FRAME_LEN];
+        let bits = hdlc_encode(frame, preamble, &mut out).unwrap();
+        (0..bits).map(|i| out[i / 8] >> (i % 8) & 1 != 0).collect()
+    }
+
+    fn decode(bits: &[bool]) -> Vec<Result<Vec<u8>, Error>> {
+        let mut decoder = HdlcDecoder::new();
+        bits.iter()
+            .filter_map(|&bit| decoder.push_bit(bit).map(|frame| frame.map(<[u8]>::to_vec)))
+            .collect()
+    }
+
+    const FLAG_BITS: [bool; 8] = [false, true, true, true, true, true, true, false];
+
+    #[test]
+    fn spec_addresses() {
+        // AX.25 2.2 section 3.12.2: NJ7P from N7LEM, as a command
+        let frame = UiFrame::new(address("NJ7P", 0), address("N7LEM", 0), b"");
+        let bytes = encode(&frame);
+        assert_eq!(
+            bytes,
+            [0x9C, 0x94, 0x6E, 0xA0, 0x40, 0x40, 0xE0, 0x9C, 0x6E, 0x98, 0x8A, 0x9A, 0x40, 0x61, 0x03, 0xF0]
+        );
+        assert_eq!(UiFrame::decode(&bytes), Ok(frame));
+    }
+
+    #[test]
+    fn aprs_position() {
+        // an APRS position report through two WIDEn-N digipeaters, encoded by hand
+        let mut frame = UiFrame::new(address("APRS", 0), address("N0CALL", 7), b"!4903.50N/07201.75W-Test");
+        frame.digipeaters.push(address("WIDE1", 1)).unwrap();
+        frame.digipeaters.push(address("WIDE2", 1)).unwrap();
+        let bytes = encode(&frame);
+        let header = [
+            0x82, 0xA0, 0xA4, 0xA6, 0x40, 0x40, 0xE0, // APRS, C bit set
+            0x9C, 0x60, 0x86, 0x82, 0x98, 0x98, 0x6E, // N0CALL-7
+            0xAE, 0x92, 0x88, 0x8A, 0x62, 0x40, 0x62, // WIDE1-1
+            0xAE, 0x92, 0x88, 0x8A, 0x64, 0x40, 0x63, // WIDE2-1, last address
+            0x03, 0xF0,
+        ];
+        assert_eq!(bytes[..header.len()], header);
+        assert_eq!(&bytes[header.len()..], frame.info);
+        assert_eq!(frame.encoded_len(), bytes.len());
+
+        let decoded = UiFrame::decode(&bytes).unwrap();
+        assert_eq!(decoded, frame);
+        assert_eq!((decoded.source.callsign(), decoded.source.ssid()), ("N0CALL", 7));
+
+        // the poll bit is allowed, anything else is not UI
+        let mut polled = bytes.clone();
+        polled[28] |= POLL_BIT;
+        assert!(UiFrame::decode(&polled).is_ok());
+        polled[28] = 0x3F;
+        assert_eq!(UiFrame::decode(&polled), Err(Error::NotUi));
+    }
+
+    #[test]
+    fn bad_frames() {
+        assert_eq!(Address::new("n0call", 0), Err(Error::Callsign));
+        assert_eq!(Address::new("", 0), Err(Error::Callsign));
+        assert_eq!(Address::new("N0CALLS", 0), Err(Error::Callsign));
+        assert_eq!(Address::new("N0CALL", 16), Err(Error::Ssid));
+
+        let mut frame = UiFrame::new(address("APRS", 0), address("N0CALL", 0), b"x");
+        let bytes = encode(&frame);
+        assert_eq!(UiFrame::decode(&bytes[..10]), Err(Error::TooShort));
+        assert_eq!(UiFrame::decode(&bytes[..15]), Err(Error::TooShort));
+        assert_eq!(frame.encode(&mut [0; 16]), Err(Error::BufferTooSmall));
+        let mut bad = bytes.clone();
+        bad[6] |= 1; // the address field ends after the destination
+        assert_eq!(UiFrame::decode(&bad), Err(Error::Address));
+
+        // three digipeaters do not fit
+        frame.digipeaters.push(address("WIDE1", 1)).unwrap();
+        frame.digipeaters.push(address("WIDE2", 1)).unwrap();
+        let mut bytes = encode(&frame);
+        bytes[27] &= !1;
+        bytes.splice(28..28, address("WIDE3", 1).encode(false, true));
+        assert_eq!(UiFrame::decode(&bytes), Err(Error::TooLong));
+
+        let info = [0; MAX_INFO_LEN + 1];
+        let frame = UiFrame::new(address("APRS", 0), address("N0CALL", 0), &info);
+        assert_eq!(frame.encode(&mut [0; 2 * MAX_FRAME_LEN]), Err(Error::TooLong));
+    }
+
+    #[test]
+    fn hdlc_round_trip() {
+        let mut frame = UiFrame::new(address("CQ", 0), address("OZ7SAT", 11), b"hello \xc0\xdb\x7e\xff\xff\x00");
+        frame.digipeaters.push(address("ARISS", 0)).unwrap();
+        let bytes = encode(&frame);
+        let bits = hdlc(&bytes, 3);
+        assert_eq!(bits[..8], FLAG_BITS);
+        assert_eq!(bits[bits.len() - 8..], FLAG_BITS);
+
+        // never six 1s in a row between the flags
+        let body = &bits[24..bits.len() - 8];
+        assert!(body.windows(6).all(|bits| !bits.iter().all(|&bit| bit)));
+
+        // noise in front, then three frames back to back
+        let mut stream = vec![true, false, true, true, true, true, true, true, true, false, true];
+        for _ in 0..3 {
+            stream.extend_from_slice(&bits);
+        }
+        assert_eq!(decode(&stream), vec![Ok(bytes.clone()); 3]);
+
+        // a flipped bit never gives a frame
+        for flip in 24..bits.len() - 8 {
+            let mut bits = bits.clone();
+            bits[flip] = !bits[flip];
+            assert!(decode(&bits).iter().all(Result::is_err), "bit {flip}");
+        }
+    }
+
+    #[test]
+    fn stuffing_at_the_edges() {
+        // frames whose FCS ends in exactly five 1s, so the stuffed 0 comes right before the
+        // closing flag, and in six, where one more 1 follows it
+        let cases: [(u8, u8, &[bool]); 2] = [
+            (0xFC, 0xF8, &[false, true, true, true, true, true, false]),
+            (0xFE, 0xFC, &[false, true, true, true, true, true, false, true]),
+        ];
+        for (mask, ones, tail) in cases {
+            let bytes = (0..=u16::MAX)
+                .map(|n| {
+                    let frame = UiFrame::new(address("CQ", 0), address("N0CALL", 0), &[]);
+                    let mut bytes = encode(&frame);
+                    bytes.extend_from_slice(&n.to_le_bytes());
+                    bytes
+                })
+                .find(|bytes| crc16_x25(bytes).to_le_bytes()[1] & mask == ones)
+                .unwrap();
+            let bits = hdlc(&bytes, 1);
+            let end = bits.len() - 8;
+            assert_eq!(bits[end - tail.len()..end], *tail, "{ones:#x}");
+            assert_eq!(decode(&bits), vec![Ok(bytes)]);
+        }
+
+        // all 1s and flags in the data, every byte is stuffed differently
+        let frame = UiFrame::new(address("CQ", 0), address("N0CALL", 0), &[0xFF, 0x7E, 0x3F, 0xFC, 0x1F, 0xF8, 0xFF]);
+        let bytes = encode(&frame);
+        assert_eq!(decode(&hdlc(&bytes, 1)), vec![Ok(bytes)]);
+    }
+
+    #[test]
+    fn aborts_and_alignment() {
+        let frame = UiFrame::new(address("CQ", 0), address("N0CALL", 0), b"abort");
+        let bytes = encode(&frame);
+        let bits = hdlc(&bytes, 1);
+
+        // seven 1s in the middle abort the frame, the next one still comes through
+        let mut stream = bits[..60].to_vec();
+        stream.extend_from_slice(&[true; 7]);
+        stream.extend_from_slice(&bits);
+        assert_eq!(decode(&stream), vec![Ok(bytes.clone())]);
+
+        // a bit lost in the middle leaves the frame off a byte boundary
+        let mut stream = bits.clone();
+        let lost = stream.iter().skip(40).position(|&bit| !bit).unwrap() + 40;
+        stream.remove(lost);
+        assert_eq!(decode(&stream), vec![Err(Error::Alignment)]);
+    }
+
+    #[test]
+    fn frames_too_long_for_the_buffer() {
+        let frame = UiFrame::new(address("CQ", 0), address("N0CALL", 0), b"after");
+        let bytes = encode(&frame);
+
+        // the encoder runs out of room
+        let mut out = [0; 16];
+        assert_eq!(hdlc_encode(&bytes, 1, &mut out), Err(Error::BufferTooSmall));
+
+        // a frame longer than any of ours is dropped without an error, the next one is fine
+        let long: Vec<u8> = (0..MAX_FRAME_LEN + 10).map(|i| i as u8).collect();
+        let mut out = vec![0; 2 * long.len()];
+        let len = hdlc_encode(&long, 1, &mut out).unwrap();
+        let mut stream: Vec<bool> = (0..len).map(|i| out[i / 8] >> (i % 8) & 1 != 0).collect();
+        stream.extend(hdlc(&bytes, 1));
+        assert_eq!(decode(&stream), vec![Ok(bytes.clone())]);
+
+        // the longest one that fits
+        let info = [0x55; MAX_INFO_LEN];
+        let mut frame = UiFrame::new(address("CQ", 0), address("N0CALL", 0), &info);
+        frame.digipeaters.push(address("WIDE1", 1)).unwrap();
+        frame.digipeaters.push(address("WIDE2", 2)).unwrap();
+        let bytes = encode(&frame);
+        assert_eq!(bytes.len() + 2, MAX_FRAME_LEN);
+        assert_eq!(decode(&hdlc(&bytes, 1)), vec![Ok(bytes)]);
+    }
+}
@@ -0,0 +1,292 @@
+//! Firmware slots and the update state machine shared with the CAN bootloader
+//!
+//! Flash layout when the bootloader is used:
+//!
+//! | sector | what                                                  |
+//! |--------|-------------------------------------------------------|
+//! | 0 - 1  | bootloader                                            |
+//! | 2 - 3  | configuration store, also holds the boot state        |
+//! | 4      | log store, see `crate::logstore`                      |
+//! | 5      | primary slot, the application always runs from here   |
+//! | 6      | download slot, new images are written here over CAN   |
+//! | 7      | backup slot, the previous image for rolling back      |
+//!
+//! Every slot ends with a [`Trailer`] describing the image in it. An update goes
+//! `Pending -> Install -> Testing`: the primary slot is copied to the backup slot, the download
+//! slot is copied to the primary slot and the new image is started. It then has
+//! `MAX_BOOT_ATTEMPTS` boots to call [`confirm`], otherwise the bootloader copies the backup slot
+//! back. Each step only moves on once its copy is finished, so a reset in the middle just repeats
+//! the step. An image in `Testing` is started with the independent watchdog running, so one that
+//! hangs is reset and uses up a boot too.
+
+use crate::config::{keys, ConfigError, ConfigStore};
+use crate::flash::{self, Flash};
+
+pub const PRIMARY_SECTOR: u8 = 5;
+pub const DOWNLOAD_SECTOR: u8 = 6;
+pub const BACKUP_SECTOR: u8 = 7;
+
+/// Timeout of the independent watchdog the bootloader starts for an image in `Testing`. It runs
+/// until the next reset, so the application has to feed it at least this often, also after it
+/// has confirmed itself.
+pub const WATCHDOG_MS: u32 = 4_000;
+
+/// All three slots are one 128K sector
+pub const SLOT_SIZE: usize = flash::sector_size(PRIMARY_SECTOR);
+
+/// Address the application is linked to, see `memory/bootloader.x`
+pub const APP_ADDRESS: usize = flash::sector_address(PRIMARY_SECTOR);
+
+/// Boots a new image gets before it is rolled back
+pub const MAX_BOOT_ATTEMPTS: u8 = 3;
+
+pub const TRAILER_LEN: usize = 48;
+
+/// Largest image that fits in a slot next to its trailer
+pub const MAX_IMAGE_LEN: usize = SLOT_SIZE - TRAILER_LEN;
+
+const TRAILER_MAGIC: u32 = 0x494D_4731; // "IMG1"
+
+/// Describes the image in a slot, stored in the last `TRAILER_LEN` bytes of the slot
+///
+/// ```text
+/// 0   magic   u32
+/// 4   len     u32  image length in bytes
+/// 8   crc     u32  CRC-32 of the image
+/// 12  -       u32  reserved, 0xFFFF_FFFF
+/// 16  sha256  [u8; 32]
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Trailer {
+    pub len: u32,
+    pub crc: u32,
+    pub sha256: [u8; 32],
+}
+
+impl Trailer {
+    pub const OFFSET: usize = SLOT_SIZE - TRAILER_LEN;
+
+    pub fn encode(&self) -> [u8; TRAILER_LEN] {
+        let mut raw = [0xFF; TRAILER_LEN];
+        raw[0..4].copy_from_slice(&TRAILER_MAGIC.to_le_bytes());
+        raw[4..8].copy_from_slice(&self.len.to_le_bytes());
+        raw[8..12].copy_from_slice(&self.crc.to_le_bytes());
+        raw[16..48].copy_from_slice(&self.sha256);
+        raw
+    }
+
+    /// `None` if there is no trailer or it describes something that can't be in a slot
+    pub fn decode(raw: &[u8; TRAILER_LEN]) -> Option<Self> {
+        let u32_at = |at: usize| u32::from_le_bytes([raw[at], raw[at + 1], raw[at + 2], raw[at + 3]]);
+        if u32_at(0) != TRAILER_MAGIC || u32_at(4) as usize > MAX_IMAGE_LEN {
+            return None;
+        }
+        let mut sha256 = [0; 32];
+        sha256.copy_from_slice(&raw[16..48]);
+        Some(Trailer {
+            len: u32_at(4),
+            crc: u32_at(8),
+            sha256,
+        })
+    }
+
+    /// Read the trailer of the slot in `sector`
+    pub fn read<F: Flash>(flash: &F, sector: u8) -> Result<Option<Self>, F::Error> {
+        let mut raw = [0; TRAILER_LEN];
+        flash.read(sector, Self::OFFSET, &mut raw)?;
+        Ok(Self::decode(&raw))
+    }
+}
+
+/// Where the firmware update is at, kept in the configuration store under `keys::BOOT_STATE`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum BootState {
+    /// The image in the primary slot is known to work
+    Confirmed,
+    /// A verified image is waiting in the download slot
+    Pending,
+    /// The backup is made, the download slot is being copied to the primary slot
+    Install,
+    /// The new image has been started `attempts` times without confirming itself
+    Testing { attempts: u8 },
+    /// A new image failed to confirm and the backup has been put back
+    RolledBack,
+}
+
+impl BootState {
+    pub fn to_u32(self) -> u32 {
+        match self {
+            BootState::Confirmed => 0,
+            BootState::Pending => 1,
+            BootState::Install => 2,
+            BootState::Testing { attempts } => 3 | (attempts as u32) << 8,
+            BootState::RolledBack => 4,
+        }
+    }
+
+    /// Unknown values are treated as `Confirmed` so a garbled state never stops the board booting
+    pub fn from_u32(value: u32) -> Self {
+        match value & 0xFF {
+            1 => BootState::Pending,
+            2 => BootState::Install,
+            3 => BootState::Testing {
+                attempts: (value >> 8) as u8,
+            },
+            4 => BootState::RolledBack,
+            _ => BootState::Confirmed,
+        }
+    }
+}
+
+/// What the bootloader should do next
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Step {
+    /// Store `state` and start the application
+    Boot { state: BootState },
+    /// Copy slot `from` over slot `to`, then store `then` and ask again
+    Copy { from: u8, to: u8, then: BootState },
+}
+
+/// The update state machine
+pub fn next_step(state: BootState) -> Step {
+    match state {
+        BootState::Confirmed | BootState::RolledBack => Step::Boot { state },
+        BootState::Pending => Step::Copy {
+            from: PRIMARY_SECTOR,
+            to: BACKUP_SECTOR,
+            then: BootState::Install,
+        },
+        BootState::Install => Step::Copy {
+            from: DOWNLOAD_SECTOR,
+            to: PRIMARY_SECTOR,
+            then: BootState::Testing { attempts: 0 },
+        },
+        BootState::Testing { attempts } if attempts >= MAX_BOOT_ATTEMPTS => Step::Copy {
+            from: BACKUP_SECTOR,
+            to: PRIMARY_SECTOR,
+            then: BootState::RolledBack,
+        },
+        BootState::Testing { attempts } => Step::Boot {
+            state: BootState::Testing {
+                attempts: attempts + 1,
+            },
+        },
+    }
+}
+
+/// Current boot state from the configuration
+pub fn state<F: Flash>(store: &ConfigStore<F>) -> BootState {
+    store
+        .get_u32(keys::BOOT_STATE)
+        .map(BootState::from_u32)
+        .unwrap_or(BootState::Confirmed)
+}
+
+/// Store a new boot state, only touches flash if it changed
+pub fn set_state<F: Flash>(
+    store: &mut ConfigStore<F>,
+    state: BootState,
+) -> Result<(), ConfigError<F::Error>> {
+    if self::state(store) == state {
+        return Ok(());
+    }
+    store.update_u32(keys::BOOT_STATE, state.to_u32())
+}
+
+/// Called by a freshly updated application once it is happy it works, stops the rollback
+pub fn confirm<F: Flash>(store: &mut ConfigStore<F>) -> Result<(), ConfigError<F::Error>> {
+    match state(store) {
+        BootState::Testing { .. } | BootState::RolledBack => set_state(store, BootState::Confirmed),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{self, Loaded};
+    use crate::flash::SimFlash;
+
+    #[test]
+    fn every_transition() {
+        let copy = |from, to, then| Step::Copy { from, to, then };
+        assert_eq!(next_step(BootState::Confirmed), Step::Boot { state: BootState::Confirmed });
+        assert_eq!(next_step(BootState::RolledBack), Step::Boot { state: BootState::RolledBack });
+        assert_eq!(next_step(BootState::Pending), copy(PRIMARY_SECTOR, BACKUP_SECTOR, BootState::Install));
+        assert_eq!(
+            next_step(BootState::Install),
+            copy(DOWNLOAD_SECTOR, PRIMARY_SECTOR, BootState::Testing { attempts: 0 })
+        );
+        for attempts in 0..MAX_BOOT_ATTEMPTS {
+            let state = BootState::Testing { attempts: attempts + 1 };
+            assert_eq!(next_step(BootState::Testing { attempts }), Step::Boot { state });
+        }
+        for attempts in [MAX_BOOT_ATTEMPTS, MAX_BOOT_ATTEMPTS + 1, u8::MAX] {
+            assert_eq!(
+                next_step(BootState::Testing { attempts }),
+                copy(BACKUP_SECTOR, PRIMARY_SECTOR, BootState::RolledBack)
+            );
+        }
+    }
+
+    #[test]
+    fn state_as_u32() {
+        let states = [
+            BootState::Confirmed,
+            BootState::Pending,
+            BootState::Install,
+            BootState::Testing { attempts: 0 },
+            BootState::Testing { attempts: 3 },
+            BootState::RolledBack,
+        ];
+        for state in states {
+            assert_eq!(BootState::from_u32(state.to_u32()), state);
+        }
+        assert_eq!(BootState::from_u32(0xFFFF_FFFF), BootState::Confirmed);
+        assert_eq!(BootState::from_u32(5), BootState::Confirmed);
+    }
+
+    #[test]
+    fn trailer() {
+        let trailer = Trailer {
+            len: 1234,
+            crc: 0xDEAD_BEEF,
+            sha256: [0x5A; 32],
+        };
+        let raw = trailer.encode();
+        assert_eq!(raw[12..16], [0xFF; 4]);
+        assert_eq!(Trailer::decode(&raw), Some(trailer));
+
+        assert_eq!(Trailer::decode(&[0xFF; TRAILER_LEN]), None, "erased");
+        let mut bad = raw;
+        bad[0] ^= 1;
+        assert_eq!(Trailer::decode(&bad), None, "magic");
+        let mut bad = raw;
+        bad[4..8].copy_from_slice(&(MAX_IMAGE_LEN as u32 + 1).to_le_bytes());
+        assert_eq!(Trailer::decode(&bad), None, "too long for a slot");
+    }
+
+    #[test]
+    fn state_in_the_config_store() {
+        let mut store = ConfigStore::new(SimFlash::new(2, 2, 1024), config::SECTORS);
+        store.load().unwrap();
+        assert_eq!(state(&store), BootState::Confirmed);
+        confirm(&mut store).unwrap();
+        assert_eq!(store.sequence(), 0, "nothing to confirm, nothing written");
+
+        set_state(&mut store, BootState::Testing { attempts: 2 }).unwrap();
+        set_state(&mut store, BootState::Testing { attempts: 2 }).unwrap();
+        assert_eq!(store.sequence(), 1, "the same state again is not written");
+
+        let mut store = ConfigStore::new(store.free(), config::SECTORS);
+        assert_eq!(store.load(), Ok(Loaded::Stored { sequence: 1 }));
+        assert_eq!(state(&store), BootState::Testing { attempts: 2 });
+        confirm(&mut store).unwrap();
+        assert_eq!(state(&store), BootState::Confirmed);
+
+        // confirming does not throw away an update that is still on its way
+        set_state(&mut store, BootState::Pending).unwrap();
+        confirm(&mut store).unwrap();
+        assert_eq!(state(&store), BootState::Pending);
+    }
+}
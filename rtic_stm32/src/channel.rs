@@ -0,0 +1,282 @@
+//! Channels from interrupt handlers to tasks
+//!
+//! A single producer, single consumer queue (`heapless::spsc`) with counters: how many
+//! messages were sent, how many were dropped because the queue was full, and the most that
+//! were ever waiting. A bound task sends, the software task it spawns receives everything
+//! that came in since, so a burst of interrupts is not folded into one.
+//!
+//! The channel lives in a `static` (e.g. `#[init(local = [..])]`) and is split in `init`; the
+//! `Sender` goes to the interrupt handler and the `Receiver` to the task. Neither needs a lock.
+//! Messages that need a time stamp go through a `Channel<Stamped<T>, N>`.
+//!
+//! A channel of `N` holds `N - 1` messages.
+
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+use heapless::spsc::{Consumer, Producer, Queue};
+
+/// A message and when it happened, in whatever unit the sender uses (monotonic µs usually)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Stamped<T> {
+    pub at: u64,
+    pub value: T,
+}
+
+/// Counters of a channel, as read at one moment
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, defmt::Format)]
+pub struct Stats {
+    pub sent: u32,
+    /// Messages dropped because the queue was full
+    pub overflows: u32,
+    /// The most messages waiting at once
+    pub high_water: usize,
+}
+
+struct Counters {
+    sent: AtomicU32,
+    overflows: AtomicU32,
+    high_water: AtomicUsize,
+}
+
+impl Counters {
+    fn read(&self) -> Stats {
+        Stats {
+            sent: self.sent.load(Ordering::Relaxed),
+            overflows: self.overflows.load(Ordering::Relaxed),
+            high_water: self.high_water.load(Ordering::Relaxed),
+        }
+    }
+}
+
+pub struct Channel<T, const N: usize> {
+    queue: Queue<T, N>,
+    counters: Counters,
+}
+
+impl<T, const N: usize> Channel<T, N> {
+    pub const fn new() -> Self {
+        Channel {
+            queue: Queue::new(),
+            counters: Counters {
+                sent: AtomicU32::new(0),
+                overflows: AtomicU32::new(0),
+                high_water: AtomicUsize::new(0),
+            },
+        }
+    }
+
+    /// The two ends, for as long as the channel is borrowed
+    pub fn split(&mut self) -> (Sender<'_, T, N>, Receiver<'_, T, N>) {
+        let counters = &self.counters;
+        let (producer, consumer) = self.queue.split();
+        (
+            Sender { producer, counters },
+            Receiver { consumer, counters },
+        )
+    }
+}
+
+impl<T, const N: usize> Default for Channel<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct Sender<'a, T, const N: usize> {
+    producer: Producer<'a, T, N>,
+    counters: &'a Counters,
+}
+
+impl<'a, T, const N: usize> Sender<'a, T, N> {
+    /// Queue a message, a full queue gives it back and counts an overflow
+    pub fn send(&mut self, value: T) -> Result<(), T> {
+        match self.producer.enqueue(value) {
+            Ok(()) => {
+                self.counters.sent.fetch_add(1, Ordering::Relaxed);
+                self.counters.high_water.fetch_max(self.producer.len(), Ordering::Relaxed);
+                Ok(())
+            }
+            Err(value) => {
+                self.counters.overflows.fetch_add(1, Ordering::Relaxed);
+                Err(value)
+            }
+        }
+    }
+
+    /// Whether a message can go in right now
+    pub fn ready(&self) -> bool {
+        self.producer.ready()
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.counters.read()
+    }
+}
+
+impl<'a, T, const N: usize> Sender<'a, Stamped<T>, N> {
+    pub fn send_at(&mut self, at: u64, value: T) -> Result<(), T> {
+        self.send(Stamped { at, value }).map_err(|message| message.value)
+    }
+}
+
+pub struct Receiver<'a, T, const N: usize> {
+    consumer: Consumer<'a, T, N>,
+    counters: &'a Counters,
+}
+
+impl<'a, T, const N: usize> Receiver<'a, T, N> {
+    /// The oldest message, `None` when there is none
+    pub fn recv(&mut self) -> Option<T> {
+        self.consumer.dequeue()
+    }
+
+    pub fn peek(&self) -> Option<&T> {
+        self.consumer.peek()
+    }
+
+    /// Messages waiting
+    pub fn len(&self) -> usize {
+        self.consumer.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.consumer.ready()
+    }
+
+    /// Every message waiting, the ones sent while this runs too
+    pub fn drain(&mut self) -> Drain<'_, 'a, T, N> {
+        Drain { receiver: self }
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.counters.read()
+    }
+}
+
+pub struct Drain<'r, 'a, T, const N: usize> {
+    receiver: &'r mut Receiver<'a, T, N>,
+}
+
+impl<T, const N: usize> Iterator for Drain<'_, '_, T, N> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.receiver.recv()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use std::{thread, vec, vec::Vec};
+
+    #[test]
+    fn overflow() {
+        let mut channel: Channel<u32, 4> = Channel::new();
+        let (mut sender, mut receiver) = channel.split();
+        assert!(receiver.is_empty());
+        for i in 0..3 {
+            sender.send(i).unwrap();
+        }
+        // N - 1 fit
+        assert!(!sender.ready());
+        assert_eq!(sender.send(9), Err(9));
+        assert_eq!(sender.send(10), Err(10));
+        assert_eq!(receiver.len(), 3);
+        assert_eq!(receiver.peek(), Some(&0));
+        assert_eq!(receiver.drain().collect::<Vec<_>>(), [0, 1, 2]);
+        assert_eq!(
+            receiver.stats(),
+            Stats {
+                sent: 3,
+                overflows: 2,
+                high_water: 3,
+            }
+        );
+        assert_eq!(receiver.recv(), None);
+
+        let mut stamped: Channel<Stamped<&str>, 2> = Channel::new();
+        let (mut sender, mut receiver) = stamped.split();
+        sender.send_at(42, "a").unwrap();
+        assert_eq!(sender.send_at(43, "b"), Err("b"));
+        assert_eq!(receiver.recv(), Some(Stamped { at: 42, value: "a" }));
+    }
+
+    #[test]
+    fn high_water_across_the_wrap() {
+        let mut channel: Channel<u32, 8> = Channel::new();
+        let (mut sender, mut receiver) = channel.split();
+        let (mut next, mut most) = (0, 0);
+        // the queue indices go round many times, with the fill straddling the end of the
+        // buffer at every offset
+        for round in 0..200 {
+            let fill = [2, 5, 3, 1, 4][round % 5];
+            for _ in 0..fill {
+                sender.send(next).unwrap();
+                next += 1;
+            }
+            most = most.max(fill);
+            assert_eq!(receiver.len(), fill);
+            assert_eq!(sender.stats().high_water, most);
+            assert_eq!(receiver.drain().count(), fill);
+        }
+        // full once, after the wrap
+        while sender.send(next).is_ok() {
+            next += 1;
+        }
+        assert_eq!(receiver.len(), 7);
+        assert_eq!(receiver.stats().high_water, 7);
+        // and it never goes down
+        receiver.drain().count();
+        sender.send(0).unwrap();
+        assert_eq!(sender.stats(), Stats { sent: next + 1, overflows: 1, high_water: 7 });
+    }
+
+    #[test]
+    fn threads() {
+        const COUNT: u32 = 200_000;
+        let mut channel: Channel<u32, 16> = Channel::new();
+        let (mut sender, mut receiver) = channel.split();
+        let (dropped, received, stats) = thread::scope(|scope| {
+            let producer = scope.spawn(move || {
+                let mut dropped = Vec::new();
+                for i in 0..COUNT {
+                    if let Err(value) = sender.send(i) {
+                        dropped.push(value);
+                    }
+                    if i % 64 == 0 {
+                        thread::yield_now();
+                    }
+                }
+                dropped
+            });
+            let consumer = scope.spawn(move || {
+                let mut received = Vec::new();
+                loop {
+                    received.extend(receiver.drain());
+                    let stats = receiver.stats();
+                    if stats.sent + stats.overflows == COUNT && stats.sent as usize == received.len() {
+                        return (received, stats);
+                    }
+                    if received.len() % 7 == 0 {
+                        thread::yield_now();
+                    }
+                }
+            });
+            let (received, stats) = consumer.join().unwrap();
+            (producer.join().unwrap(), received, stats)
+        });
+        // whatever was not dropped arrived, once and in order
+        let mut all = vec![false; COUNT as usize];
+        for &value in dropped.iter().chain(&received) {
+            assert!(!all[value as usize], "{value} twice");
+            all[value as usize] = true;
+        }
+        assert!(all.iter().all(|&seen| seen));
+        assert!(received.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(stats.overflows as usize, dropped.len());
+        assert!(stats.high_water <= 15);
+        assert!(stats.high_water > 0);
+    }
+}
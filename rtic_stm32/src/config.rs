@@ -0,0 +1,672 @@
+//! Persistent key/value configuration
+//!
+//! The configuration is kept in RAM as a small table of keys and values and written to flash as
+//! one image. Two sectors are used in turn: a commit erases the sector that is not in use, writes
+//! the new image there and only then writes the magic word that makes it valid. If the power goes
+//! in the middle the old image in the other sector is still intact, and `load` picks whichever
+//! valid image has the highest sequence number.
+//!
+//! Image layout (little endian):
+//!
+//! ```text
+//! 0   magic        u32  written last, marks the image as complete
+//! 4   sequence     u32  incremented on every commit
+//! 8   schema       u16  SCHEMA_VERSION of the firmware that wrote it
+//! 10  count        u16  number of entries
+//! 12  payload_len  u32  bytes of entries that follow
+//! 16  entries      key u16, len u16, value, padded to 4 bytes
+//! ..  crc          u32  CRC-32 of bytes 4 up to here
+//! ```
+
+use crate::crc::Crc32;
+use crate::flash::Flash;
+use heapless::Vec;
+
+/// Sectors 2 and 3 (16K each at 0x0800_8000 and 0x0800_C000), `memory/plain.x` keeps code out of them
+pub const SECTORS: [u8; 2] = [2, 3];
+
+/// Version of the stored layout, bump it and add a migration when the meaning of a key changes
+pub const SCHEMA_VERSION: u16 = 1;
+
+/// Max number of keys
+pub const MAX_ENTRIES: usize = 32;
+
+/// Max length of a single value in bytes
+pub const MAX_VALUE_LEN: usize = 16;
+
+const MAGIC: u32 = 0x4346_4731; // "CFG1"
+const HEADER_LEN: usize = 16;
+const ENTRY_HEADER_LEN: usize = 4;
+
+/// Identifies a configuration value
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Key(pub u16);
+
+/// The keys in use, never reuse a number for something else
+pub mod keys {
+    use super::Key;
+
+    /// Period of the blink task in milliseconds
+    pub const BLINK_PERIOD_MS: Key = Key(0x0001);
+    /// SPI clock in Hz
+    pub const SPI_CLOCK_HZ: Key = Key(0x0002);
+    /// Standard id used for outgoing CAN frames
+    pub const CAN_ID: Key = Key(0x0003);
+    /// Value for the bxcan BTR register
+    pub const CAN_BIT_TIMING: Key = Key(0x0004);
+    /// 1 on the node that sends the CAN time sync, 0 on the others
+    pub const TIME_SYNC_MASTER: Key = Key(0x0005);
+    /// `boot::BootState` of the firmware update, shared with the bootloader
+    pub const BOOT_STATE: Key = Key(0x0010);
+    /// Start of the mission, TAI seconds since 2000-01-01
+    pub const MISSION_EPOCH: Key = Key(0x0020);
+    /// TAI - UTC in seconds
+    pub const LEAP_SECONDS: Key = Key(0x0021);
+    /// RTC frequency correction in ppb, as an i32
+    pub const RTC_CALIBRATION_PPB: Key = Key(0x0022);
+}
+
+/// Values used for keys that are not in flash, these match what the examples had hardcoded
+pub const DEFAULTS: &[(Key, u32)] = &[
+    (keys::BLINK_PERIOD_MS, 1_000),
+    (keys::SPI_CLOCK_HZ, 1_000_000),
+    (keys::CAN_ID, 0x500),
+    (keys::CAN_BIT_TIMING, 0x001b_0002),
+    (keys::TIME_SYNC_MASTER, 0),
+    (keys::LEAP_SECONDS, 37),
+];
+
+/// Upgrades a configuration by one schema version
+pub type Migration = fn(&mut Config) -> Result<(), ValueError>;
+
+/// `MIGRATIONS[n]` turns a schema `n + 1` configuration into a schema `n + 2` one
+const MIGRATIONS: &[Migration] = &[];
+
+const _: () = assert!(MIGRATIONS.len() + 1 == SCHEMA_VERSION as usize);
+
+/// A value could not be stored in the RAM configuration
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum ValueError {
+    /// All `MAX_ENTRIES` slots are in use
+    Full,
+    /// Value longer than `MAX_VALUE_LEN`
+    TooLong,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum ConfigError<E> {
+    /// The flash driver failed
+    Flash(E),
+    /// All `MAX_ENTRIES` slots are in use
+    Full,
+    /// Value longer than `MAX_VALUE_LEN`
+    ValueTooLong,
+    /// The image does not fit in a sector
+    TooBig,
+}
+
+impl<E> From<ValueError> for ConfigError<E> {
+    fn from(error: ValueError) -> Self {
+        match error {
+            ValueError::Full => ConfigError::Full,
+            ValueError::TooLong => ConfigError::ValueTooLong,
+        }
+    }
+}
+
+/// Where the configuration came from when it was loaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Loaded {
+    /// A valid image with the current schema
+    Stored { sequence: u32 },
+    /// A valid image with an older schema that has been migrated in RAM, commit to keep it
+    Migrated { sequence: u32, from: u16 },
+    /// An image written by newer firmware, it is left alone and the defaults are used
+    Newer { sequence: u32, schema: u16 },
+    /// Nothing valid in flash
+    Defaults,
+}
+
+#[derive(Clone)]
+struct Entry {
+    key: Key,
+    value: Vec<u8, MAX_VALUE_LEN>,
+}
+
+/// The configuration as kept in RAM
+#[derive(Clone, Default)]
+pub struct Config {
+    entries: Vec<Entry, MAX_ENTRIES>,
+}
+
+impl Config {
+    /// A configuration with all the `DEFAULTS` filled in
+    pub fn with_defaults() -> Self {
+        let mut config = Config::default();
+        config.fill_defaults();
+        config
+    }
+
+    pub fn get(&self, key: Key) -> Option<&[u8]> {
+        self.entries
+            .iter()
+            .find(|entry| entry.key == key)
+            .map(|entry| &entry.value[..])
+    }
+
+    pub fn get_u32(&self, key: Key) -> Option<u32> {
+        let bytes: [u8; 4] = self.get(key)?.try_into().ok()?;
+        Some(u32::from_le_bytes(bytes))
+    }
+
+    pub fn set(&mut self, key: Key, value: &[u8]) -> Result<(), ValueError> {
+        let value = Vec::from_slice(value).map_err(|_| ValueError::TooLong)?;
+        match self.entries.iter_mut().find(|entry| entry.key == key) {
+            Some(entry) => entry.value = value,
+            None => self
+                .entries
+                .push(Entry { key, value })
+                .map_err(|_| ValueError::Full)?,
+        }
+        Ok(())
+    }
+
+    pub fn set_u32(&mut self, key: Key, value: u32) -> Result<(), ValueError> {
+        self.set(key, &value.to_le_bytes())
+    }
+
+    pub fn remove(&mut self, key: Key) -> bool {
+        match self.entries.iter().position(|entry| entry.key == key) {
+            Some(index) => {
+                self.entries.swap_remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// All keys currently set
+    pub fn keys(&self) -> impl Iterator<Item = Key> + '_ {
+        self.entries.iter().map(|entry| entry.key)
+    }
+
+    fn fill_defaults(&mut self) {
+        for &(key, value) in DEFAULTS {
+            if self.get(key).is_none() {
+                // DEFAULTS is much shorter than MAX_ENTRIES
+                self.set_u32(key, value).ok();
+            }
+        }
+    }
+
+    fn payload_len(&self) -> usize {
+        self.entries
+            .iter()
+            .map(|entry| padded(ENTRY_HEADER_LEN + entry.value.len()))
+            .sum()
+    }
+}
+
+/// The configuration together with the two sectors it lives in
+pub struct ConfigStore<F: Flash> {
+    flash: F,
+    sectors: [u8; 2],
+    active: Option<usize>,
+    sequence: u32,
+    config: Config,
+}
+
+impl<F: Flash> ConfigStore<F> {
+    /// Call `load` before using the configuration, until then it only holds the defaults
+    pub fn new(flash: F, sectors: [u8; 2]) -> Self {
+        ConfigStore {
+            flash,
+            sectors,
+            active: None,
+            sequence: 0,
+            config: Config::with_defaults(),
+        }
+    }
+
+    /// Read the newest valid image from flash, falling back to the defaults
+    pub fn load(&mut self) -> Result<Loaded, ConfigError<F::Error>> {
+        let mut newest: Option<(usize, Header)> = None;
+        for (index, &sector) in self.sectors.iter().enumerate() {
+            if let Some(header) = self.read_header(sector)? {
+                let is_newer = match newest {
+                    Some((_, ref best)) => sequence_after(header.sequence, best.sequence),
+                    None => true,
+                };
+                if is_newer {
+                    newest = Some((index, header));
+                }
+            }
+        }
+
+        let (index, header) = match newest {
+            Some(found) => found,
+            None => {
+                self.active = None;
+                self.config = Config::with_defaults();
+                return Ok(Loaded::Defaults);
+            }
+        };
+        self.active = Some(index);
+        self.sequence = header.sequence;
+
+        if header.schema > SCHEMA_VERSION {
+            self.config = Config::with_defaults();
+            return Ok(Loaded::Newer {
+                sequence: header.sequence,
+                schema: header.schema,
+            });
+        }
+
+        let mut config = self.read_entries(self.sectors[index], &header)?;
+        for migration in &MIGRATIONS[header.schema.max(1) as usize - 1..] {
+            migration(&mut config)?;
+        }
+        config.fill_defaults();
+        self.config = config;
+
+        if header.schema < SCHEMA_VERSION {
+            Ok(Loaded::Migrated {
+                sequence: header.sequence,
+                from: header.schema,
+            })
+        } else {
+            Ok(Loaded::Stored {
+                sequence: header.sequence,
+            })
+        }
+    }
+
+    /// Write the RAM configuration to the sector that is not in use and make it the active one
+    pub fn commit(&mut self) -> Result<(), ConfigError<F::Error>> {
+        let index = match self.active {
+            Some(active) => 1 - active,
+            None => 0,
+        };
+        let sector = self.sectors[index];
+        let sequence = self.sequence.wrapping_add(1);
+        let payload_len = self.config.payload_len();
+        if HEADER_LEN + payload_len + 4 > self.flash.sector_size(sector) {
+            return Err(ConfigError::TooBig);
+        }
+
+        self.flash.erase(sector).map_err(ConfigError::Flash)?;
+
+        let mut crc = Crc32::new();
+        let mut header = [0u8; HEADER_LEN - 4];
+        header[0..4].copy_from_slice(&sequence.to_le_bytes());
+        header[4..6].copy_from_slice(&SCHEMA_VERSION.to_le_bytes());
+        header[6..8].copy_from_slice(&(self.config.entries.len() as u16).to_le_bytes());
+        header[8..12].copy_from_slice(&(payload_len as u32).to_le_bytes());
+        crc.update(&header);
+        self.flash
+            .program(sector, 4, &header)
+            .map_err(ConfigError::Flash)?;
+
+        let mut offset = HEADER_LEN;
+        for entry in &self.config.entries {
+            let mut buf = [0u8; padded(ENTRY_HEADER_LEN + MAX_VALUE_LEN)];
+            let len = padded(ENTRY_HEADER_LEN + entry.value.len());
+            buf[0..2].copy_from_slice(&entry.key.0.to_le_bytes());
+            buf[2..4].copy_from_slice(&(entry.value.len() as u16).to_le_bytes());
+            buf[4..4 + entry.value.len()].copy_from_slice(&entry.value);
+            crc.update(&buf[..len]);
+            self.flash
+                .program(sector, offset, &buf[..len])
+                .map_err(ConfigError::Flash)?;
+            offset += len;
+        }
+        self.flash
+            .program(sector, offset, &crc.finish().to_le_bytes())
+            .map_err(ConfigError::Flash)?;
+
+        // everything is in place, the magic makes it count
+        self.flash
+            .program(sector, 0, &MAGIC.to_le_bytes())
+            .map_err(ConfigError::Flash)?;
+
+        self.active = Some(index);
+        self.sequence = sequence;
+        Ok(())
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Changes are only in RAM until `commit` is called
+    pub fn config_mut(&mut self) -> &mut Config {
+        &mut self.config
+    }
+
+    pub fn get_u32(&self, key: Key) -> Option<u32> {
+        self.config.get_u32(key)
+    }
+
+    /// Set a value and commit it straight away
+    pub fn update_u32(&mut self, key: Key, value: u32) -> Result<(), ConfigError<F::Error>> {
+        self.config.set_u32(key, value)?;
+        self.commit()
+    }
+
+    /// Sequence number of the image in flash
+    pub fn sequence(&self) -> u32 {
+        self.sequence
+    }
+
+    /// The flash the store lives in, for things that use other sectors of it
+    pub fn flash(&self) -> &F {
+        &self.flash
+    }
+
+    /// Don't touch the store's own sectors through this
+    pub fn flash_mut(&mut self) -> &mut F {
+        &mut self.flash
+    }
+
+    /// Give the flash back
+    pub fn free(self) -> F {
+        self.flash
+    }
+
+    // The header if the sector holds a complete image with a good CRC
+    fn read_header(&self, sector: u8) -> Result<Option<Header>, ConfigError<F::Error>> {
+        let mut raw = [0u8; HEADER_LEN];
+        self.flash
+            .read(sector, 0, &mut raw)
+            .map_err(ConfigError::Flash)?;
+        let header = Header::parse(&raw);
+        if header.magic != MAGIC
+            || HEADER_LEN + header.payload_len + 4 > self.flash.sector_size(sector)
+        {
+            return Ok(None);
+        }
+
+        let mut crc = Crc32::new();
+        crc.update(&raw[4..]);
+        let mut offset = HEADER_LEN;
+        let end = HEADER_LEN + header.payload_len;
+        let mut chunk = [0u8; 32];
+        while offset < end {
+            let len = chunk.len().min(end - offset);
+            self.flash
+                .read(sector, offset, &mut chunk[..len])
+                .map_err(ConfigError::Flash)?;
+            crc.update(&chunk[..len]);
+            offset += len;
+        }
+        let mut stored = [0u8; 4];
+        self.flash
+            .read(sector, end, &mut stored)
+            .map_err(ConfigError::Flash)?;
+        if u32::from_le_bytes(stored) != crc.finish() {
+            return Ok(None);
+        }
+        Ok(Some(header))
+    }
+
+    fn read_entries(&self, sector: u8, header: &Header) -> Result<Config, ConfigError<F::Error>> {
+        let mut config = Config::default();
+        let mut offset = HEADER_LEN;
+        for _ in 0..header.count {
+            let mut raw = [0u8; ENTRY_HEADER_LEN];
+            self.flash
+                .read(sector, offset, &mut raw)
+                .map_err(ConfigError::Flash)?;
+            let key = Key(u16::from_le_bytes([raw[0], raw[1]]));
+            let len = u16::from_le_bytes([raw[2], raw[3]]) as usize;
+            if len > MAX_VALUE_LEN {
+                return Err(ConfigError::ValueTooLong);
+            }
+            let mut value = [0u8; MAX_VALUE_LEN];
+            self.flash
+                .read(sector, offset + ENTRY_HEADER_LEN, &mut value[..len])
+                .map_err(ConfigError::Flash)?;
+            config.set(key, &value[..len])?;
+            offset += padded(ENTRY_HEADER_LEN + len);
+        }
+        Ok(config)
+    }
+}
+
+struct Header {
+    magic: u32,
+    sequence: u32,
+    schema: u16,
+    count: u16,
+    payload_len: usize,
+}
+
+impl Header {
+    fn parse(raw: &[u8; HEADER_LEN]) -> Self {
+        let u32_at = |at: usize| u32::from_le_bytes([raw[at], raw[at + 1], raw[at + 2], raw[at + 3]]);
+        Header {
+            magic: u32_at(0),
+            sequence: u32_at(4),
+            schema: u16::from_le_bytes([raw[8], raw[9]]),
+            count: u16::from_le_bytes([raw[10], raw[11]]),
+            payload_len: u32_at(12) as usize,
+        }
+    }
+}
+
+const fn padded(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+// Sequence numbers wrap, so compare them the way TCP does
+fn sequence_after(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crc::crc32;
+    use crate::flash::SimFlash;
+
+    type Sim = SimFlash;
+
+    // The two configuration sectors, small enough to look through
+    fn erased() -> Sim {
+        SimFlash::new(2, 2, 1024)
+    }
+
+    fn store(flash: Sim) -> ConfigStore<Sim> {
+        ConfigStore::new(flash, SECTORS)
+    }
+
+    // Rewrite the schema of the image in a sector and fix up its CRC, as older firmware would have written it
+    fn set_schema(flash: &mut Sim, sector: u8, schema: u16) {
+        let cells = flash.sector_mut(sector);
+        cells[8..10].copy_from_slice(&schema.to_le_bytes());
+        let end = HEADER_LEN + u32::from_le_bytes(cells[12..16].try_into().unwrap()) as usize;
+        let crc = crc32(&cells[4..end]);
+        cells[end..end + 4].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    #[test]
+    fn defaults_then_round_trip() {
+        let mut config = store(erased());
+        assert_eq!(config.load(), Ok(Loaded::Defaults));
+        assert_eq!(config.get_u32(keys::CAN_ID), Some(0x500));
+        config.update_u32(keys::BLINK_PERIOD_MS, 250).unwrap();
+        config.config_mut().set(Key(0x7000), b"sixteen bytes!!!").unwrap();
+        config.commit().unwrap();
+
+        let mut config = store(config.free());
+        assert_eq!(config.load(), Ok(Loaded::Stored { sequence: 2 }));
+        assert_eq!(config.get_u32(keys::BLINK_PERIOD_MS), Some(250));
+        assert_eq!(config.config().get(Key(0x7000)), Some(&b"sixteen bytes!!!"[..]));
+        assert_eq!(config.config_mut().set(Key(0x7001), &[0; MAX_VALUE_LEN + 1]), Err(ValueError::TooLong));
+    }
+
+    #[test]
+    fn commits_alternate_sectors() {
+        let mut config = store(erased());
+        config.load().unwrap();
+        for period in 1..=4 {
+            config.update_u32(keys::BLINK_PERIOD_MS, period).unwrap();
+        }
+        let flash = config.free();
+        assert_eq!(flash.erase_count(2), 2);
+        assert_eq!(flash.erase_count(3), 2);
+    }
+
+    #[test]
+    fn power_cut_at_every_write() {
+        let mut config = store(erased());
+        config.load().unwrap();
+        // both sectors hold an image, so a cut can hit either the older or a half written one
+        config.update_u32(keys::BLINK_PERIOD_MS, 1).unwrap();
+        config.update_u32(keys::BLINK_PERIOD_MS, 2).unwrap();
+        let mut flash = config.free();
+
+        let mut cut = 0;
+        loop {
+            let mut config = store(flash);
+            let Ok(Loaded::Stored { sequence }) = config.load() else {
+                panic!("no image before the cut at byte {}", cut)
+            };
+            let old = config.get_u32(keys::BLINK_PERIOD_MS).unwrap();
+            let new = old + 1;
+
+            config.flash_mut().fail_after(cut);
+            let result = config.update_u32(keys::BLINK_PERIOD_MS, new);
+            config.flash_mut().restore_power();
+
+            let mut config = store(config.free());
+            let loaded = config.load();
+            let value = config.get_u32(keys::BLINK_PERIOD_MS);
+            match result {
+                Ok(()) => {
+                    assert_eq!(loaded, Ok(Loaded::Stored { sequence: sequence + 1 }));
+                    assert_eq!(value, Some(new));
+                    flash = config.free();
+                    break;
+                }
+                Err(error) => {
+                    assert_eq!(error, ConfigError::Flash(crate::flash::SimError::PowerLoss));
+                    match loaded {
+                        Ok(Loaded::Stored { sequence: found }) if found == sequence => assert_eq!(value, Some(old)),
+                        Ok(Loaded::Stored { sequence: found }) if found == sequence + 1 => {
+                            assert_eq!(value, Some(new))
+                        }
+                        other => panic!("cut at byte {}: loaded {:?}", cut, other),
+                    }
+                    // the other keys came through as well
+                    assert_eq!(config.get_u32(keys::CAN_ID), Some(0x500));
+                }
+            }
+            flash = config.free();
+            cut += 1;
+        }
+        // the erase, the image and the magic
+        assert!(cut > HEADER_LEN + 4, "commit wrote only {} bytes", cut);
+
+        // and the store still works after all that
+        let mut config = store(flash);
+        config.load().unwrap();
+        config.update_u32(keys::CAN_ID, 0x501).unwrap();
+        let mut config = store(config.free());
+        config.load().unwrap();
+        assert_eq!(config.get_u32(keys::CAN_ID), Some(0x501));
+    }
+
+    #[test]
+    fn sequence_wraps() {
+        assert!(sequence_after(1, 0));
+        assert!(!sequence_after(0, 1));
+        assert!(!sequence_after(7, 7));
+        assert!(sequence_after(0, u32::MAX));
+        assert!(!sequence_after(u32::MAX, 0));
+        assert!(sequence_after(5, u32::MAX - 5));
+        assert!(sequence_after(0x8000_0000, 1));
+        assert!(!sequence_after(0x8000_0001, 1));
+    }
+
+    #[test]
+    fn newest_wins_across_the_wrap() {
+        let mut config = store(erased());
+        config.load().unwrap();
+        config.sequence = u32::MAX - 1;
+        config.update_u32(keys::BLINK_PERIOD_MS, 1).unwrap(); // u32::MAX in sector 2
+        config.update_u32(keys::BLINK_PERIOD_MS, 2).unwrap(); // 0 in sector 3
+        assert_eq!(config.sequence(), 0);
+
+        let mut config = store(config.free());
+        assert_eq!(config.load(), Ok(Loaded::Stored { sequence: 0 }));
+        assert_eq!(config.get_u32(keys::BLINK_PERIOD_MS), Some(2));
+        // the next commit goes over the older one
+        config.update_u32(keys::BLINK_PERIOD_MS, 3).unwrap();
+        let mut config = store(config.free());
+        assert_eq!(config.load(), Ok(Loaded::Stored { sequence: 1 }));
+        assert_eq!(config.get_u32(keys::BLINK_PERIOD_MS), Some(3));
+    }
+
+    #[test]
+    fn every_migration_step() {
+        // an image from every schema there has been, 0 being the one before schemas were numbered
+        for from in 0..SCHEMA_VERSION {
+            let mut config = store(erased());
+            config.load().unwrap();
+            config.update_u32(keys::BLINK_PERIOD_MS, 250).unwrap();
+            let mut flash = config.free();
+            set_schema(&mut flash, 2, from);
+
+            let mut config = store(flash);
+            assert_eq!(config.load(), Ok(Loaded::Migrated { sequence: 1, from }));
+            assert_eq!(config.get_u32(keys::BLINK_PERIOD_MS), Some(250));
+            assert_eq!(config.get_u32(keys::CAN_ID), Some(0x500));
+            // a commit stores it with the current schema
+            config.commit().unwrap();
+            let mut config = store(config.free());
+            assert_eq!(config.load(), Ok(Loaded::Stored { sequence: 2 }));
+            assert_eq!(config.get_u32(keys::BLINK_PERIOD_MS), Some(250));
+        }
+    }
+
+    #[test]
+    fn newer_schema_is_left_alone() {
+        let mut config = store(erased());
+        config.load().unwrap();
+        config.update_u32(keys::BLINK_PERIOD_MS, 250).unwrap();
+        let mut flash = config.free();
+        set_schema(&mut flash, 2, SCHEMA_VERSION + 1);
+
+        let mut config = store(flash);
+        let loaded = config.load();
+        assert_eq!(loaded, Ok(Loaded::Newer { sequence: 1, schema: SCHEMA_VERSION + 1 }));
+        assert_eq!(config.get_u32(keys::BLINK_PERIOD_MS), Some(1_000));
+    }
+
+    #[test]
+    fn bad_crc_falls_back_to_the_other_sector() {
+        let mut config = store(erased());
+        config.load().unwrap();
+        config.update_u32(keys::BLINK_PERIOD_MS, 1).unwrap();
+        config.update_u32(keys::BLINK_PERIOD_MS, 2).unwrap();
+        let mut flash = config.free();
+        flash.sector_mut(3)[HEADER_LEN + 4] ^= 0x01;
+
+        let mut config = store(flash);
+        assert_eq!(config.load(), Ok(Loaded::Stored { sequence: 1 }));
+        assert_eq!(config.get_u32(keys::BLINK_PERIOD_MS), Some(1));
+    }
+
+    #[test]
+    fn too_big_for_the_sector() {
+        let mut config = ConfigStore::new(SimFlash::new(2, 2, 256), SECTORS);
+        config.load().unwrap();
+        for key in 0..10 {
+            config.config_mut().set(Key(0x7000 + key), &[0; MAX_VALUE_LEN]).unwrap();
+        }
+        assert_eq!(config.commit(), Err(ConfigError::TooBig));
+        // nothing was erased to find that out
+        assert_eq!(config.free().erase_count(2), 0);
+    }
+}
@@ -0,0 +1,577 @@
+//! Forward error correction for the downlink, as CCSDS 131.0-B has it
+//!
+//! - Reed-Solomon (255,223): 32 parity bytes per codeword, corrects up to 16 wrong bytes.
+//!   Field polynomial x^8+x^7+x^2+x+1, roots α^(11j) for j = 112..143, symbols in the dual
+//!   (Berlekamp) basis. Codewords are interleaved byte by byte to `depth` (1 to 8) so a burst
+//!   of errors is spread over all of them, and can be shortened when the frame is smaller.
+//! - The pseudo-randomizer, x^8+x^7+x^5+x^3+1 from all ones, XORed over the codeblock after
+//!   encoding so the bit stream has enough transitions for the ground receiver to lock on.
+//! - The rate 1/2, K=7 convolutional encoder (G1 = 171, G2 = 133 octal, G2 inverted) for the
+//!   inner code. Its Viterbi decoder belongs on the ground.
+//!
+//! The encoder is a shift register with table lookups, around 32 lookups per byte. The decoder
+//! (Berlekamp-Massey, Chien search and Forney) only costs much when there are errors.
+
+/// Codeword length
+pub const N: usize = 255;
+/// Data bytes per codeword
+pub const K: usize = 223;
+/// Parity bytes per codeword
+pub const PARITY: usize = N - K;
+/// Deepest interleaving CCSDS allows
+pub const MAX_DEPTH: usize = 8;
+
+const GF_POLY: u16 = 0x187;
+const FCR: usize = 112;
+const PRIM: usize = 11;
+/// 11 * 116 = 1 mod 255
+const IPRIM: usize = 116;
+/// log of 0
+const A0: u8 = N as u8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+    /// Depth is 0 or over `MAX_DEPTH`, or the block does not hold `depth` whole codewords
+    Shape,
+    /// More errors than the code can correct
+    Uncorrectable,
+    BufferTooSmall,
+}
+
+struct Tables {
+    /// α^i for i < 255, 0 at 255
+    exp: [u8; 256],
+    /// log of x, `A0` for 0
+    log: [u8; 256],
+    /// The generator polynomial in log form, lowest order first
+    generator: [u8; PARITY + 1],
+    /// Dual basis to conventional and back
+    from_dual: [u8; 256],
+    to_dual: [u8; 256],
+}
+
+const fn modnn(mut x: usize) -> usize {
+    while x >= N {
+        x -= N;
+    }
+    x
+}
+
+const TABLES: Tables = {
+    let mut exp = [0u8; 256];
+    let mut log = [0u8; 256];
+    log[0] = A0;
+    let mut sr: u16 = 1;
+    let mut i = 0;
+    while i < N {
+        log[sr as usize] = i as u8;
+        exp[i] = sr as u8;
+        sr <<= 1;
+        if sr & 0x100 != 0 {
+            sr ^= GF_POLY;
+        }
+        i += 1;
+    }
+
+    // multiply out (x - α^(11j)) in value form, then take the logs
+    let mut generator = [0u8; PARITY + 1];
+    generator[0] = 1;
+    let mut i = 0;
+    while i < PARITY {
+        let root = (FCR + i) * PRIM;
+        generator[i + 1] = 1;
+        let mut j = i;
+        while j > 0 {
+            generator[j] = if generator[j] != 0 {
+                generator[j - 1] ^ exp[modnn(log[generator[j] as usize] as usize + root)]
+            } else {
+                generator[j - 1]
+            };
+            j -= 1;
+        }
+        generator[0] = exp[modnn(log[generator[0] as usize] as usize + root)];
+        i += 1;
+    }
+    let mut i = 0;
+    while i <= PARITY {
+        generator[i] = log[generator[i] as usize];
+        i += 1;
+    }
+
+    // the dual basis matrix of CCSDS 131.0-B annex F, one row per conventional bit
+    const TAL: [u8; 8] = [0x8d, 0xef, 0xec, 0x86, 0xfa, 0x99, 0xaf, 0x7b];
+    let mut to_dual = [0u8; 256];
+    let mut from_dual = [0u8; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut dual = 0;
+        let mut k = 0;
+        while k < 8 {
+            if i & (1 << k) != 0 {
+                dual ^= TAL[7 - k];
+            }
+            k += 1;
+        }
+        to_dual[i] = dual;
+        from_dual[dual as usize] = i as u8;
+        i += 1;
+    }
+
+    Tables {
+        exp,
+        log,
+        generator,
+        from_dual,
+        to_dual,
+    }
+};
+
+fn exp(log: usize) -> u8 {
+    TABLES.exp[log % N]
+}
+
+fn log(value: u8) -> usize {
+    TABLES.log[value as usize] as usize
+}
+
+/// Data bytes per codeword in a block of `len` bytes at `depth`
+fn shape(len: usize, depth: usize) -> Result<usize, Error> {
+    if depth == 0 || depth > MAX_DEPTH {
+        return Err(Error::Shape);
+    }
+    match len / depth {
+        n if n * depth == len && n > PARITY && n <= N => Ok(n - PARITY),
+        _ => Err(Error::Shape),
+    }
+}
+
+/// Fill in the parity of a codeblock. The block is `depth` interleaved codewords: the data up
+/// front, `PARITY * depth` bytes of parity at the end. With less than `K * depth` data bytes
+/// the code is shortened.
+pub fn encode(block: &mut [u8], depth: usize) -> Result<(), Error> {
+    let data_len = shape(block.len(), depth)?;
+    for codeword in 0..depth {
+        let mut parity = [0u8; PARITY];
+        for i in 0..data_len {
+            let byte = TABLES.from_dual[block[i * depth + codeword] as usize];
+            let feedback = log(byte ^ parity[0]);
+            parity.copy_within(1.., 0);
+            parity[PARITY - 1] = 0;
+            if feedback != A0 as usize {
+                for (j, p) in parity.iter_mut().enumerate() {
+                    *p ^= exp(feedback + TABLES.generator[PARITY - 1 - j] as usize);
+                }
+            }
+        }
+        for (j, p) in parity.iter().enumerate() {
+            block[(data_len + j) * depth + codeword] = TABLES.to_dual[*p as usize];
+        }
+    }
+    Ok(())
+}
+
+/// Correct a codeblock in place, returns how many bytes were wrong. When any codeword has
+/// more than 16 errors the block is left as it came.
+pub fn decode(block: &mut [u8], depth: usize) -> Result<usize, Error> {
+    let data_len = shape(block.len(), depth)?;
+    let len = data_len + PARITY;
+    let pad = K - data_len;
+
+    let mut corrections = [[(0usize, 0u8); PARITY / 2]; MAX_DEPTH];
+    let mut counts = [0usize; MAX_DEPTH];
+    for codeword in 0..depth {
+        let mut symbols = [0u8; N];
+        for (i, symbol) in symbols[..len].iter_mut().enumerate() {
+            *symbol = TABLES.from_dual[block[i * depth + codeword] as usize];
+        }
+        let found = decode_codeword(&symbols[..len], pad, &mut corrections[codeword])?;
+        counts[codeword] = found;
+    }
+
+    let mut total = 0;
+    for codeword in 0..depth {
+        for &(position, error) in &corrections[codeword][..counts[codeword]] {
+            let byte = &mut block[position * depth + codeword];
+            *byte = TABLES.to_dual[(TABLES.from_dual[*byte as usize] ^ error) as usize];
+        }
+        total += counts[codeword];
+    }
+    Ok(total)
+}
+
+/// Find the errors in one codeword in the conventional basis, `pad` bytes shortened off the
+/// front. Fills `corrections` with position and error value, returns how many.
+fn decode_codeword(symbols: &[u8], pad: usize, corrections: &mut [(usize, u8); PARITY / 2]) -> Result<usize, Error> {
+    // syndromes, the codeword evaluated at the roots of the generator
+    let mut syndromes = [symbols[0]; PARITY];
+    for &symbol in &symbols[1..] {
+        for (i, s) in syndromes.iter_mut().enumerate() {
+            *s = if *s == 0 {
+                symbol
+            } else {
+                symbol ^ exp(log(*s) + (FCR + i) * PRIM)
+            };
+        }
+    }
+    if syndromes.iter().all(|&s| s == 0) {
+        return Ok(0);
+    }
+    let s = syndromes.map(log);
+
+    // Berlekamp-Massey for the error locator, lambda in value form, b in log form
+    let mut lambda = [0u8; PARITY + 1];
+    lambda[0] = 1;
+    let mut b = [A0 as usize; PARITY + 1];
+    b[0] = 0;
+    let mut degree = 0;
+    for r in 1..=PARITY {
+        let mut discrepancy = 0;
+        for i in 0..r {
+            if lambda[i] != 0 && s[r - i - 1] != A0 as usize {
+                discrepancy ^= exp(log(lambda[i]) + s[r - i - 1]);
+            }
+        }
+        let discrepancy = log(discrepancy);
+        if discrepancy == A0 as usize {
+            b.copy_within(..PARITY, 1);
+            b[0] = A0 as usize;
+            continue;
+        }
+        let mut t = lambda;
+        for i in 0..PARITY {
+            if b[i] != A0 as usize {
+                t[i + 1] ^= exp(discrepancy + b[i]);
+            }
+        }
+        if 2 * degree < r {
+            degree = r - degree;
+            for (b, &l) in b.iter_mut().zip(&lambda) {
+                *b = if l == 0 { A0 as usize } else { modnn(log(l) + N - discrepancy) };
+            }
+        } else {
+            b.copy_within(..PARITY, 1);
+            b[0] = A0 as usize;
+        }
+        lambda = t;
+    }
+    let lambda = lambda.map(log);
+    let degree = lambda.iter().rposition(|&l| l != A0 as usize).unwrap_or(0);
+    if degree > PARITY / 2 {
+        return Err(Error::Uncorrectable);
+    }
+
+    // Chien search for the roots of lambda, they give the error positions
+    let mut reg = lambda;
+    let mut roots = [0usize; PARITY / 2];
+    let mut count = 0;
+    let mut k = IPRIM - 1;
+    for i in 1..=N {
+        let mut q = 1;
+        for j in (1..=degree).rev() {
+            if reg[j] != A0 as usize {
+                reg[j] = modnn(reg[j] + j);
+                q ^= exp(reg[j]);
+            }
+        }
+        if q == 0 {
+            // position k counts from the front of the full length codeword
+            if k < pad {
+                return Err(Error::Uncorrectable);
+            }
+            roots[count] = i;
+            corrections[count].0 = k - pad;
+            count += 1;
+            if count == degree {
+                break;
+            }
+        }
+        k = modnn(k + IPRIM);
+    }
+    if count != degree {
+        return Err(Error::Uncorrectable);
+    }
+
+    // Forney for the error values, omega = s * lambda mod x^32 in log form
+    let mut omega = [A0 as usize; PARITY / 2];
+    for (i, o) in omega[..degree].iter_mut().enumerate() {
+        let mut value = 0;
+        for j in 0..=i {
+            if s[i - j] != A0 as usize && lambda[j] != A0 as usize {
+                value ^= exp(s[i - j] + lambda[j]);
+            }
+        }
+        *o = log(value);
+    }
+    for (root, correction) in roots[..count].iter().zip(corrections.iter_mut()) {
+        let mut numerator = 0;
+        for (i, &o) in omega[..degree].iter().enumerate() {
+            if o != A0 as usize {
+                numerator ^= exp(o + i * root);
+            }
+        }
+        let scale = exp(root * (FCR - 1));
+        // the odd terms of lambda are its formal derivative
+        let mut denominator = 0;
+        for i in (0..degree.min(PARITY - 1) + 1).step_by(2) {
+            if lambda[i + 1] != A0 as usize {
+                denominator ^= exp(lambda[i + 1] + i * root);
+            }
+        }
+        if numerator == 0 || denominator == 0 {
+            correction.1 = 0;
+            continue;
+        }
+        correction.1 = exp(log(numerator) + log(scale) + N - log(denominator));
+    }
+    Ok(count)
+}
+
+/// The randomizer sequence, it repeats every 255 bytes
+const RANDOMIZER: [u8; N] = {
+    let mut table = [0u8; N];
+    let mut state: u8 = 0xFF;
+    let mut i = 0;
+    while i < N {
+        let mut byte = 0;
+        let mut bit = 0;
+        while bit < 8 {
+            // the top bit goes out first, s[n+8] = s[n+7] ^ s[n+5] ^ s[n+3] ^ s[n]
+            byte = byte << 1 | (state >> 7);
+            let feedback = (state ^ state >> 2 ^ state >> 4 ^ state >> 7) & 1;
+            state = state << 1 | feedback;
+            bit += 1;
+        }
+        table[i] = byte;
+        i += 1;
+    }
+    table
+};
+
+/// XOR the randomizer over everything after the sync marker, the same call undoes it
+pub fn randomize(data: &mut [u8]) {
+    for (byte, r) in data.iter_mut().zip(RANDOMIZER.iter().cycle()) {
+        *byte ^= r;
+    }
+}
+
+/// The rate 1/2 convolutional encoder, keeps its state from one call to the next like the
+/// continuous stream it codes
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConvolutionalEncoder {
+    state: u8,
+}
+
+impl ConvolutionalEncoder {
+    // the taps of G1 and G2 with the newest bit lowest
+    const G1: u8 = 0x4F;
+    const G2: u8 = 0x6D;
+
+    pub const fn new() -> Self {
+        ConvolutionalEncoder { state: 0 }
+    }
+
+    /// Two output bits for every input bit, G1 first, most significant bit first
+    pub fn encode_byte(&mut self, byte: u8) -> u16 {
+        let mut out = 0;
+        for i in (0..8).rev() {
+            self.state = (self.state << 1 | (byte >> i & 1)) & 0x7F;
+            let g1 = (self.state & Self::G1).count_ones() as u16 & 1;
+            let g2 = (self.state & Self::G2).count_ones() as u16 & 1 ^ 1;
+            out = out << 2 | g1 << 1 | g2;
+        }
+        out
+    }
+
+    /// Encode `data` into `out`, which takes twice as many bytes
+    pub fn encode(&mut self, data: &[u8], out: &mut [u8]) -> Result<(), Error> {
+        let out = out.get_mut(..data.len() * 2).ok_or(Error::BufferTooSmall)?;
+        for (&byte, pair) in data.iter().zip(out.chunks_exact_mut(2)) {
+            pair.copy_from_slice(&self.encode_byte(byte).to_be_bytes());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use std::{vec, vec::Vec};
+
+    /// xorshift, so the tests do the same every time
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        /// `count` different positions below `n`
+        fn positions(&mut self, n: usize, count: usize) -> Vec<usize> {
+            let mut positions: Vec<usize> = (0..n).collect();
+            for i in 0..count {
+                let j = i + self.below(n - i);
+                positions.swap(i, j);
+            }
+            positions.truncate(count);
+            positions
+        }
+    }
+
+    fn codeblock(rng: &mut Rng, data_len: usize, depth: usize) -> Vec<u8> {
+        let mut block = vec![0; (data_len + PARITY) * depth];
+        for byte in &mut block[..data_len * depth] {
+            *byte = rng.next() as u8;
+        }
+        encode(&mut block, depth).unwrap();
+        block
+    }
+
+    /// Flip `count` bytes of codeword `codeword` to something else
+    fn damage(rng: &mut Rng, block: &mut [u8], depth: usize, codeword: usize, count: usize) {
+        for position in rng.positions(block.len() / depth, count) {
+            block[position * depth + codeword] ^= 1 + rng.below(255) as u8;
+        }
+    }
+
+    #[test]
+    fn dual_basis() {
+        // the conventional to dual basis matrix of CCSDS 131.0-B annex F, as in Phil Karn's
+        // reference tables
+        const TAL: [u8; 8] = [0x8D, 0xEF, 0xEC, 0x86, 0xFA, 0x99, 0xAF, 0x7B];
+        for conventional in 0..=255u8 {
+            let dual = (0..8)
+                .filter(|bit| conventional >> bit & 1 != 0)
+                .fold(0, |dual, bit| dual ^ TAL[7 - bit]);
+            assert_eq!(TABLES.to_dual[conventional as usize], dual);
+            assert_eq!(TABLES.from_dual[dual as usize], conventional);
+        }
+    }
+
+    #[test]
+    fn corrects_up_to_16_errors() {
+        let mut rng = Rng(0x1234_5678_9ABC);
+        for round in 0..200 {
+            let depth = 1 + round % MAX_DEPTH;
+            let data_len = if round % 3 == 0 { K } else { 1 + rng.below(K) };
+            let good = codeblock(&mut rng, data_len, depth);
+            assert_eq!(decode(&mut good.clone(), depth), Ok(0));
+
+            let mut bad = good.clone();
+            let mut errors = 0;
+            for codeword in 0..depth {
+                // every fourth round all codewords get the full 16
+                let count = if round % 4 == 0 { 16 } else { rng.below(17) };
+                damage(&mut rng, &mut bad, depth, codeword, count);
+                errors += count;
+            }
+            assert_eq!(decode(&mut bad, depth), Ok(errors), "round {round}");
+            assert_eq!(bad, good, "round {round}");
+        }
+    }
+
+    #[test]
+    fn seventeen_errors_are_uncorrectable() {
+        let mut rng = Rng(99);
+        for round in 0..200 {
+            let depth = 1 + round % 4;
+            let data_len = if round % 2 == 0 { K } else { 1 + rng.below(K) };
+            let good = codeblock(&mut rng, data_len, depth);
+            let mut bad = good.clone();
+            // one codeword over the limit is enough, the others can be fine
+            for codeword in 0..depth {
+                let count = if codeword == round % depth { 17 } else { rng.below(17) };
+                damage(&mut rng, &mut bad, depth, codeword, count);
+            }
+            let received = bad.clone();
+            assert_eq!(decode(&mut bad, depth), Err(Error::Uncorrectable), "round {round}");
+            assert_eq!(bad, received, "left as it came");
+        }
+    }
+
+    #[test]
+    fn shapes() {
+        assert_eq!(encode(&mut [0; N], 0), Err(Error::Shape));
+        assert_eq!(encode(&mut [0; N + 1], 1), Err(Error::Shape));
+        assert_eq!(encode(&mut [0; PARITY], 1), Err(Error::Shape));
+        assert_eq!(encode(&mut [0; 2 * N + 1], 2), Err(Error::Shape));
+        assert_eq!(encode(&mut vec![0; N * (MAX_DEPTH + 1)], MAX_DEPTH + 1), Err(Error::Shape));
+        assert_eq!(decode(&mut [0; N + 1], 1), Err(Error::Shape));
+        assert_eq!(encode(&mut [0; PARITY + 1], 1), Ok(()));
+        // all zeros is a codeword
+        let mut block = [0; N];
+        encode(&mut block, 1).unwrap();
+        assert_eq!(block, [0; N]);
+    }
+
+    /// The randomizer one bit at a time from the polynomial, s[n+8] = s[n+7]+s[n+5]+s[n+3]+s[n]
+    fn randomizer_bits(count: usize) -> Vec<u8> {
+        let mut bits = vec![1u8; 8];
+        while bits.len() < count * 8 {
+            let n = bits.len() - 8;
+            bits.push(bits[n + 7] ^ bits[n + 5] ^ bits[n + 3] ^ bits[n]);
+        }
+        bits.chunks(8).map(|bits| bits.iter().fold(0, |byte, &bit| byte << 1 | bit)).collect()
+    }
+
+    #[test]
+    fn randomizer() {
+        // the start of the sequence in CCSDS 131.0-B section 10
+        const START: [u8; 32] = [
+            0xFF, 0x48, 0x0E, 0xC0, 0x9A, 0x0D, 0x70, 0xBC, 0x8E, 0x2C, 0x93, 0xAD, 0xA7, 0xB7, 0x46, 0xCE, 0x5A,
+            0x97, 0x7D, 0xCC, 0x32, 0xA2, 0xBF, 0x3E, 0x0A, 0x10, 0xF1, 0x88, 0x94, 0xCD, 0xEA, 0xB1,
+        ];
+        let mut data = [0; 3 * N];
+        randomize(&mut data);
+        assert_eq!(data[..32], START);
+        assert_eq!(data.to_vec(), randomizer_bits(3 * N));
+        // 255 bytes, then it starts over
+        assert_eq!(data[N..N + 32], START);
+        randomize(&mut data);
+        assert_eq!(data, [0; 3 * N]);
+    }
+
+    /// The convolutional code straight from the octal generators, one bit at a time
+    fn convolve(data: &[u8]) -> Vec<u8> {
+        const G1: [u8; 7] = [1, 1, 1, 1, 0, 0, 1]; // 171
+        const G2: [u8; 7] = [1, 0, 1, 1, 0, 1, 1]; // 133
+        let input: Vec<u8> = data.iter().flat_map(|&byte| (0..8).rev().map(move |i| byte >> i & 1)).collect();
+        let mut out = Vec::new();
+        for n in 0..input.len() {
+            let bit = |taps: &[u8; 7]| {
+                (0..7).filter(|&k| k <= n).fold(0, |sum, k| sum ^ taps[k] & input[n - k])
+            };
+            out.push(bit(&G1));
+            out.push(bit(&G2) ^ 1);
+        }
+        out.chunks(8).map(|bits| bits.iter().fold(0, |byte, &bit| byte << 1 | bit)).collect()
+    }
+
+    #[test]
+    fn convolutional() {
+        let mut encoder = ConvolutionalEncoder::new();
+        // zeros give 01 for every bit, G2 is inverted
+        assert_eq!(encoder.encode_byte(0x00), 0x5555);
+        // the impulse response: G1 1111001 and G2 1011011 inverted, interleaved
+        assert_eq!(encoder.encode_byte(0x80), 0b10_11_10_10_01_00_10_01);
+        assert_eq!(encoder.encode_byte(0x00), 0x5555);
+
+        let mut rng = Rng(7);
+        let data: Vec<u8> = (0..64).map(|_| rng.next() as u8).collect();
+        let mut out = [0; 128];
+        // in two pieces, the state carries over
+        let mut encoder = ConvolutionalEncoder::new();
+        encoder.encode(&data[..21], &mut out[..42]).unwrap();
+        encoder.encode(&data[21..], &mut out[42..]).unwrap();
+        assert_eq!(out.to_vec(), convolve(&data));
+
+        assert_eq!(ConvolutionalEncoder::new().encode(&data, &mut [0; 127]), Err(Error::BufferTooSmall));
+    }
+}
@@ -0,0 +1,262 @@
+//! Internal flash access
+//!
+//! The STM32F446RE has 512K of flash split into 8 sectors of different sizes:
+//!
+//! | sector | address      | size |
+//! |--------|--------------|------|
+//! | 0 - 3  | 0x0800_0000  | 16K  |
+//! | 4      | 0x0801_0000  | 64K  |
+//! | 5 - 7  | 0x0802_0000  | 128K |
+//!
+//! A sector is the smallest thing that can be erased, erasing sets every byte to 0xFF and
+//! programming can only clear bits. Everything that stores data in flash goes through the
+//! [`Flash`] trait so it can run against `SimFlash` on the host.
+
+#[cfg(not(target_os = "none"))]
+extern crate std;
+
+#[cfg(not(target_os = "none"))]
+use std::{vec, vec::Vec};
+use stm32f4xx_hal::{flash::FlashExt, pac::FLASH};
+
+/// Start of flash in the memory map
+pub const FLASH_BASE: usize = 0x0800_0000;
+
+/// Size of the whole flash
+pub const FLASH_SIZE: usize = 512 * 1024;
+
+/// Number of sectors on the STM32F446RE
+pub const SECTOR_COUNT: u8 = 8;
+
+/// Size of a sector in bytes
+pub const fn sector_size(sector: u8) -> usize {
+    match sector {
+        0..=3 => 16 * 1024,
+        4 => 64 * 1024,
+        _ => 128 * 1024,
+    }
+}
+
+/// Offset of a sector from the start of flash
+pub const fn sector_offset(sector: u8) -> usize {
+    match sector {
+        0..=4 => sector as usize * 16 * 1024,
+        _ => (sector as usize - 4) * 128 * 1024,
+    }
+}
+
+/// Absolute address of a sector, handy for pointing the vector table or a jump at it
+pub const fn sector_address(sector: u8) -> usize {
+    FLASH_BASE + sector_offset(sector)
+}
+
+/// Something that behaves like NOR flash, erased per sector and programmed per byte
+pub trait Flash {
+    type Error;
+
+    /// Size of `sector` in bytes
+    fn sector_size(&self, sector: u8) -> usize;
+
+    /// Read `buf.len()` bytes starting `offset` bytes into `sector`
+    fn read(&self, sector: u8, offset: usize, buf: &mut [u8]) -> Result<(), Self::Error>;
+
+    /// Erase a whole sector back to 0xFF
+    fn erase(&mut self, sector: u8) -> Result<(), Self::Error>;
+
+    /// Program `data` starting `offset` bytes into `sector`, the area should be erased first
+    fn program(&mut self, sector: u8, offset: usize, data: &[u8]) -> Result<(), Self::Error>;
+}
+
+/// The real flash, takes ownership of the FLASH peripheral
+pub struct InternalFlash {
+    flash: FLASH,
+}
+
+impl InternalFlash {
+    pub fn new(flash: FLASH) -> Self {
+        InternalFlash { flash }
+    }
+
+    /// Give the peripheral back
+    pub fn free(self) -> FLASH {
+        self.flash
+    }
+}
+
+impl Flash for InternalFlash {
+    type Error = stm32f4xx_hal::flash::Error;
+
+    fn sector_size(&self, sector: u8) -> usize {
+        sector_size(sector)
+    }
+
+    fn read(&self, sector: u8, offset: usize, buf: &mut [u8]) -> Result<(), Self::Error> {
+        let start = sector_offset(sector) + offset;
+        buf.copy_from_slice(&self.flash.read()[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn erase(&mut self, sector: u8) -> Result<(), Self::Error> {
+        // the flash is locked again when `unlocked` is dropped
+        let mut unlocked = self.flash.unlocked();
+        unlocked.erase(sector)
+    }
+
+    fn program(&mut self, sector: u8, offset: usize, data: &[u8]) -> Result<(), Self::Error> {
+        let mut unlocked = self.flash.unlocked();
+        unlocked.program(sector_offset(sector) + offset, data.iter())
+    }
+}
+
+/// Errors from the simulated flash
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum SimError {
+    /// The operation was cut short by an injected power failure
+    PowerLoss,
+    /// Sector or offset outside the simulated flash
+    OutOfBounds,
+}
+
+/// Flash simulated in RAM for host tests
+///
+/// Holds sectors numbered from `first_sector` so the code under test can use the same sector
+/// numbers as on the board, either a few small ones of one size or the whole flash of the chip.
+/// They are on the heap, the 128K sectors don't fit on the stack of a test. Programming ANDs the
+/// new data into the old like real NOR flash does. `fail_after` injects a power failure after a
+/// number of bytes have been programmed, an erase counts as one byte and leaves the sector half
+/// erased when it is the one that fails.
+#[cfg(not(target_os = "none"))]
+#[derive(Clone)]
+pub struct SimFlash {
+    first_sector: u8,
+    data: Vec<Vec<u8>>,
+    erase_counts: Vec<u32>,
+    budget: Option<usize>,
+    powered: bool,
+}
+
+#[cfg(not(target_os = "none"))]
+impl SimFlash {
+    /// `count` erased sectors of `size` bytes
+    pub fn new(first_sector: u8, count: usize, size: usize) -> Self {
+        SimFlash {
+            first_sector,
+            data: vec![vec![0xFF; size]; count],
+            erase_counts: vec![0; count],
+            budget: None,
+            powered: true,
+        }
+    }
+
+    /// All of the STM32F446RE flash erased, with its real sector sizes
+    pub fn stm32f446() -> Self {
+        SimFlash {
+            first_sector: 0,
+            data: (0..SECTOR_COUNT).map(|sector| vec![0xFF; sector_size(sector)]).collect(),
+            erase_counts: vec![0; SECTOR_COUNT as usize],
+            budget: None,
+            powered: true,
+        }
+    }
+
+    /// Lose power after `bytes` more bytes have been written
+    pub fn fail_after(&mut self, bytes: usize) {
+        self.budget = Some(bytes);
+    }
+
+    /// Power the flash back up as after a reset, keeping whatever made it to the cells
+    pub fn restore_power(&mut self) {
+        self.budget = None;
+        self.powered = true;
+    }
+
+    /// The power went since the last `restore_power`
+    pub fn lost_power(&self) -> bool {
+        !self.powered
+    }
+
+    /// How many times a sector has been erased
+    pub fn erase_count(&self, sector: u8) -> u32 {
+        self.erase_counts[(sector - self.first_sector) as usize]
+    }
+
+    /// Raw contents of a sector
+    pub fn sector(&self, sector: u8) -> &[u8] {
+        &self.data[(sector - self.first_sector) as usize]
+    }
+
+    /// Raw contents of a sector, for poking at it in tests
+    pub fn sector_mut(&mut self, sector: u8) -> &mut [u8] {
+        &mut self.data[(sector - self.first_sector) as usize]
+    }
+
+    fn index(&self, sector: u8) -> Result<usize, SimError> {
+        match sector.checked_sub(self.first_sector) {
+            Some(index) if (index as usize) < self.data.len() => Ok(index as usize),
+            _ => Err(SimError::OutOfBounds),
+        }
+    }
+
+    // Spend one byte of the power budget, false if the power just went
+    fn spend(&mut self) -> bool {
+        if !self.powered {
+            return false;
+        }
+        match self.budget {
+            Some(0) => {
+                self.powered = false;
+                false
+            }
+            Some(ref mut left) => {
+                *left -= 1;
+                true
+            }
+            None => true,
+        }
+    }
+}
+
+#[cfg(not(target_os = "none"))]
+impl Flash for SimFlash {
+    type Error = SimError;
+
+    fn sector_size(&self, sector: u8) -> usize {
+        self.index(sector).map_or(0, |index| self.data[index].len())
+    }
+
+    fn read(&self, sector: u8, offset: usize, buf: &mut [u8]) -> Result<(), Self::Error> {
+        let index = self.index(sector)?;
+        let cells = self.data[index]
+            .get(offset..offset + buf.len())
+            .ok_or(SimError::OutOfBounds)?;
+        buf.copy_from_slice(cells);
+        Ok(())
+    }
+
+    fn erase(&mut self, sector: u8) -> Result<(), Self::Error> {
+        let index = self.index(sector)?;
+        let len = self.data[index].len();
+        if !self.spend() {
+            // an interrupted erase leaves the sector in a mess
+            self.data[index][..len / 2].fill(0xFF);
+            return Err(SimError::PowerLoss);
+        }
+        self.data[index].fill(0xFF);
+        self.erase_counts[index] += 1;
+        Ok(())
+    }
+
+    fn program(&mut self, sector: u8, offset: usize, data: &[u8]) -> Result<(), Self::Error> {
+        let index = self.index(sector)?;
+        if offset + data.len() > self.data[index].len() {
+            return Err(SimError::OutOfBounds);
+        }
+        for (i, byte) in data.iter().enumerate() {
+            if !self.spend() {
+                return Err(SimError::PowerLoss);
+            }
+            self.data[index][offset + i] &= *byte;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,167 @@
+//! Layout of the firmware image header
+//!
+//! The header sits `HEADER_OFFSET` bytes into the image, just after the vector table, so it is
+//! easy to find in a binary. Everything but the length and CRC is filled in at compile time,
+//! those two are left erased (0xFF) by the linker and stamped into the binary afterwards by
+//! `tools/imagetool`. This file is shared with that tool, so it only depends on `crate::crc`.
+//!
+//! ```text
+//! 0   magic            u32
+//! 4   header_version   u16
+//! 6   flags            u16  bit 0: built from a dirty git tree, bit 1: linked for the bootloader slot
+//! 8   version          u16 x 3, major minor patch from Cargo.toml
+//! 14  -                u16
+//! 16  git_hash         [u8; 12]  short hash in ascii
+//! 28  -                u32
+//! 32  build_timestamp  u64  seconds since 1970
+//! 40  image_len        u32  whole image including the header, 0xFFFF_FFFF until stamped
+//! 44  image_crc        u32  CRC-32 of the image with this field read as 0xFFFF_FFFF
+//! ```
+
+use crate::crc::Crc32;
+
+/// Where the header is, counted from the start of the image
+pub const HEADER_OFFSET: usize = 0x200;
+
+pub const HEADER_LEN: usize = 48;
+
+pub const HEADER_MAGIC: u32 = 0x4844_4D49; // "IMDH"
+
+pub const HEADER_VERSION: u16 = 1;
+
+/// Value of `image_len` and `image_crc` before the image is stamped
+pub const UNSTAMPED: u32 = 0xFFFF_FFFF;
+
+/// Offset of `image_crc` within the image
+pub const CRC_OFFSET: usize = HEADER_OFFSET + 44;
+
+pub const FLAG_DIRTY: u16 = 1 << 0;
+
+/// Linked for the primary slot of the bootloader. Only those images are stamped, the plain
+/// layout starts at the beginning of flash so its CRC would cover the configuration and log
+/// store sectors in between, which change at runtime.
+pub const FLAG_SLOT: u16 = 1 << 1;
+
+/// The header as laid out in flash
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageHeader {
+    pub magic: u32,
+    pub header_version: u16,
+    pub flags: u16,
+    pub version: [u16; 3],
+    pub _reserved0: u16,
+    pub git_hash: [u8; 12],
+    pub _reserved1: u32,
+    pub build_timestamp: u64,
+    pub image_len: u32,
+    pub image_crc: u32,
+}
+
+const _: () = assert!(core::mem::size_of::<ImageHeader>() == HEADER_LEN);
+
+impl ImageHeader {
+    pub fn to_bytes(self) -> [u8; HEADER_LEN] {
+        let mut raw = [0u8; HEADER_LEN];
+        raw[0..4].copy_from_slice(&self.magic.to_le_bytes());
+        raw[4..6].copy_from_slice(&self.header_version.to_le_bytes());
+        raw[6..8].copy_from_slice(&self.flags.to_le_bytes());
+        for (i, part) in self.version.iter().enumerate() {
+            raw[8 + 2 * i..10 + 2 * i].copy_from_slice(&part.to_le_bytes());
+        }
+        raw[16..28].copy_from_slice(&self.git_hash);
+        raw[32..40].copy_from_slice(&self.build_timestamp.to_le_bytes());
+        raw[40..44].copy_from_slice(&self.image_len.to_le_bytes());
+        raw[44..48].copy_from_slice(&self.image_crc.to_le_bytes());
+        raw
+    }
+
+    /// `None` if `raw` does not start with a header this code understands
+    pub fn parse(raw: &[u8]) -> Option<Self> {
+        let raw = raw.get(..HEADER_LEN)?;
+        let u16_at = |at: usize| u16::from_le_bytes([raw[at], raw[at + 1]]);
+        let u32_at = |at: usize| u32::from_le_bytes([raw[at], raw[at + 1], raw[at + 2], raw[at + 3]]);
+
+        if u32_at(0) != HEADER_MAGIC || u16_at(4) != HEADER_VERSION {
+            return None;
+        }
+        let mut git_hash = [0; 12];
+        git_hash.copy_from_slice(&raw[16..28]);
+        let mut timestamp = [0; 8];
+        timestamp.copy_from_slice(&raw[32..40]);
+        Some(ImageHeader {
+            magic: HEADER_MAGIC,
+            header_version: HEADER_VERSION,
+            flags: u16_at(6),
+            version: [u16_at(8), u16_at(10), u16_at(12)],
+            _reserved0: 0,
+            git_hash,
+            _reserved1: 0,
+            build_timestamp: u64::from_le_bytes(timestamp),
+            image_len: u32_at(40),
+            image_crc: u32_at(44),
+        })
+    }
+
+    /// The header of a whole image, as read from a .bin file or from flash
+    pub fn from_image(image: &[u8]) -> Option<Self> {
+        Self::parse(image.get(HEADER_OFFSET..)?)
+    }
+
+    pub fn is_stamped(&self) -> bool {
+        self.image_len != UNSTAMPED && self.image_crc != UNSTAMPED
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.flags & FLAG_DIRTY != 0
+    }
+
+    pub fn is_slot(&self) -> bool {
+        self.flags & FLAG_SLOT != 0
+    }
+
+    /// The git hash as text, empty if the build script could not ask git
+    pub fn git_hash_str(&self) -> &str {
+        let len = self.git_hash.iter().position(|&b| b == 0).unwrap_or(12);
+        core::str::from_utf8(&self.git_hash[..len]).unwrap_or("")
+    }
+}
+
+/// CRC that goes in `image_crc`, computed as if the field was still erased
+pub fn image_crc(image: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(&image[..CRC_OFFSET]);
+    crc.update(&UNSTAMPED.to_le_bytes());
+    crc.update(&image[CRC_OFFSET + 4..]);
+    crc.finish()
+}
+
+/// Why an image did not pass the check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Check {
+    /// Length and CRC match the header
+    Ok,
+    /// Not stamped yet, probably flashed straight from cargo
+    Unstamped,
+    /// `image_len` does not fit in the memory given
+    BadLength,
+    /// The CRC is off, the value is what the image actually has
+    BadCrc(u32),
+}
+
+/// Check `image`, which may be longer than the image itself (a whole slot for example)
+pub fn check(header: &ImageHeader, image: &[u8]) -> Check {
+    if !header.is_stamped() {
+        return Check::Unstamped;
+    }
+    let len = header.image_len as usize;
+    if len < HEADER_OFFSET + HEADER_LEN || len > image.len() {
+        return Check::BadLength;
+    }
+    let crc = image_crc(&image[..len]);
+    if crc == header.image_crc {
+        Check::Ok
+    } else {
+        Check::BadCrc(crc)
+    }
+}
@@ -0,0 +1,37 @@
+//! Critical sections on the host, for the tests
+//!
+//! cortex-m only implements them on the chip, by masking interrupts. The tests run in threads
+//! instead, so one lock stands in for that, taken by the outermost critical section of a thread.
+
+extern crate std;
+
+use core::cell::{Cell, RefCell};
+use critical_section::RawRestoreState;
+use std::sync::{Mutex, MutexGuard};
+
+static LOCK: Mutex<()> = Mutex::new(());
+
+std::thread_local! {
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+    static GUARD: RefCell<Option<MutexGuard<'static, ()>>> = const { RefCell::new(None) };
+}
+
+struct Host;
+critical_section::set_impl!(Host);
+
+unsafe impl critical_section::Impl for Host {
+    unsafe fn acquire() -> RawRestoreState {
+        if DEPTH.with(|depth| depth.replace(depth.get() + 1)) == 0 {
+            // a test that panicked while holding it leaves nothing half done that matters here
+            let guard = LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            GUARD.with(|held| *held.borrow_mut() = Some(guard));
+        }
+        RawRestoreState::default()
+    }
+
+    unsafe fn release(_: RawRestoreState) {
+        if DEPTH.with(|depth| depth.replace(depth.get() - 1)) == 1 {
+            GUARD.with(|held| held.borrow_mut().take());
+        }
+    }
+}
@@ -0,0 +1,520 @@
+//! I2C1 bus manager
+//!
+//! A polling I2C master on I2C1 with PB8 (SCL) and PB9 (SDA), the pins on the Nucleo headers
+//! marked SCL/D15 and SDA/D14. It implements the blocking `embedded-hal` I2C traits so the
+//! sensor drivers don't need to know about it, and it is put in an RTIC shared resource so
+//! tasks take turns through the resource lock. Drivers borrow it per call instead of owning it.
+//!
+//! Every wait for a flag has a deadline, counted with the DWT cycle counter which the monotonic
+//! timer already has running. A transaction that times out or sees a bus error leaves the bus
+//! in an unknown state, so the peripheral is reset and the bus recovered: if a slave holds SDA
+//! low, SCL is clocked by hand until it lets go and a STOP is sent.
+
+use cortex_m::peripheral::DWT;
+use embedded_hal::blocking::i2c::{Read, Write, WriteRead};
+use stm32f4xx_hal::{
+    gpio::{
+        gpiob::{PB8, PB9},
+        Alternate, OpenDrain,
+    },
+    pac::{GPIOB, I2C1, RCC},
+};
+
+pub type Scl = PB8<Alternate<4, OpenDrain>>;
+pub type Sda = PB9<Alternate<4, OpenDrain>>;
+
+// Register bits
+const CR1_PE: u32 = 1 << 0;
+const CR1_START: u32 = 1 << 8;
+const CR1_STOP: u32 = 1 << 9;
+const CR1_ACK: u32 = 1 << 10;
+const CR1_POS: u32 = 1 << 11;
+const SR1_SB: u32 = 1 << 0;
+const SR1_ADDR: u32 = 1 << 1;
+const SR1_BTF: u32 = 1 << 2;
+const SR1_RXNE: u32 = 1 << 6;
+const SR1_TXE: u32 = 1 << 7;
+const SR1_BERR: u32 = 1 << 8;
+const SR1_ARLO: u32 = 1 << 9;
+const SR1_AF: u32 = 1 << 10;
+const SR1_OVR: u32 = 1 << 11;
+const SR2_BUSY: u32 = 1 << 1;
+const CCR_FS: u32 = 1 << 15;
+const RCC_I2C1: u32 = 1 << 21;
+
+/// Bus speed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Speed {
+    Standard, // 100 kHz
+    Fast,     // 400 kHz
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+    /// No acknowledge, there is no slave at the address or it did not want the data
+    Nack,
+    /// Misplaced start or stop condition
+    Bus,
+    /// Another master won the bus
+    ArbitrationLoss,
+    Overrun,
+    /// A flag did not come in time, the bus has been recovered
+    Timeout,
+}
+
+impl Error {
+    /// The error flagged in SR1, if any
+    fn from_sr1(sr1: u32) -> Option<Self> {
+        if sr1 & SR1_AF != 0 {
+            Some(Error::Nack)
+        } else if sr1 & SR1_ARLO != 0 {
+            Some(Error::ArbitrationLoss)
+        } else if sr1 & SR1_BERR != 0 {
+            Some(Error::Bus)
+        } else if sr1 & SR1_OVR != 0 {
+            Some(Error::Overrun)
+        } else {
+            None
+        }
+    }
+}
+
+/// What a failed transaction leaves to be done
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Cleanup {
+    /// The slave said no, the bus is fine once the STOP is sent
+    Stop,
+    /// The peripheral or the bus is in an unknown state
+    Recover,
+}
+
+/// Counters for the health telemetry
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, defmt::Format)]
+pub struct Stats {
+    pub transactions: u32,
+    pub nacks: u32,
+    pub errors: u32,
+    pub recoveries: u32,
+}
+
+impl Stats {
+    /// Count a finished transaction
+    fn record(&mut self, result: Result<(), Error>) -> Option<Cleanup> {
+        self.transactions += 1;
+        match result {
+            Ok(()) => None,
+            Err(Error::Nack) => {
+                self.nacks += 1;
+                Some(Cleanup::Stop)
+            }
+            Err(_) => {
+                self.errors += 1;
+                Some(Cleanup::Recover)
+            }
+        }
+    }
+}
+
+/// I2C1 with timeouts and bus recovery
+pub struct I2cBus {
+    i2c: I2C1,
+    pins: (Scl, Sda),
+    pclk1_hz: u32,
+    speed: Speed,
+    timeout_cycles: u32,
+    stats: Stats,
+}
+
+impl I2cBus {
+    /// `sysclk_hz` is what the cycle counter runs at, `timeout_us` is the longest any one flag
+    /// may take. A byte at 100 kHz takes 90 us, a slave stretching the clock can take longer.
+    pub fn new(i2c: I2C1, pins: (Scl, Sda), speed: Speed, pclk1_hz: u32, sysclk_hz: u32, timeout_us: u32) -> Self {
+        let mut bus = I2cBus {
+            i2c,
+            pins,
+            pclk1_hz,
+            speed,
+            timeout_cycles: sysclk_hz / 1_000_000 * timeout_us,
+            stats: Stats::default(),
+        };
+        bus.recover();
+        bus
+    }
+
+    pub fn free(self) -> (I2C1, (Scl, Sda)) {
+        (self.i2c, self.pins)
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    /// Reset the peripheral and free the bus if a slave is stuck in the middle of a byte
+    pub fn recover(&mut self) {
+        // Safety: only the I2C1 bits of the RCC registers are touched
+        let rcc = unsafe { &*RCC::ptr() };
+        rcc.apb1enr.modify(|r, w| unsafe { w.bits(r.bits() | RCC_I2C1) });
+        rcc.apb1rstr.modify(|r, w| unsafe { w.bits(r.bits() | RCC_I2C1) });
+        rcc.apb1rstr.modify(|r, w| unsafe { w.bits(r.bits() & !RCC_I2C1) });
+
+        let mut lines = Gpiob;
+        if !lines.sda_high() {
+            defmt::warn!("i2c: SDA stuck low, clocking the bus free");
+            self.stats.recoveries += 1;
+            set_mode(SCL_PIN, 0b01); // output, the pins are already open drain
+            set_mode(SDA_PIN, 0b01);
+            clock_free(&mut lines);
+            set_mode(SCL_PIN, 0b10); // back to the alternate function
+            set_mode(SDA_PIN, 0b10);
+        }
+        self.configure();
+    }
+
+    fn configure(&mut self) {
+        let mhz = self.pclk1_hz / 1_000_000;
+        let (ccr, trise) = match self.speed {
+            // half the SCL period each for high and low
+            Speed::Standard => ((self.pclk1_hz / (2 * 100_000)).max(4), mhz + 1),
+            // low twice as long as high, 300 ns rise time
+            Speed::Fast => (CCR_FS | (self.pclk1_hz / (3 * 400_000)).max(1), mhz * 300 / 1000 + 1),
+        };
+        self.i2c.cr1.write(|w| unsafe { w.bits(0) });
+        self.i2c.cr2.write(|w| unsafe { w.bits(mhz) });
+        self.i2c.ccr.write(|w| unsafe { w.bits(ccr) });
+        self.i2c.trise.write(|w| unsafe { w.bits(trise) });
+        self.i2c.cr1.write(|w| unsafe { w.bits(CR1_PE) });
+    }
+
+    fn set_cr1(&mut self, bits: u32) {
+        self.i2c.cr1.modify(|r, w| unsafe { w.bits(r.bits() | bits) });
+    }
+
+    fn clear_cr1(&mut self, bits: u32) {
+        self.i2c.cr1.modify(|r, w| unsafe { w.bits(r.bits() & !bits) });
+    }
+
+    /// Wait for `flag` in SR1, giving up on an error or when the deadline passes
+    fn wait(&mut self, flag: u32, start: u32) -> Result<(), Error> {
+        loop {
+            let sr1 = self.i2c.sr1.read().bits();
+            if let Some(error) = Error::from_sr1(sr1) {
+                // the error flags are cleared by writing 0
+                self.i2c.sr1.write(|w| unsafe { w.bits(0) });
+                return Err(error);
+            }
+            if sr1 & flag != 0 {
+                return Ok(());
+            }
+            if DWT::cycle_count().wrapping_sub(start) > self.timeout_cycles {
+                return Err(Error::Timeout);
+            }
+        }
+    }
+
+    fn start(&mut self, address: u8, read: bool) -> Result<(), Error> {
+        let start = DWT::cycle_count();
+        self.set_cr1(CR1_START);
+        self.wait(SR1_SB, start)?;
+        self.i2c.dr.write(|w| unsafe { w.bits((address as u32) << 1 | read as u32) });
+        self.wait(SR1_ADDR, start)
+    }
+
+    fn clear_addr(&mut self) {
+        // reading SR1 and then SR2 clears ADDR
+        self.i2c.sr1.read();
+        self.i2c.sr2.read();
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.clear_addr();
+        for &byte in bytes {
+            let start = DWT::cycle_count();
+            self.wait(SR1_TXE, start)?;
+            self.i2c.dr.write(|w| unsafe { w.bits(byte as u32) });
+        }
+        let start = DWT::cycle_count();
+        self.wait(SR1_BTF, start)
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        self.i2c.dr.read().bits() as u8
+    }
+
+    /// Receive into `buffer` and send the STOP, following the sequences of RM0390 for 1, 2 and
+    /// more bytes since the ACK and STOP have to be set before the last bytes are in
+    fn read_bytes(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+        let start = DWT::cycle_count();
+        match buffer.len() {
+            0 => {
+                self.clear_addr();
+                self.set_cr1(CR1_STOP);
+            }
+            1 => {
+                self.clear_cr1(CR1_ACK);
+                self.clear_addr();
+                self.set_cr1(CR1_STOP);
+                self.wait(SR1_RXNE, start)?;
+                buffer[0] = self.read_byte();
+            }
+            2 => {
+                self.set_cr1(CR1_POS);
+                self.clear_cr1(CR1_ACK);
+                self.clear_addr();
+                self.wait(SR1_BTF, start)?;
+                self.set_cr1(CR1_STOP);
+                buffer[0] = self.read_byte();
+                buffer[1] = self.read_byte();
+                self.clear_cr1(CR1_POS);
+            }
+            len => {
+                self.set_cr1(CR1_ACK);
+                self.clear_addr();
+                for byte in &mut buffer[..len - 3] {
+                    let start = DWT::cycle_count();
+                    self.wait(SR1_RXNE, start)?;
+                    *byte = self.read_byte();
+                }
+                // N-2 in DR and N-1 in the shift register
+                let start = DWT::cycle_count();
+                self.wait(SR1_BTF, start)?;
+                self.clear_cr1(CR1_ACK);
+                buffer[len - 3] = self.read_byte();
+                let start = DWT::cycle_count();
+                self.wait(SR1_BTF, start)?;
+                self.set_cr1(CR1_STOP);
+                buffer[len - 2] = self.read_byte();
+                buffer[len - 1] = self.read_byte();
+            }
+        }
+        Ok(())
+    }
+
+    fn wait_idle(&mut self) -> Result<(), Error> {
+        let start = DWT::cycle_count();
+        while self.i2c.sr2.read().bits() & SR2_BUSY != 0 {
+            if DWT::cycle_count().wrapping_sub(start) > self.timeout_cycles {
+                return Err(Error::Timeout);
+            }
+        }
+        Ok(())
+    }
+
+    /// Run a transaction and clean up after it whatever happened
+    fn transaction(&mut self, f: impl FnOnce(&mut Self) -> Result<(), Error>) -> Result<(), Error> {
+        let result = self.wait_idle().and_then(|()| f(self));
+        match self.stats.record(result) {
+            None => {}
+            Some(Cleanup::Stop) => self.set_cr1(CR1_STOP),
+            Some(Cleanup::Recover) => {
+                if let Err(error) = result {
+                    defmt::warn!("i2c: {}, recovering the bus", error);
+                }
+                self.recover();
+            }
+        }
+        result
+    }
+}
+
+impl Write for I2cBus {
+    type Error = Error;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Error> {
+        self.transaction(|bus| {
+            bus.start(address, false)?;
+            bus.write_bytes(bytes)?;
+            bus.set_cr1(CR1_STOP);
+            Ok(())
+        })
+    }
+}
+
+impl Read for I2cBus {
+    type Error = Error;
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), Error> {
+        self.transaction(|bus| {
+            bus.start(address, true)?;
+            bus.read_bytes(buffer)
+        })
+    }
+}
+
+impl WriteRead for I2cBus {
+    type Error = Error;
+
+    fn write_read(&mut self, address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), Error> {
+        self.transaction(|bus| {
+            bus.start(address, false)?;
+            bus.write_bytes(bytes)?;
+            // repeated start
+            bus.start(address, true)?;
+            bus.read_bytes(buffer)
+        })
+    }
+}
+
+// Bus recovery with PB8 and PB9 as plain open drain outputs, the pin types stay the same since
+// the alternate function is put back afterwards
+
+const SCL_PIN: u32 = 8;
+const SDA_PIN: u32 = 9;
+
+fn gpiob() -> &'static stm32f4xx_hal::pac::gpiob::RegisterBlock {
+    // Safety: only the mode and output bits of PB8 and PB9 are touched, which this module owns
+    unsafe { &*GPIOB::ptr() }
+}
+
+fn set_mode(pin: u32, mode: u32) {
+    gpiob()
+        .moder
+        .modify(|r, w| unsafe { w.bits(r.bits() & !(0b11 << (2 * pin)) | mode << (2 * pin)) });
+}
+
+/// SCL and SDA driven by hand
+trait Lines {
+    fn sda_high(&mut self) -> bool;
+    fn set(&mut self, pin: u32, high: bool);
+    /// Half a period at 100 kHz
+    fn half_period(&mut self);
+}
+
+/// PB8 and PB9 once they are outputs
+struct Gpiob;
+
+impl Lines for Gpiob {
+    fn sda_high(&mut self) -> bool {
+        gpiob().idr.read().bits() & (1 << SDA_PIN) != 0
+    }
+
+    fn set(&mut self, pin: u32, high: bool) {
+        let bit = if high { 1 << pin } else { 1 << (pin + 16) };
+        gpiob().bsrr.write(|w| unsafe { w.bits(bit) });
+    }
+
+    /// About 5 us at up to 180 MHz
+    fn half_period(&mut self) {
+        cortex_m::asm::delay(900);
+    }
+}
+
+/// Clock SCL until the slave lets go of SDA (at most 9 times), then send a STOP
+fn clock_free(lines: &mut impl Lines) {
+    lines.set(SCL_PIN, true);
+    lines.set(SDA_PIN, true);
+
+    for _ in 0..9 {
+        if lines.sda_high() {
+            break;
+        }
+        lines.set(SCL_PIN, false);
+        lines.half_period();
+        lines.set(SCL_PIN, true);
+        lines.half_period();
+    }
+
+    // STOP: SDA goes high while SCL is high
+    lines.set(SDA_PIN, false);
+    lines.half_period();
+    lines.set(SCL_PIN, true);
+    lines.half_period();
+    lines.set(SDA_PIN, true);
+    lines.half_period();
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use std::vec::Vec;
+
+    #[test]
+    fn errors_from_sr1() {
+        assert_eq!(Error::from_sr1(SR1_TXE | SR1_BTF), None);
+        assert_eq!(Error::from_sr1(SR1_AF | SR1_TXE), Some(Error::Nack));
+        assert_eq!(Error::from_sr1(SR1_ARLO), Some(Error::ArbitrationLoss));
+        assert_eq!(Error::from_sr1(SR1_BERR), Some(Error::Bus));
+        assert_eq!(Error::from_sr1(SR1_OVR | SR1_RXNE), Some(Error::Overrun));
+    }
+
+    #[test]
+    fn nack_stops_and_arbitration_loss_recovers() {
+        let mut stats = Stats::default();
+        assert_eq!(stats.record(Ok(())), None);
+        assert_eq!(stats.record(Err(Error::Nack)), Some(Cleanup::Stop));
+        assert_eq!(stats.record(Err(Error::ArbitrationLoss)), Some(Cleanup::Recover));
+        assert_eq!(stats.record(Err(Error::Timeout)), Some(Cleanup::Recover));
+        assert_eq!(
+            stats,
+            Stats {
+                transactions: 4,
+                nacks: 1,
+                errors: 2,
+                recoveries: 0,
+            }
+        );
+    }
+
+    /// A slave that holds SDA low for `stuck` more clocks
+    struct Stuck {
+        stuck: usize,
+        scl: bool,
+        sda: bool,
+        clocks: usize,
+        // (SCL, SDA) on the bus after every change
+        trace: Vec<(bool, bool)>,
+    }
+
+    impl Stuck {
+        fn new(stuck: usize) -> Self {
+            Stuck {
+                stuck,
+                scl: true,
+                sda: true,
+                clocks: 0,
+                trace: Vec::new(),
+            }
+        }
+
+        fn bus_sda(&self) -> bool {
+            self.sda && self.clocks >= self.stuck
+        }
+    }
+
+    impl Lines for Stuck {
+        fn sda_high(&mut self) -> bool {
+            self.bus_sda()
+        }
+
+        fn set(&mut self, pin: u32, high: bool) {
+            if pin == SCL_PIN {
+                if high && !self.scl {
+                    self.clocks += 1;
+                }
+                self.scl = high;
+            } else {
+                self.sda = high;
+            }
+            self.trace.push((self.scl, self.bus_sda()));
+        }
+
+        fn half_period(&mut self) {}
+    }
+
+    #[test]
+    fn clock_the_bus_free() {
+        for stuck in 0..12 {
+            let mut lines = Stuck::new(stuck);
+            clock_free(&mut lines);
+            // no more clocks than it takes, and at most 9
+            assert_eq!(lines.clocks, stuck.min(9), "{stuck}");
+            // ends with a STOP: SDA rises while SCL is high
+            let end = &lines.trace[lines.trace.len() - 2..];
+            if stuck <= 9 {
+                assert_eq!(end, [(true, false), (true, true)], "{stuck}");
+            } else {
+                assert!(!lines.bus_sda());
+            }
+        }
+    }
+}
@@ -0,0 +1,86 @@
+//! The header of the running firmware and the startup self-check
+//!
+//! `build.rs` generates the version, git hash and build time, the linker puts the header at
+//! `HEADER_OFFSET` (see the `.image_header` section in `memory/*.x`).
+
+use crate::flash::{FLASH_BASE, FLASH_SIZE};
+use crate::header::{
+    self, Check, ImageHeader, FLAG_DIRTY, FLAG_SLOT, HEADER_MAGIC, HEADER_OFFSET, HEADER_VERSION, UNSTAMPED,
+};
+
+mod build_info {
+    include!(concat!(env!("OUT_DIR"), "/build_info.rs"));
+}
+
+#[used]
+#[no_mangle]
+#[link_section = ".image_header"]
+pub static IMAGE_HEADER: ImageHeader = ImageHeader {
+    magic: HEADER_MAGIC,
+    header_version: HEADER_VERSION,
+    flags: if build_info::GIT_DIRTY { FLAG_DIRTY } else { 0 }
+        | if cfg!(feature = "bootloader") { FLAG_SLOT } else { 0 },
+    version: build_info::VERSION,
+    _reserved0: 0,
+    git_hash: build_info::GIT_HASH,
+    _reserved1: 0,
+    build_timestamp: build_info::BUILD_TIMESTAMP,
+    image_len: UNSTAMPED,
+    image_crc: UNSTAMPED,
+};
+
+/// The header of the running image, read back from flash so the stamped length and CRC show up
+pub fn header() -> ImageHeader {
+    // read through a pointer, the compiler would otherwise fold in the UNSTAMPED values
+    unsafe { core::ptr::read_volatile(&IMAGE_HEADER) }
+}
+
+/// Where the running image starts in flash
+pub fn image_start() -> usize {
+    &IMAGE_HEADER as *const ImageHeader as usize - HEADER_OFFSET
+}
+
+/// Check the running image against its header
+pub fn verify() -> Check {
+    let header = self::header();
+    if !header.is_stamped() {
+        return Check::Unstamped;
+    }
+    if header.image_len as usize > FLASH_BASE + FLASH_SIZE - image_start() {
+        return Check::BadLength;
+    }
+    // the image lies in flash, which always reads fine
+    let image = unsafe {
+        core::slice::from_raw_parts(image_start() as *const u8, header.image_len as usize)
+    };
+    header::check(&header, image)
+}
+
+/// Log what is running and whether it is intact, meant to be called first thing in `init`
+pub fn self_check() -> Check {
+    let header = self::header();
+    let [major, minor, patch] = header.version;
+    defmt::info!(
+        "firmware {}.{}.{} git {=str}{=str}, built {} (unix time), at {=usize:#x}",
+        major,
+        minor,
+        patch,
+        header.git_hash_str(),
+        if header.is_dirty() { "-dirty" } else { "" },
+        header.build_timestamp,
+        image_start(),
+    );
+
+    let check = verify();
+    match check {
+        Check::Ok => defmt::info!("image {} bytes, crc {=u32:#x} ok", header.image_len, header.image_crc),
+        Check::Unstamped => defmt::info!("image not stamped, skipping crc check"),
+        Check::BadLength => defmt::error!("image length {} in header is wrong", header.image_len),
+        Check::BadCrc(crc) => defmt::error!(
+            "image crc {=u32:#x} does not match header {=u32:#x}",
+            crc,
+            header.image_crc
+        ),
+    }
+    check
+}
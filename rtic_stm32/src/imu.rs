@@ -0,0 +1,514 @@
+//! Driver for the LSM9DS1 IMU: accelerometer, gyroscope and magnetometer
+//!
+//! The chip is two I2C devices, the accelerometer and gyroscope at 0x6B and the magnetometer at
+//! 0x1E (the addresses of the common breakout boards, with SDO pulled high). The driver works
+//! over any blocking `embedded-hal` I2C bus and does not keep it, every call borrows the bus, so
+//! it can share `i2c::I2cBus` with other drivers.
+//!
+//! Readings are calibrated and in SI units: m/s², rad/s and µT.
+
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+
+pub const AG_ADDRESS: u8 = 0x6B;
+pub const M_ADDRESS: u8 = 0x1E;
+
+// Accelerometer and gyroscope registers
+const WHO_AM_I: u8 = 0x0F;
+const CTRL_REG1_G: u8 = 0x10;
+const OUT_TEMP_L: u8 = 0x15;
+const STATUS_REG: u8 = 0x17;
+const OUT_X_L_G: u8 = 0x18;
+const CTRL_REG6_XL: u8 = 0x20;
+const CTRL_REG8: u8 = 0x22;
+const OUT_X_L_XL: u8 = 0x28;
+const AG_ID: u8 = 0x68;
+
+// Magnetometer registers, the address MSB turns on auto increment
+const CTRL_REG1_M: u8 = 0x20;
+const CTRL_REG2_M: u8 = 0x21;
+const CTRL_REG3_M: u8 = 0x22;
+const CTRL_REG4_M: u8 = 0x23;
+const OUT_X_L_M: u8 = 0x28;
+const M_ID: u8 = 0x3D;
+const AUTO_INCREMENT: u8 = 0x80;
+
+const STANDARD_GRAVITY: f32 = 9.806_65;
+const DEG_TO_RAD: f32 = core::f32::consts::PI / 180.0;
+
+/// Accelerometer full scale
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum AccelRange {
+    G2,
+    G4,
+    G8,
+    G16,
+}
+
+impl AccelRange {
+    fn bits(self) -> u8 {
+        match self {
+            AccelRange::G2 => 0b00,
+            AccelRange::G4 => 0b10,
+            AccelRange::G8 => 0b11,
+            AccelRange::G16 => 0b01,
+        }
+    }
+
+    /// m/s² per LSB
+    fn scale(self) -> f32 {
+        let mg = match self {
+            AccelRange::G2 => 0.061,
+            AccelRange::G4 => 0.122,
+            AccelRange::G8 => 0.244,
+            AccelRange::G16 => 0.732,
+        };
+        mg / 1000.0 * STANDARD_GRAVITY
+    }
+}
+
+/// Gyroscope full scale
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum GyroRange {
+    Dps245,
+    Dps500,
+    Dps2000,
+}
+
+impl GyroRange {
+    fn bits(self) -> u8 {
+        match self {
+            GyroRange::Dps245 => 0b00,
+            GyroRange::Dps500 => 0b01,
+            GyroRange::Dps2000 => 0b11,
+        }
+    }
+
+    /// rad/s per LSB
+    fn scale(self) -> f32 {
+        let mdps = match self {
+            GyroRange::Dps245 => 8.75,
+            GyroRange::Dps500 => 17.5,
+            GyroRange::Dps2000 => 70.0,
+        };
+        mdps / 1000.0 * DEG_TO_RAD
+    }
+}
+
+/// Magnetometer full scale
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum MagRange {
+    Gauss4,
+    Gauss8,
+    Gauss12,
+    Gauss16,
+}
+
+impl MagRange {
+    fn bits(self) -> u8 {
+        match self {
+            MagRange::Gauss4 => 0b00,
+            MagRange::Gauss8 => 0b01,
+            MagRange::Gauss12 => 0b10,
+            MagRange::Gauss16 => 0b11,
+        }
+    }
+
+    /// µT per LSB, 1 gauss is 100 µT
+    fn scale(self) -> f32 {
+        let mgauss = match self {
+            MagRange::Gauss4 => 0.14,
+            MagRange::Gauss8 => 0.29,
+            MagRange::Gauss12 => 0.43,
+            MagRange::Gauss16 => 0.58,
+        };
+        mgauss / 10.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Config {
+    pub accel: AccelRange,
+    pub gyro: GyroRange,
+    pub mag: MagRange,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            accel: AccelRange::G4,
+            gyro: GyroRange::Dps500,
+            mag: MagRange::Gauss4,
+        }
+    }
+}
+
+/// Corrections applied to every reading, `(raw in SI units - offset) * scale`
+///
+/// The gyro bias comes from `Lsm9ds1::calibrate_gyro`. The magnetometer offset is the hard
+/// iron offset and the scale a diagonal soft iron correction, both from a ground calibration
+/// where the board is turned through all orientations.
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub struct Calibration {
+    pub accel_offset: [f32; 3],
+    pub accel_scale: [f32; 3],
+    pub gyro_bias: [f32; 3],
+    pub mag_offset: [f32; 3],
+    pub mag_scale: [f32; 3],
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Calibration {
+            accel_offset: [0.0; 3],
+            accel_scale: [1.0; 3],
+            gyro_bias: [0.0; 3],
+            mag_offset: [0.0; 3],
+            mag_scale: [1.0; 3],
+        }
+    }
+}
+
+/// Raw register values
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Raw {
+    pub accel: [i16; 3],
+    pub gyro: [i16; 3],
+    pub mag: [i16; 3],
+    pub temperature: i16,
+}
+
+/// A calibrated reading
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub struct Reading {
+    /// m/s²
+    pub accel: [f32; 3],
+    /// rad/s
+    pub gyro: [f32; 3],
+    /// µT
+    pub mag: [f32; 3],
+    /// °C, of the chip
+    pub temperature: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Error<E> {
+    I2c(E),
+    /// Something else answered at the address
+    WrongDevice { address: u8, id: u8 },
+}
+
+impl<E> From<E> for Error<E> {
+    fn from(error: E) -> Self {
+        Error::I2c(error)
+    }
+}
+
+pub struct Lsm9ds1 {
+    config: Config,
+    calibration: Calibration,
+}
+
+impl Lsm9ds1 {
+    pub fn new(config: Config, calibration: Calibration) -> Self {
+        Lsm9ds1 {
+            config,
+            calibration,
+        }
+    }
+
+    pub fn calibration(&self) -> &Calibration {
+        &self.calibration
+    }
+
+    pub fn set_calibration(&mut self, calibration: Calibration) {
+        self.calibration = calibration;
+    }
+
+    /// Check both devices are there and start them at 119 Hz (accel/gyro) and 80 Hz (mag)
+    pub fn init<I, E>(&mut self, i2c: &mut I) -> Result<(), Error<E>>
+    where
+        I: Write<Error = E> + WriteRead<Error = E>,
+    {
+        for (address, expected) in [(AG_ADDRESS, AG_ID), (M_ADDRESS, M_ID)] {
+            let id = read_register(i2c, address, WHO_AM_I)?;
+            if id != expected {
+                return Err(Error::WrongDevice { address, id });
+            }
+        }
+
+        // block data update and auto increment
+        i2c.write(AG_ADDRESS, &[CTRL_REG8, 0x44])?;
+        i2c.write(AG_ADDRESS, &[CTRL_REG1_G, 0b011 << 5 | self.config.gyro.bits() << 3])?;
+        i2c.write(AG_ADDRESS, &[CTRL_REG6_XL, 0b011 << 5 | self.config.accel.bits() << 3])?;
+
+        // temperature compensated, ultra high performance on all axes, continuous
+        i2c.write(M_ADDRESS, &[CTRL_REG1_M, 0x80 | 0b11 << 5 | 0b111 << 2])?;
+        i2c.write(M_ADDRESS, &[CTRL_REG2_M, self.config.mag.bits() << 5])?;
+        i2c.write(M_ADDRESS, &[CTRL_REG3_M, 0x00])?;
+        i2c.write(M_ADDRESS, &[CTRL_REG4_M, 0b11 << 2])?;
+        Ok(())
+    }
+
+    /// Whether there is a new accelerometer and gyroscope sample
+    pub fn data_ready<I, E>(&mut self, i2c: &mut I) -> Result<bool, Error<E>>
+    where
+        I: WriteRead<Error = E>,
+    {
+        Ok(read_register(i2c, AG_ADDRESS, STATUS_REG)? & 0b11 == 0b11)
+    }
+
+    pub fn read_raw<I, E>(&mut self, i2c: &mut I) -> Result<Raw, Error<E>>
+    where
+        I: WriteRead<Error = E>,
+    {
+        let mut temperature = [0; 2];
+        i2c.write_read(AG_ADDRESS, &[OUT_TEMP_L], &mut temperature)?;
+        Ok(Raw {
+            accel: read_vector(i2c, AG_ADDRESS, OUT_X_L_XL)?,
+            gyro: read_vector(i2c, AG_ADDRESS, OUT_X_L_G)?,
+            mag: read_vector(i2c, M_ADDRESS, OUT_X_L_M | AUTO_INCREMENT)?,
+            temperature: i16::from_le_bytes(temperature),
+        })
+    }
+
+    pub fn read<I, E>(&mut self, i2c: &mut I) -> Result<Reading, Error<E>>
+    where
+        I: WriteRead<Error = E>,
+    {
+        let raw = self.read_raw(i2c)?;
+        Ok(self.convert(&raw))
+    }
+
+    /// Calibrate and scale a raw reading
+    pub fn convert(&self, raw: &Raw) -> Reading {
+        let calibration = &self.calibration;
+        let mut reading = Reading {
+            accel: [0.0; 3],
+            gyro: [0.0; 3],
+            mag: [0.0; 3],
+            // 16 LSB per °C, 0 is 25 °C
+            temperature: 25.0 + raw.temperature as f32 / 16.0,
+        };
+        for axis in 0..3 {
+            reading.accel[axis] = (raw.accel[axis] as f32 * self.config.accel.scale()
+                - calibration.accel_offset[axis])
+                * calibration.accel_scale[axis];
+            reading.gyro[axis] =
+                raw.gyro[axis] as f32 * self.config.gyro.scale() - calibration.gyro_bias[axis];
+            reading.mag[axis] = (raw.mag[axis] as f32 * self.config.mag.scale()
+                - calibration.mag_offset[axis])
+                * calibration.mag_scale[axis];
+        }
+        reading
+    }
+
+    /// Average `samples` gyroscope readings as the bias, the board has to be still
+    pub fn calibrate_gyro<I, E>(&mut self, i2c: &mut I, samples: u16) -> Result<[f32; 3], Error<E>>
+    where
+        I: WriteRead<Error = E>,
+    {
+        let mut sum = [0i32; 3];
+        for _ in 0..samples {
+            while !self.data_ready(i2c)? {}
+            let gyro = read_vector(i2c, AG_ADDRESS, OUT_X_L_G)?;
+            for axis in 0..3 {
+                sum[axis] += gyro[axis] as i32;
+            }
+        }
+        let scale = self.config.gyro.scale();
+        let bias = sum.map(|sum| sum as f32 / samples.max(1) as f32 * scale);
+        self.calibration.gyro_bias = bias;
+        Ok(bias)
+    }
+}
+
+fn read_register<I, E>(i2c: &mut I, address: u8, register: u8) -> Result<u8, E>
+where
+    I: WriteRead<Error = E>,
+{
+    let mut value = [0];
+    i2c.write_read(address, &[register], &mut value)?;
+    Ok(value[0])
+}
+
+/// X, Y and Z, little endian
+fn read_vector<I, E>(i2c: &mut I, address: u8, register: u8) -> Result<[i16; 3], E>
+where
+    I: WriteRead<Error = E>,
+{
+    let mut raw = [0; 6];
+    i2c.write_read(address, &[register], &mut raw)?;
+    Ok([
+        i16::from_le_bytes([raw[0], raw[1]]),
+        i16::from_le_bytes([raw[2], raw[3]]),
+        i16::from_le_bytes([raw[4], raw[5]]),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use embedded_hal_mock::eh0::{
+        i2c::{Mock, Transaction},
+        MockError,
+    };
+    use std::{io::ErrorKind, vec, vec::Vec};
+
+    fn init_sequence(config: Config, ag: u8, m: u8) -> Vec<Transaction> {
+        vec![
+            Transaction::write_read(AG_ADDRESS, vec![WHO_AM_I], vec![AG_ID]),
+            Transaction::write_read(M_ADDRESS, vec![WHO_AM_I], vec![M_ID]),
+            Transaction::write(AG_ADDRESS, vec![CTRL_REG8, 0x44]),
+            Transaction::write(AG_ADDRESS, vec![CTRL_REG1_G, ag]),
+            Transaction::write(AG_ADDRESS, vec![CTRL_REG6_XL, config.accel.bits() << 3 | 0x60]),
+            Transaction::write(M_ADDRESS, vec![CTRL_REG1_M, 0xFC]),
+            Transaction::write(M_ADDRESS, vec![CTRL_REG2_M, m]),
+            Transaction::write(M_ADDRESS, vec![CTRL_REG3_M, 0x00]),
+            Transaction::write(M_ADDRESS, vec![CTRL_REG4_M, 0x0C]),
+        ]
+    }
+
+    fn read_sequence(temperature: i16, accel: [i16; 3], gyro: [i16; 3], mag: [i16; 3]) -> Vec<Transaction> {
+        let bytes = |v: [i16; 3]| v.iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<_>>();
+        vec![
+            Transaction::write_read(AG_ADDRESS, vec![OUT_TEMP_L], temperature.to_le_bytes().to_vec()),
+            Transaction::write_read(AG_ADDRESS, vec![OUT_X_L_XL], bytes(accel)),
+            Transaction::write_read(AG_ADDRESS, vec![OUT_X_L_G], bytes(gyro)),
+            Transaction::write_read(M_ADDRESS, vec![OUT_X_L_M | AUTO_INCREMENT], bytes(mag)),
+        ]
+    }
+
+    #[test]
+    fn init() {
+        // 119 Hz, 500 dps, ±4 g, ±4 gauss, values from the LSM9DS1 datasheet
+        let config = Config::default();
+        let mut i2c = Mock::new(&init_sequence(config, 0x68, 0x00));
+        Lsm9ds1::new(config, Calibration::default()).init(&mut i2c).unwrap();
+        i2c.done();
+
+        let config = Config {
+            accel: AccelRange::G16,
+            gyro: GyroRange::Dps2000,
+            mag: MagRange::Gauss16,
+        };
+        let mut i2c = Mock::new(&init_sequence(config, 0x78, 0x60));
+        Lsm9ds1::new(config, Calibration::default()).init(&mut i2c).unwrap();
+        i2c.done();
+    }
+
+    #[test]
+    fn wrong_device() {
+        let mut i2c = Mock::new(&[
+            Transaction::write_read(AG_ADDRESS, vec![WHO_AM_I], vec![AG_ID]),
+            Transaction::write_read(M_ADDRESS, vec![WHO_AM_I], vec![0x00]),
+        ]);
+        let result = Lsm9ds1::new(Config::default(), Calibration::default()).init(&mut i2c);
+        assert_eq!(result, Err(Error::WrongDevice { address: M_ADDRESS, id: 0 }));
+        i2c.done();
+    }
+
+    #[test]
+    fn scaling() {
+        let g = STANDARD_GRAVITY;
+        let dps = DEG_TO_RAD;
+        // raw readings and what they are with the sensitivities of the datasheet
+        let cases = [
+            (Config::default(), [8197, -4098, 0], [57, 0, -2000], [7143, 0, -7143]),
+            (
+                Config {
+                    accel: AccelRange::G16,
+                    gyro: GyroRange::Dps245,
+                    mag: MagRange::Gauss12,
+                },
+                [1366, 0, -1366],
+                [0, 4000, 0],
+                [0, 2326, 0],
+            ),
+        ];
+        let expected = [
+            ([g, -0.5 * g, 0.0], [0.9975 * dps, 0.0, -35.0 * dps], [100.0, 0.0, -100.0]),
+            ([g, 0.0, -g], [0.0, 35.0 * dps, 0.0], [0.0, 100.0, 0.0]),
+        ];
+        for ((config, accel, gyro, mag), (ex_accel, ex_gyro, ex_mag)) in cases.into_iter().zip(expected) {
+            let mut i2c = Mock::new(&read_sequence(-400, accel, gyro, mag));
+            let reading = Lsm9ds1::new(config, Calibration::default()).read(&mut i2c).unwrap();
+            i2c.done();
+            assert!(reading.temperature.abs() < 1e-4);
+            for axis in 0..3 {
+                assert!((reading.accel[axis] - ex_accel[axis]).abs() < 0.01, "{reading:?}");
+                assert!((reading.gyro[axis] - ex_gyro[axis]).abs() < 1e-3, "{reading:?}");
+                assert!((reading.mag[axis] - ex_mag[axis]).abs() < 0.1, "{reading:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn calibration() {
+        let calibration = Calibration {
+            accel_offset: [0.1, 0.0, 0.0],
+            accel_scale: [1.0, 1.0, 0.5],
+            gyro_bias: [0.0, 0.01, 0.0],
+            mag_offset: [10.0, 0.0, 0.0],
+            mag_scale: [1.0, 2.0, 1.0],
+        };
+        let imu = Lsm9ds1::new(Config::default(), calibration);
+        let raw = Raw {
+            accel: [0, 0, 8197],
+            gyro: [0, 0, 0],
+            mag: [0, 714, 0],
+            temperature: 16,
+        };
+        let reading = imu.convert(&raw);
+        assert!((reading.accel[0] + 0.1).abs() < 1e-4);
+        assert!((reading.accel[2] - STANDARD_GRAVITY / 2.0).abs() < 0.01);
+        assert!((reading.gyro[1] + 0.01).abs() < 1e-6);
+        assert!((reading.mag[0] + 10.0).abs() < 1e-4);
+        assert!((reading.mag[1] - 20.0).abs() < 0.01);
+        assert!((reading.temperature - 26.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn gyro_bias() {
+        let mut expectations = Vec::new();
+        for sample in [[10, -20, 4], [12, -18, 4]] {
+            expectations.push(Transaction::write_read(AG_ADDRESS, vec![STATUS_REG], vec![0b01]));
+            expectations.push(Transaction::write_read(AG_ADDRESS, vec![STATUS_REG], vec![0b11]));
+            let bytes = sample.iter().flat_map(|v: &i16| v.to_le_bytes()).collect();
+            expectations.push(Transaction::write_read(AG_ADDRESS, vec![OUT_X_L_G], bytes));
+        }
+        let mut i2c = Mock::new(&expectations);
+        let mut imu = Lsm9ds1::new(Config::default(), Calibration::default());
+        let bias = imu.calibrate_gyro(&mut i2c, 2).unwrap();
+        i2c.done();
+        let scale = GyroRange::Dps500.scale();
+        assert_eq!(bias, [11.0 * scale, -19.0 * scale, 4.0 * scale]);
+        assert_eq!(imu.calibration().gyro_bias, bias);
+    }
+
+    #[test]
+    fn carries_on_after_a_bus_error() {
+        // a NACK on the magnetometer and arbitration lost on the gyro: the error comes out, the
+        // bus recovers underneath and the next calls start from the beginning
+        let nack = MockError::Io(ErrorKind::NotFound);
+        let lost = MockError::Io(ErrorKind::Interrupted);
+        let mut expectations = vec![
+            Transaction::write_read(AG_ADDRESS, vec![WHO_AM_I], vec![AG_ID]),
+            Transaction::write_read(M_ADDRESS, vec![WHO_AM_I], vec![0]).with_error(nack.clone()),
+        ];
+        expectations.extend(init_sequence(Config::default(), 0x68, 0x00));
+        let mut read = read_sequence(0, [0; 3], [0; 3], [0; 3]);
+        read[2] = Transaction::write_read(AG_ADDRESS, vec![OUT_X_L_G], vec![0; 6]).with_error(lost.clone());
+        expectations.extend(read.into_iter().take(3));
+        expectations.extend(read_sequence(16, [0, 0, 8197], [0; 3], [0; 3]));
+
+        let mut i2c = Mock::new(&expectations);
+        let mut imu = Lsm9ds1::new(Config::default(), Calibration::default());
+        assert_eq!(imu.init(&mut i2c), Err(Error::I2c(nack)));
+        imu.init(&mut i2c).unwrap();
+        assert_eq!(imu.read(&mut i2c), Err(Error::I2c(lost)));
+        let reading = imu.read(&mut i2c).unwrap();
+        assert!((reading.accel[2] - STANDARD_GRAVITY).abs() < 0.01);
+        i2c.done();
+    }
+}
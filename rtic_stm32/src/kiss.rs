@@ -0,0 +1,326 @@
+//! KISS framing between the board and a TNC
+//!
+//! A KISS TNC takes AX.25 frames (without flags and FCS, see `ax25`) over a serial line and
+//! does the HDLC and the modem itself. Each frame goes between FEND bytes, with a type byte
+//! in front: the TNC port in the high nibble and the command in the low one. FEND and FESC
+//! inside the frame are escaped.
+//!
+//! `encode` and `send` go towards the TNC, `Decoder` takes bytes from it one at a time.
+
+use embedded_hal::blocking::serial::Write;
+
+pub const FEND: u8 = 0xC0;
+pub const FESC: u8 = 0xDB;
+pub const TFEND: u8 = 0xDC;
+pub const TFESC: u8 = 0xDD;
+
+/// Longest frame the decoder takes, after unescaping and without the type byte
+pub const MAX_FRAME_LEN: usize = crate::ax25::MAX_FRAME_LEN;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+    BufferTooSmall,
+    /// Longer than `MAX_FRAME_LEN`
+    TooLong,
+    /// FESC followed by something other than TFEND or TFESC
+    Escape,
+    /// The port is above 15
+    Port,
+    /// The serial line failed
+    Serial,
+}
+
+/// The low nibble of the type byte
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Command {
+    Data,
+    /// Key-up delay in 10 ms units
+    TxDelay,
+    /// p-persistence, (p + 1) / 256
+    Persistence,
+    /// In 10 ms units
+    SlotTime,
+    TxTail,
+    FullDuplex,
+    SetHardware,
+    /// Leave KISS mode, sent with the type byte 0xFF
+    Return,
+    Unknown(u8),
+}
+
+impl Command {
+    fn from_type(byte: u8) -> Self {
+        if byte == 0xFF {
+            return Command::Return;
+        }
+        match byte & 0x0F {
+            0 => Command::Data,
+            1 => Command::TxDelay,
+            2 => Command::Persistence,
+            3 => Command::SlotTime,
+            4 => Command::TxTail,
+            5 => Command::FullDuplex,
+            6 => Command::SetHardware,
+            other => Command::Unknown(other),
+        }
+    }
+
+    fn to_type(self, port: u8) -> u8 {
+        let command = match self {
+            Command::Data => 0,
+            Command::TxDelay => 1,
+            Command::Persistence => 2,
+            Command::SlotTime => 3,
+            Command::TxTail => 4,
+            Command::FullDuplex => 5,
+            Command::SetHardware => 6,
+            Command::Return => return 0xFF,
+            Command::Unknown(command) => command & 0x0F,
+        };
+        port << 4 | command
+    }
+}
+
+/// A frame from the TNC
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Frame<'a> {
+    pub port: u8,
+    pub command: Command,
+    pub data: &'a [u8],
+}
+
+/// Escape one byte into at most two
+fn escape(byte: u8) -> ([u8; 2], usize) {
+    match byte {
+        FEND => ([FESC, TFEND], 2),
+        FESC => ([FESC, TFESC], 2),
+        byte => ([byte, 0], 1),
+    }
+}
+
+/// Wrap `data` into a KISS frame in `out`, returns the length. `out` needs two bytes per data
+/// byte plus three to be sure.
+pub fn encode(port: u8, command: Command, data: &[u8], out: &mut [u8]) -> Result<usize, Error> {
+    if port > 15 {
+        return Err(Error::Port);
+    }
+    let mut len = 0;
+    let mut put = |bytes: &[u8]| {
+        let end = len + bytes.len();
+        out.get_mut(len..end).ok_or(Error::BufferTooSmall)?.copy_from_slice(bytes);
+        len = end;
+        Ok(())
+    };
+    put(&[FEND])?;
+    let (escaped, n) = escape(command.to_type(port));
+    put(&escaped[..n])?;
+    for &byte in data {
+        let (escaped, n) = escape(byte);
+        put(&escaped[..n])?;
+    }
+    put(&[FEND])?;
+    Ok(len)
+}
+
+/// Send a data frame for port 0 straight to the TNC, nothing is buffered
+pub fn send<W: Write<u8>>(serial: &mut W, frame: &[u8]) -> Result<(), Error> {
+    let mut write = |bytes: &[u8]| serial.bwrite_all(bytes).map_err(|_| Error::Serial);
+    write(&[FEND, Command::Data.to_type(0)])?;
+    // runs of bytes that need no escaping go out in one piece
+    for run in frame.split_inclusive(|&byte| byte == FEND || byte == FESC) {
+        match run.split_last() {
+            Some((&last, head)) if last == FEND || last == FESC => {
+                write(head)?;
+                write(&escape(last).0)?;
+            }
+            _ => write(run)?,
+        }
+    }
+    write(&[FEND])?;
+    serial.bflush().map_err(|_| Error::Serial)
+}
+
+/// Puts frames back together from the bytes a TNC sends
+pub struct Decoder {
+    buffer: [u8; MAX_FRAME_LEN + 1],
+    len: usize,
+    escaped: bool,
+    error: Option<Error>,
+}
+
+impl Decoder {
+    pub const fn new() -> Self {
+        Decoder {
+            buffer: [0; MAX_FRAME_LEN + 1],
+            len: 0,
+            escaped: false,
+            error: None,
+        }
+    }
+
+    /// Feed one byte, gives the frame at its closing FEND. Empty frames, the back to back
+    /// FENDs some TNCs send, are skipped.
+    pub fn push(&mut self, byte: u8) -> Option<Result<Frame<'_>, Error>> {
+        if byte == FEND {
+            let (len, error) = (self.len, self.error);
+            self.len = 0;
+            self.escaped = false;
+            self.error = None;
+            return match error {
+                Some(error) => Some(Err(error)),
+                None if len == 0 => None,
+                None => Some(Ok(Frame {
+                    port: self.buffer[0] >> 4,
+                    command: Command::from_type(self.buffer[0]),
+                    data: &self.buffer[1..len],
+                })),
+            };
+        }
+        if self.error.is_some() {
+            return None;
+        }
+
+        let byte = match (self.escaped, byte) {
+            (false, FESC) => {
+                self.escaped = true;
+                return None;
+            }
+            (false, byte) => byte,
+            (true, TFEND) => FEND,
+            (true, TFESC) => FESC,
+            (true, _) => {
+                self.error = Some(Error::Escape);
+                return None;
+            }
+        };
+        self.escaped = false;
+        match self.buffer.get_mut(self.len) {
+            Some(slot) => {
+                *slot = byte;
+                self.len += 1;
+            }
+            None => self.error = Some(Error::TooLong),
+        }
+        None
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use std::{vec, vec::Vec};
+
+    struct Serial(Vec<u8>);
+
+    impl Write<u8> for Serial {
+        type Error = ();
+
+        fn bwrite_all(&mut self, bytes: &[u8]) -> Result<(), ()> {
+            self.0.extend_from_slice(bytes);
+            Ok(())
+        }
+
+        fn bflush(&mut self) -> Result<(), ()> {
+            Ok(())
+        }
+    }
+
+    type Decoded = Result<(u8, Command, Vec<u8>), Error>;
+
+    fn decode(decoder: &mut Decoder, bytes: &[u8]) -> Vec<Decoded> {
+        bytes
+            .iter()
+            .filter_map(|&byte| {
+                let frame = decoder.push(byte)?;
+                Some(frame.map(|frame| (frame.port, frame.command, frame.data.to_vec())))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn escaping() {
+        let data = [0x01, FEND, 0x02, FESC, FESC, FEND, TFEND, TFESC];
+        let escaped = [
+            FEND, 0x00, 0x01, FESC, TFEND, 0x02, FESC, TFESC, FESC, TFESC, FESC, TFEND, TFEND, TFESC, FEND,
+        ];
+        let mut out = [0; 2 * 8 + 3];
+        let len = encode(0, Command::Data, &data, &mut out).unwrap();
+        assert_eq!(out[..len], escaped);
+
+        let mut serial = Serial(Vec::new());
+        send(&mut serial, &data).unwrap();
+        assert_eq!(serial.0, escaped);
+        send(&mut serial, b"plain").unwrap();
+        assert_eq!(serial.0[len..], *b"\xc0\x00plain\xc0");
+        send(&mut serial, &[FEND]).unwrap();
+        assert_eq!(serial.0[len + 8..], [FEND, 0x00, FESC, TFEND, FEND]);
+
+        let frames = decode(&mut Decoder::new(), &serial.0);
+        assert_eq!(
+            frames,
+            vec![
+                Ok((0, Command::Data, data.to_vec())),
+                Ok((0, Command::Data, b"plain".to_vec())),
+                Ok((0, Command::Data, vec![FEND])),
+            ]
+        );
+    }
+
+    #[test]
+    fn commands() {
+        let mut out = [0; 8];
+        assert_eq!(encode(3, Command::TxDelay, &[50], &mut out), Ok(4));
+        assert_eq!(out[..4], [FEND, 0x31, 50, FEND]);
+        assert_eq!(encode(0, Command::Return, &[], &mut out), Ok(3));
+        assert_eq!(out[..3], [FEND, 0xFF, FEND]);
+        // port 12 with data is 0xC0, the type byte is escaped too
+        assert_eq!(encode(12, Command::Data, &[], &mut out), Ok(4));
+        assert_eq!(out[..4], [FEND, FESC, TFEND, FEND]);
+        assert_eq!(encode(16, Command::Data, &[], &mut out), Err(Error::Port));
+        assert_eq!(encode(0, Command::Data, &[FEND; 3], &mut out), Err(Error::BufferTooSmall));
+
+        let mut decoder = Decoder::new();
+        let frames = decode(&mut decoder, &[FEND, FEND, 0x31, 50, FEND, FESC, TFEND, FEND, 0xFF, FEND, 0x2A, FEND]);
+        assert_eq!(
+            frames,
+            vec![
+                Ok((3, Command::TxDelay, vec![50])),
+                Ok((12, Command::Data, vec![])),
+                Ok((15, Command::Return, vec![])),
+                Ok((2, Command::Unknown(10), vec![])),
+            ]
+        );
+    }
+
+    #[test]
+    fn bad_escape() {
+        let mut decoder = Decoder::new();
+        let frames = decode(&mut decoder, &[FEND, 0x00, 0x01, FESC, 0x02, 0x03, FEND, 0x00, 0x04, FEND]);
+        assert_eq!(frames, vec![Err(Error::Escape), Ok((0, Command::Data, vec![0x04]))]);
+    }
+
+    #[test]
+    fn frame_longer_than_the_buffer() {
+        let mut decoder = Decoder::new();
+        for len in [MAX_FRAME_LEN, MAX_FRAME_LEN + 1] {
+            let data = vec![FEND; len];
+            let mut out = vec![0; 2 * len + 3];
+            let n = encode(0, Command::Data, &data, &mut out).unwrap();
+            out.truncate(n);
+            out.extend_from_slice(&[0x00, 0x42, FEND]);
+            let expected = if len == MAX_FRAME_LEN {
+                Ok((0, Command::Data, data))
+            } else {
+                Err(Error::TooLong)
+            };
+            assert_eq!(decode(&mut decoder, &out), vec![expected, Ok((0, Command::Data, vec![0x42]))]);
+        }
+    }
+}
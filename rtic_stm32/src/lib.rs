@@ -1,6 +1,45 @@
 #![no_std]
 
-use defmt_rtt as _; // global logger
+#[cfg(test)]
+extern crate std; // for logtool's decoder below
+
+#[cfg(target_os = "none")]
 use panic_probe as _; // panic handler
+#[cfg(target_os = "none")]
 use stm32f4xx_hal as _; // memory layout
 use fugit as _; // time abstractions
+
+pub mod adc; // housekeeping: MCU temperature, VDDA and external channels
+pub mod ax25; // AX.25 UI frames and HDLC framing for the radio link
+pub mod boot; // firmware slots and update state, shared with the bootloader
+pub mod channel; // queues from interrupt handlers to tasks, with overflow counters
+pub mod config; // persistent key/value configuration
+pub mod crc; // CRC-32 and the AX.25 frame check sequence
+pub mod fec; // Reed-Solomon, randomizer and convolutional coding for the downlink
+pub mod flash; // internal flash sectors
+pub mod header; // layout of the firmware image header, shared with tools/imagetool
+#[cfg(not(target_os = "none"))]
+mod host; // critical sections when running on the host
+pub mod i2c; // I2C1 bus with timeouts and bus recovery
+pub mod image; // header of the running firmware and the startup self-check
+pub mod imu; // LSM9DS1 accelerometer, gyroscope and magnetometer
+pub mod kiss; // KISS framing to a TNC
+pub mod log; // the defmt global logger: levels per module, RTT, UART and flash sinks, rate limits
+pub mod log_uart; // defmt log out of USART2 with DMA, for when no probe is attached
+pub mod logstore; // log kept in flash across resets
+pub mod morse; // Morse code beacon on a GPIO
+pub mod nmea; // NMEA 0183 sentences from GPS receivers
+pub mod periodic; // drift-free periodic releases with overrun counting
+pub mod pwm; // PWM for H-bridges and LEDs, magnetorquer calibration
+pub mod rtc; // real-time clock on the LSE crystal
+pub mod status_led; // heartbeat, error codes and other LED patterns
+pub mod time; // mission time, TAI/UTC and clock drift correction
+pub mod timers; // named one-shot and periodic software timers on one task
+pub mod timesync; // common time base between nodes over CAN
+pub mod trace; // task trace recorder in RAM, dumped over RTT or a UART
+pub mod trace_format; // layout of a trace dump, shared with tools/tracetool
+pub mod uart; // UART receiver with DMA into a ring buffer
+
+#[cfg(test)]
+#[path = "../../tools/logtool/src/rzcobs.rs"]
+mod rzcobs; // what decodes the UART log on the ground, to test `log` against
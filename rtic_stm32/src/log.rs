@@ -0,0 +1,790 @@
+//! Log levels per module that can be changed at run time, and the log routed to RTT, a UART and
+//! flash at the same time
+//!
+//! `DEFMT_LOG` in `.cargo/config.toml` still decides at compile time what can ever be logged.
+//! The macros here check the level of their module on top of that, and the levels are changed
+//! with [`set_level`] or a [`Command`] from the ground:
+//!
+//! ```ignore
+//! use stm32f446_rtic::log::{self, modules};
+//!
+//! log::info!(modules::CAN, "frame {}", id);
+//! ```
+//!
+//! This module is the defmt global logger. Each frame is put together in RAM and then goes to
+//! every sink turned on with [`set_sinks`]: straight into the RTT buffer, and into queues for the
+//! UART and the flash log store that a task empties with [`drain_uart`] and [`drain_flash`]
+//! (`crate::log_uart` sends the UART queue with DMA instead). No sink ever waits; a frame that
+//! does not fit is dropped whole and counted in [`stats`]. RTT does not block even when the host
+//! asks for it, so a probe that stops reading cannot hang the firmware.
+//!
+//! Frames are time stamped in µs since the cycle counter started, at the clock given to
+//! [`set_clock_hz`]. The counter wraps every 2^32 cycles (89 s at 48 MHz), which is only noticed
+//! when something is logged at least that often.
+//!
+//! A [`RateLimit`] keeps a chatty interrupt handler from taking all the time and all the room:
+//!
+//! ```ignore
+//! if let Some(suppressed) = ctx.local.edge_log.allow(now) {
+//!     log::debug!(modules::APP, "edge, {} not logged before it", suppressed);
+//! }
+//! ```
+
+use core::cell::{Cell, RefCell};
+use core::ops::BitOr;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, AtomicUsize, Ordering};
+
+#[cfg(target_os = "none")]
+use cortex_m::peripheral::DWT;
+use critical_section::{CriticalSection, Mutex, RestoreState};
+use embedded_hal::blocking::serial::Write;
+use heapless::{Deque, Vec};
+
+use crate::flash::Flash;
+use crate::logstore::{LogStore, LogStoreError};
+
+/// Number of modules with a level of their own
+pub const MODULES: usize = 16;
+
+/// Level of every module after a reset, everything `DEFMT_LOG` lets through
+pub const DEFAULT_LEVEL: Level = Level::Debug;
+
+/// Longest frame, a longer one is dropped
+pub const MAX_FRAME: usize = 256;
+
+/// Bytes of the RTT buffer and the queues
+const RTT_LEN: usize = 1024;
+const UART_QUEUE_LEN: usize = 1024;
+const FLASH_QUEUE_LEN: usize = 512;
+
+/// Bytes moved out of a queue in one critical section
+const CHUNK: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, defmt::Format)]
+pub enum Level {
+    Trace = 0,
+    Debug = 1,
+    Info = 2,
+    Warn = 3,
+    Error = 4,
+    /// Nothing from the module
+    Off = 5,
+}
+
+impl Level {
+    pub fn from_u8(value: u8) -> Option<Level> {
+        Some(match value {
+            0 => Level::Trace,
+            1 => Level::Debug,
+            2 => Level::Info,
+            3 => Level::Warn,
+            4 => Level::Error,
+            5 => Level::Off,
+            _ => return None,
+        })
+    }
+}
+
+/// Part of the firmware with a level of its own, `0..MODULES`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, defmt::Format)]
+pub struct Module(pub u8);
+
+/// Modules of the board; an app numbers its own from `USER` up
+pub mod modules {
+    use super::Module;
+
+    /// The app itself, anything without a module of its own
+    pub const APP: Module = Module(0);
+    pub const CAN: Module = Module(1);
+    pub const UART: Module = Module(2);
+    pub const RADIO: Module = Module(3);
+    pub const GPS: Module = Module(4);
+    pub const IMU: Module = Module(5);
+    pub const TIME: Module = Module(6);
+    pub const FLASH: Module = Module(7);
+    /// The first module free for an app
+    pub const USER: Module = Module(8);
+}
+
+/// Where the log goes, any of them together: `Sinks::RTT | Sinks::FLASH`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Sinks(pub u8);
+
+impl Sinks {
+    pub const NONE: Sinks = Sinks(0);
+    pub const RTT: Sinks = Sinks(1);
+    pub const UART: Sinks = Sinks(2);
+    pub const FLASH: Sinks = Sinks(4);
+    pub const ALL: Sinks = Sinks(7);
+
+    pub fn contains(self, other: Sinks) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Sinks {
+    type Output = Sinks;
+
+    fn bitor(self, other: Sinks) -> Sinks {
+        Sinks(self.0 | other.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+    /// Module number not below `MODULES`
+    NoSuchModule,
+    /// Level number above `Level::Off`
+    NoSuchLevel,
+    /// Sink bits outside `Sinks::ALL`
+    NoSuchSink,
+    UnknownCommand,
+    /// A known command with too few or too many bytes
+    BadLength,
+}
+
+/// What was lost, per sink
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, defmt::Format)]
+pub struct Stats {
+    /// Frames that did not fit in the RTT buffer
+    pub rtt_dropped: u32,
+    /// Frames that did not fit in the UART queue
+    pub uart_dropped: u32,
+    /// Frames that did not fit in the flash queue
+    pub flash_dropped: u32,
+    /// Frames longer than `MAX_FRAME`, dropped everywhere
+    pub too_long: u32,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const DEFAULT: AtomicU8 = AtomicU8::new(DEFAULT_LEVEL as u8);
+static LEVELS: [AtomicU8; MODULES] = [DEFAULT; MODULES];
+static SINKS: AtomicU8 = AtomicU8::new(Sinks::RTT.0);
+
+/// Core clock for the timestamps, what the examples run at until told otherwise
+static CLOCK_HZ: AtomicU32 = AtomicU32::new(48_000_000);
+
+/// Cycle count at the last timestamp and the wraps before it
+#[cfg(target_os = "none")]
+static CYCLES: Mutex<Cell<(u32, u32)>> = Mutex::new(Cell::new((0, 0)));
+
+/// Called when a frame was queued for the UART
+static UART_WAKE: Mutex<Cell<Option<Wake>>> = Mutex::new(Cell::new(None));
+
+type Wake = fn();
+
+static RTT_DROPPED: AtomicU32 = AtomicU32::new(0);
+static UART_DROPPED: AtomicU32 = AtomicU32::new(0);
+static FLASH_DROPPED: AtomicU32 = AtomicU32::new(0);
+static TOO_LONG: AtomicU32 = AtomicU32::new(0);
+
+type Queue<const N: usize> = Mutex<RefCell<Deque<u8, N>>>;
+
+static UART_QUEUE: Queue<UART_QUEUE_LEN> = Mutex::new(RefCell::new(Deque::new()));
+static FLASH_QUEUE: Queue<FLASH_QUEUE_LEN> = Mutex::new(RefCell::new(Deque::new()));
+
+/// Whether a message at `level` from `module` is logged; a module out of range has the default
+/// level
+pub fn enabled(module: Module, level: Level) -> bool {
+    let threshold = match LEVELS.get(module.0 as usize) {
+        Some(threshold) => threshold.load(Ordering::Relaxed),
+        None => DEFAULT_LEVEL as u8,
+    };
+    level != Level::Off && level as u8 >= threshold
+}
+
+pub fn level(module: Module) -> Option<Level> {
+    LEVELS
+        .get(module.0 as usize)
+        .and_then(|level| Level::from_u8(level.load(Ordering::Relaxed)))
+}
+
+pub fn set_level(module: Module, level: Level) -> Result<(), Error> {
+    let slot = LEVELS.get(module.0 as usize).ok_or(Error::NoSuchModule)?;
+    slot.store(level as u8, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Sets the level of every module
+pub fn set_all(level: Level) {
+    for slot in &LEVELS {
+        slot.store(level as u8, Ordering::Relaxed);
+    }
+}
+
+pub fn sinks() -> Sinks {
+    Sinks(SINKS.load(Ordering::Relaxed))
+}
+
+/// Routes the log to `sinks` from the next frame on
+pub fn set_sinks(sinks: Sinks) {
+    SINKS.store(sinks.0 & Sinks::ALL.0, Ordering::Relaxed);
+}
+
+/// Core clock the timestamps are counted in, `clocks.hclk().to_Hz()`
+pub fn set_clock_hz(hz: u32) {
+    CLOCK_HZ.store(hz.max(1), Ordering::Relaxed);
+}
+
+/// Has `wake` called (with interrupts off) whenever a frame is queued for the UART, for a
+/// sender that is not polled
+pub fn on_uart_data(wake: Wake) {
+    critical_section::with(|cs| UART_WAKE.borrow(cs).set(Some(wake)));
+}
+
+pub fn stats() -> Stats {
+    Stats {
+        rtt_dropped: RTT_DROPPED.load(Ordering::Relaxed),
+        uart_dropped: UART_DROPPED.load(Ordering::Relaxed),
+        flash_dropped: FLASH_DROPPED.load(Ordering::Relaxed),
+        too_long: TOO_LONG.load(Ordering::Relaxed),
+    }
+}
+
+/// A change to the logging, from the data of a telecommand:
+///
+/// ```text
+/// 01 module level   set the level of a module
+/// 02 level          set the level of every module
+/// 03 sinks          route the log to the sinks in the bit mask
+/// ```
+///
+/// Levels are `Level` as a number, 0 (trace) to 5 (off).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Command {
+    SetLevel(Module, Level),
+    SetAll(Level),
+    Route(Sinks),
+}
+
+impl Command {
+    pub fn decode(data: &[u8]) -> Result<Command, Error> {
+        let level = |value: u8| Level::from_u8(value).ok_or(Error::NoSuchLevel);
+        match *data {
+            [0x01, module, value] => {
+                if module as usize >= MODULES {
+                    return Err(Error::NoSuchModule);
+                }
+                Ok(Command::SetLevel(Module(module), level(value)?))
+            }
+            [0x02, value] => Ok(Command::SetAll(level(value)?)),
+            [0x03, sinks] if sinks & !Sinks::ALL.0 != 0 => Err(Error::NoSuchSink),
+            [0x03, sinks] => Ok(Command::Route(Sinks(sinks))),
+            [0x01..=0x03, ..] => Err(Error::BadLength),
+            _ => Err(Error::UnknownCommand),
+        }
+    }
+
+    pub fn apply(self) -> Result<(), Error> {
+        match self {
+            Command::SetLevel(module, level) => set_level(module, level),
+            Command::SetAll(level) => {
+                set_all(level);
+                Ok(())
+            }
+            Command::Route(sinks) => {
+                set_sinks(sinks);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Lets `burst` messages through at once and one every `interval` after that; `interval` is in
+/// the unit of the time given to `allow`
+pub struct RateLimit {
+    interval: u64,
+    burst: u32,
+    /// When the next message is due if they came evenly, and how many were suppressed since
+    /// the last one let through
+    state: Mutex<Cell<(u64, u32)>>,
+}
+
+impl RateLimit {
+    pub const fn new(interval: u64, burst: u32) -> Self {
+        RateLimit {
+            interval,
+            burst,
+            state: Mutex::new(Cell::new((0, 0))),
+        }
+    }
+
+    /// Some with the number of messages suppressed before this one, None when this one has to
+    /// go
+    pub fn allow(&self, now: u64) -> Option<u32> {
+        critical_section::with(|cs| {
+            let state = self.state.borrow(cs);
+            let (due, suppressed) = state.get();
+            let slack = self.interval * self.burst.saturating_sub(1) as u64;
+            if due > now + slack {
+                state.set((due, suppressed.saturating_add(1)));
+                return None;
+            }
+            state.set((due.max(now) + self.interval, 0));
+            Some(suppressed)
+        })
+    }
+
+    /// Messages suppressed since the last one let through
+    pub fn suppressed(&self) -> u32 {
+        critical_section::with(|cs| self.state.borrow(cs).get().1)
+    }
+}
+
+/// Sends what was routed to the UART, from a task; returns the number of bytes sent
+pub fn drain_uart<S: Write<u8>>(serial: &mut S) -> Result<usize, S::Error> {
+    let mut chunk = [0u8; CHUNK];
+    let mut sent = 0;
+    loop {
+        let len = take(&UART_QUEUE, &mut chunk);
+        if len == 0 {
+            break;
+        }
+        serial.bwrite_all(&chunk[..len])?;
+        sent += len;
+    }
+    serial.bflush()?;
+    Ok(sent)
+}
+
+/// Appends what was routed to flash to the log store, from a task at the lowest priority;
+/// returns the number of bytes stored
+pub fn drain_flash<F: Flash, const N: usize>(
+    store: &mut LogStore<F, N>,
+) -> Result<usize, LogStoreError<F::Error>> {
+    let mut chunk = [0u8; CHUNK];
+    let mut stored = 0;
+    loop {
+        let len = take(&FLASH_QUEUE, &mut chunk);
+        if len == 0 {
+            break;
+        }
+        store.append(&chunk[..len])?;
+        stored += len;
+    }
+    Ok(stored)
+}
+
+/// Moves as much of the UART queue as fits into `chunk`, for a sender of its own
+pub fn take_uart(chunk: &mut [u8]) -> usize {
+    take(&UART_QUEUE, chunk)
+}
+
+/// Moves as much of a queue as fits into `chunk`
+fn take<const N: usize>(queue: &Queue<N>, chunk: &mut [u8]) -> usize {
+    critical_section::with(|cs| {
+        let mut queue = queue.borrow(cs).borrow_mut();
+        let mut len = 0;
+        for slot in chunk.iter_mut() {
+            match queue.pop_front() {
+                Some(byte) => *slot = byte,
+                None => break,
+            }
+            len += 1;
+        }
+        len
+    })
+}
+
+/// Puts a whole frame into a queue or nothing of it
+fn enqueue<const N: usize>(cs: CriticalSection, queue: &Queue<N>, frame: &[u8]) -> bool {
+    let mut queue = queue.borrow(cs).borrow_mut();
+    if N - queue.len() < frame.len() {
+        return false;
+    }
+    for &byte in frame {
+        queue.push_back(byte).ok();
+    }
+    true
+}
+
+fn route(cs: CriticalSection, frame: &[u8]) {
+    let sinks = sinks();
+    if sinks.contains(Sinks::RTT) && !rtt::write(frame) {
+        RTT_DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+    if sinks.contains(Sinks::UART) {
+        if enqueue(cs, &UART_QUEUE, frame) {
+            if let Some(wake) = UART_WAKE.borrow(cs).get() {
+                wake();
+            }
+        } else {
+            UART_DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    if sinks.contains(Sinks::FLASH) && !enqueue(cs, &FLASH_QUEUE, frame) {
+        FLASH_DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// µs since the cycle counter started
+#[cfg(target_os = "none")]
+fn uptime_micros() -> u64 {
+    let cycles = critical_section::with(|cs| {
+        let state = CYCLES.borrow(cs);
+        let (last, wraps) = state.get();
+        let now = DWT::cycle_count();
+        let wraps = if now < last { wraps + 1 } else { wraps };
+        state.set((now, wraps));
+        (wraps as u64) << 32 | now as u64
+    });
+    let hz = CLOCK_HZ.load(Ordering::Relaxed) as u64;
+    // in two parts, cycles * 1_000_000 would overflow after four days
+    cycles / hz * 1_000_000 + cycles % hz * 1_000_000 / hz
+}
+
+#[cfg(target_os = "none")]
+defmt::timestamp!("{=u64:us}", uptime_micros());
+// there is no cycle counter to read in the host tests
+#[cfg(not(target_os = "none"))]
+defmt::timestamp!("{=u64:us}", 0);
+
+/// The frame being logged
+struct Frame {
+    encoder: defmt::Encoder,
+    bytes: Vec<u8, MAX_FRAME>,
+    too_long: bool,
+    /// Whether interrupts were on before `acquire`
+    restore: RestoreState,
+}
+
+impl Frame {
+    fn push(bytes: &mut Vec<u8, MAX_FRAME>, too_long: &mut bool, data: &[u8]) {
+        if bytes.extend_from_slice(data).is_err() {
+            *too_long = true;
+        }
+    }
+}
+
+static FRAME: Mutex<RefCell<Frame>> = Mutex::new(RefCell::new(Frame {
+    encoder: defmt::Encoder::new(),
+    bytes: Vec::new(),
+    too_long: false,
+    restore: RestoreState::invalid(),
+}));
+
+static TAKEN: AtomicBool = AtomicBool::new(false);
+
+#[defmt::global_logger]
+struct Logger;
+
+unsafe impl defmt::Logger for Logger {
+    fn acquire() {
+        let restore = unsafe { critical_section::acquire() };
+        if TAKEN.load(Ordering::Relaxed) {
+            panic!("defmt logger taken reentrantly")
+        }
+        TAKEN.store(true, Ordering::Relaxed);
+
+        // interrupts stay off until `release`
+        let cs = unsafe { CriticalSection::new() };
+        let mut frame = FRAME.borrow(cs).borrow_mut();
+        let Frame { encoder, bytes, too_long, .. } = &mut *frame;
+        bytes.clear();
+        *too_long = false;
+        encoder.start_frame(|data| Frame::push(bytes, too_long, data));
+        frame.restore = restore;
+    }
+
+    unsafe fn flush() {}
+
+    unsafe fn release() {
+        let cs = CriticalSection::new();
+        let mut frame = FRAME.borrow(cs).borrow_mut();
+        let Frame { encoder, bytes, too_long, .. } = &mut *frame;
+        encoder.end_frame(|data| Frame::push(bytes, too_long, data));
+        if *too_long {
+            TOO_LONG.fetch_add(1, Ordering::Relaxed);
+        } else {
+            route(cs, bytes);
+        }
+        let restore = frame.restore;
+        drop(frame);
+
+        TAKEN.store(false, Ordering::Relaxed);
+        critical_section::release(restore);
+    }
+
+    unsafe fn write(data: &[u8]) {
+        let cs = CriticalSection::new();
+        let mut frame = FRAME.borrow(cs).borrow_mut();
+        let Frame { encoder, bytes, too_long, .. } = &mut *frame;
+        encoder.write(data, |data| Frame::push(bytes, too_long, data));
+    }
+}
+
+/// The RTT control block with one up channel, what probe-run looks for in RAM
+mod rtt {
+    use super::*;
+
+    #[repr(C)]
+    struct Header {
+        id: [u8; 16],
+        max_up_channels: usize,
+        max_down_channels: usize,
+        up: Channel,
+    }
+
+    #[repr(C)]
+    struct Channel {
+        name: *const u8,
+        buffer: *mut u8,
+        size: usize,
+        write: AtomicUsize,
+        read: AtomicUsize,
+        flags: AtomicUsize,
+    }
+
+    // the pointers never change and the offsets are atomics
+    unsafe impl Sync for Header {}
+
+    static mut BUFFER: [u8; RTT_LEN] = [0; RTT_LEN];
+
+    #[no_mangle]
+    static _SEGGER_RTT: Header = Header {
+        id: *b"SEGGER RTT\0\0\0\0\0\0",
+        max_up_channels: 1,
+        max_down_channels: 0,
+        up: Channel {
+            name: c"defmt".as_ptr().cast(),
+            buffer: ptr::addr_of_mut!(BUFFER) as *mut u8,
+            size: RTT_LEN,
+            write: AtomicUsize::new(0),
+            read: AtomicUsize::new(0),
+            flags: AtomicUsize::new(0),
+        },
+    };
+
+    /// Copies a whole frame into the buffer, false when there is no room for it
+    pub fn write(frame: &[u8]) -> bool {
+        let channel = &_SEGGER_RTT.up;
+        let write = channel.write.load(Ordering::Relaxed);
+        let read = channel.read.load(Ordering::Acquire);
+        // one byte stays free to tell a full buffer from an empty one
+        let free = (read + RTT_LEN - write - 1) % RTT_LEN;
+        if frame.len() > free {
+            return false;
+        }
+        let first = frame.len().min(RTT_LEN - write);
+        // the host only reads between `read` and `write`
+        unsafe {
+            ptr::copy_nonoverlapping(frame.as_ptr(), channel.buffer.add(write), first);
+            ptr::copy_nonoverlapping(frame[first..].as_ptr(), channel.buffer, frame.len() - first);
+        }
+        channel.write.store((write + frame.len()) % RTT_LEN, Ordering::Release);
+        true
+    }
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log {
+    ($level:ident, $defmt:ident, $module:expr, $($arg:tt)+) => {
+        if $crate::log::enabled($module, $crate::log::Level::$level) {
+            defmt::$defmt!($($arg)+);
+        }
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log_trace {
+    ($module:expr, $($arg:tt)+) => { $crate::__log!(Trace, trace, $module, $($arg)+) };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log_debug {
+    ($module:expr, $($arg:tt)+) => { $crate::__log!(Debug, debug, $module, $($arg)+) };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log_info {
+    ($module:expr, $($arg:tt)+) => { $crate::__log!(Info, info, $module, $($arg)+) };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log_warn {
+    ($module:expr, $($arg:tt)+) => { $crate::__log!(Warn, warn, $module, $($arg)+) };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log_error {
+    ($module:expr, $($arg:tt)+) => { $crate::__log!(Error, error, $module, $($arg)+) };
+}
+
+pub use crate::__log_debug as debug;
+pub use crate::__log_error as error;
+pub use crate::__log_info as info;
+pub use crate::__log_trace as trace;
+pub use crate::__log_warn as warn;
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::rzcobs::{self, Frames};
+    use std::sync::{Mutex as StdMutex, MutexGuard};
+    use std::vec::Vec;
+
+    /// Held by the tests that change the levels and the sinks, they are globals
+    fn globals() -> MutexGuard<'static, ()> {
+        static GLOBALS: StdMutex<()> = StdMutex::new(());
+        GLOBALS.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    #[test]
+    fn burst_then_rate() {
+        let limit = RateLimit::new(100, 3);
+        let allowed: Vec<_> = (0..6).map(|_| limit.allow(1000)).collect();
+        assert_eq!(allowed, [Some(0), Some(0), Some(0), None, None, None]);
+        // the next is due at 1300, 1100 with the burst of three
+        assert_eq!(limit.allow(1050), None);
+        assert_eq!(limit.suppressed(), 4);
+        assert_eq!(limit.allow(1100), Some(4));
+        assert_eq!(limit.suppressed(), 0);
+        assert_eq!(limit.allow(1100), None);
+        // after a quiet spell the whole burst is there again
+        let allowed: Vec<_> = (0..4).map(|_| limit.allow(10_000)).collect();
+        assert_eq!(allowed, [Some(1), Some(0), Some(0), None]);
+    }
+
+    #[test]
+    fn one_every_interval() {
+        // no burst, and a burst of 0 is the same
+        for burst in [1, 0] {
+            let limit = RateLimit::new(100, burst);
+            assert_eq!(limit.allow(0), Some(0));
+            assert_eq!(limit.allow(50), None);
+            assert_eq!(limit.allow(99), None);
+            assert_eq!(limit.allow(100), Some(2));
+            // a late one starts the interval over
+            assert_eq!(limit.allow(250), Some(0));
+            assert_eq!(limit.allow(349), None);
+            assert_eq!(limit.allow(350), Some(1));
+        }
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(Command::decode(&[0x01, 3, 2]), Ok(Command::SetLevel(modules::RADIO, Level::Info)));
+        assert_eq!(Command::decode(&[0x01, 15, 5]), Ok(Command::SetLevel(Module(15), Level::Off)));
+        assert_eq!(Command::decode(&[0x02, 0]), Ok(Command::SetAll(Level::Trace)));
+        assert_eq!(Command::decode(&[0x03, 0]), Ok(Command::Route(Sinks::NONE)));
+        assert_eq!(Command::decode(&[0x03, 5]), Ok(Command::Route(Sinks::RTT | Sinks::FLASH)));
+
+        assert_eq!(Command::decode(&[0x01, 16, 2]), Err(Error::NoSuchModule));
+        assert_eq!(Command::decode(&[0x01, 0, 6]), Err(Error::NoSuchLevel));
+        assert_eq!(Command::decode(&[0x02, 0xFF]), Err(Error::NoSuchLevel));
+        assert_eq!(Command::decode(&[0x03, 8]), Err(Error::NoSuchSink));
+        assert_eq!(Command::decode(&[0x01, 0]), Err(Error::BadLength));
+        assert_eq!(Command::decode(&[0x02]), Err(Error::BadLength));
+        assert_eq!(Command::decode(&[0x03, 1, 0]), Err(Error::BadLength));
+        assert_eq!(Command::decode(&[0x04, 1]), Err(Error::UnknownCommand));
+        assert_eq!(Command::decode(&[0x00]), Err(Error::UnknownCommand));
+        assert_eq!(Command::decode(&[]), Err(Error::UnknownCommand));
+    }
+
+    #[test]
+    fn frames_through_a_queue() {
+        static QUEUE: Queue<12> = Mutex::new(RefCell::new(Deque::new()));
+        let frames: [&[u8]; 5] = [&[1, 2, 3, 0], &[4; 10], &[5, 6, 0], &[7, 8, 9, 0], &[10, 11, 0]];
+        // a frame goes in whole or not at all
+        let queued: Vec<bool> = frames
+            .iter()
+            .map(|frame| critical_section::with(|cs| enqueue(cs, &QUEUE, frame)))
+            .collect();
+        assert_eq!(queued, [true, false, true, true, false]);
+
+        // taken out a DMA buffer at a time, in order
+        let mut chunk = [0; 5];
+        let mut sent = Vec::new();
+        let mut lens = Vec::new();
+        loop {
+            let len = take(&QUEUE, &mut chunk);
+            if len == 0 {
+                break;
+            }
+            lens.push(len);
+            sent.extend_from_slice(&chunk[..len]);
+        }
+        assert_eq!(lens, [5, 5, 1]);
+        assert_eq!(sent, [1, 2, 3, 0, 5, 6, 0, 7, 8, 9, 0]);
+    }
+
+    #[test]
+    fn apply() {
+        let _globals = globals();
+        let sinks_before = sinks();
+        let last = Module(MODULES as u8 - 1);
+        Command::decode(&[0x01, last.0, 4]).unwrap().apply().unwrap();
+        assert_eq!(level(last), Some(Level::Error));
+        assert!(enabled(last, Level::Error));
+        assert!(!enabled(last, Level::Warn));
+        assert!(!enabled(last, Level::Off));
+        // a module out of range logs at the default level
+        assert!(enabled(Module(200), DEFAULT_LEVEL));
+        assert_eq!(Command::SetLevel(Module(200), Level::Info).apply(), Err(Error::NoSuchModule));
+
+        Command::decode(&[0x02, 5]).unwrap().apply().unwrap();
+        assert!((0..MODULES as u8).all(|module| level(Module(module)) == Some(Level::Off)));
+        Command::decode(&[0x03, 6]).unwrap().apply().unwrap();
+        assert_eq!(sinks(), Sinks::UART | Sinks::FLASH);
+
+        set_all(DEFAULT_LEVEL);
+        set_sinks(sinks_before);
+    }
+
+    /// The UART log the way it goes out, a DMA buffer at a time, through logtool's decoder
+    #[test]
+    fn uart_into_logtool() {
+        // in every frame of this test, to tell them from those of tests running alongside
+        const MARKER: u32 = 0x10C7_00AB;
+        let _globals = globals();
+        let sinks_before = sinks();
+        set_sinks(Sinks::UART);
+        while take_uart(&mut [0; 64]) > 0 {}
+
+        // runs of zeros and runs longer than a code takes, the most of a frame
+        let long: Vec<u8> = (0..200).map(|i| if i % 150 < 12 { 0 } else { i as u8 | 1 }).collect();
+        error!(modules::USER, "{=u32}", MARKER);
+        error!(modules::USER, "{=u32} {=u8}", MARKER, 0);
+        error!(modules::USER, "{=u32} {=[u8]}", MARKER, &long[..]);
+        set_sinks(sinks_before);
+
+        let mut frames = Frames::new();
+        let mut chunk = [0; 64];
+        let mut found = Vec::new();
+        loop {
+            let len = take_uart(&mut chunk);
+            if len == 0 {
+                break;
+            }
+            for &byte in &chunk[..len] {
+                if let Some(frame) = frames.push(byte) {
+                    found.push(rzcobs::decode(&frame).unwrap());
+                }
+            }
+        }
+        // the index of the format string, 0 µs, the marker and then the rest
+        found.retain(|frame| frame.len() >= 14 && frame[10..14] == MARKER.to_le_bytes());
+        let args = [
+            Vec::new(),
+            std::vec![0],
+            [&(long.len() as u32).to_le_bytes()[..], &long].concat(),
+        ];
+        assert_eq!(found.len(), args.len());
+        for (frame, args) in found.iter().zip(&args) {
+            assert_eq!(frame[2..10], [0; 8]);
+            let (data, padding) = frame[14..].split_at(args.len());
+            assert_eq!(data, &args[..]);
+            // what the last code adds, defmt knows where a frame ends
+            assert!(padding.len() <= 7 && padding.iter().all(|&byte| byte == 0));
+        }
+    }
+}
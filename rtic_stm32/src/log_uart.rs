@@ -0,0 +1,148 @@
+//! defmt log out of a UART with DMA, for when no debug probe is attached
+//!
+//! USART2 sends on PA2, which on the Nucleo goes to the ST-LINK virtual COM port, so the log
+//! needs only the USB cable and no probe software. DMA1 stream 6 channel 4 copies the log queue
+//! of `crate::log` out of `buffer` a piece at a time. Turn the sink on with
+//! `log::set_sinks(Sinks::RTT | Sinks::UART)` and bind DMA1_STREAM6 to a task that calls
+//! [`LogUart::on_interrupt`]; a frame queued by the logger pends that interrupt, so nothing has
+//! to poll. `tools/logtool` decodes what comes out.
+//!
+//! The bytes are the defmt frames as they are encoded, rzCOBS with a 0x00 after each one, so a
+//! receiver that starts in the middle finds the next frame at the next zero. What does not fit
+//! in the queue while the line is busy is dropped whole and counted in `log::stats`; at 115200
+//! baud that is about 11 bytes a millisecond.
+//!
+//! Only stream 6 of DMA1 is touched, the other streams stay free for the HAL.
+
+use core::sync::atomic::{compiler_fence, Ordering};
+use cortex_m::peripheral::NVIC;
+use stm32f4xx_hal::{
+    gpio::{gpioa::PA2, Alternate},
+    pac::{Interrupt, DMA1, RCC, USART2},
+};
+
+use crate::log;
+
+pub type Tx = PA2<Alternate<7>>;
+
+/// What the DMA sends from, it reads it after `new` returns so it has to live forever
+pub type Buffer = &'static mut [u8];
+
+// Register bits
+const CR1_TE: u32 = 1 << 3;
+const CR1_UE: u32 = 1 << 13;
+const CR3_DMAT: u32 = 1 << 7;
+const DMA_EN: u32 = 1 << 0;
+const DMA_TEIE: u32 = 1 << 2;
+const DMA_TCIE: u32 = 1 << 4;
+const DMA_TO_PERIPHERAL: u32 = 1 << 6;
+const DMA_MINC: u32 = 1 << 10;
+const DMA_CHANNEL_4: u32 = 4 << 25;
+const STREAM: usize = 6;
+const STREAM_FLAGS: u32 = 0b11_1101 << 16; // FEIF6, DMEIF6, TEIF6, HTIF6, TCIF6
+const STREAM_TEIF: u32 = 1 << 19;
+const RCC_USART2: u32 = 1 << 17;
+const RCC_DMA1: u32 = 1 << 21;
+
+/// Counters for the health telemetry
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, defmt::Format)]
+pub struct Stats {
+    pub bytes: u32,
+    pub dma_errors: u32,
+}
+
+/// USART2 sending the log
+pub struct LogUart {
+    _usart: USART2,
+    _pin: Tx,
+    buffer: *mut u8,
+    len: usize,
+    stats: Stats,
+}
+
+// Safety: the buffer pointer comes from a `&'static mut` that this struct owns
+unsafe impl Send for LogUart {}
+
+impl LogUart {
+    pub fn new(usart: USART2, pin: Tx, baud: u32, pclk1_hz: u32, buffer: Buffer) -> Self {
+        // Safety: only the USART2 and DMA1 enable bits are touched
+        let rcc = unsafe { &*RCC::ptr() };
+        rcc.apb1enr.modify(|r, w| unsafe { w.bits(r.bits() | RCC_USART2) });
+        rcc.ahb1enr.modify(|r, w| unsafe { w.bits(r.bits() | RCC_DMA1) });
+
+        let dma = dma1();
+        let stream = &dma.st[STREAM];
+        stream.cr.write(|w| unsafe { w.bits(0) });
+        while stream.cr.read().bits() & DMA_EN != 0 {}
+        dma.hifcr.write(|w| unsafe { w.bits(STREAM_FLAGS) });
+        stream.par.write(|w| unsafe { w.bits(&usart.dr as *const _ as u32) });
+        stream.m0ar.write(|w| unsafe { w.bits(buffer.as_ptr() as u32) });
+
+        // 16x oversampling, the divider with its 4 bits of fraction is just pclk / baud
+        usart.cr1.write(|w| unsafe { w.bits(0) });
+        usart.brr.write(|w| unsafe { w.bits((pclk1_hz + baud / 2) / baud) });
+        usart.cr2.write(|w| unsafe { w.bits(0) });
+        usart.cr3.write(|w| unsafe { w.bits(CR3_DMAT) });
+        usart.cr1.write(|w| unsafe { w.bits(CR1_UE | CR1_TE) });
+
+        log::on_uart_data(wake);
+        // whatever was logged before now
+        wake();
+
+        LogUart {
+            _usart: usart,
+            _pin: pin,
+            buffer: buffer.as_mut_ptr(),
+            len: buffer.len(),
+            stats: Stats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    /// Clear the flags and send what is queued, call from the DMA1_STREAM6 interrupt
+    pub fn on_interrupt(&mut self) {
+        let dma = dma1();
+        let flags = dma.hisr.read().bits() & STREAM_FLAGS;
+        dma.hifcr.write(|w| unsafe { w.bits(flags) });
+        if flags & STREAM_TEIF != 0 {
+            // the stream stopped, what it was sending is lost
+            self.stats.dma_errors += 1;
+        }
+        self.start();
+    }
+
+    /// Send the next piece of the queue unless a transfer is still going
+    fn start(&mut self) {
+        let stream = &dma1().st[STREAM];
+        if stream.cr.read().bits() & DMA_EN != 0 {
+            return;
+        }
+        // Safety: the stream is off, so nothing else reads the buffer
+        let buffer = unsafe { core::slice::from_raw_parts_mut(self.buffer, self.len) };
+        let len = log::take_uart(buffer);
+        if len == 0 {
+            return;
+        }
+        // the bytes are in memory before the DMA reads them
+        compiler_fence(Ordering::Release);
+        stream.ndtr.write(|w| unsafe { w.bits(len as u32) });
+        // memory to peripheral, bytes
+        stream.cr.write(|w| unsafe {
+            w.bits(DMA_CHANNEL_4 | DMA_MINC | DMA_TO_PERIPHERAL | DMA_TCIE | DMA_TEIE | DMA_EN)
+        });
+        self.stats.bytes += len as u32;
+    }
+}
+
+/// Called by the logger with interrupts off, the rest happens in the interrupt
+fn wake() {
+    NVIC::pend(Interrupt::DMA1_STREAM6);
+}
+
+fn dma1() -> &'static stm32f4xx_hal::pac::dma1::RegisterBlock {
+    // Safety: only stream 6 and its flags are touched, which this module owns
+    unsafe { &*DMA1::ptr() }
+}
@@ -0,0 +1,341 @@
+//! Log kept in flash across resets
+//!
+//! What `log` routes to flash is appended here in chunks of the defmt stream, so it can be read
+//! back after a reset and decoded like the RTT output. The sectors are written in turn; when the
+//! last one is full the oldest is erased and used again, so the newest log is kept. With a single
+//! sector that means starting over.
+//!
+//! Sector layout (little endian):
+//!
+//! ```text
+//! 0   magic     u32  "LOG1"
+//! 4   sequence  u32  one more than the sector written before it
+//! 8   chunks    len u16 and that many bytes, up to a length of 0xFFFF (erased flash)
+//! ```
+//!
+//! A chunk cut short by a reset reads back as a broken defmt frame, which the decoder skips.
+//! Erasing a sector stalls the CPU for as long as it takes (up to 2 s for 128K), so appending
+//! belongs in a task at the lowest priority.
+
+use crate::flash::Flash;
+
+/// Sector 4 (64K at 0x0801_0000), free in both `memory/plain.x` and `memory/bootloader.x`
+pub const SECTORS: [u8; 1] = [4];
+
+/// Longest chunk, bigger ones are refused
+pub const MAX_CHUNK: usize = 256;
+
+const MAGIC: u32 = 0x3147_4F4C; // "LOG1"
+const HEADER_LEN: usize = 8;
+const LEN_LEN: usize = 2;
+const ERASED: u16 = 0xFFFF;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum LogStoreError<E> {
+    /// The flash driver failed
+    Flash(E),
+    /// Chunk longer than `MAX_CHUNK`
+    TooLong,
+}
+
+/// The log and the sectors it lives in
+pub struct LogStore<F: Flash, const N: usize> {
+    flash: F,
+    sectors: [u8; N],
+    /// Index into `sectors` of the one being written, None until loaded
+    current: Option<usize>,
+    /// Where the next chunk goes in the current sector
+    offset: usize,
+    sequence: u32,
+}
+
+impl<F: Flash, const N: usize> LogStore<F, N> {
+    /// `load` finds the end of the log, `append` does it too when it has not been called
+    pub fn new(flash: F, sectors: [u8; N]) -> Self {
+        LogStore {
+            flash,
+            sectors,
+            current: None,
+            offset: 0,
+            sequence: 0,
+        }
+    }
+
+    /// Finds the newest sector and the end of the log in it, sectors without a log in them are
+    /// erased and the log starts in the first one
+    pub fn load(&mut self) -> Result<(), LogStoreError<F::Error>> {
+        let mut newest: Option<(usize, u32)> = None;
+        for (index, &sector) in self.sectors.iter().enumerate() {
+            if let Some(sequence) = self.read_header(sector)? {
+                let is_newer = match newest {
+                    Some((_, best)) => sequence.wrapping_sub(best) as i32 > 0,
+                    None => true,
+                };
+                if is_newer {
+                    newest = Some((index, sequence));
+                }
+            }
+        }
+        match newest {
+            Some((index, sequence)) => {
+                self.current = Some(index);
+                self.sequence = sequence;
+                self.offset = self.end(self.sectors[index])?;
+                Ok(())
+            }
+            None => self.start(0, 0),
+        }
+    }
+
+    /// Appends a chunk to the log, moving on to the next sector when it does not fit
+    pub fn append(&mut self, data: &[u8]) -> Result<(), LogStoreError<F::Error>> {
+        if data.len() > MAX_CHUNK {
+            return Err(LogStoreError::TooLong);
+        }
+        if data.is_empty() {
+            return Ok(());
+        }
+        let index = match self.current {
+            Some(index) => index,
+            None => {
+                self.load()?;
+                self.current.unwrap_or(0)
+            }
+        };
+        let size = self.flash.sector_size(self.sectors[index]);
+        let index = if self.offset + LEN_LEN + data.len() > size {
+            let next = (index + 1) % N;
+            self.start(next, self.sequence.wrapping_add(1))?;
+            next
+        } else {
+            index
+        };
+
+        let sector = self.sectors[index];
+        let len = (data.len() as u16).to_le_bytes();
+        self.flash.program(sector, self.offset, &len).map_err(LogStoreError::Flash)?;
+        self.flash
+            .program(sector, self.offset + LEN_LEN, data)
+            .map_err(LogStoreError::Flash)?;
+        self.offset += LEN_LEN + data.len();
+        Ok(())
+    }
+
+    /// Reads the log back oldest first, in the chunks it was written in
+    pub fn read(&self, mut f: impl FnMut(&[u8])) -> Result<(), LogStoreError<F::Error>> {
+        let current = match self.current {
+            Some(current) => current,
+            None => return Ok(()),
+        };
+        let mut chunk = [0u8; MAX_CHUNK];
+        // the sectors after the current one are the oldest
+        for step in 1..=N {
+            let sector = self.sectors[(current + step) % N];
+            if self.read_header(sector)?.is_none() {
+                continue;
+            }
+            let mut offset = HEADER_LEN;
+            while let Some(len) = self.chunk_len(sector, offset)? {
+                let data = &mut chunk[..len];
+                self.flash
+                    .read(sector, offset + LEN_LEN, data)
+                    .map_err(LogStoreError::Flash)?;
+                f(data);
+                offset += LEN_LEN + len;
+            }
+        }
+        Ok(())
+    }
+
+    /// Erases the whole log
+    pub fn clear(&mut self) -> Result<(), LogStoreError<F::Error>> {
+        for index in 1..N {
+            self.flash.erase(self.sectors[index]).map_err(LogStoreError::Flash)?;
+        }
+        self.start(0, self.sequence.wrapping_add(1))
+    }
+
+    /// Bytes of log in the sector being written
+    pub fn used(&self) -> usize {
+        self.offset.saturating_sub(HEADER_LEN)
+    }
+
+    /// Give the flash back
+    pub fn free(self) -> F {
+        self.flash
+    }
+
+    /// Erases a sector and makes it the current one
+    fn start(&mut self, index: usize, sequence: u32) -> Result<(), LogStoreError<F::Error>> {
+        let sector = self.sectors[index];
+        self.flash.erase(sector).map_err(LogStoreError::Flash)?;
+        let mut header = [0u8; HEADER_LEN];
+        header[..4].copy_from_slice(&MAGIC.to_le_bytes());
+        header[4..].copy_from_slice(&sequence.to_le_bytes());
+        self.flash.program(sector, 0, &header).map_err(LogStoreError::Flash)?;
+        self.current = Some(index);
+        self.sequence = sequence;
+        self.offset = HEADER_LEN;
+        Ok(())
+    }
+
+    /// The sequence number of a sector that holds a log
+    fn read_header(&self, sector: u8) -> Result<Option<u32>, LogStoreError<F::Error>> {
+        let mut header = [0u8; HEADER_LEN];
+        self.flash.read(sector, 0, &mut header).map_err(LogStoreError::Flash)?;
+        let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        if magic != MAGIC {
+            return Ok(None);
+        }
+        Ok(Some(u32::from_le_bytes([header[4], header[5], header[6], header[7]])))
+    }
+
+    /// Length of the chunk at `offset`, None at the end of the log in the sector
+    fn chunk_len(&self, sector: u8, offset: usize) -> Result<Option<usize>, LogStoreError<F::Error>> {
+        let size = self.flash.sector_size(sector);
+        if offset + LEN_LEN > size {
+            return Ok(None);
+        }
+        let mut len = [0u8; LEN_LEN];
+        self.flash.read(sector, offset, &mut len).map_err(LogStoreError::Flash)?;
+        let len = u16::from_le_bytes(len);
+        // a length that makes no sense ends the log too, whatever comes after it is lost
+        if len == ERASED || len as usize > MAX_CHUNK || offset + LEN_LEN + len as usize > size {
+            return Ok(None);
+        }
+        Ok(Some(len as usize))
+    }
+
+    /// Where the log in a sector ends; after a length that makes no sense the sector is taken
+    /// as full so nothing is written behind it
+    fn end(&self, sector: u8) -> Result<usize, LogStoreError<F::Error>> {
+        let size = self.flash.sector_size(sector);
+        let mut offset = HEADER_LEN;
+        while let Some(len) = self.chunk_len(sector, offset)? {
+            offset += LEN_LEN + len;
+        }
+        if offset + LEN_LEN <= size {
+            let mut len = [0u8; LEN_LEN];
+            self.flash.read(sector, offset, &mut len).map_err(LogStoreError::Flash)?;
+            if u16::from_le_bytes(len) != ERASED {
+                return Ok(size);
+            }
+        }
+        Ok(offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::flash::{SimError, SimFlash};
+    use std::{vec, vec::Vec};
+
+    // 64 byte sectors: the header and 4 chunks of 10 bytes
+    fn store<const N: usize>(sectors: [u8; N]) -> LogStore<SimFlash, N> {
+        LogStore::new(SimFlash::new(4, N, 64), sectors)
+    }
+
+    // As after a reset, `cut` loses the power after that many more bytes
+    fn reload<const N: usize>(store: LogStore<SimFlash, N>, cut: Option<usize>) -> LogStore<SimFlash, N> {
+        let sectors = store.sectors;
+        let mut flash = store.free();
+        flash.restore_power();
+        if let Some(bytes) = cut {
+            flash.fail_after(bytes);
+        }
+        let mut store = LogStore::new(flash, sectors);
+        store.load().unwrap();
+        store
+    }
+
+    fn chunk(n: u8) -> [u8; 10] {
+        [n; 10]
+    }
+
+    fn chunks<const N: usize>(store: &LogStore<SimFlash, N>) -> Vec<Vec<u8>> {
+        let mut chunks = Vec::new();
+        store.read(|data| chunks.push(data.to_vec())).unwrap();
+        chunks
+    }
+
+    #[test]
+    fn oldest_first_across_the_wrap() {
+        let mut log = store([4, 5, 6]);
+        assert!(chunks(&log).is_empty());
+        for n in 0..14 {
+            log.append(&chunk(n)).unwrap();
+        }
+        // 4 a sector, the fourth sector's worth went over the oldest
+        let expected: Vec<Vec<u8>> = (4..14).map(|n| chunk(n).to_vec()).collect();
+        assert_eq!(chunks(&log), expected);
+        assert_eq!(log.used(), 24);
+
+        let mut log = reload(log, None);
+        assert_eq!(chunks(&log), expected);
+        log.append(&chunk(14)).unwrap();
+        log.append(&chunk(15)).unwrap();
+        // the second sector is the oldest now
+        log.append(&chunk(16)).unwrap();
+        let expected: Vec<Vec<u8>> = (8..17).map(|n| chunk(n).to_vec()).collect();
+        assert_eq!(chunks(&log), expected);
+
+        let flash = log.free();
+        assert_eq!([4, 5, 6].map(|sector| flash.erase_count(sector)), [2, 2, 1]);
+    }
+
+    #[test]
+    fn one_sector_starts_over() {
+        let mut log = store([4]);
+        for n in 0..5 {
+            log.append(&chunk(n)).unwrap();
+        }
+        assert_eq!(chunks(&log), [chunk(4)]);
+        log.clear().unwrap();
+        assert!(chunks(&log).is_empty());
+        assert_eq!(log.used(), 0);
+        assert_eq!(log.free().erase_count(4), 3);
+    }
+
+    #[test]
+    fn power_cut_in_a_chunk() {
+        // the length is 2 bytes and the data 10, cut after each of them
+        for budget in 0..12 {
+            let mut log = store([4, 5]);
+            log.append(&chunk(1)).unwrap();
+            log.append(&chunk(2)).unwrap();
+            let mut log = reload(log, Some(budget));
+            assert_eq!(log.append(&chunk(3)), Err(LogStoreError::Flash(SimError::PowerLoss)));
+
+            let mut log = reload(log, None);
+            let mut expected = vec![chunk(1).to_vec(), chunk(2).to_vec()];
+            if budget >= 2 {
+                // a whole length and part of the data, the rest still erased
+                let mut torn = vec![0xFF; 10];
+                torn[..budget - 2].fill(3);
+                expected.push(torn);
+            }
+            assert_eq!(chunks(&log), expected, "cut after {} bytes", budget);
+
+            // a torn length ends the sector, so the log goes on in the next one
+            log.append(&chunk(4)).unwrap();
+            expected.push(chunk(4).to_vec());
+            assert_eq!(chunks(&log), expected, "cut after {} bytes", budget);
+            let next = if budget == 1 { 1 } else { 0 };
+            assert_eq!(log.free().erase_count(5), next, "cut after {} bytes", budget);
+        }
+    }
+
+    #[test]
+    fn chunk_sizes() {
+        let mut log = LogStore::new(SimFlash::new(4, 1, 1024), [4]);
+        assert_eq!(log.append(&[0; MAX_CHUNK + 1]), Err(LogStoreError::TooLong));
+        log.append(&[]).unwrap();
+        log.append(&[7; MAX_CHUNK]).unwrap();
+        assert_eq!(log.used(), 2 + MAX_CHUNK);
+        let mut read = Vec::new();
+        log.read(|data| read.push(data.len())).unwrap();
+        assert_eq!(read, [MAX_CHUNK]);
+    }
+}
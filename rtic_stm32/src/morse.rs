@@ -0,0 +1,406 @@
+//! Morse code (CW) beacon
+//!
+//! Turns a message into the on/off keying of a pin, the LED or the keying input of a radio.
+//! The timing is the standard one in dot units: a dash is 3, the gap inside a character 1,
+//! between characters 3 and between words 7. At `wpm` words per minute a unit is 1200 / `wpm`
+//! ms (the word PARIS is 50 units).
+//!
+//! `Elements` gives the on/off sequence of a message without any hardware, `Keyer` sets a pin
+//! for each element and says how long until the next one, for a task that respawns itself
+//! with `spawn_after`. The message comes from a template like `"{call} BATT {batt}"` whose
+//! fields are filled in with live values by `expand`.
+
+use core::fmt;
+
+use embedded_hal::digital::v2::OutputPin;
+use heapless::String;
+
+/// Dots and dashes of the characters that have a code
+const CODES: [(char, &str); 54] = [
+    ('A', ".-"),
+    ('B', "-..."),
+    ('C', "-.-."),
+    ('D', "-.."),
+    ('E', "."),
+    ('F', "..-."),
+    ('G', "--."),
+    ('H', "...."),
+    ('I', ".."),
+    ('J', ".---"),
+    ('K', "-.-"),
+    ('L', ".-.."),
+    ('M', "--"),
+    ('N', "-."),
+    ('O', "---"),
+    ('P', ".--."),
+    ('Q', "--.-"),
+    ('R', ".-."),
+    ('S', "..."),
+    ('T', "-"),
+    ('U', "..-"),
+    ('V', "...-"),
+    ('W', ".--"),
+    ('X', "-..-"),
+    ('Y', "-.--"),
+    ('Z', "--.."),
+    ('0', "-----"),
+    ('1', ".----"),
+    ('2', "..---"),
+    ('3', "...--"),
+    ('4', "....-"),
+    ('5', "....."),
+    ('6', "-...."),
+    ('7', "--..."),
+    ('8', "---.."),
+    ('9', "----."),
+    ('.', ".-.-.-"),
+    (',', "--..--"),
+    ('?', "..--.."),
+    ('\'', ".----."),
+    ('!', "-.-.--"),
+    ('/', "-..-."),
+    ('(', "-.--."),
+    (')', "-.--.-"),
+    ('&', ".-..."),
+    (':', "---..."),
+    (';', "-.-.-."),
+    ('=', "-...-"),
+    ('+', ".-.-."),
+    ('-', "-....-"),
+    ('_', "..--.-"),
+    ('"', ".-..-."),
+    ('$', "...-..-"),
+    ('@', ".--.-."),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+    /// The message does not fit
+    TooLong,
+    /// A `{field}` the template fills in does not know
+    UnknownField,
+    /// A `{` without its `}`
+    Template,
+}
+
+/// Dots and dashes of a character, upper or lower case. Characters without a code are
+/// skipped when keying.
+pub fn code(c: char) -> Option<&'static str> {
+    let c = c.to_ascii_uppercase();
+    CODES.iter().find(|(known, _)| *known == c).map(|(_, code)| *code)
+}
+
+/// Key down or up for a number of dot units
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Element {
+    pub on: bool,
+    pub units: u8,
+}
+
+impl Element {
+    const DOT: Element = Element { on: true, units: 1 };
+    const DASH: Element = Element { on: true, units: 3 };
+
+    const fn off(units: u8) -> Self {
+        Element { on: false, units }
+    }
+}
+
+/// Where keying is in a message, kept apart from the message so a `Keyer` can own both
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Cursor {
+    /// Byte offset of the next character
+    position: usize,
+    /// Dots and dashes left of the current character
+    code: &'static str,
+    /// Off time before the next dot or dash
+    gap: u8,
+}
+
+impl Cursor {
+    pub const fn new() -> Self {
+        Cursor {
+            position: 0,
+            code: "",
+            gap: 0,
+        }
+    }
+
+    /// The next element of `message`, which has to be the same message every time
+    pub fn next(&mut self, message: &str) -> Option<Element> {
+        loop {
+            if let Some(symbol) = self.code.as_bytes().first() {
+                if self.gap > 0 {
+                    let gap = self.gap;
+                    self.gap = 0;
+                    return Some(Element::off(gap));
+                }
+                self.code = &self.code[1..];
+                self.gap = 1;
+                return Some(if *symbol == b'-' { Element::DASH } else { Element::DOT });
+            }
+
+            let c = message.get(self.position..)?.chars().next()?;
+            self.position += c.len_utf8();
+            // gaps only come between elements, none before the first or after the last
+            if c.is_whitespace() {
+                if self.gap > 0 {
+                    self.gap = 7;
+                }
+            } else if let Some(code) = code(c) {
+                if self.gap > 0 {
+                    self.gap = self.gap.max(3);
+                }
+                self.code = code;
+            }
+        }
+    }
+}
+
+/// The on/off sequence of a message
+pub struct Elements<'a> {
+    message: &'a str,
+    cursor: Cursor,
+}
+
+impl Iterator for Elements<'_> {
+    type Item = Element;
+
+    fn next(&mut self) -> Option<Element> {
+        self.cursor.next(self.message)
+    }
+}
+
+pub fn elements(message: &str) -> Elements<'_> {
+    Elements {
+        message,
+        cursor: Cursor::new(),
+    }
+}
+
+/// Length of a message in dot units
+pub fn units(message: &str) -> u32 {
+    elements(message).map(|element| element.units as u32).sum()
+}
+
+/// Durations at a keying speed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Timing {
+    pub unit_ms: u32,
+}
+
+impl Timing {
+    pub const fn from_wpm(wpm: u32) -> Self {
+        let wpm = if wpm == 0 { 1 } else { wpm };
+        Timing { unit_ms: 1200 / wpm }
+    }
+
+    pub const fn duration_ms(&self, element: Element) -> u32 {
+        self.unit_ms * element.units as u32
+    }
+}
+
+/// Fill in the `{field}`s of a template, `field` writes the value of one into the message.
+/// `{{` is a plain `{`.
+pub fn expand<const N: usize>(
+    template: &str,
+    mut field: impl FnMut(&str, &mut String<N>) -> Result<(), Error>,
+) -> Result<String<N>, Error> {
+    let mut message = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        message.push_str(&rest[..start]).map_err(|_| Error::TooLong)?;
+        rest = &rest[start + 1..];
+        if let Some(after) = rest.strip_prefix('{') {
+            message.push('{').map_err(|_| Error::TooLong)?;
+            rest = after;
+            continue;
+        }
+        let end = rest.find('}').ok_or(Error::Template)?;
+        field(&rest[..end], &mut message)?;
+        rest = &rest[end + 1..];
+    }
+    message.push_str(rest).map_err(|_| Error::TooLong)?;
+    Ok(message)
+}
+
+/// `write!` for `expand`'s fields, with the error it wants
+pub fn write_field<const N: usize>(message: &mut String<N>, args: fmt::Arguments) -> Result<(), Error> {
+    fmt::Write::write_fmt(message, args).map_err(|_| Error::TooLong)
+}
+
+/// Keys a pin through a message of up to `N` bytes, active high
+pub struct Keyer<P, const N: usize> {
+    pin: P,
+    timing: Timing,
+    message: String<N>,
+    cursor: Cursor,
+}
+
+impl<P: OutputPin, const N: usize> Keyer<P, N> {
+    pub fn new(mut pin: P, wpm: u32) -> Self {
+        pin.set_low().ok();
+        Keyer {
+            pin,
+            timing: Timing::from_wpm(wpm),
+            message: String::new(),
+            cursor: Cursor::new(),
+        }
+    }
+
+    pub fn set_wpm(&mut self, wpm: u32) {
+        self.timing = Timing::from_wpm(wpm);
+    }
+
+    pub fn timing(&self) -> Timing {
+        self.timing
+    }
+
+    /// Start over with a new message, the key goes up
+    pub fn start(&mut self, message: &str) -> Result<(), Error> {
+        self.pin.set_low().ok();
+        self.message.clear();
+        self.cursor = Cursor::new();
+        self.message.push_str(message).map_err(|_| Error::TooLong)
+    }
+
+    /// Key the next element, returns the ms until the next call. `None` when the message is
+    /// done, the key is up then.
+    pub fn step(&mut self) -> Option<u32> {
+        match self.cursor.next(&self.message) {
+            Some(element) => {
+                if element.on {
+                    self.pin.set_high().ok();
+                } else {
+                    self.pin.set_low().ok();
+                }
+                Some(self.timing.duration_ms(element))
+            }
+            None => {
+                self.pin.set_low().ok();
+                None
+            }
+        }
+    }
+
+    pub fn release(mut self) -> P {
+        self.pin.set_low().ok();
+        self.pin
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use std::{string::String as StdString, vec, vec::Vec};
+
+    /// Records what the keyer does to it
+    struct Pin(Vec<bool>);
+
+    impl OutputPin for Pin {
+        type Error = ();
+
+        fn set_low(&mut self) -> Result<(), ()> {
+            self.0.push(false);
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), ()> {
+            self.0.push(true);
+            Ok(())
+        }
+    }
+
+    /// Run the keyer to the end, the pin level and how long it stays there for every step
+    fn key<const N: usize>(keyer: &mut Keyer<Pin, N>) -> Vec<(bool, u32)> {
+        let mut steps = Vec::new();
+        while let Some(ms) = keyer.step() {
+            steps.push((*keyer.pin.0.last().unwrap(), ms));
+        }
+        steps
+    }
+
+    /// `=` per unit on, `.` per unit off
+    fn pattern(message: &str) -> StdString {
+        elements(message)
+            .map(|element| if element.on { "=" } else { "." }.repeat(element.units as usize))
+            .collect()
+    }
+
+    #[test]
+    fn paris() {
+        let mut keyer: Keyer<Pin, 16> = Keyer::new(Pin(Vec::new()), 20);
+        assert_eq!(keyer.timing().unit_ms, 60);
+        keyer.start("PARIS").unwrap();
+        let (on, off) = ((true, 60), (false, 60));
+        let (dash, letter) = ((true, 180), (false, 180));
+        #[rustfmt::skip]
+        let expected = vec![
+            on, off, dash, off, dash, off, on, letter, // P .--.
+            on, off, dash, letter,                     // A .-
+            on, off, dash, off, on, letter,            // R .-.
+            on, off, on, letter,                       // I ..
+            on, off, on, off, on,                      // S ...
+        ];
+        assert_eq!(key(&mut keyer), expected);
+        // 43 units, the word gap after it makes the 50 of the standard word
+        assert_eq!(expected.iter().map(|(_, ms)| ms).sum::<u32>(), 43 * 60);
+        assert_eq!(units("PARIS"), 43);
+        // the key is up at the end and stays up
+        assert_eq!(keyer.step(), None);
+        assert_eq!(keyer.release().0.last(), Some(&false));
+    }
+
+    #[test]
+    fn a_minute_of_paris() {
+        // 20 PARIS at 20 wpm take a minute, less the last word gap
+        let mut keyer: Keyer<Pin, 128> = Keyer::new(Pin(Vec::new()), 20);
+        keyer.start(&"PARIS ".repeat(20)).unwrap();
+        let ms: u32 = key(&mut keyer).iter().map(|(_, ms)| ms).sum();
+        assert_eq!(ms + 7 * 60, 60_000);
+
+        keyer.set_wpm(12);
+        keyer.start("PARIS PARIS").unwrap();
+        let ms: u32 = key(&mut keyer).iter().map(|(_, ms)| ms).sum();
+        assert_eq!(ms, (50 + 43) * 100);
+    }
+
+    #[test]
+    fn gaps() {
+        assert_eq!(pattern("SOS"), "=.=.=...===.===.===...=.=.=");
+        // runs of spaces and characters without a code make no extra gaps
+        assert_eq!(pattern("e  e"), "=.......=");
+        assert_eq!(pattern(" E# E "), "=.......=");
+        assert_eq!(pattern("E#E"), "=...=");
+        assert_eq!(pattern("E\u{fc}E"), "=...=");
+        assert_eq!(pattern(""), "");
+        assert_eq!(code('q'), Some("--.-"));
+        assert_eq!(Timing::from_wpm(0).unit_ms, 1200);
+    }
+
+    #[test]
+    fn restart() {
+        let mut keyer: Keyer<Pin, 8> = Keyer::new(Pin(Vec::new()), 20);
+        keyer.start("TT").unwrap();
+        assert_eq!(keyer.step(), Some(180));
+        // a new message mid element lifts the key and starts from its beginning
+        keyer.start("E").unwrap();
+        assert_eq!(keyer.pin.0.last(), Some(&false));
+        assert_eq!(key(&mut keyer), [(true, 60)]);
+        assert_eq!(keyer.start("123456789"), Err(Error::TooLong));
+    }
+
+    #[test]
+    fn template() {
+        let message = expand::<32>("{call} T {t} {{x}", |name, out| match name {
+            "call" => write_field(out, format_args!("OZ7SAT")),
+            "t" => write_field(out, format_args!("{}", -3)),
+            _ => Err(Error::UnknownField),
+        });
+        assert_eq!(message.as_deref(), Ok("OZ7SAT T -3 {x}"));
+        assert_eq!(expand::<32>("{nope}", |_, _| Err(Error::UnknownField)), Err(Error::UnknownField));
+        assert_eq!(expand::<32>("a {b", |_, _| Ok(())), Err(Error::Template));
+        assert_eq!(expand::<4>("abcde", |_, _| Ok(())), Err(Error::TooLong));
+    }
+}
@@ -0,0 +1,712 @@
+//! NMEA 0183 parser for GPS receivers
+//!
+//! Understands GGA (fix), RMC (recommended minimum), ZDA (date and time) and GSA (satellites
+//! and dilution of precision) from any talker (GP, GN, GL, GA, BD). Sentences have to carry a
+//! checksum, all GPS receivers send one. Empty fields, which receivers send while they have no
+//! fix, come out as `None`.
+//!
+//! `Parser` collects bytes into sentences, `Gps` keeps the latest of everything and works out
+//! UTC for the time service.
+
+use heapless::Vec;
+
+use crate::time::{DateTime, MICROS_PER_SEC, UNIX_TO_2000};
+
+/// Longest sentence allowed by the standard, `$` to `\n`, many receivers go over it a bit
+pub const MAX_SENTENCE_LEN: usize = 100;
+
+/// Satellites listed in a GSA sentence
+pub const GSA_SATELLITES: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+    /// The checksum does not match, `computed` is what the data adds up to
+    Checksum { computed: u8, received: u8 },
+    /// No `*hh` at the end
+    NoChecksum,
+    /// Not a sentence, or a field that does not parse
+    Format,
+    /// Longer than `MAX_SENTENCE_LEN`
+    TooLong,
+    /// A sentence type this parser does not know, it is fine to ignore
+    Unsupported,
+}
+
+/// UTC time of day
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Time {
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub millis: u16,
+}
+
+impl Time {
+    pub fn seconds_of_day(&self) -> u32 {
+        self.hour as u32 * 3_600 + self.minute as u32 * 60 + self.second as u32
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Date {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+}
+
+/// Degrees, north and east positive
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub struct Position {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+/// GGA fix quality
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Quality {
+    NoFix,
+    Gps,
+    Differential,
+    Pps,
+    RtkFixed,
+    RtkFloat,
+    Estimated,
+    Manual,
+    Simulation,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub struct Gga {
+    pub time: Option<Time>,
+    pub position: Option<Position>,
+    pub quality: Quality,
+    pub satellites: Option<u8>,
+    pub hdop: Option<f32>,
+    /// Above mean sea level, m
+    pub altitude: Option<f32>,
+    /// Geoid above the WGS84 ellipsoid, m
+    pub geoid_separation: Option<f32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub struct Rmc {
+    pub time: Option<Time>,
+    /// `A` in the status field, the receiver trusts the fix
+    pub valid: bool,
+    pub position: Option<Position>,
+    /// m/s
+    pub speed: Option<f32>,
+    /// Degrees from true north
+    pub course: Option<f32>,
+    pub date: Option<Date>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Zda {
+    pub time: Option<Time>,
+    pub date: Option<Date>,
+}
+
+/// GSA fix type
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum FixType {
+    NoFix,
+    Fix2d,
+    Fix3d,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub struct Gsa {
+    /// Whether the receiver picks 2D/3D by itself
+    pub automatic: bool,
+    pub fix_type: FixType,
+    /// PRNs of the satellites used, the first `used` of them
+    pub prns: [u8; GSA_SATELLITES],
+    pub used: u8,
+    pub pdop: Option<f32>,
+    pub hdop: Option<f32>,
+    pub vdop: Option<f32>,
+}
+
+impl Gsa {
+    pub fn satellites(&self) -> &[u8] {
+        &self.prns[..self.used as usize]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub enum Sentence {
+    Gga(Gga),
+    Rmc(Rmc),
+    Zda(Zda),
+    Gsa(Gsa),
+}
+
+/// Parse one sentence, with or without the line ending
+pub fn parse(line: &[u8]) -> Result<Sentence, Error> {
+    let line = trim_line_end(line);
+    let line = line.strip_prefix(b"$").ok_or(Error::Format)?;
+    let star = line.iter().rposition(|&b| b == b'*').ok_or(Error::NoChecksum)?;
+    let (data, checksum) = (&line[..star], &line[star + 1..]);
+
+    let received = match checksum {
+        [high, low] => hex(*high)? << 4 | hex(*low)?,
+        _ => return Err(Error::NoChecksum),
+    };
+    let computed = data.iter().fold(0, |sum, &b| sum ^ b);
+    if computed != received {
+        return Err(Error::Checksum { computed, received });
+    }
+
+    // NMEA is ASCII, anything else is line noise that happened to pass the checksum
+    if !data.is_ascii() {
+        return Err(Error::Format);
+    }
+    let data = core::str::from_utf8(data).map_err(|_| Error::Format)?;
+    let mut fields = data.split(',');
+    let address = fields.next().ok_or(Error::Format)?;
+    if address.len() != 5 {
+        return Err(Error::Format);
+    }
+    let mut fields = Fields(fields);
+    match address.get(2..).ok_or(Error::Format)? {
+        "GGA" => parse_gga(&mut fields).map(Sentence::Gga),
+        "RMC" => parse_rmc(&mut fields).map(Sentence::Rmc),
+        "ZDA" => parse_zda(&mut fields).map(Sentence::Zda),
+        "GSA" => parse_gsa(&mut fields).map(Sentence::Gsa),
+        _ => Err(Error::Unsupported),
+    }
+}
+
+fn trim_line_end(line: &[u8]) -> &[u8] {
+    let end = line
+        .iter()
+        .rposition(|&b| b != b'\r' && b != b'\n')
+        .map_or(0, |end| end + 1);
+    &line[..end]
+}
+
+fn hex(digit: u8) -> Result<u8, Error> {
+    match digit {
+        b'0'..=b'9' => Ok(digit - b'0'),
+        b'A'..=b'F' => Ok(digit - b'A' + 10),
+        b'a'..=b'f' => Ok(digit - b'a' + 10),
+        _ => Err(Error::Format),
+    }
+}
+
+/// The comma separated fields after the address, missing ones at the end read as empty
+struct Fields<'a>(core::str::Split<'a, char>);
+
+impl<'a> Fields<'a> {
+    fn next(&mut self) -> &'a str {
+        self.0.next().unwrap_or("")
+    }
+
+    /// The next field, `None` if empty, an error if it does not parse
+    fn parse<T>(&mut self, f: impl FnOnce(&'a str) -> Option<T>) -> Result<Option<T>, Error> {
+        match self.next() {
+            "" => Ok(None),
+            field => f(field).map(Some).ok_or(Error::Format),
+        }
+    }
+
+    fn number<T: core::str::FromStr>(&mut self) -> Result<Option<T>, Error> {
+        self.parse(|field| field.parse().ok())
+    }
+
+    fn time(&mut self) -> Result<Option<Time>, Error> {
+        self.parse(parse_time)
+    }
+
+    /// Latitude and longitude, four fields
+    fn position(&mut self) -> Result<Option<Position>, Error> {
+        let latitude = self.parse(|field| parse_angle(field, 2))?;
+        let north = self.next();
+        let longitude = self.parse(|field| parse_angle(field, 3))?;
+        let east = self.next();
+        let (latitude, longitude) = match (latitude, longitude) {
+            (Some(latitude), Some(longitude)) => (latitude, longitude),
+            _ => return Ok(None),
+        };
+        let latitude = match north {
+            "N" => latitude,
+            "S" => -latitude,
+            _ => return Err(Error::Format),
+        };
+        let longitude = match east {
+            "E" => longitude,
+            "W" => -longitude,
+            _ => return Err(Error::Format),
+        };
+        Ok(Some(Position {
+            latitude,
+            longitude,
+        }))
+    }
+}
+
+fn digits(field: &str) -> Option<u32> {
+    if field.is_empty() || !field.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    field.parse().ok()
+}
+
+/// `hhmmss` or `hhmmss.sss`
+fn parse_time(field: &str) -> Option<Time> {
+    let (whole, fraction) = field.split_once('.').unwrap_or((field, ""));
+    if whole.len() != 6 {
+        return None;
+    }
+    let millis = if fraction.is_empty() {
+        0
+    } else {
+        // scale whatever number of decimals to milliseconds
+        let fraction = fraction.get(..fraction.len().min(3))?;
+        digits(fraction)? * 10u32.pow(3 - fraction.len() as u32)
+    };
+    let time = Time {
+        hour: digits(whole.get(0..2)?)? as u8,
+        minute: digits(whole.get(2..4)?)? as u8,
+        second: digits(whole.get(4..6)?)? as u8,
+        millis: millis as u16,
+    };
+    // 60 is a leap second
+    (time.hour < 24 && time.minute < 60 && time.second <= 60).then_some(time)
+}
+
+/// `ddmm.mmmm` or `dddmm.mmmm` to degrees
+fn parse_angle(field: &str, degree_digits: usize) -> Option<f64> {
+    let degrees = digits(field.get(..degree_digits)?)? as f64;
+    let minutes: f64 = field.get(degree_digits..)?.parse().ok()?;
+    if !(0.0..60.0).contains(&minutes) {
+        return None;
+    }
+    Some(degrees + minutes / 60.0)
+}
+
+/// `ddmmyy`, RMC only has two digits of year
+fn parse_date(field: &str) -> Option<Date> {
+    if field.len() != 6 {
+        return None;
+    }
+    let date = Date {
+        day: digits(field.get(0..2)?)? as u8,
+        month: digits(field.get(2..4)?)? as u8,
+        year: 2000 + digits(field.get(4..6)?)? as u16,
+    };
+    valid_date(date)
+}
+
+fn valid_date(date: Date) -> Option<Date> {
+    ((1..=12).contains(&date.month) && (1..=31).contains(&date.day)).then_some(date)
+}
+
+fn parse_gga(fields: &mut Fields) -> Result<Gga, Error> {
+    let time = fields.time()?;
+    let position = fields.position()?;
+    let quality = match fields.next() {
+        "" | "0" => Quality::NoFix,
+        "1" => Quality::Gps,
+        "2" => Quality::Differential,
+        "3" => Quality::Pps,
+        "4" => Quality::RtkFixed,
+        "5" => Quality::RtkFloat,
+        "6" => Quality::Estimated,
+        "7" => Quality::Manual,
+        "8" => Quality::Simulation,
+        _ => return Err(Error::Format),
+    };
+    let satellites = fields.number()?;
+    let hdop = fields.number()?;
+    let altitude = fields.number()?;
+    fields.next(); // M
+    let geoid_separation = fields.number()?;
+    Ok(Gga {
+        time,
+        position,
+        quality,
+        satellites,
+        hdop,
+        altitude,
+        geoid_separation,
+    })
+}
+
+fn parse_rmc(fields: &mut Fields) -> Result<Rmc, Error> {
+    const KNOTS: f32 = 1852.0 / 3600.0;
+    let time = fields.time()?;
+    let valid = match fields.next() {
+        "A" => true,
+        "V" | "" => false,
+        _ => return Err(Error::Format),
+    };
+    let position = fields.position()?;
+    let speed = fields.number::<f32>()?.map(|knots| knots * KNOTS);
+    let course = fields.number()?;
+    let date = fields.parse(parse_date)?;
+    Ok(Rmc {
+        time,
+        valid,
+        position,
+        speed,
+        course,
+        date,
+    })
+}
+
+fn parse_zda(fields: &mut Fields) -> Result<Zda, Error> {
+    let time = fields.time()?;
+    let day = fields.parse(digits)?;
+    let month = fields.parse(digits)?;
+    let year = fields.parse(digits)?;
+    let date = match (day, month, year) {
+        (Some(day), Some(month), Some(year)) => Some(
+            valid_date(Date {
+                year: year as u16,
+                month: month as u8,
+                day: day as u8,
+            })
+            .ok_or(Error::Format)?,
+        ),
+        _ => None,
+    };
+    // the local zone fields are left alone, everything here is UTC
+    Ok(Zda { time, date })
+}
+
+fn parse_gsa(fields: &mut Fields) -> Result<Gsa, Error> {
+    let automatic = match fields.next() {
+        "A" | "" => true,
+        "M" => false,
+        _ => return Err(Error::Format),
+    };
+    let fix_type = match fields.next() {
+        "" | "1" => FixType::NoFix,
+        "2" => FixType::Fix2d,
+        "3" => FixType::Fix3d,
+        _ => return Err(Error::Format),
+    };
+    let mut prns = [0; GSA_SATELLITES];
+    let mut used = 0;
+    for _ in 0..GSA_SATELLITES {
+        if let Some(prn) = fields.parse(digits)? {
+            prns[used] = prn as u8;
+            used += 1;
+        }
+    }
+    Ok(Gsa {
+        automatic,
+        fix_type,
+        prns,
+        used: used as u8,
+        pdop: fields.number()?,
+        hdop: fields.number()?,
+        vdop: fields.number()?,
+    })
+}
+
+/// Collects bytes into sentences
+#[derive(Debug, Default)]
+pub struct Parser {
+    line: Vec<u8, MAX_SENTENCE_LEN>,
+    overlong: bool,
+}
+
+impl Parser {
+    pub fn new() -> Self {
+        Parser {
+            line: Vec::new(),
+            overlong: false,
+        }
+    }
+
+    /// Feed one byte, gives the sentence when its line ends
+    pub fn push(&mut self, byte: u8) -> Option<Result<Sentence, Error>> {
+        match byte {
+            b'$' => {
+                // a new sentence, whatever came before was cut off
+                self.line.clear();
+                self.overlong = false;
+                self.line.push(byte).ok();
+                None
+            }
+            b'\n' => {
+                if self.line.is_empty() {
+                    return None;
+                }
+                let result = if self.overlong {
+                    Err(Error::TooLong)
+                } else {
+                    parse(&self.line)
+                };
+                self.line.clear();
+                self.overlong = false;
+                Some(result)
+            }
+            _ if self.line.is_empty() => None, // noise between sentences
+            _ => {
+                if self.line.push(byte).is_err() {
+                    self.overlong = true;
+                }
+                None
+            }
+        }
+    }
+}
+
+/// The latest of everything the receiver said
+#[derive(Debug, Clone, Copy, Default, PartialEq, defmt::Format)]
+pub struct Gps {
+    pub fix: Option<Gga>,
+    pub satellites: Option<Gsa>,
+    pub date: Option<Date>,
+    pub time: Option<Time>,
+    pub position: Option<Position>,
+    /// Whether the last RMC was valid
+    pub valid: bool,
+}
+
+impl Gps {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Take in a sentence, returns the UTC unix time in microseconds when it carried a
+    /// trustworthy date and time
+    pub fn update(&mut self, sentence: Sentence) -> Option<u64> {
+        match sentence {
+            Sentence::Gga(gga) => {
+                if gga.quality != Quality::NoFix {
+                    self.position = gga.position.or(self.position);
+                }
+                self.fix = Some(gga);
+                None
+            }
+            Sentence::Gsa(gsa) => {
+                self.satellites = Some(gsa);
+                None
+            }
+            Sentence::Rmc(rmc) => {
+                self.valid = rmc.valid;
+                if !rmc.valid {
+                    return None;
+                }
+                self.position = rmc.position.or(self.position);
+                self.set_time(rmc.date, rmc.time)
+            }
+            // ZDA comes without a validity flag, so only trust it along with a valid RMC
+            Sentence::Zda(zda) if self.valid => self.set_time(zda.date, zda.time),
+            Sentence::Zda(_) => None,
+        }
+    }
+
+    fn set_time(&mut self, date: Option<Date>, time: Option<Time>) -> Option<u64> {
+        // a time is only any good with the date from the same sentence, an older date may be
+        // from before midnight
+        self.date = date;
+        self.time = time;
+        self.utc_micros()
+    }
+
+    /// UTC of the last time received as unix microseconds, a leap second reads as the first
+    /// second of the next day like unix time does
+    pub fn utc_micros(&self) -> Option<u64> {
+        let (date, time) = (self.date?, self.time?);
+        if date.year < 2000 {
+            return None;
+        }
+        let midnight = DateTime {
+            year: date.year,
+            month: date.month,
+            day: date.day,
+            hour: 0,
+            minute: 0,
+            second: 0,
+        };
+        let seconds = UNIX_TO_2000 + midnight.to_secs() + time.seconds_of_day() as u64;
+        Some(seconds * MICROS_PER_SEC + time.millis as u64 * 1_000)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use std::{format, string::String, vec::Vec};
+
+    // From a u-blox NEO-M8N and the examples everyone quotes
+    const RECORDED: &[u8] = b"\
+$GNRMC,001031.00,A,4404.13993,N,12118.86023,W,0.146,,100117,,,A*7B\r\n\
+$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47\r\n\
+$GPGSA,A,3,04,05,,09,12,,,24,,,,,2.5,1.3,2.1*39\r\n\
+$GPGSV,3,1,11,03,03,111,00,04,15,270,00,06,01,010,00,13,06,292,00*74\r\n\
+$GPZDA,201530.00,04,07,2002,00,00*60\r\n\
+$GPRMC,,V,,,,,,,,,,N*53\r\n\
+$GPGGA,,,,,,0,00,99.99,,,,,,*48\r\n";
+
+    fn feed(parser: &mut Parser, bytes: &[u8]) -> Vec<Result<Sentence, Error>> {
+        bytes.iter().filter_map(|&byte| parser.push(byte)).collect()
+    }
+
+    /// `$data*hh` with the right checksum
+    fn sentence(data: &[u8]) -> Vec<u8> {
+        let checksum = data.iter().fold(0, |sum, &b| sum ^ b);
+        let mut line = Vec::from(&b"$"[..]);
+        line.extend_from_slice(data);
+        line.extend_from_slice(format!("*{checksum:02X}\r\n").as_bytes());
+        line
+    }
+
+    #[test]
+    fn recorded() {
+        let sentences = feed(&mut Parser::new(), RECORDED);
+        assert_eq!(sentences.len(), 7);
+
+        let Ok(Sentence::Rmc(rmc)) = sentences[0] else { panic!("{:?}", sentences[0]) };
+        assert!(rmc.valid);
+        let position = rmc.position.unwrap();
+        assert!((position.latitude - 44.068_999).abs() < 1e-6);
+        assert!((position.longitude + 121.314_337).abs() < 1e-6);
+        assert!((rmc.speed.unwrap() - 0.0751).abs() < 1e-4);
+        assert_eq!(rmc.course, None);
+        assert_eq!(rmc.date, Some(Date { year: 2017, month: 1, day: 10 }));
+        let time = Time { hour: 0, minute: 10, second: 31, millis: 0 };
+        assert_eq!(rmc.time, Some(time));
+
+        let Ok(Sentence::Gga(gga)) = sentences[1] else { panic!("{:?}", sentences[1]) };
+        assert_eq!(gga.time, Some(Time { hour: 12, minute: 35, second: 19, millis: 0 }));
+        let position = gga.position.unwrap();
+        assert!((position.latitude - 48.1173).abs() < 1e-6);
+        assert!((position.longitude - 11.516_667).abs() < 1e-6);
+        assert_eq!(gga.quality, Quality::Gps);
+        assert_eq!(gga.satellites, Some(8));
+        assert_eq!(gga.hdop, Some(0.9));
+        assert_eq!(gga.altitude, Some(545.4));
+        assert_eq!(gga.geoid_separation, Some(46.9));
+
+        let Ok(Sentence::Gsa(gsa)) = sentences[2] else { panic!("{:?}", sentences[2]) };
+        assert!(gsa.automatic);
+        assert_eq!(gsa.fix_type, FixType::Fix3d);
+        assert_eq!(gsa.satellites(), [4, 5, 9, 12, 24]);
+        assert_eq!((gsa.pdop, gsa.hdop, gsa.vdop), (Some(2.5), Some(1.3), Some(2.1)));
+
+        assert_eq!(sentences[3], Err(Error::Unsupported));
+
+        let Ok(Sentence::Zda(zda)) = sentences[4] else { panic!("{:?}", sentences[4]) };
+        assert_eq!(zda.date, Some(Date { year: 2002, month: 7, day: 4 }));
+        assert_eq!(zda.time, Some(Time { hour: 20, minute: 15, second: 30, millis: 0 }));
+
+        // no fix yet
+        let Ok(Sentence::Rmc(rmc)) = sentences[5] else { panic!("{:?}", sentences[5]) };
+        assert!(!rmc.valid);
+        assert_eq!((rmc.time, rmc.position, rmc.date), (None, None, None));
+        let Ok(Sentence::Gga(gga)) = sentences[6] else { panic!("{:?}", sentences[6]) };
+        assert_eq!(gga.quality, Quality::NoFix);
+        assert_eq!(gga.position, None);
+    }
+
+    #[test]
+    fn utc() {
+        let mut gps = Gps::new();
+        let mut utc = Vec::new();
+        for sentence in feed(&mut Parser::new(), RECORDED).into_iter().flatten() {
+            utc.extend(gps.update(sentence));
+        }
+        // 2017-01-10 00:10:31 from the valid RMC, the ZDA came after a valid RMC too
+        assert_eq!(utc, [1_484_007_031_000_000, 1_025_813_730_000_000]);
+        assert!(!gps.valid);
+        assert_eq!(gps.position.map(|p| p.latitude > 44.0), Some(true));
+
+        let mut gps = Gps::new();
+        let zda = parse(b"$GPZDA,201530.00,04,07,2002,00,00*60").unwrap();
+        assert_eq!(gps.update(zda), None, "ZDA alone is not trusted");
+    }
+
+    #[test]
+    fn time_without_a_date() {
+        let mut gps = Gps::new();
+        let rmc = parse(&sentence(b"GPRMC,235959.00,A,4404.13993,N,12118.86023,W,0.146,,100117,,,A")).unwrap();
+        assert_eq!(gps.update(rmc), Some(1_484_092_799_000_000));
+        // past midnight without a date, the stored one is yesterday's
+        let rmc = parse(&sentence(b"GPRMC,000000.00,A,4404.13993,N,12118.86023,W,0.146,,,,,A")).unwrap();
+        assert_eq!(gps.update(rmc), None);
+        assert_eq!(gps.utc_micros(), None);
+        let rmc = parse(&sentence(b"GPRMC,000001.00,A,4404.13993,N,12118.86023,W,0.146,,110117,,,A")).unwrap();
+        assert_eq!(gps.update(rmc), Some(1_484_092_801_000_000));
+    }
+
+    #[test]
+    fn malformed() {
+        let cases: [(&[u8], Error); 9] = [
+            (b"$GPZDA,201530.00,04,07,2002,00,00*61", Error::Checksum { computed: 0x60, received: 0x61 }),
+            (b"$GPZDA,201530.00,04,07,2002,00,00", Error::NoChecksum),
+            (b"$GPZDA,201530.00,04,07,2002,00,00*6", Error::NoChecksum),
+            (b"$GPZDA,201530.00,04,07,2002,00,00*6G", Error::Format),
+            (b"GPZDA,201530.00,04,07,2002,00,00*60", Error::Format),
+            (&sentence(b"GPZDA,251530.00,04,07,2002,00,00"), Error::Format),
+            (&sentence(b"GPRMC,001031.00,A,4404.13993,X,12118.86023,W,0.146,,100117,,,A"), Error::Format),
+            (&sentence(b"GPRMC,001031.00,A,4404.13993,N,12118.86023,W,0.146,,101317,,,A"), Error::Format),
+            (&sentence(b"GP,001031.00"), Error::Format),
+        ];
+        for (line, error) in cases {
+            assert_eq!(parse(line), Err(error), "{}", String::from_utf8_lossy(line));
+        }
+    }
+
+    #[test]
+    fn not_ascii() {
+        // a correct checksum does not make it a sentence, and multi-byte characters must not
+        // end up in the middle of a field that is cut up by position
+        let lines: [&[u8]; 5] = [
+            "G\u{e9}ZDA,201530.00,04,07,2002,00,00".as_bytes(),
+            "GPZDA,1\u{e9}530.00,04,07,2002,00,00".as_bytes(),
+            "GPRMC,001031.00,A,4404.13993,N,12118.86023,W,0.146,,1\u{e9}017,,,A".as_bytes(),
+            "\u{1f6f0}A,001031.00".as_bytes(),
+            b"GPZDA,201530.00,04,07,2002,00,\xff\xfe",
+        ];
+        for data in lines {
+            assert_eq!(parse(&sentence(data)), Err(Error::Format), "{}", String::from_utf8_lossy(data));
+        }
+        // and the parser carries on after them
+        let mut parser = Parser::new();
+        let mut bytes = sentence(lines[0]);
+        bytes.extend_from_slice(b"$GPZDA,201530.00,04,07,2002,00,00*60\r\n");
+        let sentences = feed(&mut parser, &bytes);
+        assert_eq!(sentences[0], Err(Error::Format));
+        assert!(matches!(sentences[1], Ok(Sentence::Zda(_))));
+    }
+
+    #[test]
+    fn truncated() {
+        // a sentence cut off by the next one
+        let mut parser = Parser::new();
+        let sentences = feed(&mut parser, b"$GNGGA,001031.00,4404.139$GPZDA,201530.00,04,07,2002,00,00*60\r\n");
+        assert_eq!(sentences.len(), 1);
+        assert!(matches!(sentences[0], Ok(Sentence::Zda(_))));
+
+        // every prefix of every recorded sentence, with and without a fixed up checksum
+        for line in RECORDED.split_inclusive(|&b| b == b'\n') {
+            let line = trim_line_end(line);
+            for end in 0..line.len() {
+                assert!(parse(&line[..end]).is_err());
+                // missing fields at the end read as empty, whatever comes out it must not panic
+                parse(&sentence(&line[1..end.max(1)])).ok();
+            }
+        }
+    }
+
+    #[test]
+    fn noise_and_long_lines() {
+        let mut parser = Parser::new();
+        let mut bytes = Vec::from(&b"\xff\x00\r\njunk\n"[..]);
+        bytes.extend_from_slice(b"$GPGGA,");
+        bytes.extend_from_slice(&[b'1'; 2 * MAX_SENTENCE_LEN]);
+        bytes.extend_from_slice(b"*00\r\n$GPZDA,201530.00,04,07,2002,00,00*60\r\n");
+        let sentences = feed(&mut parser, &bytes);
+        assert_eq!(sentences.len(), 2);
+        assert_eq!(sentences[0], Err(Error::TooLong));
+        assert!(matches!(sentences[1], Ok(Sentence::Zda(_))));
+    }
+}
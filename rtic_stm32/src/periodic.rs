@@ -0,0 +1,235 @@
+//! Drift-free periodic releases
+//!
+//! A task that re-arms itself with `spawn_after(period)` at the end of its body comes back one
+//! period after it *finished*, so its own run time and every bit of scheduling latency add up
+//! and the task slowly walks away from its nominal rate. [`Periodic`] keeps the releases on a
+//! fixed grid instead, `phase + k * period` from the monotonic's zero, and the task re-arms
+//! with `spawn_at` on the next point of it:
+//!
+//! ``` ignore
+//! #[task(local = [periodic])]
+//! fn blink(ctx: blink::Context) {
+//!     // ... the work ...
+//!     let next = ctx.local.periodic.next(monotonics::now());
+//!     blink::spawn_at(next).ok();
+//! }
+//! ```
+//!
+//! A job that is still running when its next release comes due is an overrun. It is counted,
+//! and what happens next is up to [`Overrun`]: the releases that were missed are either
+//! dropped, so the task stays on the grid, or run back to back until it has caught up.
+//!
+//! Tasks whose periods share a grid (the same zero, periods that are multiples of each other)
+//! stay phase-aligned for as long as they run: a 100 ms task with phase 0 and a 1 s task with
+//! phase 50 ms never come due at the same instant.
+
+use fugit::{TimerDurationU64, TimerInstantU64};
+
+/// What to do with releases that came due while the previous job was still running
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Overrun {
+    /// Drop them and go on with the first release still ahead, on the same grid
+    Skip,
+    /// Release them anyway, one job right after the other until the task is back on time
+    CatchUp,
+}
+
+/// Counters of a periodic task
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, defmt::Format)]
+pub struct Stats {
+    /// Jobs that finished, i.e. calls to `next`
+    pub releases: u32,
+    /// Jobs that finished after the next release was due
+    pub overruns: u32,
+    /// Releases dropped by `Overrun::Skip`
+    pub skipped: u32,
+    /// The longest from a release to the end of its job, in ticks
+    pub worst_response: u64,
+}
+
+/// Release times of a task on the grid `phase + k * period`, at `HZ` ticks per second
+#[derive(Debug, Clone, Copy)]
+pub struct Periodic<const HZ: u32> {
+    period: u64,
+    phase: u64,
+    overrun: Overrun,
+    /// The release of the job that is running (or is about to), in ticks
+    release: u64,
+    stats: Stats,
+}
+
+impl<const HZ: u32> Periodic<HZ> {
+    /// Releases at every multiple of `period`, skipping the ones an overrun missed
+    pub fn new(period: impl Into<TimerDurationU64<HZ>>) -> Self {
+        let period = period.into().ticks();
+        assert!(period > 0, "a period of zero ticks");
+        Periodic {
+            period,
+            phase: 0,
+            overrun: Overrun::Skip,
+            release: 0,
+            stats: Stats::default(),
+        }
+    }
+
+    /// Moves the grid `phase` after the multiples of the period, to keep tasks with the same
+    /// period (or multiples of it) out of each other's way
+    pub fn phase(mut self, phase: impl Into<TimerDurationU64<HZ>>) -> Self {
+        self.phase = phase.into().ticks() % self.period;
+        self.release = self.phase;
+        self
+    }
+
+    pub fn on_overrun(mut self, overrun: Overrun) -> Self {
+        self.overrun = overrun;
+        self
+    }
+
+    /// The first release at or after `now`, for a task that is started after `init`
+    pub fn start(&mut self, now: TimerInstantU64<HZ>) -> TimerInstantU64<HZ> {
+        self.release = self.grid_at_or_after(now.ticks());
+        self.instant()
+    }
+
+    /// When the job that is running now was released; before the first job, the phase, which
+    /// is what `init` spawns the task at since the monotonic starts at zero
+    pub fn release(&self) -> TimerInstantU64<HZ> {
+        self.instant()
+    }
+
+    /// The next release, to be called when the job is done at `now`
+    ///
+    /// The job is an overrun if `now` is already past the next release. The instant returned
+    /// then is in the past with `Overrun::CatchUp` (so `spawn_at` runs the task right away) and
+    /// the first grid point after `now` with `Overrun::Skip`.
+    pub fn next(&mut self, now: TimerInstantU64<HZ>) -> TimerInstantU64<HZ> {
+        let now = now.ticks();
+        let stats = &mut self.stats;
+        stats.releases = stats.releases.wrapping_add(1);
+        stats.worst_response = stats.worst_response.max(now.saturating_sub(self.release));
+
+        let next = self.release + self.period;
+        if now > next {
+            stats.overruns = stats.overruns.wrapping_add(1);
+            if self.overrun == Overrun::Skip {
+                let missed = (now - next) / self.period + 1;
+                stats.skipped = stats.skipped.wrapping_add(missed as u32);
+                self.release = next + missed * self.period;
+                return self.instant();
+            }
+        }
+        self.release = next;
+        self.instant()
+    }
+
+    /// How late the job that is running was started, i.e. `now - release()` at its start
+    pub fn lateness(&self, now: TimerInstantU64<HZ>) -> TimerDurationU64<HZ> {
+        TimerDurationU64::from_ticks(now.ticks().saturating_sub(self.release))
+    }
+
+    pub fn period(&self) -> TimerDurationU64<HZ> {
+        TimerDurationU64::from_ticks(self.period)
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    fn grid_at_or_after(&self, ticks: u64) -> u64 {
+        if ticks <= self.phase {
+            return self.phase;
+        }
+        let k = (ticks - self.phase).div_ceil(self.period);
+        self.phase + k * self.period
+    }
+
+    fn instant(&self) -> TimerInstantU64<HZ> {
+        TimerInstantU64::from_ticks(self.release)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use fugit::ExtU32;
+
+    const HZ: u32 = 1_000;
+
+    fn at(ticks: u64) -> TimerInstantU64<HZ> {
+        TimerInstantU64::from_ticks(ticks)
+    }
+
+    #[test]
+    fn jitter_does_not_drift() {
+        let mut periodic = Periodic::<HZ>::new(100u32.millis()).phase(30u32.millis());
+        let start = periodic.start(at(1234)).ticks();
+        assert_eq!(start, 1330);
+        // woken up late by this much, then running for 20 ticks
+        let late = [0, 57, 3, 79, 12, 0, 41];
+        let mut release = start;
+        for (n, late) in (1..).zip(late) {
+            assert_eq!(periodic.lateness(at(release + late)).ticks(), late);
+            release = periodic.next(at(release + late + 20)).ticks();
+            assert_eq!(release, start + n * 100);
+        }
+        assert_eq!(periodic.stats(), Stats { releases: 7, worst_response: 99, ..Stats::default() });
+
+        // a long run of pseudo-random wakeups, up to 79 late
+        let mut seed = 1u32;
+        for n in 8..10_000 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            release = periodic.next(at(release + (seed >> 16) as u64 % 60 + 20)).ticks();
+            assert_eq!(release, start + n * 100);
+        }
+        assert_eq!(periodic.stats().overruns, 0);
+    }
+
+    #[test]
+    fn ending_on_the_next_release_is_not_an_overrun() {
+        let mut periodic = Periodic::<HZ>::new(100u32.millis());
+        assert_eq!(periodic.next(at(100)).ticks(), 100);
+        assert_eq!(periodic.stats().overruns, 0);
+    }
+
+    #[test]
+    fn skip_stays_on_the_grid() {
+        let mut periodic = Periodic::<HZ>::new(100u32.millis()).phase(30u32.millis());
+        periodic.start(at(0));
+        // released at 30, done at 250: 130 and 230 are missed
+        assert_eq!(periodic.next(at(250)).ticks(), 330);
+        assert_eq!(periodic.stats().skipped, 2);
+        // done right on the release after 330
+        assert_eq!(periodic.next(at(430)).ticks(), 430);
+        // one period and then some late: 530 and 630 missed
+        assert_eq!(periodic.next(at(631)).ticks(), 730);
+        assert_eq!(periodic.next(at(760)).ticks(), 830);
+        assert_eq!(periodic.stats(), Stats { releases: 4, overruns: 2, skipped: 4, worst_response: 220 });
+    }
+
+    #[test]
+    fn catch_up_stays_on_the_grid() {
+        let mut periodic = Periodic::<HZ>::new(100u32.millis()).phase(30u32.millis()).on_overrun(Overrun::CatchUp);
+        periodic.start(at(0));
+        // the releases that were missed come back in the past, one by one
+        assert_eq!(periodic.next(at(250)).ticks(), 130);
+        assert_eq!(periodic.next(at(260)).ticks(), 230);
+        assert_eq!(periodic.next(at(270)).ticks(), 330);
+        // and once the task is on time it is back on 30 + n * 100
+        assert_eq!(periodic.next(at(340)).ticks(), 430);
+        assert_eq!(periodic.next(at(450)).ticks(), 530);
+        assert_eq!(periodic.stats(), Stats { releases: 5, overruns: 2, skipped: 0, worst_response: 220 });
+    }
+
+    #[test]
+    fn start_and_phase() {
+        let mut periodic = Periodic::<HZ>::new(1u32.secs()).phase(1250u32.millis());
+        // a phase longer than the period wraps around
+        assert_eq!(periodic.release().ticks(), 250);
+        assert_eq!(periodic.start(at(0)).ticks(), 250);
+        assert_eq!(periodic.start(at(250)).ticks(), 250);
+        assert_eq!(periodic.start(at(251)).ticks(), 1250);
+        assert_eq!(periodic.start(at(3000)).ticks(), 3250);
+    }
+}
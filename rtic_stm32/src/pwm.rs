@@ -0,0 +1,383 @@
+//! PWM outputs for H-bridges (magnetorquers, motors) and plain channels (LED dimming)
+//!
+//! Two kinds of bridge are supported:
+//!
+//! - `Tim1Bridge`: TIM1 drives the two legs of a bridge made of half bridges with separate high
+//!   and low side inputs, CH1/CH1N for leg A and CH2/CH2N for leg B. The timer puts in the dead
+//!   time between a leg's high and low side, and its break input (BKIN) turns every output off
+//!   in hardware on a fault.
+//! - `Tim3Pwm`: TIM3 drives bridge chips with two logic inputs (DRV8833, DRV8871 and the like),
+//!   CH1/CH2 for one bridge and CH3/CH4 for another, or any channel as plain PWM.
+//!
+//! Duty cycles are signed, -1.0 to 1.0, the sign is the direction. A bridge runs sign-magnitude:
+//! one leg switches while the other stays low, 0 is both low.
+//!
+//! Pins are set to their alternate function by the caller:
+//!
+//! ```text
+//! TIM1 (AF1)  CH1 PA8  CH1N PB13  CH2 PA9  CH2N PB14  BKIN PB12 (active low)
+//! TIM3 (AF2)  CH1 PB4  CH2 PB5    CH3 PC8  CH4 PC9
+//! ```
+
+use stm32f4xx_hal::pac::{RCC, TIM1, TIM3};
+
+/// Copper resistance goes up by this much per °C
+pub const COPPER_TEMPCO: f32 = 0.003_93;
+
+/// Prescaler and auto reload for a PWM frequency, the reload as large as it can be while a
+/// compare of reload + 1, which is 100 %, still fits in 16 bits
+pub fn timer_setup(timer_clk_hz: u32, pwm_hz: u32) -> (u16, u16) {
+    let ticks = (timer_clk_hz / pwm_hz.max(1)).max(2);
+    let prescaler = (ticks - 1) / 65_535;
+    // a frequency above the timer clock gives the fastest the timer can do
+    let reload = (timer_clk_hz / ((prescaler + 1) * pwm_hz.max(1))).saturating_sub(1);
+    (prescaler as u16, reload.clamp(1, 65_534) as u16)
+}
+
+/// The DTG bits of TIM1_BDTR for at least `ns` of dead time, saturates at 1008 timer ticks
+pub fn dead_time_bits(timer_clk_hz: u32, ns: u32) -> u8 {
+    let ticks = (ns as u64 * timer_clk_hz as u64).div_ceil(1_000_000_000) as u32;
+    match ticks {
+        0..=127 => ticks as u8,
+        128..=254 => 0b1000_0000 | (ticks.div_ceil(2) - 64) as u8,
+        255..=504 => 0b1100_0000 | (ticks.div_ceil(8).max(32) - 32) as u8,
+        505..=1008 => 0b1110_0000 | (ticks.div_ceil(16).max(32) - 32) as u8,
+        _ => 0xFF,
+    }
+}
+
+/// Compare values of leg A and leg B for a signed duty cycle, 100 % is reload + 1. With a
+/// reload of 65_535, which `timer_setup` never gives, 100 % can't be had and saturates just below.
+pub fn sign_magnitude(duty: f32, reload: u16) -> (u16, u16) {
+    let duty = duty.clamp(-1.0, 1.0);
+    let compare = ((reload as f32 + 1.0) * duty.abs() + 0.5) as u32;
+    let compare = compare.min(reload as u32 + 1).min(u16::MAX as u32) as u16;
+    if duty >= 0.0 {
+        (compare, 0)
+    } else {
+        (0, compare)
+    }
+}
+
+/// A magnetorquer, a coil whose magnetic dipole is proportional to its current
+///
+/// With the PWM much faster than the coil's L/R time constant the current follows the average
+/// voltage, `duty * supply / R`. R changes noticeably with temperature, so that is corrected.
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub struct Magnetorquer {
+    /// A·m² per A, turns times area for an air coil, more with a core
+    pub dipole_per_amp: f32,
+    /// Ω at 20 °C
+    pub resistance: f32,
+    /// Duty cycles below this are not worth switching for and give 0
+    pub min_duty: f32,
+}
+
+/// Duty for a dipole and whether the dipole was more than the coil can do
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub struct TorquerCommand {
+    pub duty: f32,
+    pub saturated: bool,
+}
+
+impl Magnetorquer {
+    fn resistance_at(&self, temperature: f32) -> f32 {
+        self.resistance * (1.0 + COPPER_TEMPCO * (temperature - 20.0))
+    }
+
+    /// Largest dipole in A·m² at a supply voltage and coil temperature
+    pub fn max_dipole(&self, supply_volts: f32, temperature: f32) -> f32 {
+        supply_volts / self.resistance_at(temperature) * self.dipole_per_amp
+    }
+
+    /// The duty cycle for a dipole in A·m², the sign is the direction
+    pub fn command(&self, dipole: f32, supply_volts: f32, temperature: f32) -> TorquerCommand {
+        let max = self.max_dipole(supply_volts, temperature);
+        if max <= 0.0 {
+            return TorquerCommand {
+                duty: 0.0,
+                saturated: dipole != 0.0,
+            };
+        }
+        let duty = dipole / max;
+        let saturated = duty.abs() > 1.0;
+        let duty = duty.clamp(-1.0, 1.0);
+        TorquerCommand {
+            duty: if duty.abs() < self.min_duty { 0.0 } else { duty },
+            saturated,
+        }
+    }
+}
+
+// TIM register bits
+const CR1_CEN: u32 = 1 << 0;
+const CR1_ARPE: u32 = 1 << 7;
+const EGR_UG: u32 = 1 << 0;
+const PWM_MODE_1: u32 = 0b110 << 4 | 1 << 3; // OCxM and OCxPE of one channel in CCMRx
+const BDTR_OSSI: u32 = 1 << 10;
+const BDTR_OSSR: u32 = 1 << 11;
+const BDTR_BKE: u32 = 1 << 12;
+const BDTR_MOE: u32 = 1 << 15;
+const SR_BIF: u32 = 1 << 7;
+const DIER_BIE: u32 = 1 << 7;
+
+fn rcc() -> &'static stm32f4xx_hal::pac::rcc::RegisterBlock {
+    // Safety: only the enable bits of TIM1 and TIM3 are touched
+    unsafe { &*RCC::ptr() }
+}
+
+/// One H-bridge on TIM1 with complementary outputs, dead time and the break input
+pub struct Tim1Bridge {
+    tim: TIM1,
+    reload: u16,
+    duty: f32,
+}
+
+impl Tim1Bridge {
+    /// Starts with both legs low and the outputs off, `arm` turns them on
+    pub fn new(tim: TIM1, timer_clk_hz: u32, pwm_hz: u32, dead_time_ns: u32) -> Self {
+        rcc().apb2enr.modify(|r, w| unsafe { w.bits(r.bits() | 1 << 0) });
+
+        let (prescaler, reload) = timer_setup(timer_clk_hz, pwm_hz);
+        tim.cr1.write(|w| unsafe { w.bits(0) });
+        tim.psc.write(|w| unsafe { w.bits(prescaler as u32) });
+        tim.arr.write(|w| unsafe { w.bits(reload as u32) });
+        tim.ccr1.write(|w| unsafe { w.bits(0) });
+        tim.ccr2.write(|w| unsafe { w.bits(0) });
+        tim.ccmr1_output().write(|w| unsafe { w.bits(PWM_MODE_1 | PWM_MODE_1 << 8) });
+        // CH1, CH1N, CH2, CH2N enabled, all active high, all idle low
+        tim.ccer.write(|w| unsafe { w.bits(1 << 0 | 1 << 2 | 1 << 4 | 1 << 6) });
+        tim.cr2.write(|w| unsafe { w.bits(0) });
+        // break input active low, outputs driven to their idle level while off
+        tim.bdtr.write(|w| unsafe {
+            w.bits(BDTR_OSSI | BDTR_OSSR | BDTR_BKE | dead_time_bits(timer_clk_hz, dead_time_ns) as u32)
+        });
+        tim.sr.write(|w| unsafe { w.bits(0) });
+        tim.dier.write(|w| unsafe { w.bits(DIER_BIE) });
+        tim.egr.write(|w| unsafe { w.bits(EGR_UG) });
+        tim.cr1.write(|w| unsafe { w.bits(CR1_ARPE | CR1_CEN) });
+
+        Tim1Bridge {
+            tim,
+            reload,
+            duty: 0.0,
+        }
+    }
+
+    /// Turn the outputs on, fails while the break input is still active
+    pub fn arm(&mut self) -> bool {
+        self.tim.sr.modify(|r, w| unsafe { w.bits(r.bits() & !SR_BIF) });
+        self.tim.bdtr.modify(|r, w| unsafe { w.bits(r.bits() | BDTR_MOE) });
+        self.is_armed()
+    }
+
+    pub fn is_armed(&self) -> bool {
+        self.tim.bdtr.read().bits() & BDTR_MOE != 0
+    }
+
+    /// All switches off right away, the break input does the same in hardware
+    pub fn safe_off(&mut self) {
+        self.tim.bdtr.modify(|r, w| unsafe { w.bits(r.bits() & !BDTR_MOE) });
+        self.set(0.0);
+    }
+
+    /// Call from TIM1_BRK_TIM9, true if the break input tripped
+    pub fn on_break(&mut self) -> bool {
+        let tripped = self.tim.sr.read().bits() & SR_BIF != 0;
+        if tripped {
+            self.tim.sr.modify(|r, w| unsafe { w.bits(r.bits() & !SR_BIF) });
+            self.set(0.0);
+        }
+        tripped
+    }
+
+    pub fn duty(&self) -> f32 {
+        self.duty
+    }
+
+    /// Signed duty cycle, takes effect at the next PWM period
+    pub fn set(&mut self, duty: f32) {
+        let (a, b) = sign_magnitude(duty, self.reload);
+        self.tim.ccr1.write(|w| unsafe { w.bits(a as u32) });
+        self.tim.ccr2.write(|w| unsafe { w.bits(b as u32) });
+        self.duty = duty.clamp(-1.0, 1.0);
+    }
+
+    pub fn free(mut self) -> TIM1 {
+        self.safe_off();
+        self.tim
+    }
+}
+
+/// A pair of TIM3 channels driving the two inputs of a bridge chip
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Pair {
+    Ch1Ch2,
+    Ch3Ch4,
+}
+
+/// TIM3, four PWM channels with the same frequency
+pub struct Tim3Pwm {
+    tim: TIM3,
+    reload: u16,
+}
+
+impl Tim3Pwm {
+    /// All four channels start at 0
+    pub fn new(tim: TIM3, timer_clk_hz: u32, pwm_hz: u32) -> Self {
+        rcc().apb1enr.modify(|r, w| unsafe { w.bits(r.bits() | 1 << 1) });
+
+        let (prescaler, reload) = timer_setup(timer_clk_hz, pwm_hz);
+        tim.cr1.write(|w| unsafe { w.bits(0) });
+        tim.psc.write(|w| unsafe { w.bits(prescaler as u32) });
+        tim.arr.write(|w| unsafe { w.bits(reload as u32) });
+        let mut pwm = Tim3Pwm { tim, reload };
+        pwm.safe_off();
+        pwm.tim.ccmr1_output().write(|w| unsafe { w.bits(PWM_MODE_1 | PWM_MODE_1 << 8) });
+        pwm.tim.ccmr2_output().write(|w| unsafe { w.bits(PWM_MODE_1 | PWM_MODE_1 << 8) });
+        pwm.tim.ccer.write(|w| unsafe { w.bits(1 << 0 | 1 << 4 | 1 << 8 | 1 << 12) });
+        pwm.tim.egr.write(|w| unsafe { w.bits(EGR_UG) });
+        pwm.tim.cr1.write(|w| unsafe { w.bits(CR1_ARPE | CR1_CEN) });
+        pwm
+    }
+
+    /// Duty cycle of one channel, 0.0 to 1.0, `channel` counts from 1
+    pub fn set_duty(&mut self, channel: u8, duty: f32) {
+        let (compare, _) = sign_magnitude(duty.max(0.0), self.reload);
+        self.set_compare(channel, compare);
+    }
+
+    /// Signed duty cycle of the bridge on a pair of channels
+    pub fn set_bridge(&mut self, pair: Pair, duty: f32) {
+        let (a, b) = sign_magnitude(duty, self.reload);
+        let first = match pair {
+            Pair::Ch1Ch2 => 1,
+            Pair::Ch3Ch4 => 3,
+        };
+        self.set_compare(first, a);
+        self.set_compare(first + 1, b);
+    }
+
+    fn set_compare(&mut self, channel: u8, compare: u16) {
+        let compare = compare as u32;
+        match channel {
+            1 => self.tim.ccr1.write(|w| unsafe { w.bits(compare) }),
+            2 => self.tim.ccr2.write(|w| unsafe { w.bits(compare) }),
+            3 => self.tim.ccr3.write(|w| unsafe { w.bits(compare) }),
+            4 => self.tim.ccr4.write(|w| unsafe { w.bits(compare) }),
+            _ => {}
+        }
+    }
+
+    /// Every output low, which lets a bridge chip coast
+    pub fn safe_off(&mut self) {
+        for channel in 1..=4 {
+            self.set_compare(channel, 0);
+        }
+        // load the compare values now rather than at the end of the period
+        self.tim.egr.write(|w| unsafe { w.bits(EGR_UG) });
+    }
+
+    pub fn free(mut self) -> TIM3 {
+        self.safe_off();
+        self.tim
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-4
+    }
+
+    #[test]
+    fn timer_setup_frequencies() {
+        assert_eq!(timer_setup(48_000_000, 20_000), (0, 2_399));
+        assert_eq!(timer_setup(48_000_000, 100), (7, 59_999));
+        // the largest reload that still leaves room for 100 %
+        assert_eq!(timer_setup(65_535_000, 1_000), (0, 65_534));
+        assert_eq!(timer_setup(65_536_000, 1_000), (1, 32_767));
+        // faster than the timer, or no frequency at all
+        assert_eq!(timer_setup(1_000_000, 2_000_000), (0, 1));
+        assert_eq!(timer_setup(16_000_000, 0), (244, 65_305));
+    }
+
+    #[test]
+    fn dead_time() {
+        // 180 MHz, 5.56 ns a tick
+        assert_eq!(dead_time_bits(180_000_000, 0), 0);
+        assert_eq!(dead_time_bits(180_000_000, 500), 90);
+        assert_eq!(dead_time_bits(180_000_000, 1_000), 0b1000_0000 | 26); // (64 + 26) * 2 = 180
+        assert_eq!(dead_time_bits(180_000_000, 2_000), 0b1100_0000 | 13); // (32 + 13) * 8 = 360
+        assert_eq!(dead_time_bits(180_000_000, 5_000), 0b1110_0000 | 25); // (32 + 25) * 16 = 912
+        assert_eq!(dead_time_bits(180_000_000, 10_000), 0xFF);
+
+        // back to ticks the way the timer reads DTG, never less than asked for and at most one step more
+        let ticks = |bits: u8| match bits >> 5 {
+            0..=3 => bits as u32,
+            4 | 5 => (64 + (bits & 0x3F) as u32) * 2,
+            6 => (32 + (bits & 0x1F) as u32) * 8,
+            _ => (32 + (bits & 0x1F) as u32) * 16,
+        };
+        for wanted in 0..=1008 {
+            let got = ticks(dead_time_bits(1_000_000_000, wanted));
+            let step = match wanted {
+                0..=127 => 1,
+                128..=254 => 2,
+                255..=504 => 8,
+                _ => 16,
+            };
+            assert!(got >= wanted && got < wanted + step, "{wanted} ticks gave {got}");
+        }
+    }
+
+    #[test]
+    fn sign_magnitude_legs() {
+        assert_eq!(sign_magnitude(0.5, 999), (500, 0));
+        assert_eq!(sign_magnitude(-0.25, 999), (0, 250));
+        assert_eq!(sign_magnitude(0.0, 999), (0, 0));
+        // 100 % is a compare past the reload, more than that is clamped
+        assert_eq!(sign_magnitude(1.0, 999), (1000, 0));
+        assert_eq!(sign_magnitude(2.0, 999), (1000, 0));
+        assert_eq!(sign_magnitude(-3.0, 999), (0, 1000));
+        assert_eq!(sign_magnitude(1.0, 65_534), (65_535, 0));
+        // does not wrap to 0
+        assert_eq!(sign_magnitude(1.0, 65_535), (65_535, 0));
+        assert_eq!(sign_magnitude(-1.0, 65_535), (0, 65_535));
+    }
+
+    #[test]
+    fn magnetorquer_command() {
+        // 0.25 A·m² at 5 V and 20 °C
+        let torquer = Magnetorquer {
+            dipole_per_amp: 2.0,
+            resistance: 40.0,
+            min_duty: 0.05,
+        };
+        assert!(close(torquer.max_dipole(5.0, 20.0), 0.25));
+        let command = torquer.command(0.125, 5.0, 20.0);
+        assert!(close(command.duty, 0.5) && !command.saturated);
+        let command = torquer.command(-0.1, 5.0, 20.0);
+        assert!(close(command.duty, -0.4) && !command.saturated);
+
+        // more than the coil can do
+        assert_eq!(torquer.command(-0.5, 5.0, 20.0), TorquerCommand { duty: -1.0, saturated: true });
+        assert_eq!(torquer.command(0.25, 5.0, 20.0), TorquerCommand { duty: 1.0, saturated: false });
+
+        // too little to bother, 4 %
+        assert_eq!(torquer.command(0.01, 5.0, 20.0), TorquerCommand { duty: 0.0, saturated: false });
+        assert_eq!(torquer.command(-0.01, 5.0, 20.0), TorquerCommand { duty: 0.0, saturated: false });
+
+        // 47.86 Ω at 70 °C, 32.14 Ω at -30 °C
+        assert!(close(torquer.command(0.1, 5.0, 70.0).duty, 0.1 / (5.0 / 47.86 * 2.0)));
+        let command = torquer.command(0.3, 5.0, -30.0);
+        assert!(close(command.duty, 0.3 / (5.0 / 32.14 * 2.0)) && !command.saturated);
+        assert!(torquer.command(0.3, 5.0, 20.0).saturated);
+
+        // no supply
+        assert_eq!(torquer.command(0.1, 0.0, 20.0), TorquerCommand { duty: 0.0, saturated: true });
+        assert_eq!(torquer.command(0.0, 0.0, 20.0), TorquerCommand { duty: 0.0, saturated: false });
+    }
+}
@@ -0,0 +1,173 @@
+//! Real-time clock running from the LSE crystal
+//!
+//! The RTC sits in the backup domain, so it keeps counting through resets (and through power
+//! loss with a VBAT battery). It runs on TAI, see `time.rs`, with its calendar at 2000-01-01
+//! for `TaiTime(0)`. `Rtc::new` only sets it up if it is not running already.
+
+use crate::time::{Clock, DateTime, SmoothCalibration, TaiTime, MICROS_PER_SEC};
+use stm32f4xx_hal::pac::{PWR, RCC, RTC};
+
+/// Prescalers for a 32.768 kHz crystal, (127 + 1) * (255 + 1) = 32768
+const PREDIV_A: u32 = 127;
+const PREDIV_S: u32 = 255;
+
+// Register bits, the pac field names differ between chip families
+const ISR_INIT: u32 = 1 << 7;
+const ISR_INITF: u32 = 1 << 6;
+const ISR_RSF: u32 = 1 << 5;
+const ISR_SHPF: u32 = 1 << 3;
+const ISR_RECALPF: u32 = 1 << 16;
+const CALR_CALP: u32 = 1 << 15;
+const SHIFTR_ADD1S: u32 = 1 << 31;
+
+/// The RTC, counting TAI
+pub struct Rtc {
+    rtc: RTC,
+}
+
+impl Rtc {
+    /// Start the LSE and the RTC, a clock that is already running is left alone
+    pub fn new(rtc: RTC, pwr: &mut PWR) -> Self {
+        // Safety: only the power enable and the backup domain bits are touched, nothing the HAL
+        // keeps track of
+        let rcc = unsafe { &*RCC::ptr() };
+        rcc.apb1enr.modify(|_, w| w.pwren().set_bit());
+        pwr.cr.modify(|_, w| w.dbp().set_bit()); // backup domain write access
+
+        let mut rtc = Rtc { rtc };
+        let bdcr = rcc.bdcr.read();
+        if bdcr.rtcen().bit_is_set() && bdcr.rtcsel().is_lse() {
+            // the shadow registers are stale after a reset until the next RTC clock edge
+            rtc.wait_sync();
+            defmt::info!("rtc: running, {}", DateTime::from_secs(rtc.now().secs()));
+            return rtc;
+        }
+
+        defmt::warn!("rtc: not running, starting it at 2000-01-01");
+        rcc.bdcr.modify(|_, w| w.bdrst().set_bit());
+        rcc.bdcr.modify(|_, w| w.bdrst().clear_bit());
+        rcc.bdcr.modify(|_, w| w.lseon().set_bit());
+        while rcc.bdcr.read().lserdy().bit_is_clear() {}
+        rcc.bdcr.modify(|_, w| w.rtcsel().lse().rtcen().set_bit());
+
+        rtc.init_mode(|rtc| {
+            rtc.prer.write(|w| unsafe { w.bits(PREDIV_A << 16 | PREDIV_S) });
+        });
+        rtc.set(TaiTime(0));
+        rtc
+    }
+
+    pub fn free(self) -> RTC {
+        self.rtc
+    }
+
+    /// Date and time as the RTC has them, on TAI
+    pub fn date_time(&mut self) -> DateTime {
+        self.read().0
+    }
+
+    fn unlock(&mut self) {
+        self.rtc.wpr.write(|w| unsafe { w.bits(0xCA) });
+        self.rtc.wpr.write(|w| unsafe { w.bits(0x53) });
+    }
+
+    fn lock(&mut self) {
+        self.rtc.wpr.write(|w| unsafe { w.bits(0xFF) });
+    }
+
+    /// Stop the calendar, run `f` and start it again
+    fn init_mode(&mut self, f: impl FnOnce(&RTC)) {
+        self.unlock();
+        self.rtc.isr.modify(|r, w| unsafe { w.bits(r.bits() | ISR_INIT) });
+        while self.rtc.isr.read().bits() & ISR_INITF == 0 {}
+        f(&self.rtc);
+        self.rtc.isr.modify(|r, w| unsafe { w.bits(r.bits() & !ISR_INIT) });
+        self.lock();
+        self.wait_sync();
+    }
+
+    /// Wait for the shadow registers to catch up after a write
+    fn wait_sync(&mut self) {
+        self.unlock();
+        self.rtc.isr.modify(|r, w| unsafe { w.bits(r.bits() & !ISR_RSF) });
+        self.lock();
+        while self.rtc.isr.read().bits() & ISR_RSF == 0 {}
+    }
+
+    /// Calendar and sub-second counter, read so that they belong together
+    fn read(&mut self) -> (DateTime, u32) {
+        // reading SSR locks TR and DR until DR is read
+        let ss = self.rtc.ssr.read().bits() & 0xFFFF;
+        let tr = self.rtc.tr.read().bits();
+        let dr = self.rtc.dr.read().bits();
+        let time = DateTime {
+            year: 2000 + bcd(dr >> 16) as u16,
+            month: bcd(dr >> 8 & 0x1F),
+            day: bcd(dr & 0x3F),
+            hour: bcd(tr >> 16 & 0x3F),
+            minute: bcd(tr >> 8 & 0x7F),
+            second: bcd(tr & 0x7F),
+        };
+        (time, ss)
+    }
+}
+
+impl Clock for Rtc {
+    fn now(&mut self) -> TaiTime {
+        let (time, ss) = self.read();
+        // after a shift SS can be above PREDIV_S for a moment, the fraction is then negative
+        let fraction = (PREDIV_S as i64 - ss as i64) * MICROS_PER_SEC as i64 / (PREDIV_S as i64 + 1);
+        TaiTime((time.to_secs() as i64 * MICROS_PER_SEC as i64 + fraction).max(0) as u64)
+    }
+
+    fn set(&mut self, time: TaiTime) {
+        let date = DateTime::from_secs(time.secs());
+        let tr = to_bcd(date.hour) << 16 | to_bcd(date.minute) << 8 | to_bcd(date.second);
+        let dr = to_bcd((date.year % 100) as u8) << 16
+            | (date.weekday() as u32) << 13
+            | to_bcd(date.month) << 8
+            | to_bcd(date.day);
+        self.init_mode(|rtc| {
+            rtc.tr.write(|w| unsafe { w.bits(tr) });
+            rtc.dr.write(|w| unsafe { w.bits(dr) });
+        });
+        if time.subsec_micros() != 0 {
+            self.shift(time.subsec_micros() as i32);
+        }
+    }
+
+    fn shift(&mut self, micros: i32) {
+        let micros = micros.clamp(-(MICROS_PER_SEC as i32) + 1, MICROS_PER_SEC as i32 - 1);
+        // SUBFS can only delay the clock, advancing is a whole second forward and then back
+        let (add1s, delay) = if micros > 0 {
+            (SHIFTR_ADD1S, MICROS_PER_SEC as i32 - micros)
+        } else {
+            (0, -micros)
+        };
+        let subfs = delay as u32 * (PREDIV_S + 1) / MICROS_PER_SEC as u32;
+
+        while self.rtc.isr.read().bits() & ISR_SHPF != 0 {}
+        self.unlock();
+        self.rtc.shiftr.write(|w| unsafe { w.bits(add1s | subfs) });
+        self.lock();
+        self.wait_sync();
+    }
+
+    fn calibrate(&mut self, ppm: f32) {
+        let calibration = SmoothCalibration::from_ppm(ppm);
+        let calr = if calibration.calp { CALR_CALP } else { 0 } | calibration.calm as u32;
+
+        while self.rtc.isr.read().bits() & ISR_RECALPF != 0 {}
+        self.unlock();
+        self.rtc.calr.write(|w| unsafe { w.bits(calr) });
+        self.lock();
+    }
+}
+
+fn bcd(value: u32) -> u8 {
+    ((value >> 4 & 0xF) * 10 + (value & 0xF)) as u8
+}
+
+fn to_bcd(value: u8) -> u32 {
+    ((value / 10) << 4 | value % 10) as u32
+}
@@ -0,0 +1,302 @@
+//! Status LED patterns
+//!
+//! Tasks say what the LED should show with `StatusLed::show`, a timer task calls `update` every
+//! `TICK_MS` with the monotonic time and the LED follows the pattern from there, so nothing
+//! blocks and no task has to know what the LED was doing before.
+//!
+//! Each pattern has a priority. A pattern replaces the one of the same priority and the highest
+//! one set is shown: a fault overrides an error code, which overrides the normal heartbeat.
+//! `clear` takes a pattern back down, e.g. when the fault is gone.
+//!
+//! The LED is either a plain pin (`Switched`) or a TIM3 PWM channel (`Dimmed`), only the
+//! second can breathe, a pin is on for the brighter half of the breath.
+
+use embedded_hal::digital::v2::OutputPin;
+
+use crate::pwm::Tim3Pwm;
+
+/// How often `update` should be called, often enough for breathing to look smooth
+pub const TICK_MS: u32 = 20;
+
+/// Blinks of an error code
+const CODE_ON_MS: u32 = 200;
+const CODE_OFF_MS: u32 = 300;
+/// Dark time after an error code before it repeats
+const CODE_PAUSE_MS: u32 = 1500;
+const BREATHE_PERIOD_MS: u32 = 3000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Pattern {
+    Off,
+    On,
+    /// 1 Hz, like blinky
+    Blink,
+    /// Two short pulses a second, everything is fine
+    Heartbeat,
+    /// Fades up and down, e.g. waiting in a low power mode
+    Breathe,
+    /// N blinks and a pause, over and over; 1 to 9 blinks
+    Error(u8),
+    /// Fast blinking, something needs attention now
+    Fault,
+}
+
+/// Which patterns override which, the highest one set is shown
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, defmt::Format)]
+pub enum Priority {
+    Status,
+    Error,
+    Fault,
+}
+
+impl Pattern {
+    pub fn priority(self) -> Priority {
+        match self {
+            Pattern::Off | Pattern::On | Pattern::Blink | Pattern::Heartbeat | Pattern::Breathe => Priority::Status,
+            Pattern::Error(_) => Priority::Error,
+            Pattern::Fault => Priority::Fault,
+        }
+    }
+
+    /// Brightness 0.0 to 1.0 at `ms` since the pattern started
+    pub fn brightness(self, ms: u32) -> f32 {
+        let on = match self {
+            Pattern::Off => false,
+            Pattern::On => true,
+            Pattern::Blink => ms % 1000 < 500,
+            Pattern::Heartbeat => matches!(ms % 1000, 0..=99 | 200..=299),
+            Pattern::Breathe => {
+                // a triangle, squared so it looks even to the eye
+                let phase = (ms % BREATHE_PERIOD_MS) as f32 / BREATHE_PERIOD_MS as f32;
+                let level = 1.0 - (2.0 * phase - 1.0).abs();
+                return level * level;
+            }
+            Pattern::Error(blinks) => {
+                let blinks = blinks.clamp(1, 9) as u32;
+                let blink = CODE_ON_MS + CODE_OFF_MS;
+                let t = ms % (blinks * blink + CODE_PAUSE_MS);
+                t < blinks * blink && t % blink < CODE_ON_MS
+            }
+            Pattern::Fault => ms % 200 < 100,
+        };
+        if on {
+            1.0
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Something that can light up
+pub trait Led {
+    /// 0.0 is off, 1.0 full brightness
+    fn set(&mut self, brightness: f32);
+}
+
+/// An LED on a pin, active high
+pub struct Switched<P>(pub P);
+
+impl<P: OutputPin> Led for Switched<P> {
+    fn set(&mut self, brightness: f32) {
+        if brightness >= 0.5 {
+            self.0.set_high().ok();
+        } else {
+            self.0.set_low().ok();
+        }
+    }
+}
+
+/// An LED on a TIM3 channel (PB4, PB5, PC8 or PC9), `channel` counts from 1
+pub struct Dimmed {
+    pub pwm: Tim3Pwm,
+    pub channel: u8,
+}
+
+impl Led for Dimmed {
+    fn set(&mut self, brightness: f32) {
+        self.pwm.set_duty(self.channel, brightness.clamp(0.0, 1.0));
+    }
+}
+
+pub struct StatusLed<L> {
+    led: L,
+    /// The pattern set for each priority
+    patterns: [Option<Pattern>; 3],
+    /// What is on the LED and since when, in ms
+    shown: Option<(Pattern, u32)>,
+}
+
+impl<L: Led> StatusLed<L> {
+    pub fn new(mut led: L) -> Self {
+        led.set(0.0);
+        StatusLed {
+            led,
+            patterns: [None; 3],
+            shown: None,
+        }
+    }
+
+    /// Set the pattern of its priority, it is on the LED from the next `update` if nothing
+    /// higher is set. Showing the pattern that is already shown does not restart it.
+    pub fn show(&mut self, pattern: Pattern) {
+        self.patterns[pattern.priority() as usize] = Some(pattern);
+    }
+
+    /// Take away the pattern of a priority
+    pub fn clear(&mut self, priority: Priority) {
+        self.patterns[priority as usize] = None;
+    }
+
+    /// The pattern that wins, `Off` when none is set
+    pub fn current(&self) -> Pattern {
+        self.patterns.iter().rev().flatten().next().copied().unwrap_or(Pattern::Off)
+    }
+
+    /// Set the LED for the time `now_ms`, which may wrap
+    pub fn update(&mut self, now_ms: u32) {
+        let pattern = self.current();
+        let since = match self.shown {
+            Some((shown, since)) if shown == pattern => since,
+            _ => {
+                // a new pattern starts from its beginning
+                self.shown = Some((pattern, now_ms));
+                now_ms
+            }
+        };
+        self.led.set(pattern.brightness(now_ms.wrapping_sub(since)));
+    }
+
+    pub fn free(mut self) -> L {
+        self.led.set(0.0);
+        self.led
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use std::{string::String, vec::Vec};
+
+    /// Remembers everything the LED was set to
+    struct Recorder(Vec<f32>);
+
+    impl Led for Recorder {
+        fn set(&mut self, brightness: f32) {
+            self.0.push(brightness);
+        }
+    }
+
+    /// The pattern every 100 ms, # for on
+    fn trace(pattern: Pattern, until: u32) -> String {
+        (0..until)
+            .step_by(100)
+            .map(|ms| if pattern.brightness(ms) > 0.5 { '#' } else { '.' })
+            .collect()
+    }
+
+    fn last(led: &StatusLed<Recorder>) -> f32 {
+        *led.led.0.last().unwrap()
+    }
+
+    #[test]
+    fn blinking_patterns() {
+        assert_eq!(trace(Pattern::Off, 1000), "..........");
+        assert_eq!(trace(Pattern::On, 1000), "##########");
+        assert_eq!(trace(Pattern::Blink, 2000), "#####.....#####.....");
+        assert_eq!(trace(Pattern::Heartbeat, 2000), "#.#.......#.#.......");
+        assert_eq!(trace(Pattern::Fault, 600), "#.#.#.");
+        // the edges of the heartbeat's pulses
+        let heartbeat: Vec<f32> = [0, 99, 100, 199, 200, 299, 300, 999, 1000]
+            .iter()
+            .map(|&ms| Pattern::Heartbeat.brightness(ms))
+            .collect();
+        assert_eq!(heartbeat, [1.0, 1.0, 0.0, 0.0, 1.0, 1.0, 0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn error_codes() {
+        // 200 ms on, 300 ms off per blink, then 1.5 s dark
+        assert_eq!(trace(Pattern::Error(3), 3500), "##...##...##..................##...");
+        let edges: Vec<f32> = [0, 199, 200, 499, 500, 699, 700, 999, 1000, 2499, 2500]
+            .iter()
+            .map(|&ms| Pattern::Error(2).brightness(ms))
+            .collect();
+        assert_eq!(edges, [1.0, 1.0, 0.0, 0.0, 1.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0]);
+        // out of range codes show as 1 and 9 blinks
+        assert_eq!(trace(Pattern::Error(0), 4000), trace(Pattern::Error(1), 4000));
+        assert_eq!(trace(Pattern::Error(12), 12_000), trace(Pattern::Error(9), 12_000));
+        assert_eq!(Pattern::Error(12).brightness(4000), 1.0);
+        assert_eq!(Pattern::Error(12).brightness(4500), 0.0);
+        assert_eq!(Pattern::Error(12).brightness(6000), 1.0);
+    }
+
+    #[test]
+    fn breathing() {
+        // a squared triangle over 3 s
+        let levels: Vec<f32> = [0, 750, 1500, 2250, 3000, 3750]
+            .iter()
+            .map(|&ms| Pattern::Breathe.brightness(ms))
+            .collect();
+        assert_eq!(levels, [0.0, 0.25, 1.0, 0.25, 0.0, 0.25]);
+        assert!((Pattern::Breathe.brightness(300) - 0.04).abs() < 1e-6);
+        assert!((Pattern::Breathe.brightness(1200) - Pattern::Breathe.brightness(1800)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn priorities() {
+        let mut led = StatusLed::new(Recorder(Vec::new()));
+        assert_eq!(led.led.0, [0.0]);
+        assert_eq!(led.current(), Pattern::Off);
+
+        led.show(Pattern::Heartbeat);
+        led.show(Pattern::Fault);
+        led.show(Pattern::Error(2));
+        assert_eq!(led.current(), Pattern::Fault);
+        // an error code replaces the one before it
+        led.show(Pattern::Error(4));
+        led.clear(Priority::Fault);
+        assert_eq!(led.current(), Pattern::Error(4));
+        led.clear(Priority::Error);
+        assert_eq!(led.current(), Pattern::Heartbeat);
+        // clearing what is not set changes nothing
+        led.clear(Priority::Fault);
+        assert_eq!(led.current(), Pattern::Heartbeat);
+        led.clear(Priority::Status);
+        assert_eq!(led.current(), Pattern::Off);
+    }
+
+    #[test]
+    fn update_follows_the_winner() {
+        let mut led = StatusLed::new(Recorder(Vec::new()));
+        led.show(Pattern::Error(2));
+        led.update(10_000);
+        led.update(10_200);
+        assert_eq!(led.led.0, [0.0, 1.0, 0.0]);
+
+        // a fault starts from its beginning whenever it comes, here just before the clock wraps
+        led.show(Pattern::Fault);
+        let start = u32::MAX - 50;
+        for ms in [0, 60, 100, 150, 200] {
+            led.update(start.wrapping_add(ms));
+        }
+        assert_eq!(led.led.0[3..], [1.0, 1.0, 0.0, 0.0, 1.0]);
+
+        // back to the error code, which starts over too
+        led.clear(Priority::Fault);
+        led.update(1_000);
+        assert_eq!(last(&led), 1.0);
+        led.update(1_250);
+        assert_eq!(last(&led), 0.0);
+        // showing it again does not restart it
+        led.show(Pattern::Error(2));
+        led.update(1_500);
+        assert_eq!(last(&led), 1.0);
+        led.update(1_700);
+        assert_eq!(last(&led), 0.0);
+
+        let led = led.free();
+        assert_eq!(led.0.last(), Some(&0.0));
+        assert_eq!(led.0.len(), 13);
+    }
+}
@@ -0,0 +1,580 @@
+//! Mission time
+//!
+//! Time is kept as TAI, which has no leap seconds, counted from 2000-01-01 00:00:00. The RTC
+//! runs on the same scale so it never has to jump. UTC is only worked out when something asks
+//! for it, using the TAI - UTC offset (37 s since 2017) that is kept in the configuration and
+//! can be changed by telecommand if another leap second is ever announced.
+//!
+//! Mission elapsed time (MET) is the time since the mission epoch, which is also in the
+//! configuration, so both survive a reset as long as the RTC keeps running on its LSE crystal.
+//!
+//! Everything in here is plain arithmetic, the RTC itself is behind the [`Clock`] trait.
+
+use core::ops::{Add, Sub};
+
+pub const MICROS_PER_SEC: u64 = 1_000_000;
+
+/// Seconds between 1970-01-01 (unix) and 2000-01-01
+pub const UNIX_TO_2000: u64 = 946_684_800;
+
+/// TAI - UTC since 2017-01-01
+pub const DEFAULT_LEAP_SECONDS: i32 = 37;
+
+/// An error smaller than this is slewed away with the RTC shift register instead of stepping
+pub const STEP_THRESHOLD_MICROS: i64 = 1_000_000;
+
+/// Two syncs closer than this are too close together to say anything about drift
+pub const MIN_DRIFT_INTERVAL_SECS: u64 = 600;
+
+/// A point in TAI, microseconds since 2000-01-01 00:00:00 TAI
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, defmt::Format)]
+pub struct TaiTime(pub u64);
+
+impl TaiTime {
+    pub const fn from_secs(secs: u64) -> Self {
+        TaiTime(secs * MICROS_PER_SEC)
+    }
+
+    pub const fn secs(self) -> u64 {
+        self.0 / MICROS_PER_SEC
+    }
+
+    pub const fn subsec_micros(self) -> u32 {
+        (self.0 % MICROS_PER_SEC) as u32
+    }
+
+    /// TAI from a UTC unix time in microseconds
+    pub fn from_unix_utc(unix_micros: u64, leap_seconds: i32) -> Self {
+        let micros = unix_micros as i64 - (UNIX_TO_2000 * MICROS_PER_SEC) as i64
+            + leap_seconds as i64 * MICROS_PER_SEC as i64;
+        TaiTime(micros.max(0) as u64)
+    }
+
+    /// UTC unix time in microseconds
+    pub fn to_unix_utc(self, leap_seconds: i32) -> u64 {
+        (self.0 as i64 + (UNIX_TO_2000 * MICROS_PER_SEC) as i64
+            - leap_seconds as i64 * MICROS_PER_SEC as i64) as u64
+    }
+
+    /// CCSDS unsegmented time code, 4 bytes of seconds and 2 bytes of 1/65536 s
+    pub fn to_cuc(self) -> [u8; 6] {
+        let secs = (self.secs() as u32).to_be_bytes();
+        let fine = ((self.subsec_micros() as u64 * 65_536 / MICROS_PER_SEC) as u16).to_be_bytes();
+        [secs[0], secs[1], secs[2], secs[3], fine[0], fine[1]]
+    }
+
+    pub fn from_cuc(cuc: [u8; 6]) -> Self {
+        let secs = u32::from_be_bytes([cuc[0], cuc[1], cuc[2], cuc[3]]) as u64;
+        let fine = u16::from_be_bytes([cuc[4], cuc[5]]) as u64;
+        TaiTime(secs * MICROS_PER_SEC + fine * MICROS_PER_SEC / 65_536)
+    }
+
+    /// Signed difference `self - earlier` in microseconds
+    pub fn micros_since(self, earlier: TaiTime) -> i64 {
+        self.0 as i64 - earlier.0 as i64
+    }
+}
+
+impl Add<u64> for TaiTime {
+    type Output = TaiTime;
+
+    /// Add microseconds
+    fn add(self, micros: u64) -> TaiTime {
+        TaiTime(self.0 + micros)
+    }
+}
+
+impl Sub for TaiTime {
+    type Output = u64;
+
+    /// Microseconds between two times, 0 if `other` is later
+    fn sub(self, other: TaiTime) -> u64 {
+        self.0.saturating_sub(other.0)
+    }
+}
+
+/// A calendar date and time, on whatever scale the seconds it came from were on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// From seconds since 2000-01-01 00:00:00
+    pub fn from_secs(secs: u64) -> Self {
+        let days = (secs / 86_400) as i64 + days_from_civil(2000, 1, 1);
+        let (year, month, day) = civil_from_days(days);
+        let secs = secs % 86_400;
+        DateTime {
+            year: year as u16,
+            month,
+            day,
+            hour: (secs / 3_600) as u8,
+            minute: (secs / 60 % 60) as u8,
+            second: (secs % 60) as u8,
+        }
+    }
+
+    /// Seconds since 2000-01-01 00:00:00, dates before that give 0
+    pub fn to_secs(&self) -> u64 {
+        let days = days_from_civil(self.year as i64, self.month, self.day) - days_from_civil(2000, 1, 1);
+        if days < 0 {
+            return 0;
+        }
+        days as u64 * 86_400 + self.hour as u64 * 3_600 + self.minute as u64 * 60 + self.second as u64
+    }
+
+    /// Day of the week, 1 is Monday like the RTC wants it
+    pub fn weekday(&self) -> u8 {
+        // 2000-01-01 was a Saturday
+        let days = days_from_civil(self.year as i64, self.month, self.day) - days_from_civil(2000, 1, 1);
+        ((days + 5).rem_euclid(7) + 1) as u8
+    }
+}
+
+// Howard Hinnant's algorithms, days counted from 1970-01-01
+fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let month = month as i64;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// RTC smooth calibration register values
+///
+/// Over a 32 s window the RTC masks `calm` of its 2^20 clock pulses and, with `calp`, adds 512.
+/// That gives a range of -487.1 to +488.5 ppm in steps of about 0.954 ppm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct SmoothCalibration {
+    pub calp: bool,
+    pub calm: u16,
+}
+
+impl SmoothCalibration {
+    pub const NONE: SmoothCalibration = SmoothCalibration {
+        calp: false,
+        calm: 0,
+    };
+
+    /// Register values that speed the clock up by `ppm` (slow it down if negative)
+    pub fn from_ppm(ppm: f32) -> Self {
+        let pulses = ppm * (1 << 20) as f32 / 1e6;
+        let pulses = if pulses < 0.0 { pulses - 0.5 } else { pulses + 0.5 } as i32; // round
+        if pulses <= 0 {
+            SmoothCalibration {
+                calp: false,
+                calm: (-pulses).min(511) as u16,
+            }
+        } else {
+            SmoothCalibration {
+                calp: true,
+                calm: (512 - pulses).max(0) as u16,
+            }
+        }
+    }
+
+    /// The correction these register values give
+    pub fn ppm(&self) -> f32 {
+        let pulses = if self.calp { 512 } else { 0 } - self.calm as i32;
+        pulses as f32 * 1e6 / (1 << 20) as f32
+    }
+}
+
+/// Works out how far off the RTC frequency is from the references it is synced to
+///
+/// Every sync slews the clock error away, so the errors seen between two syncs far enough
+/// apart add up to how much the clock gained or lost over that time. Divided by the interval
+/// that is the remaining frequency error. It is low pass filtered since a single GPS or
+/// telecommand time is only good to some milliseconds.
+#[derive(Debug, Clone, Copy, defmt::Format)]
+pub struct DriftEstimator {
+    since: Option<TaiTime>, // reference time the measurement started at
+    error_micros: i64,      // clock error removed since then
+    correction_ppm: f32,
+}
+
+impl DriftEstimator {
+    /// How much of a new measurement goes into the estimate
+    const GAIN: f32 = 0.5;
+
+    pub fn new(correction_ppm: f32) -> Self {
+        DriftEstimator {
+            since: None,
+            error_micros: 0,
+            correction_ppm,
+        }
+    }
+
+    /// The correction that should be applied to the RTC right now
+    pub fn correction_ppm(&self) -> f32 {
+        self.correction_ppm
+    }
+
+    /// Start measuring again, after the clock has been stepped for example
+    pub fn restart(&mut self) {
+        self.since = None;
+        self.error_micros = 0;
+    }
+
+    /// Feed a sync, `error_micros` is clock minus reference and is removed by the caller
+    ///
+    /// Returns the new correction once there are two syncs far enough apart.
+    pub fn update(&mut self, reference: TaiTime, error_micros: i64) -> Option<f32> {
+        let since = match self.since {
+            Some(since) => since,
+            None => {
+                self.since = Some(reference);
+                self.error_micros = 0;
+                return None;
+            }
+        };
+        self.error_micros += error_micros;
+        let elapsed = reference.micros_since(since);
+        if elapsed < (MIN_DRIFT_INTERVAL_SECS * MICROS_PER_SEC) as i64 {
+            return None;
+        }
+
+        // positive when the clock runs fast
+        let error_ppm = self.error_micros as f32 * 1e6 / elapsed as f32;
+        self.correction_ppm -= Self::GAIN * error_ppm;
+        self.correction_ppm = self.correction_ppm.clamp(-487.0, 488.0);
+        self.since = Some(reference);
+        self.error_micros = 0;
+        Some(self.correction_ppm)
+    }
+}
+
+/// What a time reference came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Source {
+    Telecommand,
+    Gps,
+}
+
+/// What a sync did to the clock
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Correction {
+    /// The clock was set to the reference
+    Stepped { error_micros: i64 },
+    /// The clock was shifted by a fraction of a second
+    Slewed { error_micros: i64 },
+}
+
+/// A clock that counts TAI, the RTC on the board
+pub trait Clock {
+    fn now(&mut self) -> TaiTime;
+
+    /// Set the clock, the sub-second part may be dropped
+    fn set(&mut self, time: TaiTime);
+
+    /// Move the clock by less than a second either way without stopping it
+    fn shift(&mut self, micros: i32);
+
+    /// Change the clock frequency by `ppm`
+    fn calibrate(&mut self, ppm: f32);
+}
+
+/// Keeps the board clock right and turns it into UTC and mission elapsed time
+pub struct TimeService<C: Clock> {
+    clock: C,
+    leap_seconds: i32,
+    epoch: TaiTime,
+    drift: DriftEstimator,
+    synced: bool,
+}
+
+impl<C: Clock> TimeService<C> {
+    /// `epoch` is the start of the mission, `calibration_ppm` the last correction worked out
+    pub fn new(mut clock: C, epoch: TaiTime, leap_seconds: i32, calibration_ppm: f32) -> Self {
+        clock.calibrate(calibration_ppm);
+        TimeService {
+            clock,
+            leap_seconds,
+            epoch,
+            drift: DriftEstimator::new(calibration_ppm),
+            synced: false,
+        }
+    }
+
+    pub fn now(&mut self) -> TaiTime {
+        self.clock.now()
+    }
+
+    /// UTC as a calendar date
+    pub fn utc(&mut self) -> DateTime {
+        let unix = self.now().to_unix_utc(self.leap_seconds) / MICROS_PER_SEC;
+        DateTime::from_secs(unix.saturating_sub(UNIX_TO_2000))
+    }
+
+    /// Mission elapsed time in microseconds
+    pub fn met(&mut self) -> u64 {
+        self.now() - self.epoch
+    }
+
+    pub fn epoch(&self) -> TaiTime {
+        self.epoch
+    }
+
+    pub fn set_epoch(&mut self, epoch: TaiTime) {
+        self.epoch = epoch;
+    }
+
+    pub fn leap_seconds(&self) -> i32 {
+        self.leap_seconds
+    }
+
+    pub fn set_leap_seconds(&mut self, leap_seconds: i32) {
+        self.leap_seconds = leap_seconds;
+    }
+
+    /// The RTC correction in use, worth saving in the configuration
+    pub fn calibration_ppm(&self) -> f32 {
+        self.drift.correction_ppm()
+    }
+
+    /// Whether the clock has been synced since boot
+    pub fn is_synced(&self) -> bool {
+        self.synced
+    }
+
+    /// Bring the clock in line with a reference time that is valid right now
+    pub fn sync(&mut self, reference: TaiTime, source: Source) -> Correction {
+        let clock = self.clock.now();
+        let error_micros = clock.micros_since(reference);
+        self.synced = true;
+
+        if error_micros.abs() >= STEP_THRESHOLD_MICROS {
+            defmt::info!("time: stepping clock by {} us ({})", -error_micros, source);
+            self.clock.set(reference);
+            // start the drift measurement from the new setting
+            self.drift.restart();
+            self.drift.update(reference, 0);
+            return Correction::Stepped { error_micros };
+        }
+
+        if let Some(ppm) = self.drift.update(reference, error_micros) {
+            defmt::info!("time: rtc correction now {} ppm", ppm);
+            self.clock.calibrate(ppm);
+        }
+        self.clock.shift(-error_micros as i32);
+        Correction::Slewed { error_micros }
+    }
+
+    pub fn clock(&mut self) -> &mut C {
+        &mut self.clock
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Unix time of 2017-01-01 00:00:00 UTC, just after the last leap second
+    const LEAP_2017: u64 = 1_483_228_800;
+
+    fn date(year: u16, month: u8, day: u8) -> DateTime {
+        DateTime { year, month, day, hour: 0, minute: 0, second: 0 }
+    }
+
+    fn days_in_month(year: u16, month: u8) -> u8 {
+        // every 4th year, but only every 4th century
+        let leap = match (year % 4, year % 100, year % 400) {
+            (_, _, 0) => true,
+            (_, 0, _) => false,
+            (0, _, _) => true,
+            _ => false,
+        };
+        match month {
+            2 if leap => 29,
+            2 => 28,
+            4 | 6 | 9 | 11 => 30,
+            _ => 31,
+        }
+    }
+
+    #[test]
+    fn utc_across_a_leap_second() {
+        // 2016-12-31 23:59:59 UTC was still TAI - 36, then came 23:59:60 and the offset went to 37
+        let before = TaiTime::from_unix_utc((LEAP_2017 - 1) * MICROS_PER_SEC, 36);
+        let after = TaiTime::from_unix_utc(LEAP_2017 * MICROS_PER_SEC, 37);
+        assert_eq!(after - before, 2 * MICROS_PER_SEC);
+        assert_eq!(before.to_unix_utc(36), (LEAP_2017 - 1) * MICROS_PER_SEC);
+        assert_eq!(after.to_unix_utc(37), LEAP_2017 * MICROS_PER_SEC);
+        assert_eq!(after.secs(), LEAP_2017 - UNIX_TO_2000 + 37);
+
+        // the same TAI second read with either offset is a second apart in UTC
+        assert_eq!(after.to_unix_utc(36) - after.to_unix_utc(37), MICROS_PER_SEC);
+        assert_eq!(TaiTime::from_unix_utc(1_500_000, 37), TaiTime(0), "before 2000 clamps");
+    }
+
+    #[test]
+    fn cuc() {
+        let time = TaiTime::from_secs(0x1234_5678) + 500_000;
+        assert_eq!(time.to_cuc(), [0x12, 0x34, 0x56, 0x78, 0x80, 0x00]);
+        assert_eq!(TaiTime::from_cuc(time.to_cuc()), time);
+        let time = TaiTime(123_456_789);
+        assert!(TaiTime::from_cuc(time.to_cuc()).micros_since(time).abs() < 16);
+    }
+
+    #[test]
+    fn every_day_for_two_centuries() {
+        let mut previous = DateTime::from_secs(0);
+        assert_eq!(previous, date(2000, 1, 1));
+        assert_eq!(previous.weekday(), 6);
+        for days in 1..=(200 * 366) {
+            let secs = days * 86_400;
+            let today = DateTime::from_secs(secs);
+            assert_eq!(today.to_secs(), secs);
+            assert_eq!(today.weekday(), previous.weekday() % 7 + 1);
+            let expected = if previous.day < days_in_month(previous.year, previous.month) {
+                date(previous.year, previous.month, previous.day + 1)
+            } else if previous.month < 12 {
+                date(previous.year, previous.month + 1, 1)
+            } else {
+                date(previous.year + 1, 1, 1)
+            };
+            assert_eq!(today, expected);
+            previous = today;
+        }
+    }
+
+    #[test]
+    fn boundaries() {
+        let at = |date: DateTime| DateTime::from_secs(date.to_secs());
+        for date in [
+            date(2000, 2, 29), // divisible by 400, a leap year
+            date(2023, 2, 28),
+            date(2023, 3, 1),
+            date(2024, 2, 29),
+            date(2024, 12, 31),
+            date(2100, 2, 28), // divisible by 100, not a leap year
+            date(2100, 3, 1),
+        ] {
+            assert_eq!(at(date), date);
+        }
+        assert_eq!(date(2100, 3, 1).to_secs() - date(2100, 2, 28).to_secs(), 86_400);
+        assert_eq!(date(2024, 3, 1).to_secs() - date(2024, 2, 28).to_secs(), 2 * 86_400);
+        assert_eq!(date(2001, 1, 1).to_secs(), 366 * 86_400);
+        assert_eq!(date(1999, 12, 31).to_secs(), 0, "before 2000");
+        assert_eq!(date(2024, 2, 29).weekday(), 4);
+        assert_eq!(date(2100, 3, 1).weekday(), 1);
+
+        let time = DateTime::from_secs(date(2016, 12, 31).to_secs() + 86_399);
+        assert_eq!((time.hour, time.minute, time.second), (23, 59, 59));
+    }
+
+    #[test]
+    fn smooth_calibration() {
+        assert_eq!(SmoothCalibration::from_ppm(0.0), SmoothCalibration::NONE);
+        // one pulse in 2^20 is the smallest step
+        assert_eq!(SmoothCalibration::from_ppm(1.0), SmoothCalibration { calp: true, calm: 511 });
+        assert_eq!(SmoothCalibration::from_ppm(-1.0), SmoothCalibration { calp: false, calm: 1 });
+        assert_eq!(SmoothCalibration::from_ppm(488.5), SmoothCalibration { calp: true, calm: 0 });
+        assert_eq!(SmoothCalibration::from_ppm(-487.1), SmoothCalibration { calp: false, calm: 511 });
+        // out of range ends up at the limits
+        assert_eq!(SmoothCalibration::from_ppm(1000.0), SmoothCalibration { calp: true, calm: 0 });
+        assert_eq!(SmoothCalibration::from_ppm(-1000.0), SmoothCalibration { calp: false, calm: 511 });
+
+        let step = 1e6 / (1 << 20) as f32;
+        let mut ppm = -487.0;
+        while ppm < 488.0 {
+            let error = SmoothCalibration::from_ppm(ppm).ppm() - ppm;
+            assert!(error.abs() <= step / 2.0 + 1e-3, "{} ppm is off by {}", ppm, error);
+            ppm += 0.37;
+        }
+    }
+
+    /// An RTC that runs `rate_ppm` fast before its calibration, which only has the steps of the
+    /// smooth calibration register
+    struct SimClock {
+        micros: i64,
+        rate_ppm: f64,
+        calibration: SmoothCalibration,
+    }
+
+    impl SimClock {
+        fn run(&mut self, micros: i64) {
+            let ppm = self.rate_ppm + self.calibration.ppm() as f64;
+            self.micros += (micros as f64 * (1.0 + ppm * 1e-6)).round() as i64;
+        }
+    }
+
+    impl Clock for SimClock {
+        fn now(&mut self) -> TaiTime {
+            TaiTime(self.micros as u64)
+        }
+
+        fn set(&mut self, time: TaiTime) {
+            self.micros = time.0 as i64;
+        }
+
+        fn shift(&mut self, micros: i32) {
+            self.micros += micros as i64;
+        }
+
+        fn calibrate(&mut self, ppm: f32) {
+            self.calibration = SmoothCalibration::from_ppm(ppm);
+        }
+    }
+
+    #[test]
+    fn drift_converges() {
+        for rate_ppm in [-150.0, -20.0, 7.5, 42.0, 200.0] {
+            let clock = SimClock { micros: 0, rate_ppm, calibration: SmoothCalibration::NONE };
+            let mut time = TimeService::new(clock, TaiTime::from_secs(0), DEFAULT_LEAP_SECONDS, 0.0);
+            let mut reference = TaiTime::from_secs(600_000_000);
+            assert!(matches!(time.sync(reference, Source::Gps), Correction::Stepped { .. }));
+
+            // hourly GPS fixes good to a few ms
+            let mut jitter = 0x1234_5678u32;
+            let mut last_error = 0;
+            for _ in 0..24 {
+                time.clock().run(3_600 * MICROS_PER_SEC as i64);
+                reference = reference + 3_600 * MICROS_PER_SEC;
+                jitter = jitter.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                let noise = (jitter >> 20) as i64 - 2_048;
+                match time.sync(reference + (4_000 + noise) as u64, Source::Gps) {
+                    Correction::Slewed { error_micros } => last_error = error_micros,
+                    stepped => panic!("{} ppm: {:?}", rate_ppm, stepped),
+                }
+            }
+            let left = time.calibration_ppm() + rate_ppm as f32;
+            assert!(left.abs() < 1.5, "{} ppm clock corrected by {}", rate_ppm, time.calibration_ppm());
+            // a ppm is 3.6 ms an hour, what is left is the jitter and the register step
+            assert!(last_error.abs() < 10_000, "{} ppm: still off by {} us an hour", rate_ppm, last_error);
+        }
+    }
+
+    #[test]
+    fn drift_needs_a_long_enough_interval() {
+        let mut drift = DriftEstimator::new(5.0);
+        assert_eq!(drift.update(TaiTime::from_secs(1_000), 0), None);
+        assert_eq!(drift.update(TaiTime::from_secs(1_000 + MIN_DRIFT_INTERVAL_SECS - 1), 5_000), None);
+        // 6 ms over 600 s is 10 ppm fast, half of that comes off the 5 ppm
+        let ppm = drift.update(TaiTime::from_secs(1_000 + MIN_DRIFT_INTERVAL_SECS), 1_000).unwrap();
+        assert!(ppm.abs() < 1e-3, "{}", ppm);
+        drift.restart();
+        assert_eq!(drift.update(TaiTime::from_secs(5_000), 1_000_000), None);
+    }
+}
@@ -0,0 +1,353 @@
+//! Software timers on one monotonic task
+//!
+//! `spawn_after` gives a task one delayed spawn per capacity slot, and nothing keeps the
+//! handles, so a timeout cannot be stopped when the answer comes in first. [`Timers`] keeps
+//! up to `N` named timers, one-shot or periodic, and a single task of the app (the service
+//! task) is scheduled at the earliest of their deadlines. Timers are started, restarted and
+//! cancelled by name, an `enum` of the app usually, and what a timer does when it expires is
+//! up to the closure given to [`Timers::expire`], which spawns the task that handles it.
+//!
+//! The `Timers` are a shared resource. Whenever a call returns an instant, the service task
+//! has to run at it (earlier than it was scheduled, or it was not scheduled at all); the app
+//! keeps the `SpawnHandle` of the service task and reschedules it, under the same lock:
+//!
+//! ``` ignore
+//! fn arm(handle: &mut Option<timer_service::SpawnHandle>, at: Option<Instant>) {
+//!     if let Some(at) = at {
+//!         match handle.take() {
+//!             // an error means it is about to run, and it arms itself again from `expire`
+//!             Some(spawned) => *handle = spawned.reschedule_at(at).ok(),
+//!             None => *handle = timer_service::spawn_at(at).ok(),
+//!         }
+//!     }
+//! }
+//!
+//! #[task(shared = [timers, timer_handle], priority = 2)]
+//! fn timer_service(ctx: timer_service::Context) {
+//!     (ctx.shared.timers, ctx.shared.timer_handle).lock(|timers, handle| {
+//!         let next = timers.expire(monotonics::now(), |timeout| match timeout {
+//!             Timeout::Ack => ack_timeout::spawn().ok(),
+//!         });
+//!         *handle = None;
+//!         arm(handle, next);
+//!     });
+//! }
+//! ```
+//!
+//! Cancelling a timer does not move the service task, it runs at the old deadline and finds
+//! nothing to do. Instants are those of a 64 bit monotonic (`DwtSystick`), at `HZ` ticks per
+//! second.
+
+use fugit::{TimerDurationU64, TimerInstantU64};
+use heapless::Vec;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+    /// All `N` timers are running
+    Full,
+    /// The timer is not running, there is nothing to reschedule
+    NotRunning,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Timer<T> {
+    name: T,
+    /// In ticks
+    deadline: u64,
+    /// In ticks, none for a one-shot timer
+    period: Option<u64>,
+}
+
+/// Up to `N` timers named by `T`, on a monotonic of `HZ` ticks per second
+pub struct Timers<T, const HZ: u32, const N: usize> {
+    timers: Vec<Timer<T>, N>,
+    /// When the service task is scheduled, as far as the timers know
+    armed: Option<u64>,
+}
+
+impl<T: Copy + Eq, const HZ: u32, const N: usize> Timers<T, HZ, N> {
+    pub const fn new() -> Self {
+        Timers {
+            timers: Vec::new(),
+            armed: None,
+        }
+    }
+
+    /// Starts a one-shot timer that expires `after` from `now`, or restarts it if it runs.
+    /// Returns when the service task has to run, if that is earlier than it was scheduled.
+    pub fn start(
+        &mut self,
+        name: T,
+        now: TimerInstantU64<HZ>,
+        after: impl Into<TimerDurationU64<HZ>>,
+    ) -> Result<Option<TimerInstantU64<HZ>>, Error> {
+        self.set(name, now.ticks() + after.into().ticks(), None)
+    }
+
+    /// Starts a timer that expires every `period` from `now` on, or restarts it if it runs.
+    /// Its deadlines stay on that grid, however late the service task gets to them.
+    pub fn start_periodic(
+        &mut self,
+        name: T,
+        now: TimerInstantU64<HZ>,
+        period: impl Into<TimerDurationU64<HZ>>,
+    ) -> Result<Option<TimerInstantU64<HZ>>, Error> {
+        let period = period.into().ticks();
+        assert!(period > 0, "a period of zero ticks");
+        self.set(name, now.ticks() + period, Some(period))
+    }
+
+    /// Moves the next deadline of a running timer to `after` from `now`; a periodic timer
+    /// keeps its period from there
+    pub fn reschedule(
+        &mut self,
+        name: T,
+        now: TimerInstantU64<HZ>,
+        after: impl Into<TimerDurationU64<HZ>>,
+    ) -> Result<Option<TimerInstantU64<HZ>>, Error> {
+        let period = match self.find(name) {
+            Some(index) => self.timers[index].period,
+            None => return Err(Error::NotRunning),
+        };
+        self.set(name, now.ticks() + after.into().ticks(), period)
+    }
+
+    /// Stops a timer; false if it was not running (or had expired already)
+    pub fn cancel(&mut self, name: T) -> bool {
+        match self.find(name) {
+            Some(index) => {
+                self.timers.swap_remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn is_running(&self, name: T) -> bool {
+        self.find(name).is_some()
+    }
+
+    /// Time left until a running timer expires next, zero if it is overdue
+    pub fn remaining(&self, name: T, now: TimerInstantU64<HZ>) -> Option<TimerDurationU64<HZ>> {
+        self.find(name).map(|index| {
+            TimerDurationU64::from_ticks(self.timers[index].deadline.saturating_sub(now.ticks()))
+        })
+    }
+
+    /// Timers that are running
+    pub fn len(&self) -> usize {
+        self.timers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.timers.is_empty()
+    }
+
+    /// The earliest deadline of all running timers
+    pub fn next_deadline(&self) -> Option<TimerInstantU64<HZ>> {
+        self.earliest().map(TimerInstantU64::from_ticks)
+    }
+
+    /// Hands every timer that is due at `now` to `expired`, in the order of their deadlines,
+    /// and returns when the service task has to run next. Called by the service task.
+    ///
+    /// One-shot timers stop, periodic timers move on to their first deadline after `now`, so
+    /// a periodic timer the service task was late for expires once rather than once per
+    /// period missed.
+    pub fn expire<R>(
+        &mut self,
+        now: TimerInstantU64<HZ>,
+        mut expired: impl FnMut(T) -> R,
+    ) -> Option<TimerInstantU64<HZ>> {
+        let now = now.ticks();
+        while let Some(index) = self.due(now) {
+            let timer = self.timers[index];
+            match timer.period {
+                Some(period) => {
+                    let missed = (now - timer.deadline) / period + 1;
+                    self.timers[index].deadline = timer.deadline + missed * period;
+                }
+                None => {
+                    self.timers.swap_remove(index);
+                }
+            }
+            expired(timer.name);
+        }
+        self.armed = self.earliest();
+        self.next_deadline()
+    }
+
+    fn set(
+        &mut self,
+        name: T,
+        deadline: u64,
+        period: Option<u64>,
+    ) -> Result<Option<TimerInstantU64<HZ>>, Error> {
+        let timer = Timer { name, deadline, period };
+        match self.find(name) {
+            Some(index) => self.timers[index] = timer,
+            None => self.timers.push(timer).map_err(|_| Error::Full)?,
+        }
+        match self.armed {
+            Some(armed) if armed <= deadline => Ok(None),
+            _ => {
+                self.armed = Some(deadline);
+                Ok(Some(TimerInstantU64::from_ticks(deadline)))
+            }
+        }
+    }
+
+    fn find(&self, name: T) -> Option<usize> {
+        self.timers.iter().position(|timer| timer.name == name)
+    }
+
+    /// The timer with the earliest deadline at or before `now`
+    fn due(&self, now: u64) -> Option<usize> {
+        self.timers
+            .iter()
+            .enumerate()
+            .filter(|(_, timer)| timer.deadline <= now)
+            .min_by_key(|(_, timer)| timer.deadline)
+            .map(|(index, _)| index)
+    }
+
+    fn earliest(&self) -> Option<u64> {
+        self.timers.iter().map(|timer| timer.deadline).min()
+    }
+}
+
+impl<T: Copy + Eq, const HZ: u32, const N: usize> Default for Timers<T, HZ, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use std::{vec, vec::Vec};
+
+    const HZ: u32 = 1_000;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Name {
+        Ack,
+        Led,
+        Rx,
+    }
+
+    fn at(ms: u64) -> TimerInstantU64<HZ> {
+        TimerInstantU64::from_ticks(ms)
+    }
+
+    fn ms(ms: u64) -> TimerDurationU64<HZ> {
+        TimerDurationU64::from_ticks(ms)
+    }
+
+    fn expire(timers: &mut Timers<Name, HZ, 3>, now: u64) -> (Vec<Name>, Option<TimerInstantU64<HZ>>) {
+        let mut expired = Vec::new();
+        let next = timers.expire(at(now), |name| expired.push(name));
+        (expired, next)
+    }
+
+    #[test]
+    fn arming_the_service_task() {
+        let mut timers: Timers<Name, HZ, 3> = Timers::new();
+        assert_eq!(timers.start(Name::Ack, at(0), ms(100)), Ok(Some(at(100))));
+        // later than it is armed for
+        assert_eq!(timers.start(Name::Led, at(0), ms(200)), Ok(None));
+        assert_eq!(timers.start(Name::Rx, at(0), ms(50)), Ok(Some(at(50))));
+        // moved later, the service task still runs at 50 and finds nothing
+        assert_eq!(timers.start(Name::Rx, at(10), ms(300)), Ok(None));
+        assert_eq!(expire(&mut timers, 50), (Vec::new(), Some(at(100))));
+
+        // from here the service task is armed for 100
+        assert_eq!(timers.start(Name::Rx, at(50), ms(80)), Ok(None));
+        assert_eq!(timers.start(Name::Rx, at(50), ms(20)), Ok(Some(at(70))));
+        assert_eq!(expire(&mut timers, 70), (vec![Name::Rx], Some(at(100))));
+
+        // cancelling leaves it where it was
+        assert!(timers.cancel(Name::Ack));
+        assert_eq!(timers.start(Name::Ack, at(70), ms(40)), Ok(None));
+        assert_eq!(expire(&mut timers, 200), (vec![Name::Ack, Name::Led], None));
+        // nothing running, so not armed at all
+        assert_eq!(timers.start(Name::Ack, at(200), ms(500)), Ok(Some(at(700))));
+    }
+
+    #[test]
+    fn expiring_in_deadline_order() {
+        let mut timers: Timers<Name, HZ, 3> = Timers::new();
+        timers.start(Name::Rx, at(0), ms(30)).unwrap();
+        timers.start(Name::Ack, at(0), ms(20)).unwrap();
+        timers.start_periodic(Name::Led, at(0), ms(25)).unwrap();
+        assert_eq!(expire(&mut timers, 10), (Vec::new(), Some(at(20))));
+        // a late service task, everything that is due comes out oldest first
+        assert_eq!(expire(&mut timers, 40), (vec![Name::Ack, Name::Led, Name::Rx], Some(at(50))));
+        assert_eq!(timers.len(), 1);
+        assert!(timers.is_running(Name::Led));
+    }
+
+    #[test]
+    fn periodic_catch_up() {
+        let mut timers: Timers<Name, HZ, 3> = Timers::new();
+        assert_eq!(timers.start_periodic(Name::Led, at(5), ms(100)), Ok(Some(at(105))));
+        assert_eq!(expire(&mut timers, 105), (vec![Name::Led], Some(at(205))));
+        // 2.5 periods late, it expires once and stays on the grid
+        assert_eq!(expire(&mut timers, 455), (vec![Name::Led], Some(at(505))));
+        // right on a deadline, the next one is a whole period on
+        assert_eq!(expire(&mut timers, 505), (vec![Name::Led], Some(at(605))));
+        assert_eq!(expire(&mut timers, 604), (Vec::new(), Some(at(605))));
+        assert_eq!(timers.remaining(Name::Led, at(604)), Some(ms(1)));
+    }
+
+    #[test]
+    fn reschedule_and_cancel() {
+        let mut timers: Timers<Name, HZ, 3> = Timers::new();
+        assert_eq!(timers.reschedule(Name::Ack, at(0), ms(10)), Err(Error::NotRunning));
+        assert!(!timers.cancel(Name::Ack));
+        assert_eq!(timers.remaining(Name::Ack, at(0)), None);
+        assert!(timers.is_empty());
+
+        timers.start(Name::Ack, at(0), ms(100)).unwrap();
+        timers.start_periodic(Name::Led, at(0), ms(100)).unwrap();
+        assert_eq!(timers.remaining(Name::Ack, at(40)), Some(ms(60)));
+        // overdue
+        assert_eq!(timers.remaining(Name::Ack, at(140)), Some(ms(0)));
+
+        // an answer came in, give it longer; still a one-shot
+        assert_eq!(timers.reschedule(Name::Ack, at(40), ms(100)), Ok(None));
+        assert_eq!(timers.remaining(Name::Ack, at(40)), Some(ms(100)));
+        // a periodic timer keeps its period from the new deadline
+        assert_eq!(timers.reschedule(Name::Led, at(40), ms(10)), Ok(Some(at(50))));
+        assert_eq!(expire(&mut timers, 50), (vec![Name::Led], Some(at(140))));
+        assert_eq!(expire(&mut timers, 140), (vec![Name::Ack], Some(at(150))));
+        assert!(!timers.is_running(Name::Ack));
+        assert_eq!(timers.next_deadline(), Some(at(150)));
+
+        assert!(timers.cancel(Name::Led));
+        assert!(!timers.cancel(Name::Led));
+        assert!(timers.is_empty());
+        assert_eq!(timers.next_deadline(), None);
+        assert_eq!(expire(&mut timers, 150), (Vec::new(), None));
+    }
+
+    #[test]
+    fn full() {
+        let mut timers: Timers<Name, HZ, 2> = Timers::new();
+        timers.start(Name::Ack, at(0), ms(100)).unwrap();
+        timers.start(Name::Led, at(0), ms(200)).unwrap();
+        assert_eq!(timers.start(Name::Rx, at(0), ms(10)), Err(Error::Full));
+        assert_eq!(timers.start_periodic(Name::Rx, at(0), ms(10)), Err(Error::Full));
+        assert!(!timers.is_running(Name::Rx));
+        // a refused timer does not move the service task
+        assert_eq!(timers.next_deadline(), Some(at(100)));
+        assert_eq!(timers.start(Name::Ack, at(0), ms(150)), Ok(None));
+
+        // the running ones can still be restarted, and a cancelled one makes room
+        assert_eq!(timers.start(Name::Led, at(0), ms(50)), Ok(Some(at(50))));
+        assert!(timers.cancel(Name::Ack));
+        assert_eq!(timers.start(Name::Rx, at(0), ms(10)), Ok(Some(at(10))));
+        assert_eq!(timers.len(), 2);
+    }
+}
@@ -0,0 +1,489 @@
+//! Time synchronisation between nodes over CAN
+//!
+//! One node is the master. Every so often it sends a `SYNC` frame and, once that frame is out,
+//! a `FOLLOW_UP` with the master's time at the start of the `SYNC` frame. The other nodes note
+//! their own time at the start of the same `SYNC` frame, so each pair of times gives the offset
+//! between the two clocks, and a few pairs give the drift as well.
+//!
+//! The start of frame times come from the bxcan time triggered mode (TTCM), which captures a
+//! 16 bit counter of CAN bit times at the start of every frame sent or received. That counter
+//! is only readable through those captures, `CaptureClock` works out where it stands against the
+//! local monotonic so a capture can be turned into local time.
+//!
+//! All times are in microseconds. The protocol and estimators are plain arithmetic, only the
+//! functions at the bottom touch CAN1.
+
+use bxcan::{Fifo, Mailbox};
+use stm32f4xx_hal::pac::CAN1;
+
+/// Standard id of the `SYNC` frame
+pub const SYNC_ID: u16 = 0x080;
+
+/// Standard id of the `FOLLOW_UP` frame
+pub const FOLLOW_UP_ID: u16 = 0x081;
+
+/// Number of sync points the drift is fitted over
+pub const WINDOW: usize = 16;
+
+/// Number of captures `CaptureClock` looks back over for the shortest latency
+pub const CAPTURE_WINDOW: usize = 16;
+
+/// An offset further than this from the prediction means the master restarted or changed
+pub const STEP_LIMIT_MICROS: i64 = 1_000;
+
+/// The frames of the protocol
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Message {
+    /// `[sequence]`
+    Sync { sequence: u8 },
+    /// `[sequence, master time in us as 7 bytes little endian]`
+    FollowUp { sequence: u8, master_micros: u64 },
+}
+
+impl Message {
+    pub fn parse(id: u16, data: &[u8]) -> Option<Self> {
+        match (id, data) {
+            (SYNC_ID, [sequence]) => Some(Message::Sync {
+                sequence: *sequence,
+            }),
+            (FOLLOW_UP_ID, [sequence, time @ ..]) if time.len() == 7 => {
+                let mut bytes = [0; 8];
+                bytes[..7].copy_from_slice(time);
+                Some(Message::FollowUp {
+                    sequence: *sequence,
+                    master_micros: u64::from_le_bytes(bytes),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    pub fn id(&self) -> u16 {
+        match self {
+            Message::Sync { .. } => SYNC_ID,
+            Message::FollowUp { .. } => FOLLOW_UP_ID,
+        }
+    }
+
+    /// The frame data, written to `buf`
+    pub fn encode<'a>(&self, buf: &'a mut [u8; 8]) -> &'a [u8] {
+        match *self {
+            Message::Sync { sequence } => {
+                buf[0] = sequence;
+                &buf[..1]
+            }
+            Message::FollowUp {
+                sequence,
+                master_micros,
+            } => {
+                buf[0] = sequence;
+                buf[1..].copy_from_slice(&master_micros.to_le_bytes()[..7]);
+                &buf[..]
+            }
+        }
+    }
+}
+
+/// Turns start of frame captures into local time
+///
+/// For every capture the local time is read in the interrupt, which comes some latency after
+/// the start of the frame. `local - capture` is then the phase between the two counters plus
+/// that latency, so the smallest value over the last few frames is the phase plus the shortest
+/// latency. The times given out are the start of frame plus that shortest latency. For frames
+/// of the same length that is the same on every node, which is why only `SYNC` frames should be
+/// fed in.
+#[derive(Debug, Clone, defmt::Format)]
+pub struct CaptureClock {
+    tick_micros: u64,
+    candidates: [u64; CAPTURE_WINDOW],
+    count: usize,
+    next: usize,
+}
+
+impl CaptureClock {
+    /// `tick_micros` is the CAN bit time, 1 at 1 Mbit/s
+    pub fn new(tick_micros: u32) -> Self {
+        CaptureClock {
+            tick_micros: tick_micros as u64,
+            candidates: [0; CAPTURE_WINDOW],
+            count: 0,
+            next: 0,
+        }
+    }
+
+    /// How long it takes the capture counter to wrap
+    fn period(&self) -> u64 {
+        65_536 * self.tick_micros
+    }
+
+    /// Local time of a frame, `now` is the local time read in the interrupt for it
+    pub fn sample(&mut self, capture: u16, now: u64) -> u64 {
+        let period = self.period();
+        let captured = capture as u64 * self.tick_micros;
+        self.candidates[self.next] = (now % period + period - captured) % period;
+        self.next = (self.next + 1) % CAPTURE_WINDOW;
+        self.count = (self.count + 1).min(CAPTURE_WINDOW);
+
+        let elapsed = (now % period + 2 * period - self.phase() - captured) % period;
+        now.saturating_sub(elapsed)
+    }
+
+    /// Smallest candidate, compared around the newest one since they wrap
+    fn phase(&self) -> u64 {
+        let period = self.period() as i64;
+        let newest = self.candidates[(self.next + CAPTURE_WINDOW - 1) % CAPTURE_WINDOW] as i64;
+        let smallest = self.candidates[..self.count]
+            .iter()
+            .map(|&candidate| {
+                let diff = (candidate as i64 - newest).rem_euclid(period);
+                if diff >= period / 2 {
+                    diff - period
+                } else {
+                    diff
+                }
+            })
+            .min()
+            .unwrap_or(0);
+        (newest + smallest).rem_euclid(period) as u64
+    }
+}
+
+/// The master side, numbers the `SYNC` frames and makes the follow ups
+#[derive(Debug, Default, defmt::Format)]
+pub struct Master {
+    sequence: u8,
+}
+
+impl Master {
+    pub fn new() -> Self {
+        Master { sequence: 0 }
+    }
+
+    pub fn sync(&mut self) -> Message {
+        self.sequence = self.sequence.wrapping_add(1);
+        Message::Sync {
+            sequence: self.sequence,
+        }
+    }
+
+    /// The follow up for the last `SYNC`, `sent` is its local start of frame time
+    pub fn follow_up(&self, sent: u64) -> Message {
+        Message::FollowUp {
+            sequence: self.sequence,
+            master_micros: sent,
+        }
+    }
+}
+
+/// Offset and drift of the master clock against the local one
+///
+/// Each sync point is a local time and the master minus local offset at that time. A straight
+/// line is fitted through the last `WINDOW` of them, its slope is the drift.
+#[derive(Debug, Clone, defmt::Format)]
+pub struct SyncEstimator {
+    pending: Option<(u8, u64)>, // sequence and local time of the last SYNC
+    points: [(u64, i64); WINDOW],
+    count: usize,
+    next: usize,
+    fit: Option<Fit>,
+}
+
+/// Offset at a local time and how fast it changes
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub struct Fit {
+    pub local: u64,
+    pub offset_micros: i64,
+    pub drift_ppm: f32,
+}
+
+impl Fit {
+    fn offset_at(&self, local: u64) -> i64 {
+        let since = local as i64 - self.local as i64;
+        self.offset_micros + (since as f64 * self.drift_ppm as f64 / 1e6) as i64
+    }
+}
+
+impl Default for SyncEstimator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SyncEstimator {
+    pub fn new() -> Self {
+        SyncEstimator {
+            pending: None,
+            points: [(0, 0); WINDOW],
+            count: 0,
+            next: 0,
+            fit: None,
+        }
+    }
+
+    /// A `SYNC` frame came in, `local` is its time from `CaptureClock::sample`
+    pub fn sync(&mut self, sequence: u8, local: u64) {
+        self.pending = Some((sequence, local));
+    }
+
+    /// A `FOLLOW_UP` came in, returns the new fit if it belongs to the last `SYNC`
+    pub fn follow_up(&mut self, sequence: u8, master: u64) -> Option<Fit> {
+        let (pending_sequence, local) = self.pending.take()?;
+        if pending_sequence != sequence {
+            return None;
+        }
+        let offset = master as i64 - local as i64;
+
+        if let Some(fit) = self.fit {
+            if (offset - fit.offset_at(local)).abs() > STEP_LIMIT_MICROS {
+                defmt::warn!("timesync: master time jumped, starting over");
+                self.count = 0;
+                self.next = 0;
+            }
+        }
+        self.points[self.next] = (local, offset);
+        self.next = (self.next + 1) % WINDOW;
+        self.count = (self.count + 1).min(WINDOW);
+
+        self.fit = Some(self.fit_points(local, offset));
+        self.fit
+    }
+
+    /// Least squares line through the points, relative to the newest one
+    fn fit_points(&self, local: u64, offset: i64) -> Fit {
+        let points = &self.points[..self.count];
+        let n = points.len() as f64;
+        let relative = |&(x, y): &(u64, i64)| ((x as i64 - local as i64) as f64, (y - offset) as f64);
+
+        let (sum_x, sum_y) = points
+            .iter()
+            .map(relative)
+            .fold((0.0, 0.0), |(sx, sy), (x, y)| (sx + x, sy + y));
+        let (mean_x, mean_y) = (sum_x / n, sum_y / n);
+        let (sxy, sxx) = points.iter().map(relative).fold((0.0, 0.0), |(sxy, sxx), (x, y)| {
+            (sxy + (x - mean_x) * (y - mean_y), sxx + (x - mean_x) * (x - mean_x))
+        });
+        let slope = if sxx > 0.0 { sxy / sxx } else { 0.0 };
+
+        Fit {
+            local,
+            // the line at the newest point rather than the point itself, that smooths the jitter
+            offset_micros: offset + (mean_y - slope * mean_x) as i64,
+            drift_ppm: (slope * 1e6) as f32,
+        }
+    }
+
+    /// The last fit, `None` until the first sync point
+    pub fn fit(&self) -> Option<Fit> {
+        self.fit
+    }
+
+    /// Master time for a local time
+    pub fn to_master(&self, local: u64) -> Option<u64> {
+        let fit = self.fit?;
+        Some((local as i64 + fit.offset_at(local)) as u64)
+    }
+}
+
+/// Length of a CAN bit in microseconds for a BTR value, which is what the captures count
+pub fn bit_time_micros(btr: u32, pclk_hz: u32) -> u32 {
+    let prescaler = (btr & 0x3FF) + 1;
+    let quanta = 1 + (btr >> 16 & 0xF) + 1 + (btr >> 20 & 0x7) + 1;
+    (prescaler as u64 * quanta as u64 * 1_000_000 / pclk_hz as u64).max(1) as u32
+}
+
+// CAN1 registers for TTCM, bxcan does not know about it
+const MCR_TTCM: u32 = 1 << 7;
+const TSR_RQCP: [u32; 3] = [1 << 0, 1 << 8, 1 << 16];
+const TSR_TXOK: [u32; 3] = [1 << 1, 1 << 9, 1 << 17];
+
+fn can1() -> &'static stm32f4xx_hal::pac::can1::RegisterBlock {
+    // Safety: only the TTCM bit, the capture fields and the request complete flags are used,
+    // bxcan leaves those alone
+    unsafe { &*CAN1::ptr() }
+}
+
+fn mailbox_index(mailbox: Mailbox) -> usize {
+    match mailbox {
+        Mailbox::Mailbox0 => 0,
+        Mailbox::Mailbox1 => 1,
+        Mailbox::Mailbox2 => 2,
+    }
+}
+
+/// Turn on start of frame captures, only works while bxcan is still in its builder
+pub fn enable_timestamps() {
+    can1().mcr.modify(|r, w| unsafe { w.bits(r.bits() | MCR_TTCM) });
+}
+
+/// Capture of the frame at the head of `fifo`, read it before `receive` releases the frame
+pub fn rx_timestamp(fifo: Fifo) -> u16 {
+    let index = match fifo {
+        Fifo::Fifo0 => 0,
+        Fifo::Fifo1 => 1,
+    };
+    (can1().rx[index].rdtr.read().bits() >> 16) as u16
+}
+
+/// What became of a frame in a transmit mailbox
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Transmit {
+    /// Still waiting to go out
+    Busy,
+    /// Sent, with its start of frame capture
+    Sent(u16),
+    /// Aborted, or lost arbitration without automatic retransmission
+    Failed,
+}
+
+/// Capture of the frame sent from `mailbox`, once it is out
+///
+/// Clears the request complete flag, so it only says `Sent` once per frame.
+pub fn tx_timestamp(mailbox: Mailbox) -> Transmit {
+    let index = mailbox_index(mailbox);
+    let tsr = can1().tsr.read().bits();
+    if tsr & TSR_RQCP[index] == 0 {
+        return Transmit::Busy;
+    }
+    can1().tsr.write(|w| unsafe { w.bits(TSR_RQCP[index]) });
+    if tsr & TSR_TXOK[index] == 0 {
+        return Transmit::Failed;
+    }
+    Transmit::Sent((can1().tx[index].tdtr.read().bits() >> 16) as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic jitter in `-range..=range`
+    struct Jitter(u64);
+
+    impl Jitter {
+        fn next(&mut self, range: i64) -> i64 {
+            self.0 = self.0.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+            (self.0 >> 33) as i64 % (2 * range + 1) - range
+        }
+    }
+
+    #[test]
+    fn messages() {
+        let mut buf = [0; 8];
+        for message in [
+            Message::Sync { sequence: 7 },
+            Message::FollowUp { sequence: 9, master_micros: 0x00AB_CDEF_0123_4567 },
+        ] {
+            let data = message.encode(&mut buf);
+            assert_eq!(Message::parse(message.id(), data), Some(message));
+        }
+        assert_eq!(Message::parse(SYNC_ID, &[1, 2]), None);
+        assert_eq!(Message::parse(FOLLOW_UP_ID, &[1, 2, 3]), None);
+        assert_eq!(Message::parse(0x123, &[1]), None);
+    }
+
+    #[test]
+    fn bit_time() {
+        // the default 0x001b_0002 at 45 MHz is 1 Mbit/s
+        assert_eq!(bit_time_micros(0x001b_0002, 45_000_000), 1);
+        assert_eq!(bit_time_micros(0x001b_0005, 45_000_000), 2);
+    }
+
+    /// Local clock is true time, the master runs `drift_ppm` fast and `offset` ahead
+    fn converge(offset: i64, drift_ppm: f64, jitter_micros: i64) {
+        let master = |t: i64| t + offset + (t as f64 * drift_ppm / 1e6) as i64;
+        let mut jitter = Jitter(offset as u64 ^ 0x5EED);
+        let mut estimator = SyncEstimator::new();
+        for k in 0..40u8 {
+            let t = 1_000_000_000 + k as i64 * 1_000_000;
+            estimator.sync(k, (t + jitter.next(jitter_micros)) as u64);
+            let fit = estimator.follow_up(k, (master(t) + jitter.next(jitter_micros)) as u64).unwrap();
+            if k as usize >= WINDOW {
+                assert!((fit.drift_ppm as f64 - drift_ppm).abs() < 1.0, "{:?} for {} ppm", fit, drift_ppm);
+                // within the jitter, give or take the rounding of the simulated master
+                let t = fit.local as i64;
+                assert!((fit.offset_micros - (master(t) - t)).abs() <= jitter_micros + 1, "{:?}", fit);
+            }
+        }
+        // and in between syncs
+        let t = 1_040_500_000;
+        let error = estimator.to_master(t as u64).unwrap() as i64 - master(t);
+        assert!(error.abs() <= jitter_micros + 1, "{} us off at {} ppm", error, drift_ppm);
+    }
+
+    #[test]
+    fn offset_and_drift_converge() {
+        converge(0, 0.0, 0);
+        converge(123_456_789, 0.0, 5);
+        converge(-5_000_000, 42.0, 5);
+        converge(77_000, -150.0, 10);
+        converge(1, 0.5, 2);
+    }
+
+    #[test]
+    fn follow_up_has_to_match_the_sync() {
+        let mut estimator = SyncEstimator::new();
+        assert_eq!(estimator.follow_up(1, 1_000), None, "no sync yet");
+        estimator.sync(1, 500);
+        assert_eq!(estimator.follow_up(2, 1_000), None, "sync 2 was lost");
+        assert_eq!(estimator.follow_up(1, 1_000), None, "the sync is used up");
+        assert_eq!(estimator.to_master(0), None);
+        estimator.sync(3, 500);
+        assert_eq!(estimator.follow_up(3, 1_500).map(|fit| fit.offset_micros), Some(1_000));
+        assert_eq!(estimator.to_master(600), Some(1_600));
+    }
+
+    #[test]
+    fn master_restart_starts_over() {
+        let mut estimator = SyncEstimator::new();
+        for k in 0..10u8 {
+            let t = k as u64 * 1_000_000;
+            estimator.sync(k, t);
+            estimator.follow_up(k, t + 2_000 + k as u64 * 20);
+        }
+        assert!((estimator.fit().unwrap().drift_ppm - 20.0).abs() < 0.1);
+
+        // the master came back with its clock from zero, the old points would drag the fit
+        let t = 10_000_000;
+        estimator.sync(10, t);
+        let fit = estimator.follow_up(10, 500).unwrap();
+        assert_eq!(fit.offset_micros, 500 - t as i64);
+        assert_eq!(fit.drift_ppm, 0.0);
+    }
+
+    /// Both nodes take their times from the CAN captures, read out with interrupt latency
+    #[test]
+    fn two_nodes_over_can() {
+        let mut jitter = Jitter(1);
+        // true time in us, the master runs 20 ppm fast and the local node 30 ppm slow
+        let master_clock = |t: f64| t * (1.0 + 20e-6) + 5e6;
+        let local_clock = |t: f64| t * (1.0 - 30e-6) + 123e6;
+        // the capture counters count bit times with some phase to the clocks
+        let master_capture = |t: f64| ((master_clock(t) + 1_234.0) as u64 % 65_536) as u16;
+        let local_capture = |t: f64| ((local_clock(t) + 40_000.0) as u64 % 65_536) as u16;
+
+        let mut master_captures = CaptureClock::new(1);
+        let mut local_captures = CaptureClock::new(1);
+        let mut master = Master::new();
+        let mut estimator = SyncEstimator::new();
+        let mut worst = 0;
+        for k in 0..60 {
+            let t = 10e6 + k as f64 * 1e6 + (jitter.next(500) + 500) as f64;
+            let Message::Sync { sequence } = master.sync() else { panic!() };
+            // the interrupts come some 60 us after the start of frame, now and then a lot later
+            let mut latency = || 60.0 + if k % 10 == 3 { 300.0 } else { (jitter.next(2) + 2) as f64 };
+            let sent = master_captures.sample(master_capture(t), master_clock(t + latency()) as u64);
+            let received = local_captures.sample(local_capture(t), local_clock(t + latency()) as u64);
+            estimator.sync(sequence, received);
+            let Message::FollowUp { sequence, master_micros } = master.follow_up(sent) else { panic!() };
+            estimator.follow_up(sequence, master_micros).unwrap();
+
+            if k >= 10 {
+                let probe = t + 500_000.0;
+                let error = estimator.to_master(local_clock(probe) as u64).unwrap() as i64 - master_clock(probe) as i64;
+                worst = worst.max(error.abs());
+            }
+        }
+        let fit = estimator.fit().unwrap();
+        assert!((fit.drift_ppm - 50.0).abs() < 2.0, "{:?}", fit);
+        assert!(worst < 20, "{} us off", worst);
+    }
+}
@@ -0,0 +1,276 @@
+//! Task trace recorder
+//!
+//! Tasks, interrupt handlers and locks call in here as they start and end, and each call puts
+//! an event with the DWT cycle counter into a ring of the last `EVENTS` events in RAM. The
+//! ring is dumped over RTT (defmt) or a UART in the format of `crate::trace_format`, and
+//! `tools/tracetool` turns the dump into a Chrome trace to open in Perfetto
+//! (<https://ui.perfetto.dev>):
+//!
+//! ``` text
+//! $ cargo run --example fun | tee fun.log
+//! $ tracetool chrome fun.log fun.json
+//! ```
+//!
+//! Tasks, resources and interrupts are named by an index into a table of names the app
+//! keeps and hands to `dump`. A task records with a guard, which records the exit when it
+//! goes out of scope:
+//!
+//! ``` ignore
+//! #[task(priority = 2)]
+//! fn blink(ctx: blink::Context) {
+//!     let _trace = trace::task(BLINK);
+//!     ...
+//! }
+//! ```
+//!
+//! An event costs a critical section of a few dozen cycles. The cycle counter has to be
+//! running (`DWT::enable_cycle_counter`), which it is wherever `DwtSystick` is the monotonic.
+
+use core::cell::RefCell;
+#[cfg(not(target_os = "none"))]
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(target_os = "none")]
+use cortex_m::peripheral::DWT;
+use critical_section::Mutex;
+use embedded_hal::blocking::serial::Write;
+
+use crate::trace_format::{Event, Kind, Ring};
+
+/// Events kept, 8 bytes each
+pub const EVENTS: usize = 512;
+
+/// Bytes of the dump in one line of the defmt output
+const LINE: usize = 32;
+
+static RING: Mutex<RefCell<Ring<EVENTS>>> = Mutex::new(RefCell::new(Ring::new()));
+
+/// Nothing is recorded while this is false
+static RECORDING: AtomicBool = AtomicBool::new(true);
+
+/// Records an event at the current cycle count
+pub fn record(kind: Kind, id: u8, arg: u16) {
+    if !RECORDING.load(Ordering::Relaxed) {
+        return;
+    }
+    critical_section::with(|cs| {
+        let event = Event::new(cycle_count(), kind, id, arg);
+        RING.borrow(cs).borrow_mut().push(event);
+    });
+}
+
+/// Records that a software task runs until the guard is dropped
+pub fn task(id: u8) -> Guard {
+    record(Kind::Enter, id, 0);
+    Guard { kind: Kind::Exit, id }
+}
+
+/// Records that an interrupt handler runs until the guard is dropped
+pub fn isr(id: u8) -> Guard {
+    record(Kind::IsrEnter, id, 0);
+    Guard { kind: Kind::IsrExit, id }
+}
+
+/// Records that a resource is held until the guard is dropped; called first thing in the
+/// closure given to `lock`
+pub fn lock(id: u8, ceiling: u8) -> Guard {
+    record(Kind::Lock, id, ceiling as u16);
+    Guard { kind: Kind::Unlock, id }
+}
+
+/// Records a spawn of task `id`, whether or not it went through
+pub fn spawn(id: u8) {
+    record(Kind::Spawn, id, 0);
+}
+
+/// Puts `value` on the timeline under the name `id`
+pub fn mark(id: u8, value: u16) {
+    record(Kind::Mark, id, value);
+}
+
+/// Records the end of what it was made for when dropped
+#[must_use = "the exit is recorded when the guard is dropped"]
+pub struct Guard {
+    kind: Kind,
+    id: u8,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        record(self.kind, self.id, 0);
+    }
+}
+
+pub fn start() {
+    RECORDING.store(true, Ordering::Relaxed);
+}
+
+pub fn stop() {
+    RECORDING.store(false, Ordering::Relaxed);
+}
+
+/// Events lost so far because the ring was full
+pub fn lost() -> u32 {
+    critical_section::with(|cs| RING.borrow(cs).borrow().lost())
+}
+
+/// Writes the dump to `write` and starts over with an empty ring
+///
+/// Interrupts stay off until the whole dump is written, so nothing runs in the middle of it
+/// and the dump ends where it was asked for. It is a debugging aid, every task waits.
+pub fn dump<E>(
+    clock_hz: u32,
+    names: &[&str],
+    write: impl FnMut(&[u8]) -> Result<(), E>,
+) -> Result<(), E> {
+    critical_section::with(|cs| {
+        let mut ring = RING.borrow(cs).borrow_mut();
+        let result = ring.dump(clock_hz, names, write);
+        ring.clear();
+        result
+    })
+}
+
+/// Dumps over defmt, in lines of `trace <hex>` that tracetool picks out of the log
+pub fn dump_defmt(clock_hz: u32, names: &[&str]) {
+    let mut line = [0u8; LINE * 2];
+    let mut len = 0;
+    let _ = dump(clock_hz, names, |bytes| {
+        for &byte in bytes {
+            line[len] = hex(byte >> 4);
+            line[len + 1] = hex(byte & 0xF);
+            len += 2;
+            if len == line.len() {
+                print_line(&line[..len]);
+                len = 0;
+            }
+        }
+        Ok::<(), ()>(())
+    });
+    if len > 0 {
+        print_line(&line[..len]);
+    }
+    defmt::println!("trace end");
+}
+
+/// Dumps the bytes as they are over a serial port
+pub fn dump_serial<S: Write<u8>>(
+    serial: &mut S,
+    clock_hz: u32,
+    names: &[&str],
+) -> Result<(), S::Error> {
+    dump(clock_hz, names, |bytes| serial.bwrite_all(bytes))?;
+    serial.bflush()
+}
+
+#[cfg(target_os = "none")]
+fn cycle_count() -> u32 {
+    DWT::cycle_count()
+}
+
+/// The host has no cycle counter, its tests set the time here
+#[cfg(not(target_os = "none"))]
+static HOST_CYCLES: AtomicU32 = AtomicU32::new(0);
+
+#[cfg(not(target_os = "none"))]
+fn cycle_count() -> u32 {
+    HOST_CYCLES.load(Ordering::Relaxed)
+}
+
+fn print_line(line: &[u8]) {
+    // only hex digits went in
+    defmt::println!("trace {=str}", core::str::from_utf8(line).unwrap_or(""));
+}
+
+fn hex(nibble: u8) -> u8 {
+    b"0123456789abcdef"[nibble as usize]
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::trace_format::{Dump, HEADER_LEN};
+    use std::vec::Vec;
+
+    fn at(cycles: u32) {
+        HOST_CYCLES.store(cycles, Ordering::Relaxed);
+    }
+
+    fn take_dump(names: &[&str]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        dump(1_000_000, names, |piece| {
+            bytes.extend_from_slice(piece);
+            Ok::<(), ()>(())
+        })
+        .unwrap();
+        bytes
+    }
+
+    /// The dump of `blink_preempts_add`, also in `tools/tracetool/fixtures/blink.log`
+    #[rustfmt::skip]
+    const BLINK: [u8; 20 + 29 + 10 * 8] = [
+        b'T', b'R', b'C', b'E', 1, 0, 5, 0, // magic, version, names
+        0x40, 0x42, 0x0F, 0x00, 0, 0, 0, 0, 10, 0, 0, 0, // 1 MHz, none lost, events
+        5, b'b', b'l', b'i', b'n', b'k',
+        3, b'a', b'd', b'd',
+        5, b'E', b'X', b'T', b'I', b'0',
+        6, b's', b'h', b'a', b'r', b'e', b'd',
+        5, b'd', b'e', b'p', b't', b'h',
+        0x00, 0xFF, 0xFF, 0xFF, 0, 1, 0, 0, // enter add, 256 before the counter wraps
+        0x80, 0xFF, 0xFF, 0xFF, 3, 3, 2, 0, // lock shared, ceiling 2
+        0xC0, 0xFF, 0xFF, 0xFF, 4, 3, 0, 0, // unlock shared
+        0x40, 0x00, 0x00, 0x00, 5, 2, 0, 0, // EXTI0 enters
+        0x60, 0x00, 0x00, 0x00, 2, 0, 0, 0, // spawn blink
+        0x80, 0x00, 0x00, 0x00, 6, 2, 0, 0, // EXTI0 exits
+        0x00, 0x01, 0x00, 0x00, 0, 0, 0, 0, // enter blink
+        0x10, 0x01, 0x00, 0x00, 7, 4, 42, 0, // mark depth 42
+        0x80, 0x01, 0x00, 0x00, 1, 0, 0, 0, // exit blink
+        0x00, 0x02, 0x00, 0x00, 1, 1, 0, 0, // exit add
+    ];
+
+    // the one test of the recorder, it is a global
+    #[test]
+    fn blink_preempts_add() {
+        let names = ["blink", "add", "EXTI0", "shared", "depth"];
+        at(0xFFFF_FF00);
+        let add = task(1);
+        at(0xFFFF_FF80);
+        let shared = lock(3, 2);
+        at(0xFFFF_FFC0);
+        drop(shared);
+        at(0x40);
+        let exti0 = isr(2);
+        at(0x60);
+        spawn(0);
+        at(0x80);
+        drop(exti0);
+        at(0x100);
+        let blink = task(0);
+        at(0x110);
+        mark(4, 42);
+        // nothing while stopped
+        stop();
+        mark(4, 43);
+        start();
+        at(0x180);
+        drop(blink);
+        at(0x200);
+        drop(add);
+        assert_eq!(take_dump(&names), BLINK);
+
+        // the dump starts over, and counts what the ring had no room for
+        assert_eq!(take_dump(&[]).len(), HEADER_LEN);
+        for value in 0..EVENTS as u16 + 3 {
+            mark(0, value);
+        }
+        assert_eq!(lost(), 3);
+        let bytes = take_dump(&[]);
+        let dump = Dump::parse(&bytes).unwrap();
+        assert_eq!((dump.header.lost, dump.header.events), (3, EVENTS as u32));
+        assert_eq!(dump.events().next().unwrap().unwrap().arg, 3);
+        assert_eq!(lost(), 0);
+    }
+}
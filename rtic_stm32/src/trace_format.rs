@@ -0,0 +1,353 @@
+//! Layout of a task trace and its dump
+//!
+//! The recorder (`crate::trace`) keeps the last events in a ring and writes them out as one
+//! dump, which `tools/tracetool` turns into something to look at. This file is shared with
+//! that tool, so it depends on nothing but `core`.
+//!
+//! ```text
+//! dump header, 20 bytes
+//! 0   magic      u32  "TRCE"
+//! 4   version    u16
+//! 6   names      u16  number of names after the header
+//! 8   clock_hz   u32  timestamp ticks per second, the core clock for DWT cycles
+//! 12  lost       u32  events overwritten before the dump
+//! 16  events     u32  number of events after the names
+//! 20  names      u8 length and UTF-8 bytes each, what the ids of the events refer to
+//! ..  events     8 bytes each, oldest first
+//!
+//! event
+//! 0   timestamp  u32  DWT cycle counter, wraps every 2^32 cycles (89 s at 48 MHz)
+//! 4   kind       u8   see `Kind`
+//! 5   id         u8   index into the names: the task, resource or interrupt
+//! 6   arg        u16  the ceiling of a lock, the value of a mark, 0 otherwise
+//! ```
+//!
+//! Everything is little endian. Spawns, locks and marks belong to the task or interrupt
+//! handler that was entered last and has not exited.
+
+pub const MAGIC: u32 = 0x4543_5254; // "TRCE"
+
+pub const VERSION: u16 = 1;
+
+pub const HEADER_LEN: usize = 20;
+
+pub const EVENT_LEN: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// A software task starts running
+    Enter,
+    Exit,
+    /// A task is spawned, `id` is the task that will run
+    Spawn,
+    /// A resource is locked, `arg` is its ceiling
+    Lock,
+    Unlock,
+    /// An interrupt handler (a hardware task) starts running
+    IsrEnter,
+    IsrExit,
+    /// Something the application wants to see on the timeline, with a value in `arg`
+    Mark,
+}
+
+impl Kind {
+    pub fn from_u8(value: u8) -> Option<Kind> {
+        Some(match value {
+            0 => Kind::Enter,
+            1 => Kind::Exit,
+            2 => Kind::Spawn,
+            3 => Kind::Lock,
+            4 => Kind::Unlock,
+            5 => Kind::IsrEnter,
+            6 => Kind::IsrExit,
+            7 => Kind::Mark,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Event {
+    pub timestamp: u32,
+    pub kind: Kind,
+    pub id: u8,
+    pub arg: u16,
+}
+
+impl Event {
+    pub const fn new(timestamp: u32, kind: Kind, id: u8, arg: u16) -> Self {
+        Event { timestamp, kind, id, arg }
+    }
+
+    pub fn to_bytes(self) -> [u8; EVENT_LEN] {
+        let t = self.timestamp.to_le_bytes();
+        let a = self.arg.to_le_bytes();
+        [t[0], t[1], t[2], t[3], self.kind as u8, self.id, a[0], a[1]]
+    }
+
+    /// None for a kind this version does not know
+    pub fn from_bytes(bytes: &[u8; EVENT_LEN]) -> Option<Self> {
+        Some(Event {
+            timestamp: u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            kind: Kind::from_u8(bytes[4])?,
+            id: bytes[5],
+            arg: u16::from_le_bytes([bytes[6], bytes[7]]),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub names: u16,
+    pub clock_hz: u32,
+    pub lost: u32,
+    pub events: u32,
+}
+
+impl Header {
+    pub fn to_bytes(self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0; HEADER_LEN];
+        bytes[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        bytes[4..6].copy_from_slice(&VERSION.to_le_bytes());
+        bytes[6..8].copy_from_slice(&self.names.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.clock_hz.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.lost.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.events.to_le_bytes());
+        bytes
+    }
+
+    /// None unless the bytes start with the magic and this version
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < HEADER_LEN
+            || u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) != MAGIC
+            || u16::from_le_bytes([bytes[4], bytes[5]]) != VERSION
+        {
+            return None;
+        }
+        Some(Header {
+            names: u16::from_le_bytes([bytes[6], bytes[7]]),
+            clock_hz: u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]),
+            lost: u32::from_le_bytes([bytes[12], bytes[13], bytes[14], bytes[15]]),
+            events: u32::from_le_bytes([bytes[16], bytes[17], bytes[18], bytes[19]]),
+        })
+    }
+}
+
+/// The last `N` events, older ones are overwritten and counted as lost
+pub struct Ring<const N: usize> {
+    events: [Event; N],
+    /// Where the next event goes
+    next: usize,
+    len: usize,
+    lost: u32,
+}
+
+impl<const N: usize> Ring<N> {
+    pub const fn new() -> Self {
+        Ring {
+            events: [Event::new(0, Kind::Enter, 0, 0); N],
+            next: 0,
+            len: 0,
+            lost: 0,
+        }
+    }
+
+    pub fn push(&mut self, event: Event) {
+        self.events[self.next] = event;
+        self.next = (self.next + 1) % N;
+        if self.len < N {
+            self.len += 1;
+        } else {
+            self.lost = self.lost.wrapping_add(1);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn lost(&self) -> u32 {
+        self.lost
+    }
+
+    pub fn clear(&mut self) {
+        self.next = 0;
+        self.len = 0;
+        self.lost = 0;
+    }
+
+    /// Oldest first
+    pub fn iter(&self) -> impl Iterator<Item = &Event> {
+        let start = (self.next + N - self.len) % N;
+        (0..self.len).map(move |i| &self.events[(start + i) % N])
+    }
+
+    /// Writes the dump in pieces to `write`; names longer than 255 bytes are cut
+    pub fn dump<E>(
+        &self,
+        clock_hz: u32,
+        names: &[&str],
+        mut write: impl FnMut(&[u8]) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let header = Header {
+            names: names.len() as u16,
+            clock_hz,
+            lost: self.lost,
+            events: self.len as u32,
+        };
+        write(&header.to_bytes())?;
+        for name in names {
+            let name = &name.as_bytes()[..name.len().min(255)];
+            write(&[name.len() as u8])?;
+            write(name)?;
+        }
+        for event in self.iter() {
+            write(&event.to_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+impl<const N: usize> Default for Ring<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A dump split into its parts
+pub struct Dump<'a> {
+    pub header: Header,
+    names: &'a [u8],
+    events: &'a [u8],
+}
+
+impl<'a> Dump<'a> {
+    /// None if the header is not there or the dump is cut short; bytes after it are ignored
+    pub fn parse(bytes: &'a [u8]) -> Option<Self> {
+        let header = Header::from_bytes(bytes)?;
+        let mut end = HEADER_LEN;
+        for _ in 0..header.names {
+            end += 1 + *bytes.get(end)? as usize;
+        }
+        let names = bytes.get(HEADER_LEN..end)?;
+        let events = bytes.get(end..end + header.events as usize * EVENT_LEN)?;
+        Some(Dump { header, names, events })
+    }
+
+    /// Names that are not UTF-8 come out as "?"
+    pub fn names(&self) -> impl Iterator<Item = &'a str> {
+        let mut rest = self.names;
+        (0..self.header.names).map(move |_| {
+            let (name, after) = rest[1..].split_at(rest[0] as usize);
+            rest = after;
+            core::str::from_utf8(name).unwrap_or("?")
+        })
+    }
+
+    /// None for an event of a kind this version does not know
+    pub fn events(&self) -> impl Iterator<Item = Option<Event>> + 'a {
+        self.events
+            .chunks_exact(EVENT_LEN)
+            .map(|bytes| Event::from_bytes(bytes.try_into().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::{vec, vec::Vec};
+
+    #[test]
+    fn header() {
+        let header = Header { names: 3, clock_hz: 48_000_000, lost: 0x0102_0304, events: 512 };
+        let bytes = header.to_bytes();
+        assert_eq!(
+            bytes,
+            [b'T', b'R', b'C', b'E', 1, 0, 3, 0, 0x00, 0x6C, 0xDC, 0x02, 4, 3, 2, 1, 0, 2, 0, 0]
+        );
+        assert_eq!(Header::from_bytes(&bytes), Some(header));
+        assert_eq!(Header::from_bytes(&bytes[..HEADER_LEN - 1]), None);
+        let mut other = bytes;
+        other[4] = 2;
+        assert_eq!(Header::from_bytes(&other), None);
+        other = bytes;
+        other[0] = b't';
+        assert_eq!(Header::from_bytes(&other), None);
+    }
+
+    #[test]
+    fn event() {
+        let event = Event::new(0x8000_0001, Kind::Lock, 7, 0x0203);
+        assert_eq!(event.to_bytes(), [0x01, 0x00, 0x00, 0x80, 3, 7, 0x03, 0x02]);
+        assert_eq!(Event::from_bytes(&event.to_bytes()), Some(event));
+        assert_eq!(Event::from_bytes(&[0, 0, 0, 0, 8, 0, 0, 0]), None);
+        for kind in 0..8 {
+            assert_eq!(Kind::from_u8(kind).map(|kind| kind as u8), Some(kind));
+        }
+    }
+
+    #[test]
+    fn ring_keeps_the_newest() {
+        let mut ring = Ring::<4>::new();
+        assert!(ring.is_empty());
+        for t in 0..6 {
+            ring.push(Event::new(t, Kind::Mark, 0, t as u16));
+        }
+        assert_eq!((ring.len(), ring.lost()), (4, 2));
+        assert_eq!(ring.iter().map(|event| event.timestamp).collect::<Vec<_>>(), [2, 3, 4, 5]);
+        ring.clear();
+        assert_eq!((ring.len(), ring.lost()), (0, 0));
+        ring.push(Event::new(9, Kind::Enter, 1, 0));
+        assert_eq!(ring.iter().map(|event| event.timestamp).collect::<Vec<_>>(), [9]);
+    }
+
+    #[test]
+    fn dump_and_parse() {
+        let mut ring = Ring::<4>::new();
+        ring.push(Event::new(100, Kind::Enter, 0, 0));
+        ring.push(Event::new(200, Kind::Exit, 0, 0));
+        let long = "x".repeat(300);
+        let names = ["a", long.as_str()];
+        let mut bytes = Vec::new();
+        ring.dump(1000, &names, |piece| {
+            bytes.extend_from_slice(piece);
+            Ok::<(), ()>(())
+        })
+        .unwrap();
+        // the long name is cut to 255 bytes
+        assert_eq!(bytes.len(), HEADER_LEN + 2 + 256 + 2 * EVENT_LEN);
+        assert_eq!(bytes[HEADER_LEN..HEADER_LEN + 3], [1, b'a', 255]);
+
+        let dump = Dump::parse(&bytes).unwrap();
+        assert_eq!(dump.header, Header { names: 2, clock_hz: 1000, lost: 0, events: 2 });
+        assert_eq!(dump.names().collect::<Vec<_>>(), ["a", &long[..255]]);
+        assert_eq!(
+            dump.events().collect::<Vec<_>>(),
+            [Some(Event::new(100, Kind::Enter, 0, 0)), Some(Event::new(200, Kind::Exit, 0, 0))]
+        );
+
+        // cut anywhere is no dump, more after it is ignored
+        for len in 0..bytes.len() {
+            assert!(Dump::parse(&bytes[..len]).is_none(), "{}", len);
+        }
+        bytes.extend_from_slice(&[0xAA; 5]);
+        assert_eq!(Dump::parse(&bytes).unwrap().events().count(), 2);
+    }
+
+    #[test]
+    fn unknown_kinds_and_names() {
+        let mut bytes = Header { names: 1, clock_hz: 1, lost: 0, events: 2 }.to_bytes().to_vec();
+        bytes.extend_from_slice(&[2, 0xC3, 0x28]);
+        bytes.extend_from_slice(&[1, 0, 0, 0, 9, 0, 0, 0]);
+        bytes.extend_from_slice(&Event::new(2, Kind::Spawn, 0, 0).to_bytes());
+        let dump = Dump::parse(&bytes).unwrap();
+        assert_eq!(dump.names().collect::<Vec<_>>(), ["?"]);
+        assert_eq!(dump.events().collect::<Vec<_>>(), vec![None, Some(Event::new(2, Kind::Spawn, 0, 0))]);
+    }
+}
@@ -0,0 +1,163 @@
+//! UART receiver with DMA and idle-line detection
+//!
+//! USART6 receives on PC7 (D9 on the Nucleo headers) and DMA2 stream 1 channel 5 copies every
+//! byte into a ring buffer in circular mode, so nothing is lost while the CPU is busy. The CPU
+//! only hears about the data when the line goes idle after a burst, or when half of the
+//! buffer has filled, and then reads what is new from the ring. A GPS receiver sends its
+//! sentences once a second in one burst, so that is one or two interrupts a second.
+//!
+//! The buffer has to hold what comes in between two interrupts: with the half and full
+//! interrupts that is half of it, 512 bytes are more than enough for 9600 baud.
+//!
+//! Only stream 1 of DMA2 is touched, the other streams stay free for the HAL (the ADC uses
+//! stream 0).
+
+use core::sync::atomic::{compiler_fence, Ordering};
+use stm32f4xx_hal::{
+    gpio::{gpioc::PC7, Alternate},
+    pac::{DMA2, RCC, USART6},
+};
+
+pub type Rx = PC7<Alternate<8>>;
+
+/// The ring buffer, DMA writes into it so it has to live forever
+pub type Buffer = &'static mut [u8];
+
+// Register bits
+const SR_FE: u32 = 1 << 1;
+const SR_NF: u32 = 1 << 2;
+const SR_ORE: u32 = 1 << 3;
+const SR_IDLE: u32 = 1 << 4;
+const CR1_RE: u32 = 1 << 2;
+const CR1_IDLEIE: u32 = 1 << 4;
+const CR1_UE: u32 = 1 << 13;
+const CR3_EIE: u32 = 1 << 0;
+const CR3_DMAR: u32 = 1 << 6;
+const DMA_EN: u32 = 1 << 0;
+const DMA_HTIE: u32 = 1 << 3;
+const DMA_TCIE: u32 = 1 << 4;
+const DMA_CIRC: u32 = 1 << 8;
+const DMA_MINC: u32 = 1 << 10;
+const DMA_CHANNEL_5: u32 = 5 << 25;
+const STREAM: usize = 1;
+const STREAM_FLAGS: u32 = 0b11_1101 << 6; // FEIF1, DMEIF1, TEIF1, HTIF1, TCIF1
+const STREAM_TEIF: u32 = 1 << 9;
+const RCC_USART6: u32 = 1 << 5;
+const RCC_DMA2: u32 = 1 << 22;
+
+/// Counters for the health telemetry
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, defmt::Format)]
+pub struct Stats {
+    pub bytes: u32,
+    /// Bytes that did not make it into the buffer
+    pub overruns: u32,
+    /// Framing and noise errors, usually the wrong baud rate or a loose wire
+    pub line_errors: u32,
+    pub dma_errors: u32,
+}
+
+/// USART6 receiving into a ring buffer
+pub struct UartRx {
+    usart: USART6,
+    _pin: Rx,
+    buffer: *const u8,
+    len: usize,
+    /// Where the next new byte is
+    read: usize,
+    stats: Stats,
+}
+
+// Safety: the buffer pointer comes from a `&'static mut` that this struct owns
+unsafe impl Send for UartRx {}
+
+impl UartRx {
+    pub fn new(usart: USART6, pin: Rx, baud: u32, pclk2_hz: u32, buffer: Buffer) -> Self {
+        // Safety: only the USART6 and DMA2 enable bits are touched
+        let rcc = unsafe { &*RCC::ptr() };
+        rcc.apb2enr.modify(|r, w| unsafe { w.bits(r.bits() | RCC_USART6) });
+        rcc.ahb1enr.modify(|r, w| unsafe { w.bits(r.bits() | RCC_DMA2) });
+
+        let dma = dma2();
+        let stream = &dma.st[STREAM];
+        stream.cr.write(|w| unsafe { w.bits(0) });
+        while stream.cr.read().bits() & DMA_EN != 0 {}
+        dma.lifcr.write(|w| unsafe { w.bits(STREAM_FLAGS) });
+        stream.par.write(|w| unsafe { w.bits(&usart.dr as *const _ as u32) });
+        stream.m0ar.write(|w| unsafe { w.bits(buffer.as_ptr() as u32) });
+        stream.ndtr.write(|w| unsafe { w.bits(buffer.len() as u32) });
+        // peripheral to memory, bytes, circular
+        stream.cr.write(|w| unsafe {
+            w.bits(DMA_CHANNEL_5 | DMA_MINC | DMA_CIRC | DMA_HTIE | DMA_TCIE | DMA_EN)
+        });
+
+        // 16x oversampling, the divider with its 4 bits of fraction is just pclk / baud
+        usart.cr1.write(|w| unsafe { w.bits(0) });
+        usart.brr.write(|w| unsafe { w.bits((pclk2_hz + baud / 2) / baud) });
+        usart.cr2.write(|w| unsafe { w.bits(0) });
+        usart.cr3.write(|w| unsafe { w.bits(CR3_DMAR | CR3_EIE) });
+        usart.cr1.write(|w| unsafe { w.bits(CR1_UE | CR1_RE | CR1_IDLEIE) });
+
+        UartRx {
+            usart,
+            _pin: pin,
+            buffer: buffer.as_ptr(),
+            len: buffer.len(),
+            read: 0,
+            stats: Stats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    /// Clear the interrupt flags, call from both the USART6 and the DMA2_STREAM1 interrupts
+    pub fn on_interrupt(&mut self) {
+        // reading SR then DR clears IDLE and the error flags, DR is empty at idle so the DMA
+        // does not miss a byte
+        let sr = self.usart.sr.read().bits();
+        if sr & (SR_IDLE | SR_ORE | SR_NF | SR_FE) != 0 {
+            let _ = self.usart.dr.read().bits();
+        }
+        if sr & SR_ORE != 0 {
+            self.stats.overruns += 1;
+        }
+        if sr & (SR_NF | SR_FE) != 0 {
+            self.stats.line_errors += 1;
+        }
+
+        let dma = dma2();
+        let flags = dma.lisr.read().bits() & STREAM_FLAGS;
+        dma.lifcr.write(|w| unsafe { w.bits(flags) });
+        if flags & STREAM_TEIF != 0 {
+            // a transfer error disables the stream, start it again from the top
+            self.stats.dma_errors += 1;
+            let stream = &dma.st[STREAM];
+            stream.ndtr.write(|w| unsafe { w.bits(self.len as u32) });
+            stream.cr.modify(|r, w| unsafe { w.bits(r.bits() | DMA_EN) });
+            self.read = 0;
+        }
+    }
+
+    /// Hand every byte received since the last call to `f`, returns how many there were
+    pub fn read(&mut self, mut f: impl FnMut(u8)) -> usize {
+        let write = self.len - dma2().st[STREAM].ndtr.read().bits() as usize;
+        // the bytes before `write` are in memory before we look at them
+        compiler_fence(Ordering::Acquire);
+
+        let mut count = 0;
+        while self.read != write % self.len {
+            // Safety: `read` is inside the buffer, volatile because the DMA writes it
+            f(unsafe { self.buffer.add(self.read).read_volatile() });
+            self.read = (self.read + 1) % self.len;
+            count += 1;
+        }
+        self.stats.bytes += count as u32;
+        count
+    }
+}
+
+fn dma2() -> &'static stm32f4xx_hal::pac::dma2::RegisterBlock {
+    // Safety: only stream 1 and its flags are touched, which this module owns
+    unsafe { &*DMA2::ptr() }
+}
@@ -1,12 +1,14 @@
-//! This build script copies the `memory.x` file from the crate root into
-//! a directory where the linker can always find it at build time.
-//! For many projects this is optional, as the linker always searches the
-//! project root directory -- wherever `Cargo.toml` is. However, if you
-//! are using a workspace or have a more complicated build setup, this
-//! build script becomes required. Additionally, by requesting that
-//! Cargo re-run the build script whenever `memory.x` is changed,
-//! updating `memory.x` ensures a rebuild of the application with the
-//! new memory settings.
+//! This build script copies the memory layout from `memory/` into
+//! a directory where the linker can always find it as `memory.x` at build time.
+//! The layouts are kept out of the crate root on purpose: the linker searches
+//! the project root directory -- wherever `Cargo.toml` is -- before anything
+//! else, so a `memory.x` there would always win. Additionally, by requesting
+//! that Cargo re-run the build script whenever a layout is changed, updating
+//! it ensures a rebuild of the application with the new memory settings.
+//!
+//! `memory/stm32f446.x` is the board's layout. With the `qemu` feature
+//! `memory/lm3s6965.x` is used instead, the LM3S6965 that
+//! `qemu-system-arm -machine lm3s6965evb` emulates (see `../xtask`).
 //!
 //! The build script also sets the linker flags to tell it which link script to use.
 
@@ -19,17 +21,22 @@ fn main() {
     // Put `memory.x` in our output directory and ensure it's
     // on the linker search path.
     let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    let memory: &[u8] = if env::var_os("CARGO_FEATURE_QEMU").is_some() {
+        include_bytes!("memory/lm3s6965.x")
+    } else {
+        include_bytes!("memory/stm32f446.x")
+    };
     File::create(out.join("memory.x"))
         .unwrap()
-        .write_all(include_bytes!("memory.x"))
+        .write_all(memory)
         .unwrap();
     println!("cargo:rustc-link-search={}", out.display());
 
     // By default, Cargo will re-run a build script whenever
-    // any file in the project changes. By specifying `memory.x`
+    // any file in the project changes. By specifying `memory`
     // here, we ensure the build script is only re-run when
-    // `memory.x` is changed.
-    println!("cargo:rerun-if-changed=memory.x");
+    // a layout is changed.
+    println!("cargo:rerun-if-changed=memory");
 
     // Specify linker arguments.
 
@@ -1,44 +1,58 @@
 //! How to use the heap and a dynamic memory allocator
 //!
-//! This example depends on the alloc-cortex-m crate so you'll have to add it to your Cargo.toml:
+//! The heap is a pool of 16 blocks of 64 bytes from `../heap`: allocating and freeing take the
+//! same time whatever was allocated before and freed blocks cannot fragment, so what fitted once
+//! fits again. `Instrumented` around it counts what is in use and tells `OutOfMemory` the layout
+//! that did not fit.
 //!
-//! ``` text
-//! # or edit the Cargo.toml file manually
-//! $ cargo add alloc-cortex-m
-//! ```
+//! Runs on QEMU: `cargo xtask qemu allocator` from `rust/`.
 //!
 //! ---
 
-#![feature(alloc_error_handler)]
 #![no_main]
 #![no_std]
 
 extern crate alloc;
-use panic_halt as _;
+use panic_semihosting as _;
 
-use self::alloc::vec;
+use alloc::vec;
+use alloc::vec::Vec;
 use core::alloc::Layout;
 
-use alloc_cortex_m::CortexMHeap;
-use cortex_m::asm;
 use cortex_m_rt::entry;
-use cortex_m_semihosting::{hprintln, debug};
+use cortex_m_semihosting::{debug, hprintln};
+use heap::{Instrumented, OnFailure, Pool, Stats};
+use lm3s6965 as _; // the interrupt vectors
+
+const BLOCK: usize = 64; // in bytes
+const BLOCKS: usize = 16;
 
 // this is the allocator the application will use
 #[global_allocator]
-static ALLOCATOR: CortexMHeap = CortexMHeap::empty();
-
-const HEAP_SIZE: usize = 1024; // in bytes
+static ALLOCATOR: Instrumented<Pool<BLOCK, BLOCKS>, OutOfMemory> = Instrumented::new(Pool::new());
 
 #[entry]
 fn main() -> ! {
-    // Initialize the allocator BEFORE you use it
-    unsafe { ALLOCATOR.init(cortex_m_rt::heap_start() as usize, HEAP_SIZE) }
-
     // Growable array allocated on the heap
     let xs = vec![0, 1, 2];
 
-    hprintln!("{:?}", xs).unwrap();
+    hprintln!("{:?}", xs);
+    hprintln!("{}", ALLOCATOR.stats());
+
+    // a 48 byte message in each free block until there are none; `try_reserve` gives back an
+    // error where `Vec::with_capacity` would end in the out of memory handler, a panic
+    let mut messages: [Vec<u8>; BLOCKS] = Default::default();
+    let mut queued = 0;
+    for message in messages.iter_mut() {
+        if message.try_reserve_exact(48).is_err() {
+            break;
+        }
+        queued += 1;
+    }
+    hprintln!("{} messages", queued);
+
+    drop(messages);
+    hprintln!("{}", ALLOCATOR.stats());
 
     // exit QEMU
     // NOTE do not run this on hardware; it can corrupt OpenOCD state
@@ -47,10 +61,12 @@ fn main() -> ! {
     loop {}
 }
 
-// define what happens in an Out Of Memory (OOM) condition
-#[alloc_error_handler]
-fn alloc_error(_layout: Layout) -> ! {
-    asm::bkpt();
+// called when an allocation fails; it must not allocate
+struct OutOfMemory;
 
-    loop {}
+impl OnFailure for OutOfMemory {
+    fn failed(layout: Layout, stats: Stats) {
+        hprintln!("out of memory: {} bytes aligned to {}", layout.size(), layout.align());
+        hprintln!("{}", stats);
+    }
 }
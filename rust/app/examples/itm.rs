@@ -12,6 +12,9 @@
 //!
 //! [`itmdump`]: https://docs.rs/itm/0.2.1/itm/
 //!
+//! `tools/itmtool` in this repository reads the same capture: `itmtool ports itm.txt` prints
+//! what each stimulus port received, `itmtool dump itm.txt` every packet.
+//!
 //! ---
 
 #![no_main]
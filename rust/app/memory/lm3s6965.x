@@ -0,0 +1,6 @@
+/* The LM3S6965 emulated by `qemu-system-arm -machine lm3s6965evb`, used with the `qemu` feature */
+MEMORY
+{
+  FLASH : ORIGIN = 0x00000000, LENGTH = 256K
+  RAM : ORIGIN = 0x20000000, LENGTH = 64K
+}
@@ -12,6 +12,10 @@ use cortex_m_rt::entry;
 use panic_probe as _;
 use rtt_target::{rtt_init_print, rprintln};
 
+// Built with the `qemu` feature too, for `cargo xtask test`; it then links for the LM3S6965
+#[cfg(feature = "qemu")]
+use lm3s6965 as _; // the interrupt vectors
+
 #[entry]
 fn main() -> ! {
     rtt_init_print!();
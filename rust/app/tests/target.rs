@@ -0,0 +1,66 @@
+//! Tests that run on the LM3S6965 in QEMU, see the `target-test` crate
+//!
+//! `cargo xtask test` from `rust/` builds and runs them.
+
+#![no_main]
+#![no_std]
+
+use lm3s6965 as _; // the interrupt vectors
+
+#[target_test::tests]
+mod tests {
+    use core::hint::black_box;
+    use core::sync::atomic::{AtomicU32, Ordering};
+
+    use cortex_m::peripheral::syst::SystClkSource;
+    use cortex_m_semihosting::hprintln;
+
+    static INITIALIZED: AtomicU32 = AtomicU32::new(42);
+    static ZEROED: AtomicU32 = AtomicU32::new(0);
+
+    #[target_test]
+    fn statics_are_initialized() {
+        // .data copied from flash and .bss zeroed by the reset handler
+        assert_eq!(INITIALIZED.load(Ordering::Relaxed), 42);
+        assert_eq!(ZEROED.load(Ordering::Relaxed), 0);
+    }
+
+    #[target_test]
+    fn systick_counts_down() {
+        // taken once per boot, the tests share it
+        let mut core = unsafe { cortex_m::Peripherals::steal() };
+        core.SYST.set_clock_source(SystClkSource::Core);
+        core.SYST.set_reload(0x00ff_ffff);
+        core.SYST.clear_current();
+        core.SYST.enable_counter();
+        while !core.SYST.has_wrapped() && cortex_m::peripheral::SYST::get_current() > 0x00ff_0000 {
+            continue;
+        }
+        core.SYST.disable_counter();
+        hprintln!("systick at {:#08x}", cortex_m::peripheral::SYST::get_current());
+    }
+
+    #[target_test(should_panic)]
+    fn overflow_panics() {
+        // debug builds check for overflow
+        let _ = black_box(u8::MAX) + 1;
+    }
+
+    #[target_test(should_fault)]
+    fn undefined_instruction_faults() {
+        cortex_m::asm::udf();
+    }
+
+    #[target_test]
+    fn runs_after_a_fault() {
+        // this one comes after a reset
+        assert_eq!(INITIALIZED.fetch_add(1, Ordering::Relaxed), 42);
+    }
+
+    #[target_test(ignore)]
+    fn runs_on_a_cortex_m4() {
+        // QEMU's LM3S6965 is a Cortex-M3, take the ignore off to run this on an M4 board
+        let cpuid = unsafe { (*cortex_m::peripheral::CPUID::PTR).base.read() };
+        assert_eq!(cpuid >> 4 & 0xFFF, 0xC24, "CPUID {:#010x}", cpuid);
+    }
+}
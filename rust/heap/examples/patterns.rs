@@ -0,0 +1,67 @@
+//! An allocation pattern tried on the host
+//!
+//! ``` text
+//! $ cargo run --example patterns
+//! ```
+//!
+//! Packets are queued faster than they are sent until the pool runs out, then the queue drains.
+//! The pool is not the global allocator here, the pattern calls it directly, so the host's own
+//! allocations (printing, the queue) do not count. A failure hook prints the layout that did not
+//! fit as the out of memory handler on the target would.
+
+use std::alloc::{GlobalAlloc, Layout};
+use std::collections::VecDeque;
+
+use heap::{Instrumented, OnFailure, Pool, Stats};
+
+const PACKET: usize = 48;
+
+static HEAP: Instrumented<Pool<64, 16>, OutOfMemory> = Instrumented::new(Pool::new());
+
+struct OutOfMemory;
+
+impl OnFailure for OutOfMemory {
+    fn failed(layout: Layout, stats: Stats) {
+        println!("out of memory: {} bytes aligned to {}; {}", layout.size(), layout.align(), stats);
+    }
+}
+
+fn main() {
+    let layout = Layout::from_size_align(PACKET, 4).unwrap();
+    let mut queue = VecDeque::new();
+
+    // three in for every two out
+    'rounds: for round in 0.. {
+        for _ in 0..3 {
+            let packet = unsafe { HEAP.alloc(layout) };
+            if packet.is_null() {
+                println!("full after {} rounds", round);
+                break 'rounds;
+            }
+            queue.push_back(packet);
+        }
+        for _ in 0..2 {
+            if let Some(packet) = queue.pop_front() {
+                unsafe { HEAP.dealloc(packet, layout) };
+            }
+        }
+    }
+    println!("{}", HEAP.stats());
+
+    while let Some(packet) = queue.pop_front() {
+        unsafe { HEAP.dealloc(packet, layout) };
+    }
+    println!("{}", HEAP.stats());
+
+    // a pool never fragments, the freed blocks take the same packets again
+    let again: Vec<*mut u8> = (0..16).map(|_| unsafe { HEAP.alloc(layout) }).collect();
+    assert!(again.iter().all(|packet| !packet.is_null()));
+    for packet in again {
+        unsafe { HEAP.dealloc(packet, layout) };
+    }
+
+    // and never anything larger than a block
+    let large = Layout::from_size_align(65, 4).unwrap();
+    assert!(unsafe { HEAP.alloc(large) }.is_null());
+    println!("{}", HEAP.stats());
+}
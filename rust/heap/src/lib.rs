@@ -0,0 +1,21 @@
+//! Heap usage statistics and a fixed-block pool allocator
+//!
+//! `Instrumented` goes around the global allocator and counts what is in use, the most that
+//! ever was, and the allocations that failed; its `OnFailure` hook gets the `Layout` that did not
+//! fit, which the out of memory handler cannot see on stable Rust.
+//!
+//! `Pool` is an allocator of same size blocks: allocating and freeing take the same few
+//! instructions whatever happened before, and freed memory cannot fragment, so a layout that
+//! fitted once fits again after it was freed. What it needs is known up front, blocks of the
+//! largest thing allocated times the most that are alive at once.
+//!
+//! Nothing in here is specific to the target, allocation patterns can be tried on the host by
+//! calling the `GlobalAlloc` methods directly (see `examples/patterns.rs`).
+
+#![no_std]
+
+pub mod pool;
+pub mod stats;
+
+pub use pool::Pool;
+pub use stats::{FreeSpace, Instrumented, OnFailure, Stats};
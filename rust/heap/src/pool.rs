@@ -0,0 +1,226 @@
+//! Fixed-size block pool
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::{RefCell, UnsafeCell};
+use core::mem::{self, MaybeUninit};
+use core::ptr;
+
+use critical_section::Mutex;
+
+use crate::stats::FreeSpace;
+
+/// Alignment of every block
+pub const ALIGN: usize = 8;
+
+#[repr(C, align(8))]
+struct Block<const SIZE: usize>([u8; SIZE]);
+
+/// The blocks that can be handed out. Everything starts at zero so a pool in a `static` goes in
+/// .bss rather than being copied from flash.
+struct FreeList {
+    /// Index + 1 of the last freed block, 0 when none is; each freed block holds the next one
+    head: usize,
+    /// Blocks from here on were never handed out
+    unused: usize,
+    allocated: usize,
+}
+
+/// `N` blocks of `BLOCK` bytes, each allocation takes one whole block
+///
+/// A layout larger than `BLOCK` or aligned to more than `ALIGN` is never allocated. `BLOCK` is
+/// at least a `usize`, a free block holds the link to the next one. Usable as the
+/// `#[global_allocator]`, in a `static` since `new` is const.
+pub struct Pool<const BLOCK: usize, const N: usize> {
+    blocks: UnsafeCell<MaybeUninit<[Block<BLOCK>; N]>>,
+    free_list: Mutex<RefCell<FreeList>>,
+}
+
+// the blocks are handed out by the free list, one owner each
+unsafe impl<const BLOCK: usize, const N: usize> Sync for Pool<BLOCK, N> {}
+
+impl<const BLOCK: usize, const N: usize> Pool<BLOCK, N> {
+    pub const fn new() -> Self {
+        assert!(BLOCK >= mem::size_of::<usize>(), "blocks hold a usize when free");
+        Pool {
+            blocks: UnsafeCell::new(MaybeUninit::uninit()),
+            free_list: Mutex::new(RefCell::new(FreeList {
+                head: 0,
+                unused: 0,
+                allocated: 0,
+            })),
+        }
+    }
+
+    pub const fn block_size(&self) -> usize {
+        BLOCK
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Blocks not allocated
+    pub fn free_blocks(&self) -> usize {
+        N - critical_section::with(|cs| self.free_list.borrow_ref(cs).allocated)
+    }
+
+    /// Whether a layout can ever be allocated from the pool
+    pub const fn fits(layout: Layout) -> bool {
+        layout.size() <= BLOCK && layout.align() <= ALIGN
+    }
+
+    fn block(&self, index: usize) -> *mut u8 {
+        unsafe { (self.blocks.get() as *mut Block<BLOCK>).add(index) as *mut u8 }
+    }
+
+    fn index(&self, block: *mut u8) -> usize {
+        (block as usize - self.blocks.get() as usize) / mem::size_of::<Block<BLOCK>>()
+    }
+}
+
+impl<const BLOCK: usize, const N: usize> Default for Pool<BLOCK, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl<const BLOCK: usize, const N: usize> GlobalAlloc for Pool<BLOCK, N> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if !Self::fits(layout) {
+            return ptr::null_mut();
+        }
+        critical_section::with(|cs| {
+            let mut list = self.free_list.borrow_ref_mut(cs);
+            let block = if list.head > 0 {
+                let block = self.block(list.head - 1);
+                list.head = (block as *const usize).read();
+                block
+            } else if list.unused < N {
+                list.unused += 1;
+                self.block(list.unused - 1)
+            } else {
+                return ptr::null_mut();
+            };
+            list.allocated += 1;
+            block
+        })
+    }
+
+    unsafe fn dealloc(&self, block: *mut u8, _: Layout) {
+        let index = self.index(block);
+        critical_section::with(|cs| {
+            let mut list = self.free_list.borrow_ref_mut(cs);
+            (block as *mut usize).write(list.head);
+            list.head = index + 1;
+            list.allocated -= 1;
+        });
+    }
+
+    unsafe fn realloc(&self, block: *mut u8, _: Layout, new_size: usize) -> *mut u8 {
+        // the block is as large as it gets either way
+        if new_size <= BLOCK {
+            block
+        } else {
+            ptr::null_mut()
+        }
+    }
+}
+
+impl<const BLOCK: usize, const N: usize> FreeSpace for Pool<BLOCK, N> {
+    fn free(&self) -> usize {
+        self.free_blocks() * BLOCK
+    }
+
+    fn largest_free(&self) -> usize {
+        if self.free_blocks() > 0 {
+            BLOCK
+        } else {
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::vec::Vec;
+
+    const WORD: Layout = Layout::new::<u64>();
+
+    #[test]
+    fn blocks() {
+        let pool = Pool::<16, 4>::new();
+        let blocks: Vec<_> = (0..4).map(|_| unsafe { pool.alloc(WORD) }).collect();
+        // aligned, 16 bytes apart, one after another the first time through
+        for (i, &block) in blocks.iter().enumerate() {
+            assert_eq!(block as usize % ALIGN, 0);
+            assert_eq!(block as usize - blocks[0] as usize, 16 * i);
+        }
+        assert_eq!(pool.free_blocks(), 0);
+        assert_eq!((pool.free(), pool.largest_free()), (0, 0));
+    }
+
+    #[test]
+    fn exhaustion() {
+        let pool = Pool::<16, 4>::new();
+        for _ in 0..4 {
+            assert!(!unsafe { pool.alloc(WORD) }.is_null());
+        }
+        assert!(unsafe { pool.alloc(WORD) }.is_null());
+        assert!(unsafe { pool.alloc(Layout::new::<u8>()) }.is_null());
+        // failing takes nothing
+        assert_eq!(pool.free_blocks(), 0);
+    }
+
+    #[test]
+    fn reuse() {
+        let pool = Pool::<16, 4>::new();
+        let blocks: Vec<_> = (0..4).map(|_| unsafe { pool.alloc(WORD) }).collect();
+        unsafe {
+            pool.dealloc(blocks[1], WORD);
+            pool.dealloc(blocks[3], WORD);
+        }
+        assert_eq!(pool.free_blocks(), 2);
+        assert_eq!((pool.free(), pool.largest_free()), (32, 16));
+        // the last freed comes back first
+        assert_eq!(unsafe { pool.alloc(WORD) }, blocks[3]);
+        assert_eq!(unsafe { pool.alloc(WORD) }, blocks[1]);
+        assert!(unsafe { pool.alloc(WORD) }.is_null());
+
+        // round and round, never running out
+        for _ in 0..100 {
+            unsafe {
+                pool.dealloc(blocks[2], WORD);
+                assert_eq!(pool.alloc(WORD), blocks[2]);
+            }
+        }
+        assert_eq!(pool.free_blocks(), 0);
+    }
+
+    #[test]
+    fn reuse_before_the_unused_blocks() {
+        let pool = Pool::<16, 4>::new();
+        let first = unsafe { pool.alloc(WORD) };
+        unsafe { pool.dealloc(first, WORD) };
+        assert_eq!(unsafe { pool.alloc(WORD) }, first);
+        assert_eq!(pool.free_blocks(), 3);
+    }
+
+    #[test]
+    fn layouts() {
+        let pool = Pool::<16, 4>::new();
+        assert!(Pool::<16, 4>::fits(Layout::from_size_align(16, 8).unwrap()));
+        assert!(!Pool::<16, 4>::fits(Layout::from_size_align(17, 1).unwrap()));
+        assert!(!Pool::<16, 4>::fits(Layout::from_size_align(8, 16).unwrap()));
+        assert!(unsafe { pool.alloc(Layout::from_size_align(17, 1).unwrap()) }.is_null());
+        assert!(unsafe { pool.alloc(Layout::from_size_align(8, 16).unwrap()) }.is_null());
+        assert_eq!(pool.free_blocks(), 4);
+
+        let block = unsafe { pool.alloc(WORD) };
+        assert_eq!(unsafe { pool.realloc(block, WORD, 16) }, block);
+        assert!(unsafe { pool.realloc(block, WORD, 17) }.is_null());
+        assert_eq!(pool.free_blocks(), 3);
+    }
+}
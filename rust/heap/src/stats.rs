@@ -0,0 +1,236 @@
+//! Counting what an allocator hands out
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::fmt;
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// What an allocator can tell about the memory it has left
+pub trait FreeSpace {
+    /// Bytes not allocated
+    fn free(&self) -> usize;
+    /// The largest allocation that would succeed right now
+    fn largest_free(&self) -> usize;
+}
+
+/// What to do when an allocation fails, before the null goes back to the caller (and on to the
+/// out of memory handler, which panics). It must not allocate.
+pub trait OnFailure {
+    fn failed(layout: Layout, stats: Stats);
+}
+
+/// Nothing but counting the failure
+impl OnFailure for () {
+    fn failed(_: Layout, _: Stats) {}
+}
+
+/// Heap usage at one moment
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Bytes allocated and not freed, as asked for in the layouts
+    pub current: usize,
+    /// The most `current` ever was
+    pub peak: usize,
+    pub allocations: usize,
+    pub deallocations: usize,
+    /// Allocations the allocator could not serve
+    pub failures: usize,
+    pub free: usize,
+    pub largest_free: usize,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} bytes in use (peak {}), {} allocations, {} frees, {} failed, {} bytes free, largest free block {}",
+            self.current,
+            self.peak,
+            self.allocations,
+            self.deallocations,
+            self.failures,
+            self.free,
+            self.largest_free
+        )
+    }
+}
+
+/// An allocator that counts what goes through it and calls `H` when it fails
+///
+/// The hook is a type rather than a function pointer so a `static` of it stays all zeros and
+/// goes in .bss, a `Pool` in it too.
+pub struct Instrumented<A, H = ()> {
+    inner: A,
+    current: AtomicUsize,
+    peak: AtomicUsize,
+    allocations: AtomicUsize,
+    deallocations: AtomicUsize,
+    failures: AtomicUsize,
+    hook: PhantomData<H>,
+}
+
+impl<A, H> Instrumented<A, H> {
+    pub const fn new(inner: A) -> Self {
+        Instrumented {
+            inner,
+            current: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            allocations: AtomicUsize::new(0),
+            deallocations: AtomicUsize::new(0),
+            failures: AtomicUsize::new(0),
+            hook: PhantomData,
+        }
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    fn allocated(&self, size: usize) {
+        let current = self.current.fetch_add(size, Ordering::Relaxed) + size;
+        self.peak.fetch_max(current, Ordering::Relaxed);
+    }
+}
+
+impl<A: FreeSpace, H> Instrumented<A, H> {
+    pub fn stats(&self) -> Stats {
+        Stats {
+            current: self.current.load(Ordering::Relaxed),
+            peak: self.peak.load(Ordering::Relaxed),
+            allocations: self.allocations.load(Ordering::Relaxed),
+            deallocations: self.deallocations.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+            free: self.inner.free(),
+            largest_free: self.inner.largest_free(),
+        }
+    }
+
+    /// Start the peak over from what is in use now, e.g. after start up
+    pub fn reset_peak(&self) {
+        self.peak.store(self.current.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+}
+
+impl<A: FreeSpace, H: OnFailure> Instrumented<A, H> {
+    fn failed(&self, layout: Layout) {
+        self.failures.fetch_add(1, Ordering::Relaxed);
+        H::failed(layout, self.stats());
+    }
+}
+
+unsafe impl<A: GlobalAlloc + FreeSpace, H: OnFailure> GlobalAlloc for Instrumented<A, H> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let block = self.inner.alloc(layout);
+        if block.is_null() {
+            self.failed(layout);
+        } else {
+            self.allocations.fetch_add(1, Ordering::Relaxed);
+            self.allocated(layout.size());
+        }
+        block
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let block = self.inner.alloc_zeroed(layout);
+        if block.is_null() {
+            self.failed(layout);
+        } else {
+            self.allocations.fetch_add(1, Ordering::Relaxed);
+            self.allocated(layout.size());
+        }
+        block
+    }
+
+    unsafe fn dealloc(&self, block: *mut u8, layout: Layout) {
+        self.inner.dealloc(block, layout);
+        self.deallocations.fetch_add(1, Ordering::Relaxed);
+        self.current.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, block: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let moved = self.inner.realloc(block, layout, new_size);
+        if moved.is_null() {
+            self.failed(Layout::from_size_align_unchecked(new_size, layout.align()));
+        } else if new_size >= layout.size() {
+            self.allocated(new_size - layout.size());
+        } else {
+            self.current.fetch_sub(layout.size() - new_size, Ordering::Relaxed);
+        }
+        moved
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::Pool;
+    use std::string::ToString;
+    use std::sync::Mutex;
+    use std::vec::Vec;
+
+    /// The failures `Hook` saw
+    static FAILED: Mutex<Vec<(Layout, Stats)>> = Mutex::new(Vec::new());
+
+    struct Hook;
+
+    impl OnFailure for Hook {
+        fn failed(layout: Layout, stats: Stats) {
+            FAILED.lock().unwrap().push((layout, stats));
+        }
+    }
+
+    fn layout(size: usize) -> Layout {
+        Layout::from_size_align(size, 4).unwrap()
+    }
+
+    #[test]
+    fn usage() {
+        let heap = Instrumented::<Pool<16, 4>>::new(Pool::new());
+        let a = unsafe { heap.alloc(layout(12)) };
+        let b = unsafe { heap.alloc_zeroed(layout(4)) };
+        assert_eq!(unsafe { core::slice::from_raw_parts(b, 4) }, [0; 4]);
+        assert_eq!(
+            heap.stats(),
+            Stats { current: 16, peak: 16, allocations: 2, free: 32, largest_free: 16, ..Stats::default() }
+        );
+
+        unsafe { heap.dealloc(a, layout(12)) };
+        let c = unsafe { heap.alloc(layout(8)) };
+        assert_eq!(
+            heap.stats(),
+            Stats { current: 12, peak: 16, allocations: 3, deallocations: 1, free: 32, largest_free: 16, failures: 0 }
+        );
+
+        // growing in place counts the difference, shrinking gives it back
+        let c = unsafe { heap.realloc(c, layout(8), 16) };
+        assert_eq!((heap.stats().current, heap.stats().peak), (20, 20));
+        unsafe { heap.realloc(c, layout(16), 2) };
+        assert_eq!((heap.stats().current, heap.stats().peak), (6, 20));
+
+        heap.reset_peak();
+        assert_eq!(heap.stats().peak, 6);
+        assert_eq!(
+            heap.stats().to_string(),
+            "6 bytes in use (peak 6), 3 allocations, 1 frees, 0 failed, 32 bytes free, largest free block 16"
+        );
+    }
+
+    #[test]
+    fn failures() {
+        let heap = Instrumented::<Pool<16, 2>, Hook>::new(Pool::new());
+        let a = unsafe { heap.alloc(layout(16)) };
+        assert!(unsafe { heap.alloc(layout(32)) }.is_null());
+        unsafe { heap.alloc(layout(8)) };
+        assert!(unsafe { heap.alloc_zeroed(layout(8)) }.is_null());
+        assert!(unsafe { heap.realloc(a, layout(16), 20) }.is_null());
+
+        let stats = heap.stats();
+        assert_eq!((stats.current, stats.allocations, stats.failures), (24, 2, 3));
+        // the hook sees the layout that did not fit and the counts with it
+        let failed = FAILED.lock().unwrap();
+        let seen: Vec<_> = failed.iter().map(|(layout, stats)| (layout.size(), stats.failures, stats.free)).collect();
+        assert_eq!(seen, [(32, 1, 16), (8, 2, 0), (20, 3, 0)]);
+    }
+}
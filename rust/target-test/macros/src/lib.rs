@@ -0,0 +1,148 @@
+//! `#[target_test::tests]`, see the `target-test` crate
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::quote;
+use syn::punctuated::Punctuated;
+use syn::{parse_macro_input, Attribute, Error, Item, ItemFn, ItemMod, Meta, ReturnType, Token};
+
+/// Marks a test function inside a `#[target_test::tests]` module, which is what reads it
+#[proc_macro_attribute]
+pub fn target_test(_: TokenStream, item: TokenStream) -> TokenStream {
+    let mut tokens: TokenStream2 = item.into();
+    tokens.extend(
+        Error::new(
+            Span::call_site(),
+            "#[target_test] only works inside a #[target_test::tests] module",
+        )
+        .to_compile_error(),
+    );
+    tokens.into()
+}
+
+/// Makes the test table, the entry point and the `HardFault` handler of a test binary from the
+/// `#[target_test]` functions of a module
+#[proc_macro_attribute]
+pub fn tests(args: TokenStream, item: TokenStream) -> TokenStream {
+    let module = parse_macro_input!(item as ItemMod);
+    let result = if args.is_empty() {
+        expand(module)
+    } else {
+        Err(Error::new(Span::call_site(), "#[tests] takes no arguments"))
+    };
+    result.unwrap_or_else(Error::into_compile_error).into()
+}
+
+/// What `#[target_test(..)]` says about a test
+struct Options {
+    expect: TokenStream2,
+    ignore: bool,
+}
+
+fn expand(mut module: ItemMod) -> syn::Result<TokenStream2> {
+    let (_, items) = module
+        .content
+        .as_mut()
+        .ok_or_else(|| Error::new_spanned(&module.ident, "#[tests] needs a module with a body"))?;
+
+    let mut entries = Vec::new();
+    for item in items.iter_mut() {
+        if let Item::Fn(function) = item {
+            if let Some(options) = take_options(function)? {
+                check(function)?;
+                let ident = &function.sig.ident;
+                let name = ident.to_string();
+                let Options { expect, ignore } = options;
+                entries.push(quote! {
+                    ::target_test::Test {
+                        name: #name,
+                        function: #ident,
+                        expect: ::target_test::Expect::#expect,
+                        ignore: #ignore,
+                    }
+                });
+            }
+        }
+    }
+    if entries.is_empty() {
+        return Err(Error::new_spanned(&module.ident, "no #[target_test] functions in the module"));
+    }
+
+    let count = entries.len();
+    items.push(syn::parse_quote! {
+        static __TARGET_TESTS: [::target_test::Test; #count] = [#(#entries),*];
+    });
+    items.push(syn::parse_quote! {
+        #[::cortex_m_rt::entry]
+        fn __target_test_main() -> ! {
+            ::target_test::run(&__TARGET_TESTS)
+        }
+    });
+    items.push(syn::parse_quote! {
+        #[::cortex_m_rt::exception]
+        unsafe fn HardFault(frame: &::cortex_m_rt::ExceptionFrame) -> ! {
+            ::target_test::hard_fault(frame)
+        }
+    });
+    Ok(quote!(#module))
+}
+
+/// Remove the `#[target_test]` attribute of a function and read it, `None` for other functions
+fn take_options(function: &mut ItemFn) -> syn::Result<Option<Options>> {
+    let position = function.attrs.iter().position(is_target_test);
+    let attr = match position {
+        Some(position) => function.attrs.remove(position),
+        None => return Ok(None),
+    };
+    if function.attrs.iter().any(is_target_test) {
+        return Err(Error::new_spanned(&function.sig.ident, "more than one #[target_test]"));
+    }
+
+    let mut options = Options {
+        expect: quote!(Return),
+        ignore: false,
+    };
+    if let Meta::List(_) = attr.meta {
+        let flags = attr.parse_args_with(Punctuated::<syn::Ident, Token![,]>::parse_terminated)?;
+        for flag in flags {
+            match flag.to_string().as_str() {
+                "should_panic" => options.expect = quote!(Panic),
+                "should_fault" => options.expect = quote!(Fault),
+                "ignore" => options.ignore = true,
+                _ => {
+                    return Err(Error::new_spanned(
+                        flag,
+                        "expected `should_panic`, `should_fault` or `ignore`",
+                    ))
+                }
+            }
+        }
+    }
+    Ok(Some(options))
+}
+
+fn is_target_test(attr: &Attribute) -> bool {
+    attr.path()
+        .segments
+        .last()
+        .map(|segment| segment.ident == "target_test")
+        .unwrap_or(false)
+}
+
+/// A test is a plain `fn name()`, it goes in a table of `fn()`
+fn check(function: &ItemFn) -> syn::Result<()> {
+    let sig = &function.sig;
+    if !sig.inputs.is_empty() {
+        return Err(Error::new_spanned(&sig.inputs, "a test takes no arguments"));
+    }
+    if !sig.generics.params.is_empty() {
+        return Err(Error::new_spanned(&sig.generics, "a test cannot be generic"));
+    }
+    if let ReturnType::Type(..) = sig.output {
+        return Err(Error::new_spanned(&sig.output, "a test returns nothing"));
+    }
+    if sig.asyncness.is_some() || sig.unsafety.is_some() || sig.abi.is_some() {
+        return Err(Error::new_spanned(sig, "a test is a plain `fn`"));
+    }
+    Ok(())
+}
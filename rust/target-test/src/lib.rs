@@ -0,0 +1,242 @@
+//! Unit tests that run on the target
+//!
+//! `rust/app/examples/test_on_host.rs` can only test what builds for the host. This runs test
+//! functions on the microcontroller itself (the LM3S6965 that QEMU emulates, or a board) and
+//! reports the results over semihosting, where `cargo xtask test` picks them up.
+//!
+//! ``` ignore
+//! #![no_main]
+//! #![no_std]
+//!
+//! use lm3s6965 as _; // the device crate, for the interrupt vectors
+//!
+//! #[target_test::tests]
+//! mod tests {
+//!     #[target_test]
+//!     fn adds() {
+//!         assert_eq!(1 + 1, 2);
+//!     }
+//!
+//!     #[target_test(should_panic)]
+//!     fn overflows() {
+//!         let _ = core::hint::black_box(u8::MAX) + 1;
+//!     }
+//! }
+//! ```
+//!
+//! `#[tests]` collects the `#[target_test]` functions of the module into a table and makes the
+//! entry point and the `HardFault` handler, so the crate has to depend on `cortex-m-rt` and must
+//! not have its own. A test passes when it returns; `should_panic` and `should_fault` turn that
+//! around and `ignore` skips it.
+//!
+//! There is no unwinding on a Cortex-M, so a panic or a HardFault cannot simply be caught.
+//! Instead the handler reports the test and resets the core. The progress of the run is kept in
+//! `.uninit` RAM, which the reset leaves alone, so the tests carry on from the next one. Tests
+//! that return run one after the other without a reset in between, so a test cannot count on
+//! the peripherals being as they are after reset.
+//!
+//! The results are lines on the host's stdout, anything else the tests print is in between:
+//!
+//! ``` text
+//! target-test start <number of tests>
+//! target-test ok <name>
+//! target-test fail <name> <reason>
+//! target-test ignored <name>
+//! target-test done <passed> <failed> <ignored>
+//! ```
+//!
+//! After `done` it exits through semihosting, with failure if a test failed.
+
+#![no_std]
+
+use core::fmt::{self, Write};
+use core::mem::MaybeUninit;
+use core::panic::PanicInfo;
+use core::ptr;
+
+use cortex_m::interrupt;
+use cortex_m::peripheral::SCB;
+use cortex_m_rt::ExceptionFrame;
+use cortex_m_semihosting::{debug, hio};
+
+pub use target_test_macros::{target_test, tests};
+
+/// How a test ends when it passes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expect {
+    Return,
+    Panic,
+    Fault,
+}
+
+/// An entry of the table `#[tests]` makes
+pub struct Test {
+    pub name: &'static str,
+    pub function: fn(),
+    pub expect: Expect,
+    pub ignore: bool,
+}
+
+/// Marks `PROGRESS` as written by this run rather than whatever was in RAM at power up
+const MAGIC: u32 = 0x7e57_7e57;
+/// No test running
+const NONE: u32 = u32::MAX;
+
+/// Where the run is, survives the resets after a panic or fault
+#[repr(C)]
+struct Progress {
+    magic: u32,
+    /// Tests in the table, another binary starts over
+    count: u32,
+    next: u32,
+    running: u32,
+    passed: u32,
+    failed: u32,
+    ignored: u32,
+}
+
+#[link_section = ".uninit.target_test.PROGRESS"]
+static mut PROGRESS: MaybeUninit<Progress> = MaybeUninit::uninit();
+
+/// The table, for the panic and fault handlers
+static mut TESTS: &[Test] = &[];
+
+// Only used through a raw pointer: a test that panics or faults ends up in the handlers, which
+// update it too, while `run` is in the middle of the test.
+fn progress() -> *mut Progress {
+    unsafe { (*ptr::addr_of_mut!(PROGRESS)).as_mut_ptr() }
+}
+
+/// Run the tests from where the run got to, then exit. Called by the entry point of `#[tests]`.
+pub fn run(tests: &'static [Test]) -> ! {
+    let progress = progress();
+    unsafe {
+        TESTS = tests;
+        if (*progress).magic != MAGIC || (*progress).count != tests.len() as u32 {
+            progress.write(Progress {
+                magic: MAGIC,
+                count: tests.len() as u32,
+                next: 0,
+                running: NONE,
+                passed: 0,
+                failed: 0,
+                ignored: 0,
+            });
+            report(format_args!("start {}", tests.len()));
+        }
+
+        while let Some(test) = tests.get((*progress).next as usize) {
+            // a test that does not return carries on with the next one after the reset
+            (*progress).next += 1;
+            if test.ignore {
+                (*progress).ignored += 1;
+                report(format_args!("ignored {}", test.name));
+                continue;
+            }
+
+            (*progress).running = (*progress).next - 1;
+            (test.function)();
+            (*progress).running = NONE;
+            match test.expect {
+                Expect::Return => passed(test),
+                Expect::Panic => failed(test, format_args!("did not panic")),
+                Expect::Fault => failed(test, format_args!("did not fault")),
+            }
+        }
+
+        let Progress {
+            passed,
+            failed,
+            ignored,
+            ..
+        } = *progress;
+        report(format_args!("done {} {} {}", passed, failed, ignored));
+        // the next run starts over
+        (*progress).magic = 0;
+        debug::exit(if failed == 0 {
+            debug::EXIT_SUCCESS
+        } else {
+            debug::EXIT_FAILURE
+        });
+    }
+    // no debugger to exit to
+    loop {
+        continue;
+    }
+}
+
+unsafe fn passed(test: &Test) {
+    (*progress()).passed += 1;
+    report(format_args!("ok {}", test.name));
+}
+
+unsafe fn failed(test: &Test, reason: fmt::Arguments) {
+    (*progress()).failed += 1;
+    report(format_args!("fail {} {}", test.name, reason));
+}
+
+/// The running test did not return: report it and reset to carry on with the next one
+fn ended(how: Expect, reason: fmt::Arguments) -> ! {
+    interrupt::disable();
+    let progress = progress();
+    unsafe {
+        let running = if (*progress).magic == MAGIC {
+            (*progress).running
+        } else {
+            NONE
+        };
+        let tests: &[Test] = *ptr::addr_of!(TESTS);
+        match tests.get(running as usize) {
+            Some(test) => {
+                (*progress).running = NONE;
+                if test.expect == how {
+                    passed(test);
+                } else {
+                    failed(test, reason);
+                }
+                SCB::sys_reset()
+            }
+            None => {
+                // not in a test, the run itself is broken
+                report(format_args!("error {}", reason));
+                debug::exit(debug::EXIT_FAILURE);
+                loop {
+                    continue;
+                }
+            }
+        }
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ended(Expect::Panic, format_args!("{}", info))
+}
+
+/// Called by the `HardFault` handler that `#[tests]` makes
+#[doc(hidden)]
+pub fn hard_fault(frame: &ExceptionFrame) -> ! {
+    ended(Expect::Fault, format_args!("hard fault at {:#010x}", frame.pc()))
+}
+
+/// A result line; newlines in it become spaces so a panic message stays on its line
+fn report(line: fmt::Arguments) {
+    if let Ok(mut stdout) = hio::hstdout() {
+        write!(OneLine(&mut stdout), "target-test {}", line).ok();
+        stdout.write_str("\n").ok();
+    }
+}
+
+struct OneLine<'a, W>(&'a mut W);
+
+impl<W: Write> Write for OneLine<'_, W> {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        for (i, part) in text.split('\n').enumerate() {
+            if i > 0 {
+                self.0.write_str(" ")?;
+            }
+            self.0.write_str(part)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,590 @@
+//! Runs the examples and the on-target tests of `app` on QEMU
+//!
+//! ```text
+//! cargo xtask qemu [--timeout <seconds>] [--bless] [example...]
+//! cargo xtask test [--timeout <seconds>] [test...]
+//! ```
+//!
+//! `qemu`: every example with an expected output in `app/ci/expected/<example>.run` (or the ones
+//! named) is built for thumbv7m-none-eabi with the `qemu` feature and run on
+//! `qemu-system-arm -machine lm3s6965evb` with semihosting. An example passes when QEMU exits
+//! with 0, which `debug::exit(debug::EXIT_SUCCESS)` does, before the timeout and it printed
+//! what was expected. `--bless` writes what it printed as the new expected output instead.
+//!
+//! `test`: the tests in `app/tests/` (or the ones named) are built the same way and run on QEMU,
+//! each prints its results as `target-test ...` lines (see `target-test/src/lib.rs`). The
+//! timeout is for all the tests of a file together.
+//!
+//! Needs `qemu-system-arm` on the PATH and `rustup target add thumbv7m-none-eabi`.
+
+use std::env;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitCode, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const USAGE: &str = "usage: cargo xtask qemu [--timeout <seconds>] [--bless] [example...]
+       cargo xtask test [--timeout <seconds>] [test...]";
+
+const TARGET: &str = "thumbv7m-none-eabi";
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Options {
+    timeout: Duration,
+    bless: bool,
+    /// Examples or tests, all of them when empty
+    names: Vec<String>,
+}
+
+/// How QEMU ended and what it printed
+struct Finished {
+    /// `None` when it timed out
+    status: Option<ExitStatus>,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+/// What happened to one example
+enum Outcome {
+    Passed,
+    Blessed,
+    Failed(String),
+}
+
+/// Results of one file of on-target tests
+#[derive(Debug, Default, PartialEq)]
+struct Tally {
+    passed: usize,
+    failed: usize,
+    ignored: usize,
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.split_first() {
+        Some((command, rest)) if command == "qemu" => options(rest).and_then(|options| qemu(&options)),
+        Some((command, rest)) if command == "test" => options(rest).and_then(|options| test(&options)),
+        _ => Err(USAGE.to_string()),
+    };
+    match result {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(error) => {
+            eprintln!("xtask: {}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn options(args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        timeout: DEFAULT_TIMEOUT,
+        bless: false,
+        names: Vec::new(),
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--bless" => options.bless = true,
+            "--timeout" => {
+                let seconds = args
+                    .next()
+                    .and_then(|seconds| seconds.parse().ok())
+                    .ok_or("--timeout needs a number of seconds")?;
+                options.timeout = Duration::from_secs(seconds);
+            }
+            flag if flag.starts_with('-') => return Err(USAGE.to_string()),
+            name => options.names.push(name.to_string()),
+        }
+    }
+    Ok(options)
+}
+
+fn app_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("../app")
+}
+
+fn expected_path(example: &str) -> PathBuf {
+    app_dir().join("ci/expected").join(format!("{}.run", example))
+}
+
+/// The examples that have an expected output
+fn all_examples() -> Result<Vec<String>, String> {
+    let dir = app_dir().join("ci/expected");
+    let entries = fs::read_dir(&dir).map_err(|error| format!("{}: {}", dir.display(), error))?;
+    let mut examples: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            name.strip_suffix(".run").map(str::to_string)
+        })
+        .collect();
+    examples.sort();
+    Ok(examples)
+}
+
+fn qemu(options: &Options) -> Result<bool, String> {
+    let examples = if options.names.is_empty() {
+        all_examples()?
+    } else {
+        options.names.clone()
+    };
+
+    let mut failed = Vec::new();
+    for example in &examples {
+        let outcome = match build(example) {
+            Ok(elf) => run(example, &elf, options),
+            Err(error) => Outcome::Failed(error),
+        };
+        match outcome {
+            Outcome::Passed => println!("{} ... ok", example),
+            Outcome::Blessed => println!("{} ... blessed", example),
+            Outcome::Failed(reason) => {
+                println!("{} ... FAILED\n{}", example, reason);
+                failed.push(example.as_str());
+            }
+        }
+    }
+
+    println!(
+        "\n{} passed, {} failed{}",
+        examples.len() - failed.len(),
+        failed.len(),
+        if failed.is_empty() {
+            String::new()
+        } else {
+            format!(": {}", failed.join(", "))
+        }
+    );
+    Ok(failed.is_empty())
+}
+
+/// Build an example, returns the ELF
+fn build(example: &str) -> Result<PathBuf, String> {
+    let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+    let status = Command::new(cargo)
+        .current_dir(app_dir())
+        .args(["build", "--example", example, "--features", "qemu", "--target", TARGET])
+        .status()
+        .map_err(|error| format!("cargo: {}", error))?;
+    if !status.success() {
+        return Err(format!("build failed ({})", status));
+    }
+    Ok(app_dir().join("target").join(TARGET).join("debug/examples").join(example))
+}
+
+fn run(example: &str, elf: &Path, options: &Options) -> Outcome {
+    let finished = match emulate(elf, options.timeout) {
+        Ok(finished) => finished,
+        Err(error) => return Outcome::Failed(error),
+    };
+    let output = normalize(&finished.stdout);
+    let stderr = normalize(&finished.stderr);
+
+    let status = match finished.status {
+        Some(status) => status,
+        None => {
+            return Outcome::Failed(format!(
+                "timed out after {} s, printed:\n{}{}",
+                options.timeout.as_secs(),
+                output,
+                stderr
+            ))
+        }
+    };
+    if !status.success() {
+        return Outcome::Failed(format!("qemu exited with {}, printed:\n{}{}", status, output, stderr));
+    }
+
+    let path = expected_path(example);
+    if options.bless {
+        return match fs::write(&path, &output) {
+            Ok(()) => Outcome::Blessed,
+            Err(error) => Outcome::Failed(format!("{}: {}", path.display(), error)),
+        };
+    }
+    let expected = match fs::read(&path) {
+        Ok(expected) => normalize(&expected),
+        Err(error) => return Outcome::Failed(format!("{}: {} (run with --bless to make it)", path.display(), error)),
+    };
+    if output == expected {
+        Outcome::Passed
+    } else {
+        Outcome::Failed(diff(&expected, &output))
+    }
+}
+
+fn test(options: &Options) -> Result<bool, String> {
+    if options.bless {
+        return Err("--bless is only for `qemu`, the tests check themselves".to_string());
+    }
+    let files = build_tests(&options.names)?;
+    if files.is_empty() {
+        return Err("no tests to run".to_string());
+    }
+
+    let mut total = Tally::default();
+    let mut failed = Vec::new();
+    for (file, elf) in &files {
+        match run_tests(file, elf, options.timeout) {
+            Ok(tally) => {
+                if tally.failed > 0 {
+                    failed.push(file.as_str());
+                }
+                total.passed += tally.passed;
+                total.failed += tally.failed;
+                total.ignored += tally.ignored;
+            }
+            Err(error) => {
+                println!("{} ... FAILED\n{}", file, error);
+                failed.push(file.as_str());
+            }
+        }
+    }
+
+    println!(
+        "\n{} passed, {} failed, {} ignored{}",
+        total.passed,
+        total.failed,
+        total.ignored,
+        if failed.is_empty() {
+            String::new()
+        } else {
+            format!(": {}", failed.join(", "))
+        }
+    );
+    Ok(failed.is_empty())
+}
+
+/// Build the on-target tests, returns the name and ELF of each file
+fn build_tests(names: &[String]) -> Result<Vec<(String, PathBuf)>, String> {
+    let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+    let mut command = Command::new(cargo);
+    command
+        .current_dir(app_dir())
+        .args(["test", "--no-run", "--features", "qemu", "--target", TARGET])
+        .args(["--message-format", "json-render-diagnostics"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit());
+    if names.is_empty() {
+        command.arg("--tests");
+    }
+    for name in names {
+        command.args(["--test", name]);
+    }
+    let output = command.output().map_err(|error| format!("cargo: {}", error))?;
+    if !output.status.success() {
+        return Err(format!("build failed ({})", output.status));
+    }
+
+    // one JSON message a line, the test executables are the artifacts of kind "test"
+    let mut files = Vec::new();
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        if !line.contains("\"reason\":\"compiler-artifact\"") || !line.contains("\"kind\":[\"test\"]") {
+            continue;
+        }
+        let target = match line.find("\"target\":") {
+            Some(start) => &line[start..],
+            None => continue,
+        };
+        if let (Some(name), Some(elf)) = (json_string(target, "name"), json_string(line, "executable")) {
+            files.push((name, PathBuf::from(elf)));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// The first `"key":"value"` in a line of JSON, enough for names and paths
+fn json_string(json: &str, key: &str) -> Option<String> {
+    let start = json.find(&format!("\"{}\":\"", key))? + key.len() + 4;
+    let mut value = String::new();
+    let mut chars = json[start..].chars();
+    loop {
+        match chars.next()? {
+            '"' => return Some(value),
+            '\\' => match chars.next()? {
+                'n' => value.push('\n'),
+                't' => value.push('\t'),
+                escaped => value.push(escaped),
+            },
+            c => value.push(c),
+        }
+    }
+}
+
+/// Run one file of tests on QEMU and print the result of each test. `Err` when the run itself
+/// went wrong: it did not finish, or the results do not add up.
+fn run_tests(file: &str, elf: &Path, timeout: Duration) -> Result<Tally, String> {
+    let finished = emulate(elf, timeout)?;
+    results(file, &finished, timeout)
+}
+
+/// Count the result lines of a finished run of one file
+fn results(file: &str, finished: &Finished, timeout: Duration) -> Result<Tally, String> {
+    let output = normalize(&finished.stdout);
+    let stderr = normalize(&finished.stderr);
+
+    let mut tally = Tally::default();
+    let mut started = None;
+    let mut done = None;
+    // what the tests printed since the last result, shown with a failure
+    let mut printed = String::new();
+    for line in output.lines() {
+        let result = match line.strip_prefix("target-test ") {
+            Some(result) => result,
+            None => {
+                printed += &format!("  {}\n", line);
+                continue;
+            }
+        };
+        let (kind, rest) = result.split_once(' ').unwrap_or((result, ""));
+        match kind {
+            "start" => started = rest.parse::<usize>().ok(),
+            "ok" => {
+                tally.passed += 1;
+                println!("{}::{} ... ok", file, rest);
+            }
+            "ignored" => {
+                tally.ignored += 1;
+                println!("{}::{} ... ignored", file, rest);
+            }
+            "fail" => {
+                tally.failed += 1;
+                let (test, reason) = rest.split_once(' ').unwrap_or((rest, ""));
+                println!("{}::{} ... FAILED\n  {}", file, test, reason);
+                print!("{}", printed);
+            }
+            "done" => done = Some(rest.to_string()),
+            "error" => return Err(format!("the tests broke: {}\n{}{}", rest, printed, stderr)),
+            _ => return Err(format!("unknown result `{}`", line)),
+        }
+        printed.clear();
+    }
+
+    let status = match finished.status {
+        Some(status) => status,
+        None => {
+            return Err(format!(
+                "timed out after {} s, then printed:\n{}{}",
+                timeout.as_secs(),
+                printed,
+                stderr
+            ))
+        }
+    };
+    let done = match done {
+        Some(done) => done,
+        None => return Err(format!("stopped before the end ({}), then printed:\n{}{}", status, printed, stderr)),
+    };
+    let counted = format!("{} {} {}", tally.passed, tally.failed, tally.ignored);
+    if done != counted || started != Some(tally.passed + tally.failed + tally.ignored) {
+        return Err(format!(
+            "the results do not add up: {:?} tests, passed failed ignored {} but {} reported",
+            started, counted, done
+        ));
+    }
+    if status.success() != (tally.failed == 0) {
+        return Err(format!("qemu exited with {}\n{}", status, stderr));
+    }
+    Ok(tally)
+}
+
+/// Run an ELF on the emulated LM3S6965
+fn emulate(elf: &Path, timeout: Duration) -> Result<Finished, String> {
+    let mut child = Command::new("qemu-system-arm")
+        .args(["-cpu", "cortex-m3", "-machine", "lm3s6965evb", "-nographic"])
+        .args(["-semihosting-config", "enable=on,target=native", "-kernel"])
+        .arg(elf)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|error| format!("qemu-system-arm: {}", error))?;
+    wait(&mut child, timeout)
+}
+
+/// Wait for QEMU to exit, killing it at the timeout. The output is read all along so a chatty
+/// example cannot fill the pipe and stall.
+fn wait(child: &mut Child, timeout: Duration) -> Result<Finished, String> {
+    let stdout = child.stdout.take().map(read_all);
+    let stderr = child.stderr.take().map(read_all);
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break Some(status),
+            Ok(None) if Instant::now() >= deadline => {
+                child.kill().ok();
+                child.wait().ok();
+                break None;
+            }
+            Ok(None) => thread::sleep(Duration::from_millis(10)),
+            Err(error) => return Err(format!("qemu-system-arm: {}", error)),
+        }
+    };
+
+    let join = |reader: Option<thread::JoinHandle<Vec<u8>>>| {
+        reader.and_then(|reader| reader.join().ok()).unwrap_or_default()
+    };
+    Ok(Finished {
+        status,
+        stdout: join(stdout),
+        stderr: join(stderr),
+    })
+}
+
+fn read_all(mut pipe: impl Read + Send + 'static) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut data = Vec::new();
+        pipe.read_to_end(&mut data).ok();
+        data
+    })
+}
+
+/// Line endings as `\n` and exactly one at the end, so editors do not break the comparison
+fn normalize(output: &[u8]) -> String {
+    let text = String::from_utf8_lossy(output).replace("\r\n", "\n");
+    let text = text.trim_end();
+    if text.is_empty() {
+        String::new()
+    } else {
+        format!("{}\n", text)
+    }
+}
+
+/// The lines that differ, `-` expected and `+` printed
+fn diff(expected: &str, output: &str) -> String {
+    let expected: Vec<&str> = expected.lines().collect();
+    let output: Vec<&str> = output.lines().collect();
+    let mut diff = String::from("output differs from the expected one:\n");
+    for line in 0..expected.len().max(output.len()) {
+        match (expected.get(line), output.get(line)) {
+            (Some(want), Some(got)) if want == got => diff += &format!("  {}\n", want),
+            (want, got) => {
+                if let Some(want) = want {
+                    diff += &format!("- {}\n", want);
+                }
+                if let Some(got) = got {
+                    diff += &format!("+ {}\n", got);
+                }
+            }
+        }
+    }
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    // exit codes made up without running anything
+    use std::os::unix::process::ExitStatusExt;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn finished(code: Option<i32>, stdout: &str) -> Finished {
+        Finished {
+            status: code.map(|code| ExitStatus::from_raw(code << 8)),
+            stdout: stdout.as_bytes().to_vec(),
+            stderr: Vec::new(),
+        }
+    }
+
+    fn tally(passed: usize, failed: usize, ignored: usize) -> Tally {
+        Tally { passed, failed, ignored }
+    }
+
+    #[test]
+    fn json_strings() {
+        // trimmed down from `cargo test --message-format json`
+        let line = concat!(
+            r#"{"reason":"compiler-artifact","target":{"kind":["test"],"name":"target"},"#,
+            r#""executable":"/app/target/deps/target-4e1f"}"#
+        );
+        assert_eq!(json_string(line, "reason").as_deref(), Some("compiler-artifact"));
+        assert_eq!(json_string(line, "name").as_deref(), Some("target"));
+        assert_eq!(json_string(line, "executable").as_deref(), Some("/app/target/deps/target-4e1f"));
+        assert_eq!(json_string(line, "kind"), None, "not a string");
+        assert_eq!(json_string(line, "path"), None);
+
+        let escaped = r#"{"path":"C:\\app\\\"x\"\tout\n"}"#;
+        assert_eq!(json_string(escaped, "path").as_deref(), Some("C:\\app\\\"x\"\tout\n"));
+        assert_eq!(json_string(r#"{"path":"cut off"#, "path"), None);
+        assert_eq!(json_string(r#"{"path":"cut off\"#, "path"), None);
+    }
+
+    #[test]
+    fn normalized() {
+        assert_eq!(normalize(b"one\r\ntwo\r\n\r\n\n"), "one\ntwo\n");
+        assert_eq!(normalize(b"no newline"), "no newline\n");
+        assert_eq!(normalize(b"  indented\n"), "  indented\n");
+        assert_eq!(normalize(b"\r\n \n"), "");
+        assert_eq!(normalize(b""), "");
+    }
+
+    #[test]
+    fn diffs() {
+        assert_eq!(
+            diff("a\nb\nc\n", "a\nB\nc\nd\n"),
+            "output differs from the expected one:\n  a\n- b\n+ B\n  c\n+ d\n"
+        );
+        assert_eq!(diff("a\nb\n", "a\n"), "output differs from the expected one:\n  a\n- b\n");
+    }
+
+    #[test]
+    fn results_that_add_up() {
+        let output = "target-test start 3\n\
+                      target-test ok adds\n\
+                      target-test ignored blinks\n\
+                      target-test ok divides\n\
+                      target-test done 2 0 1\n";
+        assert_eq!(results("math", &finished(Some(0), output), TIMEOUT), Ok(tally(2, 0, 1)));
+
+        // a failure exits with 1, the lines printed before it go with it
+        let output = "target-test start 2\n\
+                      left 1 right 2\n\
+                      target-test fail adds assertion failed\n\
+                      target-test ok divides\n\
+                      target-test done 1 1 0\n";
+        assert_eq!(results("math", &finished(Some(1), output), TIMEOUT), Ok(tally(1, 1, 0)));
+    }
+
+    #[test]
+    fn results_that_do_not() {
+        let error = |code, output: &str| results("math", &finished(code, output), TIMEOUT).unwrap_err();
+
+        // a test that never reported
+        let output = "target-test start 3\ntarget-test ok adds\ntarget-test ok divides\n\
+                      target-test done 2 0 0\n";
+        assert!(error(Some(0), output).starts_with("the results do not add up: Some(3) tests"));
+        // done disagrees with the lines
+        let output = "target-test start 2\ntarget-test ok adds\ntarget-test ok divides\n\
+                      target-test done 2 0 1\n";
+        assert!(error(Some(0), output).contains("passed failed ignored 2 0 0 but 2 0 1 reported"));
+        // no start line
+        let output = "target-test ok adds\ntarget-test done 1 0 0\n";
+        assert!(error(Some(0), output).starts_with("the results do not add up: None tests"));
+        // no done line, the board stopped halfway
+        let output = "target-test start 2\ntarget-test ok adds\npanicked\n";
+        assert_eq!(
+            error(Some(1), output),
+            "stopped before the end (exit status: 1), then printed:\n  panicked\n"
+        );
+        // still running at the timeout
+        let output = "target-test start 2\ntarget-test ok adds\n";
+        assert!(error(None, output).starts_with("timed out after 10 s"));
+        // the exit code has to agree with the failures
+        let output = "target-test start 1\ntarget-test ok adds\ntarget-test done 1 0 0\n";
+        assert!(error(Some(1), output).starts_with("qemu exited with exit status: 1"));
+        let output = "target-test start 1\ntarget-test fail adds no\ntarget-test done 0 1 0\n";
+        assert!(error(Some(0), output).starts_with("qemu exited with exit status: 0"));
+        // lines the runner does not know
+        let output = "target-test start 1\ntarget-test maybe adds\n";
+        assert_eq!(error(Some(0), output), "unknown result `target-test maybe adds`");
+        let output = "target-test start 1\ntarget-test error no tests\n";
+        assert!(error(Some(1), output).starts_with("the tests broke: no tests"));
+    }
+}
+
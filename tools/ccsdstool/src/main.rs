@@ -0,0 +1,189 @@
+//! Ground end of the CCSDS link in `rtic_stm32/examples/telemetry.rs`
+//!
+//! ```text
+//! ccsdstool tm <capture>                              print the packets in captured frames
+//! ccsdstool tc <spacecraft id> <apid> <hex> <file>    write a telecommand to send
+//! ```
+//!
+//! Both ends talk KISS to the TNC. A capture is what came out of the serial port, e.g.
+//! `cat /dev/ttyACM0 > capture.kiss`, and a telecommand file goes the other way with
+//! `cat command.kiss > /dev/ttyACM0`. Numbers can be given in hex with 0x.
+
+use std::env;
+use std::fs;
+use std::process::ExitCode;
+
+use ccsds::packet::{PacketType, SpacePacket};
+use ccsds::tc::{TcFrame, TcHeader};
+use ccsds::tm::{PacketExtractor, TmFrame, MAX_VIRTUAL_CHANNEL};
+
+const USAGE: &str = "usage: ccsdstool tm <capture> | tc <spacecraft id> <apid> <hex data> <file>";
+
+const FEND: u8 = 0xC0;
+const FESC: u8 = 0xDB;
+const TFEND: u8 = 0xDC;
+const TFESC: u8 = 0xDD;
+
+/// Longest packet put back together from the frames
+const MAX_PACKET_LEN: usize = 4096;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        ["tm", path] => tm(path),
+        ["tc", spacecraft_id, apid, data, path] => tc(spacecraft_id, apid, data, path),
+        _ => Err(USAGE.to_string()),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("ccsdstool: {}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn tm(path: &str) -> Result<(), String> {
+    let capture = fs::read(path).map_err(|error| format!("{}: {}", path, error))?;
+    let mut extractors: Vec<PacketExtractor<MAX_PACKET_LEN>> =
+        (0..=MAX_VIRTUAL_CHANNEL).map(|_| PacketExtractor::new()).collect();
+
+    for (index, frame) in kiss_frames(&capture).iter().enumerate() {
+        let frame = match TmFrame::decode(frame, true) {
+            Ok(frame) => frame,
+            Err(error) => {
+                println!("frame {}: {:?}", index, error);
+                continue;
+            }
+        };
+        let header = frame.header;
+        println!(
+            "frame {}: spacecraft {:#05x} vc {} count {}/{}",
+            index, header.spacecraft_id, header.virtual_channel, header.master_count, header.virtual_count
+        );
+        extractors[header.virtual_channel as usize].push(&frame, |packet| match packet {
+            Ok(packet) => print_packet(packet),
+            Err(error) => println!("  {:?}", error),
+        });
+    }
+    Ok(())
+}
+
+fn print_packet(bytes: &[u8]) {
+    let packet = match SpacePacket::decode(bytes) {
+        Ok(packet) => packet,
+        Err(error) => {
+            println!("  packet: {:?}", error);
+            return;
+        }
+    };
+    let header = packet.header;
+    let time = match packet.time {
+        Some(time) => format!(" at {}.{:06}", time.seconds, time.micros()),
+        None => String::new(),
+    };
+    println!(
+        "  {} apid {:#05x} count {}{}: {}",
+        if header.packet_type == PacketType::Telemetry { "tm" } else { "tc" },
+        header.apid,
+        header.sequence_count,
+        time,
+        hex(packet.data)
+    );
+}
+
+fn tc(spacecraft_id: &str, apid: &str, data: &str, path: &str) -> Result<(), String> {
+    let spacecraft_id = number(spacecraft_id)?;
+    let apid = number(apid)?;
+    let data = unhex(data)?;
+
+    let packet = SpacePacket::new(PacketType::Telecommand, apid, 0, None, &data);
+    let mut packet_bytes = vec![0; packet.encoded_len()];
+    packet.encode(&mut packet_bytes).map_err(|error| format!("packet: {:?}", error))?;
+
+    // bypass frames, the firmware does no sequence checks
+    let header = TcHeader {
+        bypass: true,
+        control: false,
+        spacecraft_id,
+        virtual_channel: 0,
+        frame_len: 0,
+        sequence: 0,
+    };
+    let mut frame = [0; ccsds::tc::MAX_FRAME_LEN];
+    let len = TcFrame::encode(header, &packet_bytes, &mut frame).map_err(|error| format!("frame: {:?}", error))?;
+
+    fs::write(path, kiss_frame(&frame[..len])).map_err(|error| format!("{}: {}", path, error))?;
+    println!("wrote {} byte frame to {}", len, path);
+    Ok(())
+}
+
+/// The data frames in a KISS stream, unescaped
+fn kiss_frames(stream: &[u8]) -> Vec<Vec<u8>> {
+    stream
+        .split(|&byte| byte == FEND)
+        // the type byte of data frames on port 0
+        .filter(|frame| frame.first() == Some(&0))
+        .map(|frame| {
+            let mut data = Vec::with_capacity(frame.len());
+            let mut escaped = false;
+            for &byte in &frame[1..] {
+                match (escaped, byte) {
+                    (false, FESC) => escaped = true,
+                    (true, TFEND) => {
+                        data.push(FEND);
+                        escaped = false;
+                    }
+                    (true, TFESC) => {
+                        data.push(FESC);
+                        escaped = false;
+                    }
+                    _ => {
+                        data.push(byte);
+                        escaped = false;
+                    }
+                }
+            }
+            data
+        })
+        .collect()
+}
+
+fn kiss_frame(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![FEND, 0];
+    for &byte in data {
+        match byte {
+            FEND => out.extend_from_slice(&[FESC, TFEND]),
+            FESC => out.extend_from_slice(&[FESC, TFESC]),
+            _ => out.push(byte),
+        }
+    }
+    out.push(FEND);
+    out
+}
+
+fn number(text: &str) -> Result<u16, String> {
+    let result = match text.strip_prefix("0x") {
+        Some(digits) => u16::from_str_radix(digits, 16),
+        None => text.parse(),
+    };
+    result.map_err(|_| format!("not a number: {}", text))
+}
+
+fn unhex(text: &str) -> Result<Vec<u8>, String> {
+    if text.len() & 1 != 0 {
+        return Err(format!("odd number of hex digits: {}", text));
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|at| {
+            text.get(at..at + 2)
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .ok_or_else(|| format!("not hex: {}", text))
+        })
+        .collect()
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
@@ -0,0 +1,279 @@
+//! Reads and stamps the firmware image header from `rtic_stm32/src/header.rs`
+//!
+//! ```text
+//! imagetool show <file.elf|file.bin>   print the header
+//! imagetool stamp <file.bin>           write the image length and CRC into the header
+//! imagetool verify <file.bin>          check the length and CRC, exits with 1 if they are off
+//! ```
+//!
+//! A .bin is what `cargo objcopy -- -O binary` makes, it is stamped before it is sent to the
+//! bootloader. An ELF is never stamped since the CRC is over the binary, and neither is an image
+//! linked for the start of flash: its CRC would cover the configuration and log store sectors.
+
+use std::env;
+use std::fs;
+use std::process::ExitCode;
+
+use object::{Object, ObjectSection};
+
+// The header layout is shared with the firmware
+#[path = "../../../rtic_stm32/src/crc.rs"]
+#[allow(dead_code)]
+mod crc;
+#[path = "../../../rtic_stm32/src/header.rs"]
+#[allow(dead_code)]
+mod header;
+
+use header::{Check, ImageHeader, CRC_OFFSET, HEADER_OFFSET};
+
+const USAGE: &str = "usage: imagetool show|stamp|verify <file>";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (command, path) = match args.as_slice() {
+        [command, path] => (command.as_str(), path.as_str()),
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let result = match command {
+        "show" => show(path),
+        "stamp" => stamp(path),
+        "verify" => verify(path),
+        _ => Err(USAGE.to_string()),
+    };
+    match result {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(error) => {
+            eprintln!("imagetool: {}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn show(path: &str) -> Result<bool, String> {
+    let data = read(path)?;
+    if is_elf(&data) {
+        let header = elf_header(&data)?;
+        print_header(&header);
+        return Ok(true);
+    }
+
+    let header = ImageHeader::from_image(&data).ok_or("no image header in binary")?;
+    print_header(&header);
+    Ok(print_check(&header, &data))
+}
+
+fn stamp(path: &str) -> Result<bool, String> {
+    let mut data = read(path)?;
+    if is_elf(&data) {
+        return Err("only binaries can be stamped, use cargo objcopy -- -O binary".to_string());
+    }
+    let header = stamp_image(&mut data)?;
+    fs::write(path, &data).map_err(|error| format!("{}: {}", path, error))?;
+    println!(
+        "stamped {}: {} bytes, crc {:#010x}",
+        path, header.image_len, header.image_crc
+    );
+    Ok(true)
+}
+
+/// Write the length and CRC of `image` into its header, stamped or not
+fn stamp_image(image: &mut [u8]) -> Result<ImageHeader, String> {
+    let mut header = ImageHeader::from_image(image).ok_or("no image header in binary")?;
+    if !header.is_slot() {
+        return Err("only images built with the bootloader feature can be stamped".to_string());
+    }
+    header.image_len = image.len() as u32;
+    header.image_crc = header::UNSTAMPED;
+    image[HEADER_OFFSET..HEADER_OFFSET + header::HEADER_LEN].copy_from_slice(&header.to_bytes());
+    header.image_crc = header::image_crc(image);
+    image[CRC_OFFSET..CRC_OFFSET + 4].copy_from_slice(&header.image_crc.to_le_bytes());
+    Ok(header)
+}
+
+fn verify(path: &str) -> Result<bool, String> {
+    let data = read(path)?;
+    let header = ImageHeader::from_image(&data).ok_or("no image header in binary")?;
+    Ok(print_check(&header, &data))
+}
+
+fn read(path: &str) -> Result<Vec<u8>, String> {
+    fs::read(path).map_err(|error| format!("{}: {}", path, error))
+}
+
+fn is_elf(data: &[u8]) -> bool {
+    data.starts_with(b"\x7fELF")
+}
+
+fn elf_header(data: &[u8]) -> Result<ImageHeader, String> {
+    let file = object::File::parse(data).map_err(|error| error.to_string())?;
+    let section = file
+        .section_by_name(".image_header")
+        .ok_or("no .image_header section, is the memory.x from rtic_stm32/memory used?")?;
+    let raw = section.data().map_err(|error| error.to_string())?;
+    ImageHeader::parse(raw).ok_or_else(|| "unknown header in .image_header".to_string())
+}
+
+fn print_header(header: &ImageHeader) {
+    let [major, minor, patch] = header.version;
+    println!("version:    {}.{}.{}", major, minor, patch);
+    println!(
+        "git:        {}{}",
+        header.git_hash_str(),
+        if header.is_dirty() { "-dirty" } else { "" }
+    );
+    println!(
+        "linked for: {}",
+        if header.is_slot() { "the bootloader slot" } else { "the start of flash" }
+    );
+    println!(
+        "built:      {} ({})",
+        format_time(header.build_timestamp),
+        header.build_timestamp
+    );
+    if header.is_stamped() {
+        println!("length:     {} bytes", header.image_len);
+        println!("crc:        {:#010x}", header.image_crc);
+    } else {
+        println!("length/crc: not stamped");
+    }
+}
+
+fn print_check(header: &ImageHeader, data: &[u8]) -> bool {
+    match header::check(header, data) {
+        Check::Ok => {
+            println!("check:      ok");
+            true
+        }
+        Check::Unstamped => {
+            println!("check:      not stamped");
+            false
+        }
+        Check::BadLength => {
+            println!(
+                "check:      length {} but the file is {} bytes",
+                header.image_len,
+                data.len()
+            );
+            false
+        }
+        Check::BadCrc(crc) => {
+            println!("check:      crc is {:#010x}", crc);
+            false
+        }
+    }
+}
+
+/// UTC date and time from seconds since 1970
+fn format_time(timestamp: u64) -> String {
+    let days = (timestamp / 86_400) as i64;
+    let secs = timestamp % 86_400;
+
+    // Howard Hinnant's days_from_civil, backwards
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        secs / 3_600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use header::{HEADER_LEN, HEADER_MAGIC, HEADER_VERSION, UNSTAMPED};
+
+    /// A binary as cargo objcopy makes it: vector table, the unstamped header, code
+    fn image(len: usize) -> Vec<u8> {
+        let mut image: Vec<u8> = (0..len).map(|i| (i * 13 + 7) as u8).collect();
+        let header = ImageHeader {
+            magic: HEADER_MAGIC,
+            header_version: HEADER_VERSION,
+            flags: header::FLAG_DIRTY | header::FLAG_SLOT,
+            version: [1, 2, 3],
+            _reserved0: 0,
+            git_hash: *b"0123456789ab",
+            _reserved1: 0,
+            build_timestamp: 1_700_000_000,
+            image_len: UNSTAMPED,
+            image_crc: UNSTAMPED,
+        };
+        image[HEADER_OFFSET..HEADER_OFFSET + HEADER_LEN].copy_from_slice(&header.to_bytes());
+        image
+    }
+
+    #[test]
+    fn stamp_then_check_like_the_firmware() {
+        let mut image = image(4000);
+        let before = ImageHeader::from_image(&image).unwrap();
+        assert_eq!(header::check(&before, &image), Check::Unstamped);
+
+        let stamped = stamp_image(&mut image).unwrap();
+        assert_eq!(stamped.image_len, 4000);
+        let header = ImageHeader::from_image(&image).unwrap();
+        assert_eq!(header, stamped);
+        assert_eq!(header.git_hash_str(), "0123456789ab");
+        assert!(header.is_dirty());
+        assert_eq!(header::check(&header, &image), Check::Ok);
+
+        // the firmware reads the crc field as erased, so it is the CRC of the image with 0xFF there
+        let mut erased = image.clone();
+        erased[CRC_OFFSET..CRC_OFFSET + 4].fill(0xFF);
+        assert_eq!(crc::crc32(&erased), header.image_crc);
+
+        // in flash the image is followed by whatever else is in the slot
+        let mut slot = image.clone();
+        slot.resize(16 * 1024, 0xFF);
+        assert_eq!(header::check(&header, &slot), Check::Ok);
+
+        // stamping again changes nothing
+        let mut again = image.clone();
+        stamp_image(&mut again).unwrap();
+        assert_eq!(again, image);
+    }
+
+    #[test]
+    fn damaged_image() {
+        let mut image = image(2000);
+        stamp_image(&mut image).unwrap();
+        let header = ImageHeader::from_image(&image).unwrap();
+
+        let mut flipped = image.clone();
+        flipped[1500] ^= 0x80;
+        assert!(matches!(header::check(&header, &flipped), Check::BadCrc(_)));
+        assert_eq!(header::check(&header, &image[..1999]), Check::BadLength);
+    }
+
+    #[test]
+    fn plain_layout() {
+        let mut image = image(2000);
+        image[HEADER_OFFSET + 6..HEADER_OFFSET + 8].copy_from_slice(&header::FLAG_DIRTY.to_le_bytes());
+        let before = image.clone();
+        assert!(stamp_image(&mut image).is_err());
+        assert_eq!(image, before);
+    }
+
+    #[test]
+    fn no_header() {
+        let mut data = vec![0u8; 2000];
+        assert!(stamp_image(&mut data).is_err());
+        assert!(stamp_image(&mut [0u8; 16]).is_err());
+    }
+}
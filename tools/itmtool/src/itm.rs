@@ -0,0 +1,567 @@
+//! ITM packets, as in appendix D4 of the ARMv7-M Architecture Reference Manual
+//!
+//! `Decoder` takes the bytes one at a time as they come off the SWO pin (or out of OpenOCD's
+//! trace file) and returns a packet whenever one is complete, so a capture can be decoded while
+//! it is still being written. A byte that cannot start a packet is returned as `Invalid` and
+//! skipped; the stream does not have to start with a sync packet, OpenOCD's usually does not.
+
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Packet {
+    /// At least 47 zero bits and a one
+    Sync,
+    /// Packets were lost, the ITM could not keep up
+    Overflow,
+    /// A write to a stimulus port
+    Instrumentation { port: u8, payload: Payload },
+    /// Timestamp clock cycles since the last local timestamp
+    LocalTimestamp { delta: u32, relation: Relation },
+    /// The low 26 bits of the global timestamp, or only the lowest `bits` of them when the
+    /// others did not change
+    GlobalTimestampLow {
+        value: u32,
+        bits: u8,
+        wrapped: bool,
+        clock_changed: bool,
+    },
+    /// Bits 26 and up of the global timestamp, already shifted into place
+    GlobalTimestampHigh { value: u64 },
+    /// Stimulus port page (`hardware` false) or a hardware source's extension
+    Extension { hardware: bool, value: u32 },
+    /// DWT counters that wrapped, see `Events`
+    EventCounter(Events),
+    Exception { number: u16, action: ExceptionAction },
+    /// Periodic PC sample, `None` when the core was sleeping
+    PcSample(Option<u32>),
+    /// The PC of an access that matched a DWT comparator
+    DataTracePc { comparator: u8, pc: u32 },
+    /// The low 16 bits of the address that matched a DWT comparator
+    DataTraceAddress { comparator: u8, address: u16 },
+    /// The value read or written at the address that matched a DWT comparator
+    DataTraceValue {
+        comparator: u8,
+        write: bool,
+        payload: Payload,
+    },
+    /// A hardware source packet this does not know
+    Hardware { discriminator: u8, payload: Payload },
+    /// A byte that does not start a packet
+    Invalid(u8),
+}
+
+/// The 1, 2 or 4 bytes of a source packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Payload {
+    data: [u8; 4],
+    len: u8,
+}
+
+impl Payload {
+    pub fn bytes(&self) -> &[u8] {
+        &self.data[..self.len as usize]
+    }
+
+    /// The bytes as a little endian number
+    pub fn value(&self) -> u32 {
+        u32::from_le_bytes(self.data)
+    }
+
+    fn push(&mut self, byte: u8) {
+        self.data[self.len as usize] = byte;
+        self.len += 1;
+    }
+}
+
+/// How a local timestamp relates to the packet it comes with (the TC field)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Relation {
+    Synchronous,
+    TimestampDelayed,
+    PacketDelayed,
+    BothDelayed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExceptionAction {
+    Entered,
+    Exited,
+    Returned,
+    Reserved,
+}
+
+/// The DWT counters that wrapped, one bit each
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Events(pub u8);
+
+impl Events {
+    const NAMES: [&'static str; 6] = ["CPI", "EXC", "SLEEP", "LSU", "FOLD", "CYC"];
+}
+
+/// Where a packet with a payload of continuation bytes is going
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Continued {
+    LocalTimestamp(Relation),
+    GlobalTimestampLow,
+    GlobalTimestampHigh,
+    Extension { hardware: bool, low: u32 },
+}
+
+#[derive(Debug, Clone, Copy)]
+enum State {
+    Header,
+    /// A source packet with `size` payload bytes
+    Source { header: u8, size: u8, payload: Payload },
+    /// Payload bytes with a continuation bit, up to `max`
+    Continued {
+        kind: Continued,
+        value: u64,
+        count: u8,
+        max: u8,
+        last: u8,
+    },
+}
+
+pub struct Decoder {
+    state: State,
+    /// Zero bytes in a row, a sync packet when 0x80 follows five of them
+    zeros: u32,
+    position: u64,
+    start: u64,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Decoder {
+            state: State::Header,
+            zeros: 0,
+            position: 0,
+            start: 0,
+        }
+    }
+
+    /// Byte offset of the packet `push` returned last
+    pub fn offset(&self) -> u64 {
+        self.start
+    }
+
+    /// Where the packet that is not complete yet starts, at the end of a capture it was cut off
+    pub fn unfinished(&self) -> Option<u64> {
+        match self.state {
+            State::Header => None,
+            _ => Some(self.start),
+        }
+    }
+
+    /// The next byte of the stream, a packet when it completes one
+    pub fn push(&mut self, byte: u8) -> Option<Packet> {
+        let position = self.position;
+        self.position += 1;
+        match self.state {
+            State::Header => self.header(byte, position),
+            State::Source {
+                header,
+                size,
+                mut payload,
+            } => {
+                payload.push(byte);
+                if payload.len < size {
+                    self.state = State::Source { header, size, payload };
+                    return None;
+                }
+                self.state = State::Header;
+                Some(source(header, payload))
+            }
+            State::Continued {
+                kind,
+                mut value,
+                count,
+                max,
+                last,
+            } => {
+                let more = byte & 0x80 != 0 && count + 1 < max;
+                let bits = if count + 1 == max { last } else { 7 };
+                value |= ((byte & ((1 << bits) - 1) as u8) as u64) << (7 * count);
+                if more {
+                    self.state = State::Continued {
+                        kind,
+                        value,
+                        count: count + 1,
+                        max,
+                        last,
+                    };
+                    return None;
+                }
+                self.state = State::Header;
+                Some(continued(kind, value, count + 1, byte))
+            }
+        }
+    }
+
+    fn header(&mut self, byte: u8, position: u64) -> Option<Packet> {
+        if byte == 0x00 {
+            if self.zeros == 0 {
+                self.start = position;
+            }
+            self.zeros += 1;
+            return None;
+        }
+        let zeros = self.zeros;
+        self.zeros = 0;
+        if byte == 0x80 && zeros >= 5 {
+            return Some(Packet::Sync);
+        }
+        self.start = position;
+
+        let continued = |kind, max, last| State::Continued {
+            kind,
+            value: 0,
+            count: 0,
+            max,
+            last,
+        };
+        match byte {
+            0x70 => return Some(Packet::Overflow),
+            // local timestamp in the header
+            _ if byte & 0x8f == 0x00 => {
+                return Some(Packet::LocalTimestamp {
+                    delta: (byte >> 4) as u32,
+                    relation: Relation::Synchronous,
+                })
+            }
+            _ if byte & 0xcf == 0xc0 => {
+                let relation = match (byte >> 4) & 0x03 {
+                    0 => Relation::Synchronous,
+                    1 => Relation::TimestampDelayed,
+                    2 => Relation::PacketDelayed,
+                    _ => Relation::BothDelayed,
+                };
+                self.state = continued(Continued::LocalTimestamp(relation), 4, 7);
+            }
+            0x94 => self.state = continued(Continued::GlobalTimestampLow, 4, 5),
+            0xb4 => self.state = continued(Continued::GlobalTimestampHigh, 6, 7),
+            _ if byte & 0x0b == 0x08 => {
+                let hardware = byte & 0x04 != 0;
+                let low = ((byte >> 4) & 0x07) as u32;
+                if byte & 0x80 == 0 {
+                    return Some(Packet::Extension { hardware, value: low });
+                }
+                self.state = continued(Continued::Extension { hardware, low }, 4, 8);
+            }
+            _ if byte & 0x03 != 0 => {
+                self.state = State::Source {
+                    header: byte,
+                    size: [0, 1, 2, 4][(byte & 0x03) as usize],
+                    payload: Payload::default(),
+                };
+            }
+            _ => return Some(Packet::Invalid(byte)),
+        }
+        None
+    }
+}
+
+fn source(header: u8, payload: Payload) -> Packet {
+    let id = header >> 3;
+    if header & 0x04 == 0 {
+        return Packet::Instrumentation { port: id, payload };
+    }
+    let comparator = (id >> 1) & 0x03;
+    match id {
+        0 => Packet::EventCounter(Events(payload.bytes()[0])),
+        1 => Packet::Exception {
+            number: (payload.value() & 0x1ff) as u16,
+            action: match (payload.value() >> 12) & 0x03 {
+                1 => ExceptionAction::Entered,
+                2 => ExceptionAction::Exited,
+                3 => ExceptionAction::Returned,
+                _ => ExceptionAction::Reserved,
+            },
+        },
+        // a single byte is the sleep marker
+        2 if payload.len == 4 => Packet::PcSample(Some(payload.value())),
+        2 => Packet::PcSample(None),
+        8..=15 if id & 1 == 0 => Packet::DataTracePc {
+            comparator,
+            pc: payload.value(),
+        },
+        8..=15 => Packet::DataTraceAddress {
+            comparator,
+            address: payload.value() as u16,
+        },
+        16..=23 => Packet::DataTraceValue {
+            comparator,
+            write: id & 1 != 0,
+            payload,
+        },
+        _ => Packet::Hardware {
+            discriminator: id,
+            payload,
+        },
+    }
+}
+
+fn continued(kind: Continued, value: u64, count: u8, last: u8) -> Packet {
+    match kind {
+        Continued::LocalTimestamp(relation) => Packet::LocalTimestamp {
+            delta: value as u32,
+            relation,
+        },
+        Continued::GlobalTimestampLow => Packet::GlobalTimestampLow {
+            value: value as u32,
+            bits: (7 * count).min(26),
+            // only in the fourth byte
+            wrapped: count == 4 && last & 0x40 != 0,
+            clock_changed: count == 4 && last & 0x20 != 0,
+        },
+        Continued::GlobalTimestampHigh => Packet::GlobalTimestampHigh { value: value << 26 },
+        Continued::Extension { hardware, low } => Packet::Extension {
+            hardware,
+            value: low | (value as u32) << 3,
+        },
+    }
+}
+
+impl fmt::Display for Packet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Packet::Sync => write!(f, "sync"),
+            Packet::Overflow => write!(f, "overflow"),
+            Packet::Instrumentation { port, payload } => {
+                write!(f, "port {} {:?}", port, String::from_utf8_lossy(payload.bytes()))
+            }
+            Packet::LocalTimestamp { delta, relation } => {
+                write!(f, "local timestamp +{}", delta)?;
+                match relation {
+                    Relation::Synchronous => Ok(()),
+                    Relation::TimestampDelayed => write!(f, " (timestamp delayed)"),
+                    Relation::PacketDelayed => write!(f, " (packet delayed)"),
+                    Relation::BothDelayed => write!(f, " (both delayed)"),
+                }
+            }
+            Packet::GlobalTimestampLow {
+                value,
+                bits,
+                wrapped,
+                clock_changed,
+            } => {
+                write!(f, "global timestamp low {:#x} ({} bits)", value, bits)?;
+                if wrapped {
+                    write!(f, " wrapped")?;
+                }
+                if clock_changed {
+                    write!(f, " clock changed")?;
+                }
+                Ok(())
+            }
+            Packet::GlobalTimestampHigh { value } => write!(f, "global timestamp high {:#x}", value),
+            Packet::Extension { hardware, value } => {
+                if hardware {
+                    write!(f, "extension {:#x}", value)
+                } else {
+                    write!(f, "stimulus page {}", value)
+                }
+            }
+            Packet::EventCounter(events) => write!(f, "counters wrapped {}", events),
+            Packet::Exception { number, action } => {
+                let action = match action {
+                    ExceptionAction::Entered => "entered",
+                    ExceptionAction::Exited => "exited",
+                    ExceptionAction::Returned => "returned to",
+                    ExceptionAction::Reserved => "reserved action of",
+                };
+                write!(f, "{} {}", action, exception_name(number))
+            }
+            Packet::PcSample(Some(pc)) => write!(f, "pc {:#010x}", pc),
+            Packet::PcSample(None) => write!(f, "pc sleeping"),
+            Packet::DataTracePc { comparator, pc } => write!(f, "comparator {} pc {:#010x}", comparator, pc),
+            Packet::DataTraceAddress { comparator, address } => {
+                write!(f, "comparator {} address ..{:04x}", comparator, address)
+            }
+            Packet::DataTraceValue {
+                comparator,
+                write,
+                payload,
+            } => write!(
+                f,
+                "comparator {} {} {:#x}",
+                comparator,
+                if write { "write" } else { "read" },
+                payload.value()
+            ),
+            Packet::Hardware { discriminator, payload } => {
+                write!(f, "hardware source {} {:02x?}", discriminator, payload.bytes())
+            }
+            Packet::Invalid(byte) => write!(f, "invalid byte {:#04x}", byte),
+        }
+    }
+}
+
+impl fmt::Display for Events {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut names = Self::NAMES
+            .iter()
+            .enumerate()
+            .filter(|(bit, _)| self.0 & (1 << bit) != 0)
+            .map(|(_, name)| *name);
+        match names.next() {
+            Some(first) => {
+                write!(f, "{}", first)?;
+                names.try_for_each(|name| write!(f, " {}", name))
+            }
+            None => write!(f, "none"),
+        }
+    }
+}
+
+/// The name of a Cortex-M exception number, IRQn for the interrupts
+pub fn exception_name(number: u16) -> String {
+    let name = match number {
+        0 => "thread mode",
+        1 => "Reset",
+        2 => "NMI",
+        3 => "HardFault",
+        4 => "MemManage",
+        5 => "BusFault",
+        6 => "UsageFault",
+        11 => "SVCall",
+        12 => "DebugMonitor",
+        14 => "PendSV",
+        15 => "SysTick",
+        16.. => return format!("IRQ{}", number - 16),
+        _ => return format!("exception {}", number),
+    };
+    name.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every packet in `bytes` with its offset
+    fn decode(bytes: &[u8]) -> Vec<(u64, Packet)> {
+        let mut decoder = Decoder::new();
+        bytes
+            .iter()
+            .filter_map(|&byte| decoder.push(byte).map(|packet| (decoder.offset(), packet)))
+            .collect()
+    }
+
+    fn payload(bytes: &[u8]) -> Payload {
+        let mut payload = Payload::default();
+        bytes.iter().for_each(|&byte| payload.push(byte));
+        payload
+    }
+
+    #[test]
+    fn sync_and_overflow() {
+        // five zeros and 0x80 is the shortest sync, OpenOCD sends more
+        let bytes = [0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0x70, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0x70];
+        assert_eq!(
+            decode(&bytes),
+            [(0, Packet::Sync), (6, Packet::Overflow), (7, Packet::Sync), (15, Packet::Overflow)]
+        );
+        // too few zeros is no sync
+        assert_eq!(decode(&[0x00, 0x00, 0x00, 0x00, 0x80]), [(4, Packet::Invalid(0x80))]);
+    }
+
+    #[test]
+    fn instrumentation() {
+        let bytes = [
+            0x01, b'A', // port 0, one byte
+            0x0A, b'h', b'i', // port 1, two bytes
+            0xFB, 0x78, 0x56, 0x34, 0x12, // port 31, four bytes
+            0x09, b'\n', // port 1 again
+        ];
+        let packets = decode(&bytes);
+        assert_eq!(
+            packets,
+            [
+                (0, Packet::Instrumentation { port: 0, payload: payload(b"A") }),
+                (2, Packet::Instrumentation { port: 1, payload: payload(b"hi") }),
+                (5, Packet::Instrumentation { port: 31, payload: payload(&[0x78, 0x56, 0x34, 0x12]) }),
+                (10, Packet::Instrumentation { port: 1, payload: payload(b"\n") }),
+            ]
+        );
+        let Packet::Instrumentation { payload, .. } = packets[2].1 else { unreachable!() };
+        assert_eq!(payload.value(), 0x1234_5678);
+        assert_eq!(packets[1].1.to_string(), "port 1 \"hi\"");
+    }
+
+    #[test]
+    fn local_timestamps() {
+        let bytes = [
+            0x30, // delta 3 in the header
+            0xC0, 0x05, // delta 5 in one byte
+            0xD0, 0x85, 0x01, // 5 + 1 << 7, timestamp delayed
+            0xE0, 0xFF, 0xFF, 0xFF, 0x7F, // the longest, 28 bits, packet delayed
+            0xF0, 0x80, 0x80, 0x80, 0xFF, // the fourth byte ends it whatever its top bit
+        ];
+        let local = |delta, relation| Packet::LocalTimestamp { delta, relation };
+        assert_eq!(
+            decode(&bytes),
+            [
+                (0, local(3, Relation::Synchronous)),
+                (1, local(5, Relation::Synchronous)),
+                (3, local(133, Relation::TimestampDelayed)),
+                (6, local(0x0FFF_FFFF, Relation::PacketDelayed)),
+                (11, local(0x7F << 21, Relation::BothDelayed)),
+            ]
+        );
+    }
+
+    #[test]
+    fn hardware_packets() {
+        let bytes = [
+            0x0E, 0x11, 0x10, // exception 17 (IRQ1) entered
+            0x17, 0x00, 0x80, 0x00, 0x08, // PC sample
+            0x15, 0x00, // PC sample while sleeping
+            0x05, 0x21, // CPI and CYC counters wrapped
+            0x94, 0x81, 0x01, // global timestamp, 14 bits
+            0x08, // stimulus port page 0
+            0x25, 0x00, // unknown hardware source 4
+        ];
+        let packets: Vec<String> = decode(&bytes).iter().map(|(_, packet)| packet.to_string()).collect();
+        assert_eq!(
+            packets,
+            [
+                "entered IRQ1",
+                "pc 0x08008000",
+                "pc sleeping",
+                "counters wrapped CPI CYC",
+                "global timestamp low 0x81 (14 bits)",
+                "stimulus page 0",
+                "hardware source 4 [00]",
+            ]
+        );
+    }
+
+    #[test]
+    fn cut_off_at_the_end() {
+        let mut decoder = Decoder::new();
+        for &byte in &[0x01, b'A', 0x00, 0x00] {
+            decoder.push(byte);
+        }
+        // a run of zeros is not a packet yet
+        assert_eq!(decoder.unfinished(), None);
+        for &byte in &[0x80, 0x03, 0x01, 0x02] {
+            decoder.push(byte);
+        }
+        // a four byte payload with two of them there
+        assert_eq!(decoder.unfinished(), Some(5));
+        assert_eq!(decoder.push(0x03), None);
+        assert_eq!(decoder.push(0x04), Some(Packet::Instrumentation { port: 0, payload: payload(&[1, 2, 3, 4]) }));
+        assert_eq!(decoder.unfinished(), None);
+
+        let mut decoder = Decoder::new();
+        decoder.push(0xC0);
+        decoder.push(0x81);
+        assert_eq!(decoder.unfinished(), Some(0));
+    }
+}
@@ -0,0 +1,241 @@
+//! Decodes the ITM trace that comes out of the SWO pin, e.g. from `rust/app/examples/itm.rs`
+//!
+//! ```text
+//! itmtool dump <capture>                  every packet, one a line
+//! itmtool ports <capture> [<dir>]         the text written to the stimulus ports
+//! itmtool pc <capture> [<file.elf>]       where the PC samples landed, by function
+//! ```
+//!
+//! A capture is what OpenOCD writes with `monitor tpiu config internal itm.txt uart off 8000000`
+//! (see `rust/app/openocd.gdb`). It can be a FIFO (`mkfifo itm.txt` before OpenOCD starts), which
+//! is decoded as it comes in until OpenOCD closes it.
+//!
+//! `ports` writes port N to `<dir>/portN.txt`, or to stdout as `N: line` without a directory.
+//! `pc` needs the DWT to sample the PC (DWT_CTRL.PCSAMPLENA); without the ELF the samples are
+//! counted by address.
+
+mod itm;
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read, Write};
+use std::path::Path;
+use std::process::ExitCode;
+
+use object::{Object, ObjectSymbol, SymbolKind};
+
+use itm::{Decoder, Packet};
+
+const USAGE: &str = "usage: itmtool dump <capture> | ports <capture> [<dir>] | pc <capture> [<file.elf>]";
+
+/// Functions shown by `pc`, the busiest first
+const TOP: usize = 30;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        ["dump", path] => dump(path),
+        ["ports", path] => ports(path, None),
+        ["ports", path, dir] => ports(path, Some(dir)),
+        ["pc", path] => pc(path, None),
+        ["pc", path, elf] => pc(path, Some(elf)),
+        _ => Err(USAGE.to_string()),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("itmtool: {}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Run a capture through the decoder as it is read; `packet` gets each one with its offset
+fn decode(path: &str, mut packet: impl FnMut(u64, Packet) -> Result<(), String>) -> Result<(), String> {
+    let mut file = File::open(path).map_err(|error| format!("{}: {}", path, error))?;
+    let mut decoder = Decoder::new();
+    let mut buffer = [0; 4096];
+    loop {
+        let read = match file.read(&mut buffer) {
+            Ok(0) => {
+                if let Some(offset) = decoder.unfinished() {
+                    eprintln!("itmtool: the capture ends in the middle of the packet at byte {}", offset);
+                }
+                return Ok(());
+            }
+            Ok(read) => read,
+            Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+            Err(error) => return Err(format!("{}: {}", path, error)),
+        };
+        for &byte in &buffer[..read] {
+            if let Some(decoded) = decoder.push(byte) {
+                packet(decoder.offset(), decoded)?;
+            }
+        }
+    }
+}
+
+fn dump(path: &str) -> Result<(), String> {
+    // local timestamps add up, global ones come in two halves
+    let mut local: u64 = 0;
+    let mut global: u64 = 0;
+    decode(path, |offset, packet| {
+        let time = match packet {
+            Packet::LocalTimestamp { delta, .. } => {
+                local += delta as u64;
+                format!(" = {}", local)
+            }
+            Packet::GlobalTimestampLow { value, bits, .. } => {
+                let mask = (1u64 << bits) - 1;
+                global = (global & !mask) | value as u64;
+                format!(" = {:#x}", global)
+            }
+            Packet::GlobalTimestampHigh { value } => {
+                global = (global & ((1 << 26) - 1)) | value;
+                format!(" = {:#x}", global)
+            }
+            _ => String::new(),
+        };
+        println!("{:>8}  {}{}", offset, packet, time);
+        Ok(())
+    })
+}
+
+fn ports(path: &str, dir: Option<&str>) -> Result<(), String> {
+    // the line each port is in the middle of
+    let mut lines: HashMap<u8, Vec<u8>> = HashMap::new();
+    let mut files: HashMap<u8, File> = HashMap::new();
+    let mut write_line = |port: u8, line: &[u8]| -> Result<(), String> {
+        let dir = match dir {
+            Some(dir) => dir,
+            None => {
+                let text = String::from_utf8_lossy(line);
+                println!("{}: {}", port, text.trim_end_matches('\r'));
+                return Ok(());
+            }
+        };
+        let path = Path::new(dir).join(format!("port{}.txt", port));
+        let file = match files.entry(port) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                entry.insert(File::create(&path).map_err(|error| format!("{}: {}", path.display(), error))?)
+            }
+        };
+        file.write_all(line)
+            .and_then(|()| file.write_all(b"\n"))
+            .map_err(|error| format!("{}: {}", path.display(), error))
+    };
+
+    if let Some(dir) = dir {
+        fs::create_dir_all(dir).map_err(|error| format!("{}: {}", dir, error))?;
+    }
+    decode(path, |offset, packet| {
+        match packet {
+            Packet::Instrumentation { port, payload } => {
+                let line = lines.entry(port).or_default();
+                for &byte in payload.bytes() {
+                    if byte == b'\n' {
+                        write_line(port, line)?;
+                        line.clear();
+                    } else {
+                        line.push(byte);
+                    }
+                }
+            }
+            Packet::Overflow => eprintln!("itmtool: overflow at byte {}, some output is missing", offset),
+            _ => {}
+        }
+        Ok(())
+    })?;
+
+    // what is left without a newline
+    let mut ports: Vec<u8> = lines.keys().copied().collect();
+    ports.sort();
+    for port in ports {
+        let line = &lines[&port];
+        if !line.is_empty() {
+            write_line(port, line)?;
+        }
+    }
+    Ok(())
+}
+
+fn pc(path: &str, elf: Option<&str>) -> Result<(), String> {
+    let functions = match elf {
+        Some(elf) => Functions::load(elf)?,
+        None => Functions::default(),
+    };
+
+    let mut samples: HashMap<String, u64> = HashMap::new();
+    let mut total: u64 = 0;
+    decode(path, |_, packet| {
+        if let Packet::PcSample(pc) = packet {
+            let place = match pc {
+                Some(pc) => match functions.find(pc) {
+                    Some(name) => name.to_string(),
+                    None => format!("{:#010x}", pc),
+                },
+                None => "(sleeping)".to_string(),
+            };
+            *samples.entry(place).or_default() += 1;
+            total += 1;
+        }
+        Ok(())
+    })?;
+    if total == 0 {
+        return Err("no PC samples in the capture, is DWT_CTRL.PCSAMPLENA set?".to_string());
+    }
+
+    let mut samples: Vec<(String, u64)> = samples.into_iter().collect();
+    samples.sort_by(|(a_place, a), (b_place, b)| b.cmp(a).then_with(|| a_place.cmp(b_place)));
+    println!("{} samples", total);
+    for (place, count) in samples.iter().take(TOP) {
+        println!("{:>8} {:>5.1}%  {}", count, *count as f64 * 100.0 / total as f64, place);
+    }
+    if samples.len() > TOP {
+        let rest: u64 = samples[TOP..].iter().map(|(_, count)| count).sum();
+        println!("{:>8} {:>5.1}%  ({} more)", rest, rest as f64 * 100.0 / total as f64, samples.len() - TOP);
+    }
+    Ok(())
+}
+
+/// The functions of an ELF by address
+#[derive(Default)]
+struct Functions {
+    /// start, end and name, sorted by start
+    ranges: Vec<(u32, u32, String)>,
+}
+
+impl Functions {
+    fn load(path: &str) -> Result<Self, String> {
+        let data = fs::read(path).map_err(|error| format!("{}: {}", path, error))?;
+        let file = object::File::parse(&*data).map_err(|error| format!("{}: {}", path, error))?;
+        let mut ranges: Vec<(u32, u32, String)> = file
+            .symbols()
+            .filter(|symbol| symbol.kind() == SymbolKind::Text && symbol.size() > 0)
+            .filter_map(|symbol| {
+                // the Thumb bit is not part of the address
+                let start = symbol.address() as u32 & !1;
+                let name = symbol.name().ok()?;
+                Some((start, start + symbol.size() as u32, format!("{:#}", rustc_demangle::demangle(name))))
+            })
+            .collect();
+        if ranges.is_empty() {
+            return Err(format!("{}: no function symbols, is it stripped?", path));
+        }
+        ranges.sort();
+        Ok(Functions { ranges })
+    }
+
+    fn find(&self, pc: u32) -> Option<&str> {
+        let after = self.ranges.partition_point(|(start, _, _)| *start <= pc);
+        let (_, end, name) = self.ranges.get(after.checked_sub(1)?)?;
+        if pc < *end {
+            Some(name)
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,414 @@
+//! Turns a defmt frame back into text
+//!
+//! A frame is the index of its format string, the arguments of the timestamp and then its own
+//! arguments, each once in the order of their argument index and without any padding:
+//!
+//! ```text
+//! {=u32}    4 bytes          {=str} {=[u8]}    length u32 and the bytes
+//! {=usize}  4 bytes          {=[u8; N]}        N bytes
+//! {=bool}   1 byte           {=istr}           index u16 of the string
+//! {=char}   u32              {:?} {}  (Debug)  the text, up to a 0xFF
+//! {=?}      index u16 of its format string, then its arguments
+//! {=[?]}    length u32, index u16 once, then the arguments of every element
+//! {=[?; N]} index u16 once, then the arguments of every element
+//! ```
+//!
+//! A format string with a `|` in it is an enum, one variant each, and the arguments start with
+//! which one it is. All integers are little endian.
+
+use std::ascii;
+use std::fmt::Write;
+
+use defmt_parser::{DisplayHint, Fragment, Parameter, ParserMode, TimePrecision, Type};
+
+use crate::table::Table;
+
+/// A decoded frame
+pub struct Message {
+    /// None for `defmt::println!`
+    pub level: Option<&'static str>,
+    /// None without a `defmt::timestamp!`
+    pub timestamp: Option<String>,
+    pub text: String,
+}
+
+pub fn decode(table: &Table, frame: &[u8]) -> Result<Message, String> {
+    let mut decoder = Decoder { table, bytes: frame };
+    let index = decoder.u16()?;
+    let entry = table.get(index).ok_or_else(|| format!("no format string {} in the ELF", index))?;
+    if !entry.is_message() {
+        return Err(format!("{} is not a log message but {}", index, entry.tag));
+    }
+    let timestamp = match &table.timestamp {
+        Some(format) => Some(decoder.format(format, None)?),
+        None => None,
+    };
+    let text = decoder.format(&entry.data, None)?;
+    // what the framing leaves over is zeros, anything else means the frame is not what the ELF
+    // says it is
+    if decoder.bytes.iter().any(|&byte| byte != 0) {
+        return Err(format!("{} bytes left over after {:?}", decoder.bytes.len(), entry.data));
+    }
+    Ok(Message {
+        level: entry.level(),
+        timestamp,
+        text,
+    })
+}
+
+/// An argument as it came out of the frame
+enum Arg {
+    Unsigned(u128),
+    /// With its size in bits
+    Signed(i128, u32),
+    F32(f32),
+    F64(f64),
+    Bool(bool),
+    Char(char),
+    Str(String),
+    Bytes(Vec<u8>),
+    /// The bytes of a bitfield argument, shifted back to where they were
+    Bits(u128),
+    /// Formatted already, like nested formats and Debug text
+    Text(String),
+}
+
+struct Decoder<'t, 'f> {
+    table: &'t Table,
+    /// What is left of the frame
+    bytes: &'f [u8],
+}
+
+impl<'t, 'f> Decoder<'t, 'f> {
+    fn take(&mut self, len: usize) -> Result<&'f [u8], String> {
+        if self.bytes.len() < len {
+            return Err("frame cut short".to_string());
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn u128(&mut self) -> Result<u128, String> {
+        Ok(u128::from_le_bytes(self.take(16)?.try_into().unwrap()))
+    }
+
+    /// A format string with its arguments; `hint` goes to the parameters without one of their
+    /// own, like `{:x}` around a struct
+    fn format(&mut self, format: &str, hint: Option<&DisplayHint>) -> Result<String, String> {
+        let fragments = defmt_parser::parse(format, ParserMode::ForwardsCompatible)
+            .map_err(|error| format!("{:?}: {}", format, error))?;
+        let params: Vec<&Parameter> = fragments
+            .iter()
+            .filter_map(|fragment| match fragment {
+                Fragment::Parameter(param) => Some(param),
+                Fragment::Literal(_) => None,
+            })
+            .collect();
+
+        // an argument used twice is sent once
+        let count = params.iter().map(|param| param.index + 1).max().unwrap_or(0);
+        let mut args = Vec::with_capacity(count);
+        for index in 0..count {
+            let uses: Vec<&Parameter> = params.iter().copied().filter(|param| param.index == index).collect();
+            let first = uses
+                .first()
+                .ok_or_else(|| format!("{:?}: argument {} is not used", format, index))?;
+            args.push(self.arg(&first.ty, &uses, first.hint.as_ref().or(hint))?);
+        }
+
+        let mut text = String::new();
+        for fragment in &fragments {
+            match fragment {
+                Fragment::Literal(literal) => text.push_str(literal),
+                Fragment::Parameter(param) => {
+                    render(&mut text, &args[param.index], &param.ty, param.hint.as_ref().or(hint))
+                }
+            }
+        }
+        Ok(text)
+    }
+
+    /// The argument for the parameters in `uses`, which all have the same index
+    fn arg(&mut self, ty: &Type, uses: &[&Parameter], hint: Option<&DisplayHint>) -> Result<Arg, String> {
+        Ok(match ty {
+            Type::U8 => Arg::Unsigned(self.u8()? as u128),
+            Type::U16 => Arg::Unsigned(self.u16()? as u128),
+            Type::U32 | Type::Usize => Arg::Unsigned(self.u32()? as u128),
+            Type::U64 => Arg::Unsigned(self.u64()? as u128),
+            Type::U128 => Arg::Unsigned(self.u128()?),
+            Type::I8 => Arg::Signed(self.u8()? as i8 as i128, 8),
+            Type::I16 => Arg::Signed(self.u16()? as i16 as i128, 16),
+            Type::I32 | Type::Isize => Arg::Signed(self.u32()? as i32 as i128, 32),
+            Type::I64 => Arg::Signed(self.u64()? as i64 as i128, 64),
+            Type::I128 => Arg::Signed(self.u128()? as i128, 128),
+            Type::F32 => Arg::F32(f32::from_bits(self.u32()?)),
+            Type::F64 => Arg::F64(f64::from_bits(self.u64()?)),
+            Type::Bool => Arg::Bool(self.u8()? != 0),
+            Type::Char => {
+                let code = self.u32()?;
+                Arg::Char(char::from_u32(code).ok_or_else(|| format!("{:#x} is not a char", code))?)
+            }
+            Type::Str => {
+                let len = self.u32()? as usize;
+                Arg::Str(String::from_utf8_lossy(self.take(len)?).into_owned())
+            }
+            Type::IStr => {
+                let index = self.u16()?;
+                let entry = self.table.get(index).ok_or_else(|| format!("no string {} in the ELF", index))?;
+                Arg::Str(entry.data.clone())
+            }
+            Type::U8Slice => {
+                let len = self.u32()? as usize;
+                Arg::Bytes(self.take(len)?.to_vec())
+            }
+            Type::U8Array(len) => Arg::Bytes(self.take(*len)?.to_vec()),
+            Type::Debug | Type::Display => {
+                let end = self
+                    .bytes
+                    .iter()
+                    .position(|&byte| byte == 0xFF)
+                    .ok_or_else(|| "Debug text without its end".to_string())?;
+                let text = String::from_utf8_lossy(self.take(end)?).into_owned();
+                self.take(1)?;
+                Arg::Text(text)
+            }
+            Type::Format => {
+                let index = self.u16()?;
+                Arg::Text(self.tagged(index, hint)?)
+            }
+            Type::FormatSlice => {
+                let len = self.u32()? as usize;
+                let index = self.u16()?;
+                Arg::Text(self.elements(index, len, hint)?)
+            }
+            Type::FormatArray(len) => {
+                let index = self.u16()?;
+                Arg::Text(self.elements(index, *len, hint)?)
+            }
+            Type::FormatSequence => {
+                let mut text = String::new();
+                loop {
+                    let index = self.u16()?;
+                    if index == 0 {
+                        break;
+                    }
+                    text.push_str(&self.tagged(index, hint)?);
+                }
+                Arg::Text(text)
+            }
+            Type::BitField(_) => {
+                // only the bytes with bits of some range in them are sent
+                let (start, end) = defmt_parser::get_max_bitfield_range(uses.iter().copied()).unwrap();
+                let lowest = start / 8;
+                let value = match (end - 1) / 8 - lowest + 1 {
+                    1 => self.u8()? as u128,
+                    2 => self.u16()? as u128,
+                    3..=4 => self.u32()? as u128,
+                    5..=8 => self.u64()? as u128,
+                    _ => self.u128()?,
+                };
+                Arg::Bits(value << (lowest as u32 * 8))
+            }
+        })
+    }
+
+    /// The value behind a format string index
+    fn tagged(&mut self, index: u16, hint: Option<&DisplayHint>) -> Result<String, String> {
+        let table = self.table;
+        let entry = table
+            .get(index)
+            .ok_or_else(|| format!("no format string {} in the ELF", index))?;
+        let is_enum = (entry.tag == "defmt_derived" || entry.tag == "defmt_prim") && entry.data.contains('|');
+        if !is_enum {
+            return self.format(&entry.data, hint);
+        }
+        let variants: Vec<&str> = entry.data.split('|').collect();
+        let variant = if variants.len() <= u8::MAX as usize {
+            self.u8()? as usize
+        } else if variants.len() <= u16::MAX as usize {
+            self.u16()? as usize
+        } else {
+            self.u32()? as usize
+        };
+        let format = variants
+            .get(variant)
+            .ok_or_else(|| format!("variant {} of {:?}", variant, entry.data))?;
+        self.format(format, hint)
+    }
+
+    /// `len` values of the same format, as a list
+    fn elements(&mut self, index: u16, len: usize, hint: Option<&DisplayHint>) -> Result<String, String> {
+        let mut text = String::from("[");
+        for element in 0..len {
+            if element > 0 {
+                text.push_str(", ");
+            }
+            text.push_str(&self.tagged(index, hint)?);
+        }
+        text.push(']');
+        Ok(text)
+    }
+}
+
+fn render(text: &mut String, arg: &Arg, ty: &Type, hint: Option<&DisplayHint>) {
+    match arg {
+        Arg::Unsigned(value) => unsigned(text, *value, hint),
+        Arg::Signed(value, bits) => match hint {
+            // the bits as they are, like Rust does it
+            Some(DisplayHint::Hexadecimal { .. } | DisplayHint::Octal { .. } | DisplayHint::Binary { .. }) => {
+                unsigned(text, *value as u128 & mask(*bits), hint)
+            }
+            Some(DisplayHint::NoHint { zero_pad }) => write!(text, "{:01$}", value, zero_pad).unwrap(),
+            _ => write!(text, "{}", value).unwrap(),
+        },
+        Arg::F32(value) => match hint {
+            Some(DisplayHint::Debug) => write!(text, "{:?}", value).unwrap(),
+            _ => write!(text, "{}", value).unwrap(),
+        },
+        Arg::F64(value) => match hint {
+            Some(DisplayHint::Debug) => write!(text, "{:?}", value).unwrap(),
+            _ => write!(text, "{}", value).unwrap(),
+        },
+        Arg::Bool(value) => write!(text, "{}", value).unwrap(),
+        Arg::Char(value) => match hint {
+            Some(DisplayHint::Debug) => write!(text, "{:?}", value).unwrap(),
+            _ => text.push(*value),
+        },
+        Arg::Str(value) => match hint {
+            Some(DisplayHint::Debug) => write!(text, "{:?}", value).unwrap(),
+            _ => text.push_str(value),
+        },
+        Arg::Bytes(bytes) => match hint {
+            Some(DisplayHint::Ascii) => {
+                text.push_str("b\"");
+                for &byte in bytes {
+                    text.extend(ascii::escape_default(byte).map(char::from));
+                }
+                text.push('"');
+            }
+            _ => {
+                text.push('[');
+                for (index, &byte) in bytes.iter().enumerate() {
+                    if index > 0 {
+                        text.push_str(", ");
+                    }
+                    unsigned(text, byte as u128, hint);
+                }
+                text.push(']');
+            }
+        },
+        Arg::Bits(value) => {
+            let range = match ty {
+                Type::BitField(range) => range.clone(),
+                _ => 0..128,
+            };
+            let bits = (range.end - range.start) as u32;
+            unsigned(text, value >> range.start & mask(bits), hint)
+        }
+        Arg::Text(value) => text.push_str(value),
+    }
+}
+
+fn unsigned(text: &mut String, value: u128, hint: Option<&DisplayHint>) {
+    match hint {
+        Some(DisplayHint::NoHint { zero_pad }) => write!(text, "{:01$}", value, zero_pad),
+        Some(&DisplayHint::Hexadecimal {
+            alternate,
+            uppercase,
+            zero_pad,
+        }) => match (alternate, uppercase) {
+            (false, false) => write!(text, "{:01$x}", value, zero_pad),
+            (false, true) => write!(text, "{:01$X}", value, zero_pad),
+            (true, false) => write!(text, "{:#01$x}", value, zero_pad),
+            (true, true) => write!(text, "{:#01$X}", value, zero_pad),
+        },
+        Some(&DisplayHint::Octal { alternate, zero_pad }) => match alternate {
+            false => write!(text, "{:01$o}", value, zero_pad),
+            true => write!(text, "{:#01$o}", value, zero_pad),
+        },
+        Some(&DisplayHint::Binary { alternate, zero_pad }) => match alternate {
+            false => write!(text, "{:01$b}", value, zero_pad),
+            true => write!(text, "{:#01$b}", value, zero_pad),
+        },
+        Some(DisplayHint::Seconds(precision)) => {
+            let (seconds, fraction) = split_seconds(value, precision);
+            write!(text, "{}{}", seconds, fraction)
+        }
+        Some(DisplayHint::Time(precision)) => {
+            let (seconds, fraction) = split_seconds(value, precision);
+            let (days, seconds) = (seconds / 86_400, seconds % 86_400);
+            if days > 0 {
+                write!(text, "{}:", days).unwrap();
+            }
+            write!(text, "{:02}:{:02}:{:02}{}", seconds / 3600, seconds / 60 % 60, seconds % 60, fraction)
+        }
+        Some(DisplayHint::ISO8601(precision)) => {
+            let (seconds, fraction) = split_seconds(value, precision);
+            let (year, month, day) = civil_from_days((seconds / 86_400) as i64);
+            let seconds = seconds % 86_400;
+            write!(
+                text,
+                "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}{}Z",
+                year,
+                month,
+                day,
+                seconds / 3600,
+                seconds / 60 % 60,
+                seconds % 60,
+                fraction
+            )
+        }
+        Some(DisplayHint::Bitflags { name, .. }) => write!(text, "{}({:#x})", name, value),
+        _ => write!(text, "{}", value),
+    }
+    .unwrap()
+}
+
+/// Whole seconds and the fraction with its dot
+fn split_seconds(value: u128, precision: &TimePrecision) -> (u128, String) {
+    match precision {
+        TimePrecision::Micros => (value / 1_000_000, format!(".{:06}", value % 1_000_000)),
+        TimePrecision::Millis => (value / 1000, format!(".{:03}", value % 1000)),
+        TimePrecision::Seconds => (value, String::new()),
+    }
+}
+
+/// Year, month and day of a day since 1970-01-01, from Howard Hinnant's `civil_from_days`
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month + 2) / 5 + 1) as u32;
+    let month = if month < 10 { month + 3 } else { month - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// The low `bits` bits
+fn mask(bits: u32) -> u128 {
+    if bits >= 128 {
+        u128::MAX
+    } else {
+        (1 << bits) - 1
+    }
+}
@@ -0,0 +1,311 @@
+//! Decodes the defmt log that `rtic_stm32/src/log.rs` sends over a UART or keeps in flash
+//!
+//! ```text
+//! logtool show <file.elf> <capture>       every log message, one a line
+//! logtool table <file.elf>                the format strings in the ELF, by index
+//! ```
+//!
+//! A capture is what came out of the UART of `rtic_stm32/src/log_uart.rs` (see
+//! `examples/serial_log.rs`): the serial port itself, decoded as it comes in,
+//!
+//! ```text
+//! stty -F /dev/ttyACM0 115200 raw -echo
+//! logtool show target/thumbv7em-none-eabihf/debug/examples/serial_log /dev/ttyACM0
+//! ```
+//!
+//! or a file or FIFO it was recorded to (`cat /dev/ttyACM0 > log.bin`). It can also be the log
+//! store of `rtic_stm32/src/logstore.rs` read out of flash with
+//! `st-flash read store.bin 0x08010000 0x10000`, which is put back in order first.
+//!
+//! The ELF has to be the one running, the frames only hold indexes into its defmt table. A
+//! frame that does not decode is reported and skipped, the next one starts after the next zero.
+
+mod format;
+mod rzcobs;
+mod table;
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Read};
+use std::process::ExitCode;
+
+use rzcobs::Frames;
+use table::Table;
+
+const USAGE: &str = "usage: logtool show <file.elf> <capture> | table <file.elf>";
+
+/// Sector layout of the log store, see `rtic_stm32/src/logstore.rs`
+const STORE_MAGIC: &[u8; 4] = b"LOG1";
+const STORE_HEADER_LEN: usize = 8;
+const STORE_MAX_CHUNK: usize = 256;
+/// Sectors start at a multiple of the smallest sector
+const STORE_ALIGN: usize = 16 * 1024;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        ["show", elf, path] => show(elf, path),
+        ["table", elf] => table(elf),
+        _ => Err(USAGE.to_string()),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("logtool: {}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn show(elf: &str, path: &str) -> Result<(), String> {
+    let table = Table::load(elf)?;
+    frames(path, |offset, frame| match line(&table, frame) {
+        Ok(line) => println!("{}", line),
+        Err(error) => eprintln!("logtool: frame ending at byte {}: {}", offset, error),
+    })
+}
+
+/// The line `show` prints for a frame, still encoded
+fn line(table: &Table, frame: &[u8]) -> Result<String, String> {
+    let frame = rzcobs::decode(frame).map_err(|error| error.to_string())?;
+    let message = format::decode(table, &frame)?;
+    let mut line = String::new();
+    if let Some(timestamp) = message.timestamp {
+        line.push_str(&timestamp);
+        line.push(' ');
+    }
+    if let Some(level) = message.level {
+        line.push_str(&format!("{:<5} ", level));
+    }
+    line.push_str(&message.text);
+    Ok(line)
+}
+
+fn table(elf: &str) -> Result<(), String> {
+    let table = Table::load(elf)?;
+    if let Some(timestamp) = &table.timestamp {
+        println!("{:>5}  {:<14} {:?}", "-", "defmt_timestamp", timestamp);
+    }
+    for (index, entry) in &table.entries {
+        println!("{:>5}  {:<14} {:?}", index, entry.tag, entry.data);
+    }
+    Ok(())
+}
+
+/// Hands each frame of a capture to `frame`, still encoded, with the offset of its end
+fn frames(path: &str, mut frame: impl FnMut(u64, &[u8])) -> Result<(), String> {
+    let file = File::open(path).map_err(|error| format!("{}: {}", path, error))?;
+
+    // a log store image is read whole, everything else as it comes
+    let is_file = file.metadata().map(|metadata| metadata.is_file()).unwrap_or(false);
+    if is_file {
+        let data = fs::read(path).map_err(|error| format!("{}: {}", path, error))?;
+        if data.starts_with(STORE_MAGIC) {
+            let mut frames = Frames::new();
+            for &byte in &unwrap_store(&data) {
+                if let Some(encoded) = frames.push(byte) {
+                    frame(frames.offset(), &encoded);
+                }
+            }
+            return Ok(());
+        }
+    }
+    read_frames(file, frame).map_err(|error| format!("{}: {}", path, error))
+}
+
+/// Hands each frame of a stream to `frame` as soon as its zero comes in
+fn read_frames(mut stream: impl Read, mut frame: impl FnMut(u64, &[u8])) -> io::Result<()> {
+    let mut frames = Frames::new();
+    let mut buffer = [0; 4096];
+    loop {
+        let read = match stream.read(&mut buffer) {
+            Ok(0) => return Ok(()),
+            Ok(read) => read,
+            Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error),
+        };
+        for &byte in &buffer[..read] {
+            if let Some(encoded) = frames.push(byte) {
+                frame(frames.offset(), &encoded);
+            }
+        }
+    }
+}
+
+/// The chunks of a log store image joined up, oldest sector first; a frame can go on in the
+/// next sector, and the oldest one starts in the middle of one when its start was erased
+fn unwrap_store(image: &[u8]) -> Vec<u8> {
+    let mut sectors: Vec<(usize, u32)> = (0..image.len())
+        .step_by(STORE_ALIGN)
+        .filter(|&start| image[start..].starts_with(STORE_MAGIC) && start + STORE_HEADER_LEN <= image.len())
+        .map(|start| (start, u32::from_le_bytes(image[start + 4..start + 8].try_into().unwrap())))
+        .collect();
+    let starts: Vec<usize> = sectors.iter().map(|&(start, _)| start).collect();
+    // the newest is the one the others are older than, the sequence wraps
+    let newest = sectors
+        .iter()
+        .map(|&(_, sequence)| sequence)
+        .reduce(|best, sequence| if sequence.wrapping_sub(best) as i32 > 0 { sequence } else { best })
+        .unwrap_or(0);
+    sectors.sort_by_key(|&(_, sequence)| sequence.wrapping_sub(newest) as i32);
+
+    let mut stream = Vec::new();
+    for (start, _) in sectors {
+        // a sector ends where the next one starts
+        let end = starts.iter().copied().find(|&next| next > start).unwrap_or(image.len());
+        let mut offset = start + STORE_HEADER_LEN;
+        while offset + 2 <= end {
+            let len = u16::from_le_bytes([image[offset], image[offset + 1]]) as usize;
+            if len > STORE_MAX_CHUNK || offset + 2 + len > end {
+                break;
+            }
+            stream.extend_from_slice(&image[offset + 2..offset + 2 + len]);
+            offset += 2 + len;
+        }
+    }
+    stream
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rzcobs::tests::encode;
+    use crate::table::Entry;
+    use std::collections::BTreeMap;
+
+    const FRAMES: usize = 30;
+
+    fn table() -> Table {
+        let entry = |tag: &str, data: &str| Entry { tag: tag.to_string(), data: data.to_string() };
+        let entries = BTreeMap::from([
+            (1, entry("defmt_info", "boot {=u8}")),
+            (2, entry("defmt_warn", "{=u32} of {=str}")),
+            (3, entry("defmt_println", "{=[u8]:x}")),
+        ]);
+        Table { entries, timestamp: Some("{=u64:us}".to_string()) }
+    }
+
+    /// Frame `n` before the encoding: a message of each kind in turn, some with long runs
+    /// of zeros and some with none
+    fn frame(n: usize) -> Vec<u8> {
+        let mut frame = vec![(n % 3 + 1) as u8, 0];
+        frame.extend((n as u64 * 1500).to_le_bytes());
+        match n % 3 {
+            0 => frame.push(n as u8),
+            1 => {
+                frame.extend((n as u32 * 0x0100_0001).to_le_bytes());
+                frame.extend(5u32.to_le_bytes());
+                frame.extend(b"tasks");
+            }
+            _ => {
+                let bytes: Vec<u8> = (0..n * 10).map(|i| if i % 140 < 20 { 0 } else { i as u8 | 1 }).collect();
+                frame.extend((bytes.len() as u32).to_le_bytes());
+                frame.extend(bytes);
+            }
+        }
+        frame
+    }
+
+    /// The encoded frames one after the other the way the UART sends them, and where each
+    /// one ends
+    fn stream() -> (Vec<u8>, Vec<usize>) {
+        // the encoder starts with a zero
+        let mut stream = vec![0];
+        let mut ends = Vec::new();
+        for n in 0..FRAMES {
+            stream.extend(encode(&frame(n)));
+            ends.push(stream.len());
+        }
+        (stream, ends)
+    }
+
+    /// Hands out what it was given in pieces of the sizes in turn, and now and then nothing
+    /// but an interruption
+    struct Pipe<'a> {
+        bytes: &'a [u8],
+        pieces: std::iter::Cycle<std::slice::Iter<'a, usize>>,
+        /// Reads since the last interruption
+        reads: usize,
+    }
+
+    impl Read for Pipe<'_> {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            self.reads = (self.reads + 1) % 5;
+            if self.reads == 0 {
+                return Err(ErrorKind::Interrupted.into());
+            }
+            let len = (*self.pieces.next().unwrap()).min(buffer.len()).min(self.bytes.len());
+            let (piece, rest) = self.bytes.split_at(len);
+            buffer[..len].copy_from_slice(piece);
+            self.bytes = rest;
+            Ok(len)
+        }
+    }
+
+    /// What `show` makes of the frames coming through a pipe
+    fn show(bytes: &[u8], pieces: &[usize]) -> Vec<Result<String, String>> {
+        let table = table();
+        let pipe = Pipe { bytes, pieces: pieces.iter().cycle(), reads: 0 };
+        let mut lines = Vec::new();
+        read_frames(pipe, |_, frame| lines.push(line(&table, frame))).unwrap();
+        lines
+    }
+
+    /// The lines of the frames before `bad` and from `good` on are all there and come out
+    /// as without the damage
+    fn recovers(damaged: &[u8], bad: usize, good: usize) {
+        let (stream, _) = stream();
+        let clean = show(&stream, &[4096]);
+        for pieces in [&[1][..], &[5, 64, 3], &[4096]] {
+            let lines = show(damaged, pieces);
+            assert_ne!(lines, clean);
+            assert_eq!(lines[..bad], clean[..bad]);
+            assert_eq!(lines[lines.len() - (FRAMES - good)..], clean[good..]);
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let (stream, _) = stream();
+        let lines = show(&stream, &[4096]);
+        assert_eq!(lines.len(), FRAMES);
+        assert_eq!(lines[0], Ok("0.000000 INFO  boot 0".to_string()));
+        assert_eq!(lines[1], Ok("0.001500 WARN  16777217 of tasks".to_string()));
+        assert_eq!(lines[3], Ok("0.004500 INFO  boot 3".to_string()));
+        assert_eq!(lines[2], Ok(format!("0.003000 {:x?}", &[0u8; 20])));
+        assert!(lines.iter().all(Result::is_ok), "{:?}", lines);
+        // however it is split up on the way
+        for pieces in [&[1][..], &[7], &[5, 64, 3], &[900, 1, 2]] {
+            assert_eq!(show(&stream, pieces), lines);
+        }
+    }
+
+    #[test]
+    fn a_byte_changed() {
+        let (mut stream, ends) = stream();
+        let byte = &mut stream[ends[9] + 3];
+        *byte = if *byte == 0x55 { 0xAA } else { *byte ^ 0x55 }.max(1);
+        recovers(&stream, 10, 11);
+    }
+
+    #[test]
+    fn a_zero_of_noise() {
+        let (mut stream, ends) = stream();
+        stream.insert(ends[10] + 4, 0);
+        recovers(&stream, 11, 12);
+    }
+
+    #[test]
+    fn a_piece_lost() {
+        // across the end of frame 14, which runs into 15
+        let (mut stream, ends) = stream();
+        stream.drain(ends[14] - 3..ends[14] + 2);
+        recovers(&stream, 14, 16);
+    }
+
+    #[test]
+    fn started_in_the_middle() {
+        let (stream, ends) = stream();
+        recovers(&stream[ends[0] - 2..], 0, 1);
+    }
+}
@@ -0,0 +1,221 @@
+//! rzCOBS, the framing defmt puts around each log frame
+//!
+//! Every frame ends with a 0x00 and has no 0x00 in it. Inside, a code byte tells where the zeros
+//! go for the bytes before it, so a frame is undone from its end:
+//!
+//! ```text
+//! 0xxxxxxx   the 7 bytes before it, a set bit x is a 0x00 and a clear one the next byte in
+//! 1nnnnnnn   n + 7 bytes, then a 0x00
+//! 11111111   134 bytes
+//! ```
+//!
+//! The last code fills up its 7 bytes or ends on a 0x00 either way, so a frame comes out with up
+//! to 7 zeros too many at the end. A defmt frame knows its own length, so they do not matter.
+
+// spelled out, the firmware's tests use this decoder too and have no std prelude
+use std::{fmt, vec::Vec};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// A code wants more bytes than there are before it
+    Truncated,
+    /// A 0x00 inside a frame, which cannot be
+    Zero,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Truncated => write!(f, "rzCOBS frame cut short"),
+            Error::Zero => write!(f, "zero byte inside an rzCOBS frame"),
+        }
+    }
+}
+
+/// Splits a byte stream into frames at the zeros
+#[derive(Default)]
+pub struct Frames {
+    frame: Vec<u8>,
+    /// Bytes taken so far
+    offset: u64,
+}
+
+impl Frames {
+    pub fn new() -> Self {
+        Frames::default()
+    }
+
+    /// Offset of the byte after the last one pushed
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Takes the next byte, returns a frame (still encoded) when it was the end of one
+    pub fn push(&mut self, byte: u8) -> Option<Vec<u8>> {
+        self.offset += 1;
+        if byte != 0 {
+            self.frame.push(byte);
+            return None;
+        }
+        // zeros in a row are empty frames, the first one in a stream is one
+        if self.frame.is_empty() {
+            return None;
+        }
+        Some(std::mem::take(&mut self.frame))
+    }
+}
+
+/// Undoes the encoding of a frame without its 0x00
+pub fn decode(frame: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut decoded = Vec::with_capacity(frame.len() * 2);
+    let mut rest = frame;
+    // takes `count` bytes from the end of `rest` into `decoded`, last first
+    let take = |rest: &mut &[u8], decoded: &mut Vec<u8>, count: usize| -> Result<(), Error> {
+        if rest.len() < count {
+            return Err(Error::Truncated);
+        }
+        let (front, back) = rest.split_at(rest.len() - count);
+        decoded.extend(back.iter().rev());
+        *rest = front;
+        Ok(())
+    };
+    while let Some((&code, front)) = rest.split_last() {
+        rest = front;
+        match code {
+            0x00 => return Err(Error::Zero),
+            0x01..=0x7F => {
+                for bit in (0..7).rev() {
+                    if code & 1 << bit != 0 {
+                        decoded.push(0);
+                    } else {
+                        take(&mut rest, &mut decoded, 1)?;
+                    }
+                }
+            }
+            0x80..=0xFE => {
+                decoded.push(0);
+                take(&mut rest, &mut decoded, (code & 0x7F) as usize + 7)?;
+            }
+            0xFF => take(&mut rest, &mut decoded, 134)?,
+        }
+    }
+    decoded.reverse();
+    Ok(decoded)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::vec;
+
+    /// The frame with its 0x00 the way defmt's encoder on the target makes it
+    pub(crate) fn encode(data: &[u8]) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        let (mut run, mut zeros) = (0u8, 0u8);
+        for &byte in data {
+            if run < 7 {
+                if byte == 0 {
+                    zeros |= 1 << run;
+                } else {
+                    encoded.push(byte);
+                }
+                run += 1;
+                if run == 7 && zeros != 0 {
+                    encoded.push(zeros);
+                    (run, zeros) = (0, 0);
+                }
+            } else if byte == 0 {
+                encoded.push((run - 7) | 0x80);
+                (run, zeros) = (0, 0);
+            } else {
+                encoded.push(byte);
+                run += 1;
+                if run == 134 {
+                    encoded.push(0xFF);
+                    (run, zeros) = (0, 0);
+                }
+            }
+        }
+        match run {
+            0 => {}
+            1..=6 => encoded.push((zeros | (0xFF << run)) & 0x7F),
+            _ => encoded.push((run - 7) | 0x80),
+        }
+        encoded.push(0);
+        encoded
+    }
+
+    /// The data of a frame and what it decodes to, at most 7 zeros longer
+    fn round_trip(data: &[u8]) {
+        let encoded = encode(data);
+        assert!(!encoded[..encoded.len() - 1].contains(&0), "{:02x?}", encoded);
+        let decoded = decode(&encoded[..encoded.len() - 1]).unwrap();
+        assert_eq!(&decoded[..data.len()], data);
+        assert!(decoded.len() - data.len() <= 7 && decoded[data.len()..].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn codes() {
+        // from the tests of defmt's encoder
+        let cases: &[(&[u8], &[u8])] = &[
+            (&[0x00], &[0x7f]),
+            (&[0x00; 8], &[0x7f, 0x7f]),
+            (&[0x01], &[0x01, 0x7e]),
+            (&[0x00, 0x01], &[0x01, 0x7d]),
+            (&[0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x00], &[0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x40]),
+            (
+                &[0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88],
+                &[0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x81],
+            ),
+            (&[0, 0, 0, 0, 0, 0x44, 0, 0, 0, 0, 0, 0, 0, 0xff], &[0x44, 0x5f, 0xff, 0x3f]),
+        ];
+        for &(data, encoded) in cases {
+            assert_eq!(encode(data), [encoded, &[0]].concat());
+            round_trip(data);
+        }
+        // 133 bytes end on a zero that is not there, 134 fill a code of their own
+        let run: Vec<u8> = (1..=134).collect();
+        assert_eq!(encode(&run[..133])[133..], [0xFE, 0]);
+        assert_eq!(decode(&encode(&run[..133])[..134]).unwrap(), [&run[..133], &[0]].concat());
+        assert_eq!(encode(&run)[134..], [0xFF, 0]);
+        assert_eq!(decode(&encode(&run)[..135]).unwrap(), run);
+    }
+
+    #[test]
+    fn round_trips() {
+        let mut seed = 7u32;
+        for len in 0..600 {
+            let data: Vec<u8> = (0..len)
+                .map(|_| {
+                    seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                    // mostly zeros in some frames, none in others
+                    match (len % 3, seed >> 24) {
+                        (0, value) if value < 160 => 0,
+                        (1, value) => value as u8 | 1,
+                        (_, value) => value as u8,
+                    }
+                })
+                .collect();
+            round_trip(&data);
+        }
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(decode(&[0x01, 0x7C]), Err(Error::Truncated));
+        assert_eq!(decode(&[0x05, 0x81]), Err(Error::Truncated));
+        assert_eq!(decode(&[0xFF]), Err(Error::Truncated));
+        assert_eq!(decode(&[0x01, 0x7E, 0x00]), Err(Error::Zero));
+    }
+
+    #[test]
+    fn frames() {
+        let mut frames = Frames::new();
+        let stream = [0, 0, 0x01, 0x7E, 0, 0, 0x7F, 0];
+        let found: Vec<_> = stream
+            .iter()
+            .filter_map(|&byte| frames.push(byte).map(|frame| (frames.offset(), frame)))
+            .collect();
+        assert_eq!(found, [(5, vec![0x01, 0x7E]), (8, vec![0x7F])]);
+    }
+}
@@ -0,0 +1,151 @@
+//! The defmt table of an ELF: what every interned index stands for
+//!
+//! defmt puts a symbol in the `.defmt` section for every format string and interned string, its
+//! address is the index that goes over the wire and its name is a bit of JSON:
+//!
+//! ```text
+//! {"package":"..","tag":"defmt_info","data":"Blink {}!","disambiguator":"..","crate_name":".."}
+//! ```
+
+use std::collections::BTreeMap;
+use std::fs;
+
+use object::{Object, ObjectSection, ObjectSymbol};
+
+/// The wire format this decoder reads, from `_defmt_version_ = 4`
+const VERSION: &str = "4";
+
+/// What an index stands for
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// Like `defmt_info` or `defmt_prim`
+    pub tag: String,
+    /// The format string or interned string
+    pub data: String,
+}
+
+impl Entry {
+    /// The level of a log message, None for other entries
+    pub fn level(&self) -> Option<&'static str> {
+        match self.tag.as_str() {
+            "defmt_trace" => Some("TRACE"),
+            "defmt_debug" => Some("DEBUG"),
+            "defmt_info" => Some("INFO"),
+            "defmt_warn" => Some("WARN"),
+            "defmt_error" => Some("ERROR"),
+            _ => None,
+        }
+    }
+
+    /// A log message or a `defmt::println!`, what a frame starts with
+    pub fn is_message(&self) -> bool {
+        self.level().is_some() || self.tag == "defmt_println"
+    }
+}
+
+pub struct Table {
+    pub entries: BTreeMap<u16, Entry>,
+    /// The format string of `defmt::timestamp!`, None without one
+    pub timestamp: Option<String>,
+}
+
+impl Table {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let data = fs::read(path).map_err(|error| format!("{}: {}", path, error))?;
+        let file = object::File::parse(&*data).map_err(|error| format!("{}: {}", path, error))?;
+        let section = file
+            .section_by_name(".defmt")
+            .ok_or_else(|| format!("{}: no .defmt section, does it use defmt?", path))?;
+
+        let mut entries = BTreeMap::new();
+        let mut timestamp = None;
+        let mut version = None;
+        for symbol in file.symbols() {
+            let name = match symbol.name() {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            if let Some(found) = name.strip_prefix("_defmt_version_ = ") {
+                version = Some(found.to_string());
+                continue;
+            }
+            if symbol.section_index() != Some(section.index()) || !name.starts_with('{') {
+                continue;
+            }
+            let fields = parse_json(name).ok_or_else(|| format!("{}: bad defmt symbol {}", path, name))?;
+            let field = |key: &str| fields.iter().find(|(k, _)| k == key).map(|(_, v)| v.clone());
+            let (tag, data) = match (field("tag"), field("data")) {
+                (Some(tag), Some(data)) => (tag, data),
+                _ => return Err(format!("{}: bad defmt symbol {}", path, name)),
+            };
+            if tag == "defmt_timestamp" {
+                timestamp = Some(data);
+                continue;
+            }
+            let index = u16::try_from(symbol.address())
+                .map_err(|_| format!("{}: defmt index {:#x} too big", path, symbol.address()))?;
+            entries.insert(index, Entry { tag, data });
+        }
+
+        match version {
+            Some(version) if version != VERSION => Err(format!(
+                "{}: defmt wire format version {}, logtool reads version {}",
+                path, version, VERSION
+            )),
+            _ if entries.is_empty() => Err(format!("{}: the .defmt section is empty", path)),
+            _ => Ok(Table { entries, timestamp }),
+        }
+    }
+
+    pub fn get(&self, index: u16) -> Option<&Entry> {
+        self.entries.get(&index)
+    }
+}
+
+/// The keys and string values of a flat JSON object, which is all a defmt symbol is
+fn parse_json(text: &str) -> Option<Vec<(String, String)>> {
+    let mut chars = text.trim().chars();
+    let mut fields = Vec::new();
+    if chars.next()? != '{' {
+        return None;
+    }
+    loop {
+        match chars.next()? {
+            '}' => break,
+            ',' => continue,
+            '"' => {}
+            _ => return None,
+        }
+        let key = parse_string(&mut chars)?;
+        if chars.next()? != ':' || chars.next()? != '"' {
+            return None;
+        }
+        let value = parse_string(&mut chars)?;
+        fields.push((key, value));
+    }
+    if chars.next().is_some() {
+        return None;
+    }
+    Some(fields)
+}
+
+/// A JSON string after its opening quote, up to and with the closing one
+fn parse_string(chars: &mut impl Iterator<Item = char>) -> Option<String> {
+    let mut string = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return Some(string),
+            '\\' => match chars.next()? {
+                'n' => string.push('\n'),
+                't' => string.push('\t'),
+                'r' => string.push('\r'),
+                'u' => {
+                    let hex: String = chars.by_ref().take(4).collect();
+                    string.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+                }
+                other => string.push(other),
+            },
+            other => string.push(other),
+        }
+    }
+}
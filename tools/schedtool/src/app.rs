@@ -0,0 +1,326 @@
+//! What an RTIC 1 app declares: its tasks, their priorities and resources, and how often they
+//! are spawned
+//!
+//! Periods are worked out from the source where they are plain to see:
+//!
+//! - a task that spawns itself with `spawn_after(<literal>.secs())` (or `millis`, `micros`)
+//! - a task with a `Periodic` local made in `init` by `Periodic::new(<literal>.secs())`
+//! - a task spawned by another one comes as often as that one runs
+//!
+//! Anything else (a period read from the configuration, an interrupt handler) has to be given
+//! in the WCET file.
+
+use std::collections::BTreeMap;
+
+use proc_macro2::{Span, TokenTree};
+use syn::parse::{ParseStream, Parser};
+use syn::visit::{self, Visit};
+use syn::{Attribute, Expr, ExprCall, ExprLit, ExprMethodCall, ExprPath, Item, ItemFn, Lit, Local, Pat};
+
+/// Priority RTIC gives a task without `priority = ..`
+pub const DEFAULT_PRIORITY: u8 = 1;
+
+#[derive(Debug)]
+pub struct Task {
+    pub name: String,
+    pub priority: u8,
+    /// The interrupt of a hardware task
+    pub binds: Option<String>,
+    pub shared: Vec<String>,
+    pub local: Vec<String>,
+    /// In µs, from a spawn of itself or a `Periodic`
+    pub period: Option<u64>,
+    /// Other tasks it spawns
+    pub spawns: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct App {
+    pub tasks: Vec<Task>,
+    /// Resource and the highest priority of the tasks sharing it
+    pub ceilings: BTreeMap<String, u8>,
+    /// Tasks spawned from `init`
+    pub init_spawns: Vec<String>,
+    /// Things that could not be worked out, with their line
+    pub notes: Vec<(usize, String)>,
+}
+
+impl App {
+    pub fn task(&self, name: &str) -> Option<&Task> {
+        self.tasks.iter().find(|task| task.name == name)
+    }
+
+    /// The tasks that spawn `name`
+    pub fn spawners(&self, name: &str) -> Vec<&Task> {
+        self.tasks.iter().filter(|task| task.spawns.iter().any(|spawn| spawn == name)).collect()
+    }
+}
+
+/// The app in a source file, the `mod` with `#[rtic::app(..)]` (or `#[app(..)]`) on it
+pub fn parse(source: &str) -> Result<App, String> {
+    let file = syn::parse_file(source).map_err(|error| {
+        let start = error.span().start();
+        format!("line {}: {}", start.line, error)
+    })?;
+    let module = file
+        .items
+        .iter()
+        .find_map(|item| match item {
+            Item::Mod(module) if module.attrs.iter().any(is_app) => Some(module),
+            _ => None,
+        })
+        .ok_or("no #[rtic::app] module")?;
+    let items = match &module.content {
+        Some((_, items)) => items,
+        None => return Err("the #[rtic::app] module is not inline".to_string()),
+    };
+
+    let mut app = App { tasks: Vec::new(), ceilings: BTreeMap::new(), init_spawns: Vec::new(), notes: Vec::new() };
+    // `Periodic` locals made in init, by name
+    let mut periodics = BTreeMap::new();
+
+    for item in items {
+        let function = match item {
+            Item::Fn(function) => function,
+            _ => continue,
+        };
+        for attr in &function.attrs {
+            match attr_name(attr).as_deref() {
+                Some("task") => {
+                    let task = task(function, attr, &mut app.notes)?;
+                    app.tasks.push(task);
+                }
+                Some("init") => {
+                    let mut body = Body::new(None);
+                    body.visit_item_fn(function);
+                    app.init_spawns = body.spawns;
+                    periodics = body.periodics;
+                    app.notes.extend(body.notes);
+                }
+                Some("idle") => {
+                    let args = args(attr)?;
+                    for resource in args.get("shared").into_iter().flatten() {
+                        app.ceilings.entry(resource.clone()).or_insert(0);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // `x::spawn(..)` where x is not a task is some other function of that name
+    let names: Vec<String> = app.tasks.iter().map(|task| task.name.clone()).collect();
+    app.init_spawns.retain(|spawn| names.contains(spawn));
+    for task in &mut app.tasks {
+        task.spawns.retain(|spawn| names.contains(spawn));
+        task.spawns.dedup();
+        if task.period.is_none() {
+            task.period = task.local.iter().find_map(|local| periodics.get(local).copied());
+        }
+        for resource in &task.shared {
+            let ceiling = app.ceilings.entry(resource.clone()).or_insert(0);
+            *ceiling = (*ceiling).max(task.priority);
+        }
+    }
+    Ok(app)
+}
+
+fn task(function: &ItemFn, attr: &Attribute, notes: &mut Vec<(usize, String)>) -> Result<Task, String> {
+    let name = function.sig.ident.to_string();
+    let line = line(function.sig.ident.span());
+    let args = args(attr).map_err(|error| format!("line {}: {}", line, error))?;
+    let priority = match args.get("priority").and_then(|value| value.first()) {
+        Some(priority) => priority
+            .parse()
+            .map_err(|_| format!("line {}: priority {} is not a number", line, priority))?,
+        None => DEFAULT_PRIORITY,
+    };
+    let mut body = Body::new(Some(&name));
+    body.visit_item_fn(function);
+    notes.extend(body.notes);
+    Ok(Task {
+        binds: args.get("binds").and_then(|value| value.first().cloned()),
+        shared: args.get("shared").cloned().unwrap_or_default(),
+        local: args.get("local").cloned().unwrap_or_default(),
+        period: body.period,
+        spawns: body.spawns.into_iter().filter(|spawn| *spawn != name).collect(),
+        priority,
+        name,
+    })
+}
+
+fn is_app(attr: &Attribute) -> bool {
+    attr_name(attr).as_deref() == Some("app")
+}
+
+/// The last segment of the attribute path, `app` for `#[rtic::app]`
+fn attr_name(attr: &Attribute) -> Option<String> {
+    attr.path().segments.last().map(|segment| segment.ident.to_string())
+}
+
+fn line(span: Span) -> usize {
+    span.start().line
+}
+
+/// The arguments of `#[task(..)]` by key; a list (`shared = [a, b]`) is its names, with the
+/// types and initial values of `local = [x: T = ..]` left out. Anything else is its tokens.
+fn args(attr: &Attribute) -> Result<BTreeMap<String, Vec<String>>, String> {
+    let mut args = BTreeMap::new();
+    if matches!(attr.meta, syn::Meta::Path(_)) {
+        return Ok(args);
+    }
+    let parser = |input: ParseStream| {
+        while !input.is_empty() {
+            let key: syn::Ident = input.parse()?;
+            input.parse::<syn::Token![=]>()?;
+            let value: Vec<TokenTree> = until_comma(input)?;
+            let values = match value.as_slice() {
+                [TokenTree::Group(group)] if group.delimiter() == proc_macro2::Delimiter::Bracket => {
+                    list_names(group.stream())
+                }
+                tokens => vec![tokens.iter().map(|token| token.to_string()).collect::<String>()],
+            };
+            args.insert(key.to_string(), values);
+            if !input.is_empty() {
+                input.parse::<syn::Token![,]>()?;
+            }
+        }
+        Ok(())
+    };
+    attr.parse_args_with(parser).map_err(|error| error.to_string())?;
+    Ok(args)
+}
+
+/// Tokens up to a comma that is not inside `<..>`
+fn until_comma(input: ParseStream) -> syn::Result<Vec<TokenTree>> {
+    let mut tokens = Vec::new();
+    let mut depth = 0usize;
+    while !input.is_empty() {
+        if depth == 0 && input.peek(syn::Token![,]) {
+            break;
+        }
+        let token: TokenTree = input.parse()?;
+        if let TokenTree::Punct(punct) = &token {
+            match punct.as_char() {
+                '<' => depth += 1,
+                '>' => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+        }
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+/// The first name of each entry of a list, `[a, &b, c: T = ..]` is `a b c`
+fn list_names(stream: proc_macro2::TokenStream) -> Vec<String> {
+    let parser = |input: ParseStream| {
+        let mut names = Vec::new();
+        while !input.is_empty() {
+            let entry = until_comma(input)?;
+            if let Some(name) = entry.iter().find_map(|token| match token {
+                TokenTree::Ident(ident) => Some(ident.to_string()),
+                _ => None,
+            }) {
+                names.push(name);
+            }
+            if !input.is_empty() {
+                input.parse::<syn::Token![,]>()?;
+            }
+        }
+        Ok(names)
+    };
+    parser.parse2(stream).unwrap_or_default()
+}
+
+/// A literal duration in µs: `1.secs()`, `300.millis()`, `50.micros()`
+fn duration(expr: &Expr) -> Option<u64> {
+    let call = match expr {
+        Expr::MethodCall(call) => call,
+        _ => return None,
+    };
+    let value: u64 = match &*call.receiver {
+        Expr::Lit(ExprLit { lit: Lit::Int(int), .. }) => int.base10_parse().ok()?,
+        _ => return None,
+    };
+    match call.method.to_string().as_str() {
+        "secs" => Some(value * 1_000_000),
+        "millis" => Some(value * 1_000),
+        "micros" => Some(value),
+        "nanos" => Some(value / 1_000),
+        "minutes" => Some(value * 60_000_000),
+        _ => None,
+    }
+}
+
+/// Spawns and periods in the body of a task or `init`
+struct Body<'a> {
+    task: Option<&'a str>,
+    spawns: Vec<String>,
+    period: Option<u64>,
+    periodics: BTreeMap<String, u64>,
+    notes: Vec<(usize, String)>,
+}
+
+impl<'a> Body<'a> {
+    fn new(task: Option<&'a str>) -> Self {
+        Body { task, spawns: Vec::new(), period: None, periodics: BTreeMap::new(), notes: Vec::new() }
+    }
+}
+
+impl<'ast> Visit<'ast> for Body<'_> {
+    fn visit_expr_call(&mut self, call: &'ast ExprCall) {
+        if let Expr::Path(ExprPath { path, .. }) = &*call.func {
+            let segments: Vec<_> = path.segments.iter().collect();
+            if let [.., task, method] = segments.as_slice() {
+                let method = method.ident.to_string();
+                if matches!(method.as_str(), "spawn" | "spawn_after" | "spawn_at") {
+                    let task = task.ident.to_string();
+                    if Some(task.as_str()) == self.task && method == "spawn_after" {
+                        match call.args.first().and_then(duration) {
+                            Some(delay) => self.period = Some(delay),
+                            None => self.notes.push((
+                                line(method_span(path)),
+                                format!("{} spawns itself after a delay that is not a literal", task),
+                            )),
+                        }
+                    }
+                    self.spawns.push(task);
+                }
+            }
+        }
+        visit::visit_expr_call(self, call);
+    }
+
+    fn visit_local(&mut self, local: &'ast Local) {
+        // let name = Periodic::new(1.secs())[.phase(..)..];
+        if let (Pat::Ident(name), Some(init)) = (&local.pat, &local.init) {
+            if let Some(period) = periodic_new(&init.expr) {
+                self.periodics.insert(name.ident.to_string(), period);
+            }
+        }
+        visit::visit_local(self, local);
+    }
+}
+
+fn method_span(path: &syn::Path) -> Span {
+    path.segments.last().map_or_else(Span::call_site, |segment| segment.ident.span())
+}
+
+/// The period of `Periodic::new(<duration>)`, through any builder calls after it
+fn periodic_new(expr: &Expr) -> Option<u64> {
+    match expr {
+        Expr::MethodCall(ExprMethodCall { receiver, .. }) => periodic_new(receiver),
+        Expr::Call(call) => match &*call.func {
+            Expr::Path(ExprPath { path, .. }) => {
+                let segments: Vec<String> = path.segments.iter().map(|segment| segment.ident.to_string()).collect();
+                match segments.as_slice() {
+                    [.., ty, new] if ty == "Periodic" && new == "new" => call.args.first().and_then(duration),
+                    _ => None,
+                }
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
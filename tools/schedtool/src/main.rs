@@ -0,0 +1,153 @@
+//! Schedulability of an RTIC 1 app, from its source and measured WCETs
+//!
+//! ```text
+//! schedtool show <app.rs>                 tasks, priorities, resource ceilings and periods
+//! schedtool check <app.rs> <wcets>        worst-case response times against the deadlines
+//! ```
+//!
+//! `check` exits with 1 when a deadline is missed or cannot be shown to hold. The WCET file is
+//! described in `src/rta.rs`; the times can come from the cycle counts of a trace
+//! (`tools/tracetool`) under a worst-case load. `fun.wcet` has made up figures for
+//! `rtic_stm32/examples/fun.rs` to start from.
+
+mod app;
+mod rta;
+
+use std::env;
+use std::fs;
+use std::process::ExitCode;
+
+use app::App;
+use rta::{Arrival, Response};
+
+const USAGE: &str = "usage: schedtool show <app.rs> | check <app.rs> <wcets>";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        ["show", path] => show(path),
+        ["check", path, wcets] => check(path, wcets),
+        _ => Err(USAGE.to_string()),
+    };
+    match result {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(error) => {
+            eprintln!("schedtool: {}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn load(path: &str) -> Result<App, String> {
+    let source = fs::read_to_string(path).map_err(|error| format!("{}: {}", path, error))?;
+    app::parse(&source).map_err(|error| format!("{}: {}", path, error))
+}
+
+fn show(path: &str) -> Result<bool, String> {
+    let app = load(path)?;
+    println!("{:<16} {:>4}  {:<16} {:>10}  shared", "task", "prio", "binds", "period");
+    for task in &app.tasks {
+        println!(
+            "{:<16} {:>4}  {:<16} {:>10}  {}",
+            task.name,
+            task.priority,
+            task.binds.as_deref().unwrap_or("-"),
+            task.period.map_or("-".to_string(), time),
+            task.shared.join(", ")
+        );
+    }
+    println!();
+    println!("{:<16} ceiling", "resource");
+    for (resource, ceiling) in &app.ceilings {
+        println!("{:<16} {}", resource, ceiling);
+    }
+    println!();
+    println!("spawned from init: {}", app.init_spawns.join(", "));
+    for task in app.tasks.iter().filter(|task| !task.spawns.is_empty()) {
+        println!("spawned from {}: {}", task.name, task.spawns.join(", "));
+    }
+    print_notes(path, &app);
+    Ok(true)
+}
+
+fn check(path: &str, wcets: &str) -> Result<bool, String> {
+    let app = load(path)?;
+    let text = fs::read_to_string(wcets).map_err(|error| format!("{}: {}", wcets, error))?;
+    let wcets = rta::parse_wcets(&text).map_err(|error| format!("{}: {}", wcets, error))?;
+    for name in wcets.tasks.keys().filter(|name| app.task(name).is_none()) {
+        eprintln!("schedtool: warning: {} is not a task of the app", name);
+    }
+    let analysis = rta::analyse(&app, &wcets)?;
+
+    println!(
+        "{:<16} {:>4}  {:>10} {:>10} {:>10} {:>10} {:>10}",
+        "task", "prio", "period", "wcet", "blocking", "response", "deadline"
+    );
+    let mut ok = true;
+    for row in &analysis.rows {
+        let period = match row.arrivals.as_slice() {
+            [] => "?".to_string(),
+            [Arrival::Once] => "once".to_string(),
+            [Arrival::Every(period)] => time(*period),
+            _ => "several".to_string(),
+        };
+        let response = match &row.response {
+            Response::Bounded(response) => time(*response),
+            Response::Overload => "overload".to_string(),
+            Response::Unknown(_) => "?".to_string(),
+        };
+        let verdict = match (&row.response, row.meets_deadline()) {
+            (Response::Unknown(_), _) => {
+                ok = false;
+                "?"
+            }
+            (Response::Overload, _) | (_, Some(false)) => {
+                ok = false;
+                "MISSED"
+            }
+            (_, Some(true)) => "ok",
+            (_, None) => "",
+        };
+        println!(
+            "{:<16} {:>4}  {:>10} {:>10} {:>10} {:>10} {:>10}  {}",
+            row.task,
+            row.priority,
+            period,
+            time(row.wcet),
+            time(row.blocking),
+            response,
+            row.deadline.map_or("-".to_string(), time),
+            verdict
+        );
+    }
+    println!();
+    println!("utilisation {:.1}%", analysis.utilisation * 100.0);
+    for row in &analysis.rows {
+        if let Some((task, resource)) = &row.blocker {
+            println!("{} is blocked longest by {} holding {}", row.task, task, resource);
+        }
+        if let Response::Unknown(why) = &row.response {
+            println!("{}: {}", row.task, why);
+        }
+    }
+    print_notes(path, &app);
+    Ok(ok)
+}
+
+fn print_notes(path: &str, app: &App) {
+    for (line, note) in &app.notes {
+        eprintln!("{}:{}: {}", path, line, note);
+    }
+}
+
+/// A time in µs in the largest unit it fills
+fn time(micros: u64) -> String {
+    if micros >= 1_000_000 {
+        format!("{}s", micros as f64 / 1e6)
+    } else if micros >= 1_000 {
+        format!("{}ms", micros as f64 / 1e3)
+    } else {
+        format!("{}us", micros)
+    }
+}
@@ -0,0 +1,446 @@
+//! Response-time analysis of the tasks of an app
+//!
+//! RTIC runs tasks by fixed priority and locks resources with the stack resource policy, so
+//! a task waits for the tasks at its priority and above, and at most once for a lower one
+//! holding a resource with a ceiling at or above its priority. Its worst-case response is the
+//! smallest `R` with
+//!
+//! ```text
+//! R = C + B + sum over the other tasks j at priority >= its own of ceil(R / T_j) * C_j
+//! ```
+//!
+//! `C` is the WCET, `B` the longest such lock (the WCET of the lower task when the lock time
+//! is not given) and `T` the period. A task spawned by another one is taken to come at most
+//! once per job of that one; one spawned only from `init` comes once.
+//!
+//! The WCET file has a line per task, times in `us`, `ms` or `s` (µs without a unit), and
+//! `-` for a value left out:
+//!
+//! ```text
+//! # task      wcet    period   deadline
+//! blink       120us
+//! button      15us    50ms              # an interrupt handler, at most every 50 ms
+//! lock add global 4us                   # add holds global for 4 µs at most
+//! ```
+//!
+//! The period and deadline override what the source says; the deadline is the period
+//! otherwise.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::app::{App, Task};
+
+/// A response time longer than this is taken as never ending, in µs
+const HORIZON: u64 = 3_600_000_000;
+
+#[derive(Debug, Default)]
+pub struct Timing {
+    pub wcet: u64,
+    pub period: Option<u64>,
+    pub deadline: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+pub struct Wcets {
+    pub tasks: BTreeMap<String, Timing>,
+    /// How long a task holds a resource, by task and resource
+    pub locks: BTreeMap<(String, String), u64>,
+}
+
+pub fn parse_wcets(text: &str) -> Result<Wcets, String> {
+    let mut wcets = Wcets::default();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("");
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let error = |what: &str| format!("line {}: {}", number + 1, what);
+        match words.as_slice() {
+            [] => {}
+            ["lock", task, resource, time] => {
+                let time = micros(time).ok_or_else(|| error("not a time"))?;
+                wcets.locks.insert((task.to_string(), resource.to_string()), time);
+            }
+            [task, wcet, rest @ ..] if rest.len() <= 2 => {
+                let optional = |word: Option<&&str>| match word {
+                    None | Some(&"-") => Ok(None),
+                    Some(word) => micros(word).map(Some).ok_or_else(|| error("not a time")),
+                };
+                let timing = Timing {
+                    wcet: micros(wcet).ok_or_else(|| error("not a time"))?,
+                    period: optional(rest.first())?,
+                    deadline: optional(rest.get(1))?,
+                };
+                wcets.tasks.insert(task.to_string(), timing);
+            }
+            _ => return Err(error("expected `task wcet [period [deadline]]` or `lock task resource time`")),
+        }
+    }
+    Ok(wcets)
+}
+
+/// A time in µs from `120us`, `2.5ms`, `1s` or `120`
+pub fn micros(text: &str) -> Option<u64> {
+    let (number, scale) = if let Some(number) = text.strip_suffix("us") {
+        (number, 1.0)
+    } else if let Some(number) = text.strip_suffix("ms") {
+        (number, 1e3)
+    } else if let Some(number) = text.strip_suffix('s') {
+        (number, 1e6)
+    } else {
+        (text, 1.0)
+    };
+    let value: f64 = number.parse().ok()?;
+    if value.is_nan() || value < 0.0 {
+        return None;
+    }
+    Some((value * scale).round() as u64)
+}
+
+/// How often a task is released
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Arrival {
+    /// At most once every so many µs
+    Every(u64),
+    Once,
+}
+
+#[derive(Debug)]
+pub enum Response {
+    /// In µs
+    Bounded(u64),
+    /// It does not converge: the tasks above need all of the CPU or more
+    Overload,
+    /// The analysis cannot say, and why
+    Unknown(String),
+}
+
+#[derive(Debug)]
+pub struct Row {
+    pub task: String,
+    pub priority: u8,
+    pub arrivals: Vec<Arrival>,
+    pub wcet: u64,
+    pub blocking: u64,
+    /// Who the blocking comes from, task and resource
+    pub blocker: Option<(String, String)>,
+    pub response: Response,
+    pub deadline: Option<u64>,
+}
+
+impl Row {
+    /// Met, missed, or None when there is no deadline
+    pub fn meets_deadline(&self) -> Option<bool> {
+        let deadline = self.deadline?;
+        Some(matches!(self.response, Response::Bounded(response) if response <= deadline))
+    }
+}
+
+pub struct Analysis {
+    pub rows: Vec<Row>,
+    /// Sum of WCET / period over the periodic tasks
+    pub utilisation: f64,
+}
+
+pub fn analyse(app: &App, wcets: &Wcets) -> Result<Analysis, String> {
+    let missing: Vec<&str> = app
+        .tasks
+        .iter()
+        .filter(|task| !wcets.tasks.contains_key(&task.name))
+        .map(|task| task.name.as_str())
+        .collect();
+    if !missing.is_empty() {
+        return Err(format!("no WCET for {}", missing.join(", ")));
+    }
+    let wcet = |task: &Task| wcets.tasks[&task.name].wcet;
+
+    let arrivals: BTreeMap<&str, Result<Vec<Arrival>, String>> = app
+        .tasks
+        .iter()
+        .map(|task| (task.name.as_str(), arrivals(app, wcets, task, &mut BTreeSet::new())))
+        .collect();
+
+    let mut rows = Vec::new();
+    let mut utilisation = 0.0;
+    for task in &app.tasks {
+        let own = arrivals[task.name.as_str()].clone().unwrap_or_default();
+        for arrival in &own {
+            if let Arrival::Every(period) = arrival {
+                utilisation += wcet(task) as f64 / *period as f64;
+            }
+        }
+
+        // the longest a lower task can hold a resource this one may need to preempt it
+        let mut blocking = 0;
+        let mut blocker = None;
+        for lower in app.tasks.iter().filter(|lower| lower.priority < task.priority) {
+            for resource in &lower.shared {
+                if app.ceilings.get(resource).copied().unwrap_or(0) < task.priority {
+                    continue;
+                }
+                let held = wcets
+                    .locks
+                    .get(&(lower.name.clone(), resource.clone()))
+                    .copied()
+                    .unwrap_or_else(|| wcet(lower));
+                if held > blocking {
+                    blocking = held;
+                    blocker = Some((lower.name.clone(), resource.clone()));
+                }
+            }
+        }
+
+        let timing = &wcets.tasks[&task.name];
+        let deadline = timing.deadline.or_else(|| {
+            own.iter().map(|arrival| match arrival {
+                Arrival::Every(period) => Some(*period),
+                Arrival::Once => None,
+            }).collect::<Option<Vec<u64>>>().and_then(|periods| periods.into_iter().min())
+        });
+
+        // everything that can run ahead of it
+        let mut interference = Vec::new();
+        let mut unknown = None;
+        for other in app.tasks.iter().filter(|other| other.priority >= task.priority && other.name != task.name) {
+            match &arrivals[other.name.as_str()] {
+                Ok(arrivals) => interference.push((wcet(other), arrivals.clone())),
+                Err(why) => {
+                    unknown.get_or_insert_with(|| format!("{} ({})", other.name, why));
+                }
+            }
+        }
+
+        let response = match unknown {
+            Some(why) => Response::Unknown(format!("no period for {}", why)),
+            None => response(wcet(task) + blocking, &interference),
+        };
+        rows.push(Row {
+            task: task.name.clone(),
+            priority: task.priority,
+            arrivals: own,
+            wcet: wcet(task),
+            blocking,
+            blocker,
+            response,
+            deadline,
+        });
+    }
+    Ok(Analysis { rows, utilisation })
+}
+
+/// The fixed point of `R = own + sum of the interference in R`
+fn response(own: u64, interference: &[(u64, Vec<Arrival>)]) -> Response {
+    let demand = |window: u64| -> u64 {
+        interference
+            .iter()
+            .flat_map(|(wcet, arrivals)| arrivals.iter().map(move |arrival| (*wcet, *arrival)))
+            .map(|(wcet, arrival)| match arrival {
+                Arrival::Every(period) => window.div_ceil(period.max(1)) * wcet,
+                Arrival::Once => wcet,
+            })
+            .sum()
+    };
+    let mut window = own;
+    loop {
+        let next = own + demand(window);
+        if next == window {
+            return Response::Bounded(window);
+        }
+        if next > HORIZON {
+            return Response::Overload;
+        }
+        window = next;
+    }
+}
+
+/// How often `task` is released: by the WCET file, its own period, or the tasks spawning it
+fn arrivals<'a>(
+    app: &'a App,
+    wcets: &Wcets,
+    task: &'a Task,
+    seen: &mut BTreeSet<&'a str>,
+) -> Result<Vec<Arrival>, String> {
+    if let Some(period) = wcets.tasks.get(&task.name).and_then(|timing| timing.period) {
+        return Ok(vec![Arrival::Every(period)]);
+    }
+    if let Some(period) = task.period {
+        return Ok(vec![Arrival::Every(period)]);
+    }
+    if !seen.insert(&task.name) {
+        return Err("spawned in a loop".to_string());
+    }
+    let mut inherited = Vec::new();
+    for spawner in app.spawners(&task.name) {
+        let theirs = arrivals(app, wcets, spawner, seen)
+            .map_err(|why| format!("spawned by {}, {}", spawner.name, why))?;
+        inherited.extend(theirs);
+    }
+    if app.init_spawns.contains(&task.name) {
+        inherited.push(Arrival::Once);
+    }
+    seen.remove(task.name.as_str());
+    if inherited.is_empty() {
+        return Err(match &task.binds {
+            Some(interrupt) => format!("bound to {}", interrupt),
+            None => "never spawned".to_string(),
+        });
+    }
+    Ok(inherited)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app;
+
+    /// Three tasks that spawn themselves every 4, 6 and 10 ms; t1 and t3 share `r`
+    const THREE: &str = r#"
+        #[rtic::app(device = stm32f4xx_hal::pac)]
+        mod app {
+            #[shared]
+            struct Shared { r: u32 }
+
+            #[task(priority = 3, shared = [r])]
+            fn t1(mut ctx: t1::Context) {
+                ctx.shared.r.lock(|r| *r += 1);
+                t1::spawn_after(4.millis()).ok();
+            }
+
+            #[task(priority = 2)]
+            fn t2(_: t2::Context) {
+                t2::spawn_after(6.millis()).ok();
+            }
+
+            #[task(priority = 1, shared = [r])]
+            fn t3(mut ctx: t3::Context) {
+                ctx.shared.r.lock(|r| *r = 0);
+                t3::spawn_after(10.millis()).ok();
+            }
+        }
+    "#;
+
+    fn analyse_text(source: &str, wcets: &str) -> Analysis {
+        analyse(&app::parse(source).unwrap(), &parse_wcets(wcets).unwrap()).unwrap()
+    }
+
+    /// Response, blocking and deadline of each task in µs, the response 0 when there is none
+    fn responses(analysis: &Analysis) -> Vec<(&str, u64, u64, Option<u64>)> {
+        analysis
+            .rows
+            .iter()
+            .map(|row| {
+                let response = match row.response {
+                    Response::Bounded(response) => response,
+                    _ => 0,
+                };
+                (row.task.as_str(), response, row.blocking, row.deadline)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn response_times() {
+        let analysis = analyse_text(THREE, "t1 1ms\nt2 2ms\nt3 3ms\nlock t3 r 500us\n");
+        // t1: 1 + 0.5 blocked by t3 holding r
+        // t2: 2 + 0.5, then one job of t1 in 3.5
+        // t3: 3, 3 + 1 + 2 = 6, 3 + 2 + 2 = 7, 3 + 2 + 4 = 9, 3 + 3 + 4 = 10, 3 + 3 + 4 = 10
+        assert_eq!(
+            responses(&analysis),
+            [("t1", 1500, 500, Some(4000)), ("t2", 3500, 500, Some(6000)), ("t3", 10_000, 0, Some(10_000))]
+        );
+        assert!(analysis.rows.iter().all(|row| row.meets_deadline() == Some(true)));
+        assert_eq!(analysis.rows[0].blocker, Some(("t3".to_string(), "r".to_string())));
+        // 1/4 + 2/6 + 3/10
+        assert!((analysis.utilisation - 0.883_333).abs() < 1e-6);
+
+        // without a lock time, t3 is taken to hold r for all of its WCET
+        let analysis = analyse_text(THREE, "t1 1ms\nt2 2ms\nt3 3ms\n");
+        assert_eq!(responses(&analysis)[0], ("t1", 4000, 3000, Some(4000)));
+    }
+
+    #[test]
+    fn not_schedulable() {
+        // 98% busy and still too late: t3 needs 4 + 3 + 4 = 11 ms
+        let analysis = analyse_text(THREE, "t1 1ms\nt2 2ms\nt3 4ms\nlock t3 r 500us\n");
+        assert_eq!(responses(&analysis)[2], ("t3", 11_000, 0, Some(10_000)));
+        assert_eq!(analysis.rows[2].meets_deadline(), Some(false));
+
+        // t1 and t2 need more than all of the CPU, t3 never gets it
+        let analysis = analyse_text(THREE, "t1 1ms\nt2 5ms\nt3 1ms\n");
+        // t2: 5 + 1 blocked by t3 = 6, 6 + 2 jobs of t1 = 8, 2 ms late
+        assert_eq!(responses(&analysis)[1], ("t2", 8000, 1000, Some(6000)));
+        assert!(matches!(analysis.rows[2].response, Response::Overload));
+        assert_eq!(analysis.rows[2].meets_deadline(), Some(false));
+    }
+
+    #[test]
+    fn arrivals_from_spawns() {
+        let source = r#"
+            #[rtic::app(device = pac)]
+            mod app {
+                #[init]
+                fn init(_: init::Context) -> (Shared, Local, init::Monotonics) {
+                    worker::spawn().ok();
+                }
+                #[task(binds = EXTI0, priority = 3)]
+                fn button(_: button::Context) {
+                    worker::spawn().ok();
+                }
+                #[task(priority = 2)]
+                fn tick(_: tick::Context) {
+                    worker::spawn().ok();
+                    tick::spawn_after(5.millis()).ok();
+                }
+                #[task(priority = 1)]
+                fn worker(_: worker::Context) {}
+            }
+        "#;
+        let app = app::parse(source).unwrap();
+        // the interrupt needs a period from the file, the tasks below it cannot be analysed without
+        let analysis = analyse(&app, &parse_wcets("button 10us\ntick 100us\nworker 1ms\n").unwrap()).unwrap();
+        assert!(matches!(analysis.rows[0].response, Response::Bounded(10)));
+        let unknown = "no period for button (bound to EXTI0)";
+        assert!(matches!(&analysis.rows[1].response, Response::Unknown(why) if why == unknown));
+        assert!(matches!(&analysis.rows[2].response, Response::Unknown(_)));
+
+        let analysis = analyse(&app, &parse_wcets("button 10us 20ms\ntick 100us\nworker 1ms\n").unwrap()).unwrap();
+        let worker = &analysis.rows[2];
+        assert_eq!(worker.arrivals, [Arrival::Every(20_000), Arrival::Every(5000), Arrival::Once]);
+        // itself, once from init, and a job each of button and tick in its window
+        assert_eq!(responses(&analysis)[2], ("worker", 1000 + 10 + 100, 0, None));
+        assert_eq!(worker.meets_deadline(), None);
+    }
+
+    #[test]
+    fn the_fun_example() {
+        let source = include_str!("../../../rtic_stm32/examples/fun.rs");
+        let analysis = analyse_text(source, include_str!("../fun.wcet"));
+        // blink is blocked for the 3 µs add holds global, the three at priority 1 wait for
+        // each other and blink: 60 + 250 + 8000 + 180
+        assert_eq!(
+            responses(&analysis),
+            [
+                ("fancy", 8490, 0, Some(100_000)),
+                ("blink", 183, 3, Some(1_000_000)),
+                ("add", 8490, 0, Some(2_000_000)),
+                ("dump", 8490, 0, Some(10_000_000)),
+            ]
+        );
+        // fancy comes with blink
+        assert_eq!(analysis.rows[0].arrivals, [Arrival::Every(1_000_000)]);
+        assert!(analysis.rows.iter().all(|row| row.meets_deadline() == Some(true)));
+    }
+
+    #[test]
+    fn wcet_file() {
+        let text = "# task wcet\nblink 120us # comment\nbutton 15 50ms\nx 1.5ms - 2s\nlock a g 4us\n";
+        let wcets = parse_wcets(text).unwrap();
+        assert_eq!(wcets.tasks["blink"].wcet, 120);
+        assert_eq!((wcets.tasks["button"].wcet, wcets.tasks["button"].period), (15, Some(50_000)));
+        let x = &wcets.tasks["x"];
+        assert_eq!((x.wcet, x.period, x.deadline), (1500, None, Some(2_000_000)));
+        assert_eq!(wcets.locks[&("a".to_string(), "g".to_string())], 4);
+        let usage = "line 1: expected `task wcet [period [deadline]]` or `lock task resource time`";
+        assert_eq!(parse_wcets("blink\n").unwrap_err(), usage);
+        assert_eq!(parse_wcets("\nblink 1h\n").unwrap_err(), "line 2: not a time");
+        assert_eq!(micros("-1us"), None);
+    }
+}
@@ -0,0 +1,284 @@
+//! Chrome trace events from a dump
+//!
+//! Every task and interrupt handler gets a track of its own, so preemption shows as one track
+//! stopping while another runs. Locks are slices inside the track of whoever took them, spawns
+//! are instants and marks are counters. Perfetto and chrome://tracing read the JSON as it is.
+
+use std::fmt::Write;
+
+use crate::trace_format::{Dump, Event, Kind};
+
+/// Track of what runs outside of any task, `idle` and `init`
+const IDLE: usize = 0;
+
+/// An event with its time since the first one in the dump
+pub struct Timed {
+    pub cycles: u64,
+    pub event: Event,
+}
+
+/// The events of a dump on one time line; the 32 bit cycle counter wraps, which is undone by
+/// assuming less than a wrap between two events. Events of unknown kinds are left out and
+/// counted.
+pub fn timeline(dump: &Dump) -> (Vec<Timed>, usize) {
+    let mut timed = Vec::new();
+    let mut unknown = 0;
+    let mut last: Option<u32> = None;
+    let mut cycles: u64 = 0;
+    for event in dump.events() {
+        let event = match event {
+            Some(event) => event,
+            None => {
+                unknown += 1;
+                continue;
+            }
+        };
+        if let Some(last) = last {
+            cycles += event.timestamp.wrapping_sub(last) as u64;
+        }
+        last = Some(event.timestamp);
+        timed.push(Timed { cycles, event });
+    }
+    (timed, unknown)
+}
+
+/// Name of id `id`, or its number when the dump has no name for it
+pub fn name(names: &[&str], id: u8) -> String {
+    match names.get(id as usize) {
+        Some(name) => name.to_string(),
+        None => format!("#{}", id),
+    }
+}
+
+/// The JSON of a Chrome trace (the "JSON Object Format")
+pub fn convert(dump: &Dump) -> String {
+    let names: Vec<&str> = dump.names().collect();
+    let (timeline, _) = timeline(dump);
+    let micros = |cycles: u64| cycles as f64 * 1e6 / dump.header.clock_hz.max(1) as f64;
+    let end = timeline.last().map_or(0, |timed| timed.cycles);
+
+    let mut events: Vec<String> = Vec::new();
+    let mut tracks = vec![IDLE];
+    // tasks and handlers that are running with their category, the last one entered on top
+    let mut running: Vec<(usize, &str)> = Vec::new();
+    // locks held: track and resource
+    let mut locks: Vec<(usize, u8)> = Vec::new();
+
+    for Timed { cycles, event } in &timeline {
+        let ts = micros(*cycles);
+        let current = running.last().map_or(IDLE, |&(track, _)| track);
+        let name = name(&names, event.id);
+        match event.kind {
+            Kind::Enter | Kind::IsrEnter => {
+                let track = event.id as usize + 1;
+                if !tracks.contains(&track) {
+                    tracks.push(track);
+                }
+                running.push((track, category(event.kind)));
+                events.push(slice('B', &name, category(event.kind), ts, track, ""));
+            }
+            Kind::Exit | Kind::IsrExit => {
+                let track = event.id as usize + 1;
+                if !tracks.contains(&track) {
+                    tracks.push(track);
+                }
+                match running.iter().rposition(|&(running, _)| running == track) {
+                    Some(index) => {
+                        running.remove(index);
+                    }
+                    // it started before the oldest event in the dump
+                    None => events.push(slice('B', &name, category(event.kind), 0.0, track, "")),
+                }
+                events.push(slice('E', &name, category(event.kind), ts, track, ""));
+            }
+            Kind::Lock => {
+                locks.push((current, event.id));
+                let args = format!("{{\"ceiling\":{}}}", event.arg);
+                events.push(slice('B', &format!("lock {}", name), "lock", ts, current, &args));
+            }
+            Kind::Unlock => {
+                // one that was taken before the dump started is left out
+                if let Some(index) = locks.iter().rposition(|&(_, id)| id == event.id) {
+                    let (track, _) = locks.remove(index);
+                    events.push(slice('E', &format!("lock {}", name), "lock", ts, track, ""));
+                }
+            }
+            Kind::Spawn => events.push(format!(
+                concat!(
+                    "{{\"name\":\"spawn {}\",\"cat\":\"spawn\",\"ph\":\"i\",\"s\":\"t\",",
+                    "\"ts\":{:.3},\"pid\":0,\"tid\":{}}}"
+                ),
+                escape(&name),
+                ts,
+                current
+            )),
+            Kind::Mark => events.push(format!(
+                "{{\"name\":\"{}\",\"ph\":\"C\",\"ts\":{:.3},\"pid\":0,\"args\":{{\"value\":{}}}}}",
+                escape(&name),
+                ts,
+                event.arg
+            )),
+        }
+    }
+
+    // what is still running or held when the dump was taken ends with it
+    let ts = micros(end);
+    for (track, id) in locks.into_iter().rev() {
+        events.push(slice('E', &format!("lock {}", name(&names, id)), "lock", ts, track, ""));
+    }
+    for (track, category) in running.into_iter().rev() {
+        events.push(slice('E', &name(&names, (track - 1) as u8), category, ts, track, ""));
+    }
+
+    let mut json = String::from("{\"displayTimeUnit\":\"ns\",\"traceEvents\":[\n");
+    json.push_str("{\"name\":\"process_name\",\"ph\":\"M\",\"pid\":0,\"args\":{\"name\":\"rtic\"}}");
+    for track in tracks {
+        let name = if track == IDLE { "idle".to_string() } else { name(&names, (track - 1) as u8) };
+        write!(
+            json,
+            ",\n{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":0,\"tid\":{},\"args\":{{\"name\":\"{}\"}}}}",
+            track,
+            escape(&name)
+        )
+        .unwrap();
+        write!(
+            json,
+            ",\n{{\"name\":\"thread_sort_index\",\"ph\":\"M\",\"pid\":0,\"tid\":{},\"args\":{{\"sort_index\":{}}}}}",
+            track, track
+        )
+        .unwrap();
+    }
+    for event in events {
+        json.push_str(",\n");
+        json.push_str(&event);
+    }
+    json.push_str("\n]}\n");
+    json
+}
+
+fn category(kind: Kind) -> &'static str {
+    match kind {
+        Kind::IsrEnter | Kind::IsrExit => "isr",
+        _ => "task",
+    }
+}
+
+/// The start ('B') or end ('E') of a slice; `args` is a JSON object or empty
+fn slice(phase: char, name: &str, category: &str, ts: f64, track: usize, args: &str) -> String {
+    let mut event = format!(
+        "{{\"name\":\"{}\",\"cat\":\"{}\",\"ph\":\"{}\",\"ts\":{:.3},\"pid\":0,\"tid\":{}",
+        escape(name),
+        category,
+        phase,
+        ts,
+        track
+    );
+    if !args.is_empty() {
+        write!(event, ",\"args\":{}", args).unwrap();
+    }
+    event.push('}');
+    event
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => write!(escaped, "\\u{:04x}", c as u32).unwrap(),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace_format::Ring;
+
+    /// `blink` preempting `add` after EXTI0 spawned it, at 1 MHz across a wrap of the counter
+    const BLINK: &str = include_str!("../fixtures/blink.log");
+
+    fn dump_of(clock_hz: u32, names: &[&str], events: &[Event]) -> Vec<u8> {
+        let mut ring = Ring::<16>::new();
+        events.iter().for_each(|&event| ring.push(event));
+        let mut bytes = Vec::new();
+        ring.dump(clock_hz, names, |piece| {
+            bytes.extend_from_slice(piece);
+            Ok::<(), ()>(())
+        })
+        .unwrap();
+        bytes
+    }
+
+    #[test]
+    fn fixture() {
+        let bytes = crate::from_log(BLINK).unwrap();
+        let json = convert(&Dump::parse(&bytes).unwrap());
+        let expected = [
+            r#"{"displayTimeUnit":"ns","traceEvents":["#,
+            r#"{"name":"process_name","ph":"M","pid":0,"args":{"name":"rtic"}},"#,
+            r#"{"name":"thread_name","ph":"M","pid":0,"tid":0,"args":{"name":"idle"}},"#,
+            r#"{"name":"thread_sort_index","ph":"M","pid":0,"tid":0,"args":{"sort_index":0}},"#,
+            r#"{"name":"thread_name","ph":"M","pid":0,"tid":2,"args":{"name":"add"}},"#,
+            r#"{"name":"thread_sort_index","ph":"M","pid":0,"tid":2,"args":{"sort_index":2}},"#,
+            r#"{"name":"thread_name","ph":"M","pid":0,"tid":3,"args":{"name":"EXTI0"}},"#,
+            r#"{"name":"thread_sort_index","ph":"M","pid":0,"tid":3,"args":{"sort_index":3}},"#,
+            r#"{"name":"thread_name","ph":"M","pid":0,"tid":1,"args":{"name":"blink"}},"#,
+            r#"{"name":"thread_sort_index","ph":"M","pid":0,"tid":1,"args":{"sort_index":1}},"#,
+            r#"{"name":"add","cat":"task","ph":"B","ts":0.000,"pid":0,"tid":2},"#,
+            r#"{"name":"lock shared","cat":"lock","ph":"B","ts":128.000,"pid":0,"tid":2,"args":{"ceiling":2}},"#,
+            r#"{"name":"lock shared","cat":"lock","ph":"E","ts":192.000,"pid":0,"tid":2},"#,
+            r#"{"name":"EXTI0","cat":"isr","ph":"B","ts":320.000,"pid":0,"tid":3},"#,
+            r#"{"name":"spawn blink","cat":"spawn","ph":"i","s":"t","ts":352.000,"pid":0,"tid":3},"#,
+            r#"{"name":"EXTI0","cat":"isr","ph":"E","ts":384.000,"pid":0,"tid":3},"#,
+            r#"{"name":"blink","cat":"task","ph":"B","ts":512.000,"pid":0,"tid":1},"#,
+            r#"{"name":"depth","ph":"C","ts":528.000,"pid":0,"args":{"value":42}},"#,
+            r#"{"name":"blink","cat":"task","ph":"E","ts":640.000,"pid":0,"tid":1},"#,
+            r#"{"name":"add","cat":"task","ph":"E","ts":768.000,"pid":0,"tid":2}"#,
+            r#"]}"#,
+        ];
+        assert_eq!(json.lines().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn cut_by_the_dump() {
+        // blink started and a lock was taken before the oldest event, EXTI0 still runs at the end
+        let events = [
+            Event::new(100, Kind::Exit, 0, 0),
+            Event::new(150, Kind::Unlock, 1, 0),
+            Event::new(200, Kind::IsrEnter, 2, 0),
+            Event::new(250, Kind::Lock, 1, 3),
+        ];
+        let bytes = dump_of(1000, &["blink", "shared", "EXTI0"], &events);
+        let json = convert(&Dump::parse(&bytes).unwrap());
+        let expected = [
+            r#"{"name":"blink","cat":"task","ph":"B","ts":0.000,"pid":0,"tid":1},"#,
+            r#"{"name":"blink","cat":"task","ph":"E","ts":0.000,"pid":0,"tid":1},"#,
+            r#"{"name":"EXTI0","cat":"isr","ph":"B","ts":100000.000,"pid":0,"tid":3},"#,
+            r#"{"name":"lock shared","cat":"lock","ph":"B","ts":150000.000,"pid":0,"tid":3,"args":{"ceiling":3}},"#,
+            r#"{"name":"lock shared","cat":"lock","ph":"E","ts":150000.000,"pid":0,"tid":3},"#,
+            r#"{"name":"EXTI0","cat":"isr","ph":"E","ts":150000.000,"pid":0,"tid":3}"#,
+        ];
+        let lines: Vec<_> = json.lines().filter(|line| line.contains("\"ts\"")).collect();
+        assert_eq!(lines, expected);
+    }
+
+    #[test]
+    fn timeline_and_names() {
+        let mut bytes = dump_of(1000, &["a\"b"], &[Event::new(u32::MAX, Kind::Spawn, 1, 0)]);
+        // one more, of a kind from a later version
+        bytes[16] = 2;
+        bytes.extend_from_slice(&[5, 0, 0, 0, 99, 0, 0, 0]);
+        let dump = Dump::parse(&bytes).unwrap();
+        let (timeline, unknown) = timeline(&dump);
+        assert_eq!((timeline.len(), unknown), (1, 1));
+        assert_eq!(name(&["a"], 0), "a");
+        assert_eq!(name(&["a"], 1), "#1");
+        assert_eq!(escape("a\"b\\c\n"), "a\\\"b\\\\c\\u000a");
+        assert!(convert(&dump).contains(r#"{"name":"thread_name","ph":"M","pid":0,"tid":0,"args":{"name":"idle"}}"#));
+        assert!(convert(&dump).contains(r#""name":"spawn #1""#));
+    }
+}
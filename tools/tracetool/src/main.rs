@@ -0,0 +1,188 @@
+//! Reads the task traces of `rtic_stm32/src/trace.rs`
+//!
+//! ```text
+//! tracetool show <dump>                   every event, one a line
+//! tracetool chrome <dump> [<file.json>]   Chrome trace events, for Perfetto or chrome://tracing
+//! ```
+//!
+//! A dump is the bytes `trace::dump_serial` sent over a UART, or a log with the `trace <hex>`
+//! lines of `trace::dump_defmt` in it (what probe-run prints); with several dumps in a log the
+//! last one is read. `chrome` writes to stdout without a file. Open the JSON on
+//! <https://ui.perfetto.dev>.
+
+mod chrome;
+
+use std::env;
+use std::fs;
+use std::process::ExitCode;
+
+// The dump layout is shared with the firmware
+#[path = "../../../rtic_stm32/src/trace_format.rs"]
+#[allow(dead_code)]
+mod trace_format;
+
+use trace_format::{Dump, Kind, MAGIC};
+
+const USAGE: &str = "usage: tracetool show <dump> | chrome <dump> [<file.json>]";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        ["show", path] => show(path),
+        ["chrome", path] => chrome(path, None),
+        ["chrome", path, json] => chrome(path, Some(json)),
+        _ => Err(USAGE.to_string()),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("tracetool: {}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn show(path: &str) -> Result<(), String> {
+    let bytes = load(path)?;
+    let dump = parse(&bytes)?;
+    let names: Vec<&str> = dump.names().collect();
+    let (timeline, unknown) = chrome::timeline(&dump);
+    let clock_hz = dump.header.clock_hz.max(1) as f64;
+
+    println!(
+        "{} events at {} Hz, {} lost before the dump, {} of unknown kinds",
+        timeline.len(),
+        dump.header.clock_hz,
+        dump.header.lost,
+        unknown
+    );
+    for timed in &timeline {
+        let event = &timed.event;
+        let name = chrome::name(&names, event.id);
+        let what = match event.kind {
+            Kind::Enter => format!("enter {}", name),
+            Kind::Exit => format!("exit {}", name),
+            Kind::Spawn => format!("spawn {}", name),
+            Kind::Lock => format!("lock {} (ceiling {})", name, event.arg),
+            Kind::Unlock => format!("unlock {}", name),
+            Kind::IsrEnter => format!("isr enter {}", name),
+            Kind::IsrExit => format!("isr exit {}", name),
+            Kind::Mark => format!("mark {} = {}", name, event.arg),
+        };
+        println!("{:12.3} us  {}", timed.cycles as f64 * 1e6 / clock_hz, what);
+    }
+    Ok(())
+}
+
+fn chrome(path: &str, json: Option<&str>) -> Result<(), String> {
+    let bytes = load(path)?;
+    let dump = parse(&bytes)?;
+    let trace = chrome::convert(&dump);
+    match json {
+        Some(json) => fs::write(json, trace).map_err(|error| format!("{}: {}", json, error)),
+        None => {
+            print!("{}", trace);
+            Ok(())
+        }
+    }
+}
+
+fn parse(bytes: &[u8]) -> Result<Dump<'_>, String> {
+    Dump::parse(bytes).ok_or_else(|| "not a trace dump, or it was cut short".to_string())
+}
+
+/// The bytes of the dump, straight from a binary file or out of the lines of a log
+fn load(path: &str) -> Result<Vec<u8>, String> {
+    let data = fs::read(path).map_err(|error| format!("{}: {}", path, error))?;
+    if data.starts_with(&MAGIC.to_le_bytes()) {
+        return Ok(data);
+    }
+    from_log(&String::from_utf8_lossy(&data)).ok_or_else(|| format!("{}: no trace in it", path))
+}
+
+/// The last dump in a log, from `trace <hex>` lines up to `trace end`
+fn from_log(log: &str) -> Option<Vec<u8>> {
+    let mut dumps: Vec<Vec<u8>> = Vec::new();
+    let mut current: Option<Vec<u8>> = None;
+    for line in log.lines() {
+        let mut words = line.split_whitespace().skip_while(|&word| word != "trace").skip(1);
+        match words.next() {
+            Some("end") => dumps.extend(current.take()),
+            Some(hex) => {
+                let bytes = match decode_hex(hex) {
+                    Some(bytes) => bytes,
+                    None => continue,
+                };
+                // a dump starts with the magic, anything before is from an unfinished one
+                if bytes.starts_with(&MAGIC.to_le_bytes()) {
+                    current = Some(bytes);
+                } else if let Some(current) = current.as_mut() {
+                    current.extend(bytes);
+                }
+            }
+            None => {}
+        }
+    }
+    // a log that was stopped in the middle of a dump
+    dumps.extend(current);
+    dumps.pop()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [high, low] => Some((digit(*high)? << 4) | digit(*low)?),
+            _ => None,
+        })
+        .collect()
+}
+
+fn digit(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|digit| digit as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLINK: &str = include_str!("../fixtures/blink.log");
+
+    #[test]
+    fn dump_from_a_log() {
+        let bytes = from_log(BLINK).unwrap();
+        assert_eq!(bytes.len(), 20 + 29 + 10 * 8);
+        let dump = parse(&bytes).unwrap();
+        assert_eq!(dump.header.clock_hz, 1_000_000);
+        assert_eq!(dump.names().collect::<Vec<_>>(), ["blink", "add", "EXTI0", "shared", "depth"]);
+        assert_eq!(dump.events().flatten().count(), 10);
+    }
+
+    #[test]
+    fn the_last_dump() {
+        let dump = |magic_end: &str, rest: &str| format!("1.0 trace 54524345{}\n1.1 trace {}\n", magic_end, rest);
+        let log = [
+            "0.1 INFO  trace started\n".to_string(),
+            dump("01", "00"),
+            "1.2 trace end\n".to_string(),
+            dump("02", "00"),
+            "1.3 trace end\n".to_string(),
+            // a dump that was never finished is the last one, after a line that is not hex
+            dump("03", "zz"),
+        ]
+        .concat();
+        assert_eq!(from_log(&log), Some(vec![0x54, 0x52, 0x43, 0x45, 3]));
+        let finished = &log[..log.find("1.0 trace 5452434503").unwrap()];
+        assert_eq!(from_log(finished), Some(vec![0x54, 0x52, 0x43, 0x45, 2, 0]));
+        // hex before any magic is from a dump that started before the log
+        assert_eq!(from_log("trace 0102\ntrace end\n"), None);
+        assert!(parse(&[0x54, 0x52, 0x43, 0x45, 1]).is_err());
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(decode_hex("00ff7A"), Some(vec![0x00, 0xFF, 0x7A]));
+        assert_eq!(decode_hex("abc"), None);
+        assert_eq!(decode_hex("0g"), None);
+    }
+}